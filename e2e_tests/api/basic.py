@@ -6,19 +6,33 @@ import time
 
 port = 4242
 
-server = subprocess.Popen(["cargo", "run", "--features=mock_system", "--", "--bind-port", str(port), "--pg-uri=x"])
+server = subprocess.Popen(["cargo", "run", "--features=mock_system", "--", "--bind-port", str(port), "--pg-uri=x", "--token-key-file=/tmp/selfhost-dashboard-token-key", "--setup-token-file=/tmp/selfhost-dashboard-setup-token"])
 
 try:
     time.sleep(3)
 
     uri = "http://localhost:" + str(port) + "/dashboard"
 
+    # Sessions of scripts submit the CSRF token in a header
+    def new_session():
+        session = requests.Session()
+        session.get(uri + "/login")
+        session.headers["X-CSRF-Token"] = session.cookies["csrf_token"]
+        return session
+
     entered = requests.get(uri)
     assert entered.status_code == 200
-    assert entered.url.endswith("/dashboard/login#uninitialized=true")
+    assert entered.url.endswith("/dashboard/setup")
 
-    session = requests.Session()
-    assert session.post(uri + "/login", data = {"username": "admin", "password": "123"}).status_code == 200
+    assert requests.post(uri + "/login", data = {"username": "admin", "password": "123"}).url.endswith("#failure=input")
+    session = new_session()
+    # Logging in as admin no longer creates the account
+    assert session.post(uri + "/login", data = {"username": "admin", "password": "123"}).url.endswith("#failure=credentials")
+    assert session.post(uri + "/setup", data = {"setup_token": "00" * 16, "username": "admin", "password": "123"}).url.endswith("#failure=token")
+    with open("/tmp/selfhost-dashboard-setup-token") as token_file:
+        setup_token = token_file.read().strip()
+    assert session.post(uri + "/setup", data = {"setup_token": setup_token, "username": "admin", "password": "123"}).status_code == 200
+    assert session.get(uri + "/setup").url.endswith("/dashboard/login")
     apps_resp = session.get(uri + "/apps")
     assert apps_resp.status_code == 200
     apps = apps_resp.json()
@@ -27,7 +41,53 @@ try:
     thunderhub = session.get(uri + "/open-app/thunderhub-mainnet")
     assert thunderhub.status_code == 404
     assert thunderhub.url.endswith("/thunderhub?token=this_is_a_test")
-    assert session.get(uri + "/logout").status_code == 200
+    sessions_resp = session.get(uri + "/sessions")
+    assert sessions_resp.status_code == 200
+    sessions = sessions_resp.json()["sessions"]
+    assert len(sessions) == 1
+    assert sessions[0]["current"]
+    assert session.post(uri + "/admin/users", data = {"username": "satoshi", "password": "456"}).status_code == 200
+    users = session.get(uri + "/admin/users").json()["users"]
+    assert [user["name"] for user in users] == ["admin", "satoshi"]
+    satoshi = new_session()
+    assert satoshi.post(uri + "/login", data = {"username": "satoshi", "password": "456"}).status_code == 200
+    assert satoshi.get(uri + "/apps").status_code == 200
+    assert satoshi.get(uri + "/admin/users").status_code == 403
+    assert session.post(uri + "/admin/users/satoshi/groups", data = {"groups": "admins,family"}).status_code == 200
+    assert satoshi.get(uri + "/admin/users").status_code == 200
+    assert session.post(uri + "/admin/users/satoshi/groups", data = {"groups": "family"}).status_code == 200
+    assert satoshi.get(uri + "/admin/users").status_code == 403
+    assert satoshi.get(uri + "/auth/verify").status_code == 403
+    assert satoshi.get(uri + "/auth/verify", headers = {"X-Original-URI": "/unknown"}).status_code == 403
+    assert satoshi.get(uri + "/auth/verify/thunderhub-mainnet").status_code == 403
+    assert satoshi.get(uri + "/auth/verify", headers = {"X-Original-URI": "/thunderhub/channels?x=1"}).status_code == 403
+    assert satoshi.get(uri + "/auth/verify", headers = {"X-Original-URI": "//%74hunderhub/../thunderhub/channels"}).status_code == 403
+    verified = session.get(uri + "/auth/verify", headers = {"X-Forwarded-Uri": "/thunderhub/channels"})
+    assert verified.status_code == 200
+    assert verified.headers["X-Remote-User"] == "admin"
+    assert verified.headers["X-Remote-Groups"] == "admins"
+    unauthenticated = requests.get(uri + "/auth/verify", headers = {"X-Original-URI": "/thunderhub/channels"}, allow_redirects = False)
+    assert unauthenticated.status_code == 401
+    assert unauthenticated.headers["Location"] == "/dashboard/login"
+    assert session.post(uri + "/admin/users/admin/groups", data = {"groups": ""}).status_code == 403
+    assert session.post(uri + "/admin/users/satoshi/disable").status_code == 200
+    assert satoshi.get(uri + "/apps").status_code == 401
+    assert session.post(uri + "/admin/users/satoshi/delete").status_code == 200
+    invite = session.post(uri + "/admin/invites", data = {"groups": "family"}).json()
+    assert [invite["id"] for invite in session.get(uri + "/admin/invites").json()["invites"]] == [invite["id"]]
+    invitee = new_session()
+    assert invitee.get("http://localhost:" + str(port) + invite["path"]).status_code == 200
+    assert invitee.post("http://localhost:" + str(port) + invite["path"], data = {"username": "admin", "password": "789"}).url.endswith("#failure=exists")
+    assert invitee.post("http://localhost:" + str(port) + invite["path"], data = {"username": "dorian", "password": "789"}).status_code == 200
+    assert invitee.get(uri + "/apps").status_code == 200
+    assert invitee.get("http://localhost:" + str(port) + invite["path"]).url.endswith("#failure=invite")
+    assert session.get(uri + "/admin/invites").json()["invites"] == []
+    invite = session.post(uri + "/admin/invites").json()
+    assert session.post(uri + "/admin/invites/" + invite["id"] + "/revoke").status_code == 200
+    assert requests.get("http://localhost:" + str(port) + invite["path"]).url.endswith("#failure=invite")
+    assert session.post(uri + "/logout", headers = {"Origin": "https://evil.example.com"}).status_code == 403
+    assert session.get(uri + "/logout").status_code == 404
+    assert session.post(uri + "/logout").status_code == 200
     bad_password = session.post(uri + "/login", data = {"username": "admin", "password": "567"})
     assert bad_password.status_code == 200
     assert bad_password.url.endswith("#failure=credentials")
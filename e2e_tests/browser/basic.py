@@ -12,7 +12,7 @@ def eprint(msg):
 
 port = 4242
 
-server = subprocess.Popen(["cargo", "run", "--features=mock_system", "--", "--bind-port", str(port), "--pg-uri=x"])
+server = subprocess.Popen(["cargo", "run", "--features=mock_system", "--", "--bind-port", str(port), "--pg-uri=x", "--token-key-file=/tmp/selfhost-dashboard-token-key", "--setup-token-file=/tmp/selfhost-dashboard-setup-token"])
 
 try:
     sleep(3)
@@ -30,12 +30,15 @@ try:
 
     eprint("Opening dashboard")
     driver.get(uri)
-    eprint("Registering admin")
-    driver.find_element_by_name("username").send_keys("admin")
+    eprint("Setting up admin")
+    with open("/tmp/selfhost-dashboard-setup-token") as token_file:
+        driver.find_element_by_name("setup_token").send_keys(token_file.read().strip())
     driver.find_element_by_name("password").send_keys("123")
-    driver.find_element_by_name("password").send_keys(Keys.RETURN)
+    driver.find_element_by_name("re-password").send_keys("123")
+    driver.find_element_by_name("re-password").send_keys(Keys.TAB)
+    driver.find_element_by_id("fort-loginBtnMain").click()
     eprint("Logging out")
-    driver.get(uri + "/logout")
+    driver.execute_script("document.getElementById('fort-logoutForm').submit()")
     eprint("Logging in")
     driver.find_element_by_name("username").send_keys("admin")
     driver.find_element_by_name("password").send_keys("123")
@@ -0,0 +1,942 @@
+//! Execution of dynamic entry points
+//!
+//! The entry point is a program run as the system user of the app in a sandbox (see `sandbox`)
+//! with the name of the user as its first argument. The protocol it speaks is declared by `entry_point_protocol` in
+//! `meta.toml`:
+//!
+//! * Version 1 (default): the program prints the path to open on the first line of its standard
+//!   output or exits with code 1 and the reason on the first line of standard error to reject
+//!   the user.
+//! * Version 2: the program gets details of the request as a JSON object on its standard input
+//!   and answers with a JSON object on its standard output. Leaving out `url` rejects the user,
+//!   any non-zero exit code is a failure.
+//!
+//! Apps with an HTTP entry point are asked by calling a local endpoint with the same context as
+//! version 2 entry points get, the answer is the same JSON object too. A redirect or `403` are
+//! understood as well.
+//!
+//! All pipes are serviced concurrently while waiting for the process so that it can't block on a
+//! full pipe. The process gets its own process group which is killed if it doesn't finish in
+//! time, together with anything it spawned.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncRead;
+use tokio::sync::Semaphore;
+use crate::user;
+use crate::webserver::Request;
+use super::config::CallbackMethod;
+use super::sandbox::{ResourceLimits, Sandbox};
+use super::OpenError;
+
+/// Maximum length of the line read from the outputs
+const MAX_LINE: usize = 1024;
+/// Maximum length of the JSON response of version 2 entry points
+const MAX_RESPONSE: usize = 64 * 1024;
+
+/// Cookies of the dashboard itself, the entry point must not overwrite them
+const RESERVED_COOKIES: &[&str] = &[
+    "user_name",
+    "auth_token",
+    crate::login::FORWARD_AUTH_COOKIE,
+    "login_challenge",
+    crate::webserver::CSRF_COOKIE,
+    crate::login::oidc::PENDING_REQUEST_COOKIE,
+    crate::login::lnurl::SECRET_COOKIE,
+];
+
+/// Headers controlled by the dashboard or the connection, lowercase
+const FORBIDDEN_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "content-type",
+    "keep-alive",
+    "location",
+    "set-cookie",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Version of the protocol between the dashboard and the entry point
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Deserialize)]
+#[serde(try_from = "u64")]
+pub enum Protocol {
+    V1,
+    V2,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::V1
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unsupported entry point protocol version {0}")]
+pub struct UnsupportedProtocol(u64);
+
+impl TryFrom<u64> for Protocol {
+    type Error = UnsupportedProtocol;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Protocol::V1),
+            2 => Ok(Protocol::V2),
+            _ => Err(UnsupportedProtocol(value)),
+        }
+    }
+}
+
+/// Details of the request opening the app, only passed to version 2 entry points
+#[derive(Default)]
+pub struct RequestInfo {
+    pub client_address: Option<IpAddr>,
+    /// Host requested by the browser, `X-Forwarded-Host` is preferred over `Host`
+    pub host: Option<String>,
+    /// The first language from `Accept-Language`
+    pub locale: Option<String>,
+    /// Deep link into the app requested using the `path` query argument
+    pub path: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn from_request<R: Request>(request: &R, client_address: Option<IpAddr>) -> Self {
+        RequestInfo {
+            client_address,
+            host: request.get_header("x-forwarded-host").or_else(|| request.get_header("host")).map(ToOwned::to_owned),
+            locale: request.get_header("accept-language").and_then(preferred_locale),
+            path: request.query_arg("path").map(ToOwned::to_owned),
+        }
+    }
+}
+
+fn preferred_locale(accept_language: &str) -> Option<String> {
+    let tag = accept_language.split(',').next()?.split(';').next()?.trim();
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+    Some(tag.to_owned())
+}
+
+/// What version 2 entry points get on standard input
+#[derive(serde_derive::Serialize)]
+struct Input<'a> {
+    version: u64,
+    user: &'a str,
+    groups: Vec<&'a str>,
+    client_address: Option<IpAddr>,
+    host: Option<&'a str>,
+    locale: Option<&'a str>,
+    path: Option<&'a str>,
+}
+
+impl<'a> Input<'a> {
+    fn new(user: &'a user::Authenticated, request: &'a RequestInfo) -> Self {
+        Input {
+            version: 2,
+            user: user.name(),
+            groups: user.groups().iter().map(|group| &**group).collect(),
+            client_address: request.client_address,
+            host: request.host.as_ref().map(String::as_str),
+            locale: request.locale.as_ref().map(String::as_str),
+            path: request.path.as_ref().map(String::as_str),
+        }
+    }
+
+    /// Appends the context to the URL for `GET` requests, the groups are separated by commas
+    fn append_to(&self, url: &mut url::Url) {
+        let mut query = url.query_pairs_mut();
+        query
+            .append_pair("user", self.user)
+            .append_pair("groups", &self.groups.join(","));
+        if let Some(client_address) = self.client_address {
+            query.append_pair("client_address", &client_address.to_string());
+        }
+        for (key, value) in [("host", self.host), ("locale", self.locale), ("path", self.path)].iter() {
+            if let Some(value) = value {
+                query.append_pair(key, value);
+            }
+        }
+    }
+}
+
+/// What version 2 entry points print on standard output
+#[derive(serde_derive::Deserialize)]
+struct Output {
+    /// Appended to the root path of the app, the user is rejected if it's missing
+    url: Option<String>,
+    #[serde(default)]
+    cookies: Vec<OutputCookie>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Seconds since UNIX epoch, the default expiry of the cookies
+    expires: Option<u64>,
+    /// Shown to the user if rejected
+    message: Option<String>,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct OutputCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<u64>,
+}
+
+/// Cookie set by the entry point, the attributes are the same as for cookies of the dashboard
+#[derive(Debug)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// The root path of the app is used if missing
+    pub path: Option<String>,
+    pub max_age: Option<u64>,
+}
+
+impl Cookie {
+    /// Formats the value of the `Set-Cookie` header
+    pub fn header_value(&self, default_path: &str) -> String {
+        let path = self.path.as_ref().map(String::as_str).unwrap_or(default_path);
+        match self.max_age {
+            Some(max_age) => format!("{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax", self.name, self.value, path, max_age),
+            None => format!("{}={}; Path={}; HttpOnly; SameSite=Lax", self.name, self.value, path),
+        }
+    }
+}
+
+/// Successful result of the entry point
+#[derive(Debug)]
+pub struct Response {
+    /// Appended to the root path of the app
+    pub path: String,
+    pub cookies: Vec<Cookie>,
+    /// Extra headers of the redirect, names are lowercase
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResponseError {
+    #[error("the response is longer than {} bytes", MAX_RESPONSE)]
+    TooLong,
+    #[error("failed to parse the response")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid URL {0:?}")]
+    InvalidUrl(String),
+    #[error("invalid cookie {0:?}")]
+    InvalidCookie(String),
+    #[error("cookie {0} belongs to the dashboard")]
+    ReservedCookie(String),
+    #[error("invalid header {0:?}")]
+    InvalidHeader(String),
+    #[error("header {0} can't be set by the entry point")]
+    ForbiddenHeader(String),
+    #[error("expiration time {0} is out of range")]
+    InvalidExpiration(u64),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CallbackError {
+    #[error("invalid URL {0}")]
+    InvalidUrl(String),
+    #[error("the request failed")]
+    Request(#[from] hyper::Error),
+    #[error("unexpected status {0}")]
+    Status(u16),
+    #[error("missing or invalid Location of the redirect")]
+    InvalidRedirect,
+}
+
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+fn is_cookie_value_char(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"\",;\\".contains(&byte)
+}
+
+fn is_header_value_char(byte: u8) -> bool {
+    byte == b'\t' || (b' '..=b'~').contains(&byte)
+}
+
+fn check_cookie(cookie: OutputCookie, expires_after_seconds: Option<u64>) -> Result<Cookie, ResponseError> {
+    let valid = !cookie.name.is_empty()
+        && cookie.name.bytes().all(is_token_char)
+        && cookie.value.bytes().all(is_cookie_value_char)
+        && cookie.path.as_ref().map(|path| path.bytes().all(|byte| byte.is_ascii_graphic() && byte != b';')).unwrap_or(true);
+    if !valid {
+        return Err(ResponseError::InvalidCookie(cookie.name));
+    }
+    if RESERVED_COOKIES.contains(&&*cookie.name) {
+        return Err(ResponseError::ReservedCookie(cookie.name));
+    }
+
+    Ok(Cookie {
+        name: cookie.name,
+        value: cookie.value,
+        path: cookie.path,
+        max_age: cookie.max_age.or(expires_after_seconds),
+    })
+}
+
+fn check_header(name: String, value: String) -> Result<(String, String), ResponseError> {
+    if name.is_empty() || !name.bytes().all(is_token_char) || !value.bytes().all(is_header_value_char) {
+        return Err(ResponseError::InvalidHeader(name));
+    }
+    let name = name.to_ascii_lowercase();
+    if FORBIDDEN_HEADERS.contains(&&*name) {
+        return Err(ResponseError::ForbiddenHeader(name));
+    }
+    Ok((name, value))
+}
+
+/// Validates the output, `Ok(Err(message))` means the user was rejected
+fn check_output(output: Output, now: SystemTime) -> Result<Result<Response, Option<String>>, ResponseError> {
+    let path = match output.url {
+        Some(url) if url.bytes().all(|byte| byte.is_ascii_graphic()) => url,
+        Some(url) => return Err(ResponseError::InvalidUrl(url)),
+        None => return Ok(Err(output.message)),
+    };
+
+    let expires_after_seconds = output
+        .expires
+        .map(|expires| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(expires)).ok_or(ResponseError::InvalidExpiration(expires)))
+        .transpose()?
+        .map(|expires| expires.duration_since(now).map(|duration| duration.as_secs()).unwrap_or(0));
+    let cookies = output
+        .cookies
+        .into_iter()
+        .map(|cookie| check_cookie(cookie, expires_after_seconds))
+        .collect::<Result<_, _>>()?;
+    let headers = output
+        .headers
+        .into_iter()
+        .map(|(name, value)| check_header(name, value))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Ok(Response { path, cookies, headers, }))
+}
+
+pub struct Limits {
+    /// Used by apps that don't set their own timeout
+    pub default_timeout: Duration,
+    /// Maximum number of entry point processes running at the same time
+    pub max_processes: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("the maximum number of entry point processes must not be zero, no entry point could run")]
+pub struct NoProcesses;
+
+impl Limits {
+    /// Rejects limits that would make every request wait for its timeout
+    pub fn check(&self) -> Result<(), NoProcesses> {
+        if self.max_processes == 0 {
+            return Err(NoProcesses);
+        }
+        Ok(())
+    }
+}
+
+/// Runs entry points of all apps, shared by all requests
+pub struct Runner {
+    slots: Semaphore,
+    default_timeout: Duration,
+    http_client: hyper::Client<hyper::client::HttpConnector>,
+}
+
+impl Runner {
+    pub fn new(limits: Limits) -> Self {
+        Runner {
+            slots: Semaphore::new(limits.max_processes),
+            default_timeout: limits.default_timeout,
+            http_client: hyper::Client::new(),
+        }
+    }
+
+    /// Calls the HTTP entry point of the app and returns its validated response
+    ///
+    /// The process limit doesn't apply, no process is spawned.
+    pub async fn call(&self, app_name: &str, url: &str, method: CallbackMethod, timeout: Option<Duration>, user: &user::Authenticated, request: &RequestInfo) -> Result<Response, OpenError> {
+        let failed = |error| OpenError::HttpEntryPointFailed { app: app_name.to_owned(), error, };
+        let input = Input::new(user, request);
+        let callback_url = url::Url::parse(url).map_err(|_| failed(CallbackError::InvalidUrl(url.to_owned())))?;
+
+        let http_request = match method {
+            CallbackMethod::Get => {
+                let mut url = callback_url.clone();
+                input.append_to(&mut url);
+                http::Request::get(url.as_str()).body(hyper::Body::empty())
+            },
+            CallbackMethod::Post => {
+                let body = serde_json::to_vec(&input).expect("Serialization to vec should never fail");
+                http::Request::post(url)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(body.into())
+            },
+        };
+        let http_request = http_request.map_err(|_| failed(CallbackError::InvalidUrl(url.to_owned())))?;
+
+        let timeout = timeout.unwrap_or(self.default_timeout);
+        let response = tokio::time::timeout(timeout, async move {
+            let response = self.http_client.request(http_request).await?;
+            let (parts, body) = response.into_parts();
+            let body = read_body(body, MAX_RESPONSE + 1).await?;
+            Ok::<_, hyper::Error>((parts, body))
+        }).await;
+
+        let (parts, body) = response
+            .map_err(|_| OpenError::EntryPointTimedOut { app: app_name.to_owned(), timeout, })?
+            .map_err(|error| failed(error.into()))?;
+        finish_http(parts.status, &parts.headers, body, &callback_url, app_name, SystemTime::now())
+    }
+
+    /// Runs the entry point of the app and returns its validated response
+    ///
+    /// Waiting for a free slot and running the process are limited by the timeout separately.
+    pub async fn open(&self, app_name: &str, user: &user::Authenticated, request: &RequestInfo, protocol: Protocol, timeout: Option<Duration>, limits: &ResourceLimits) -> Result<Response, OpenError> {
+        let entry_point_path = format!("{}/{}/open", super::config::DIRS.app_entry_points, app_name);
+        let mut command = std::process::Command::new(&entry_point_path);
+        command.arg(user.name());
+
+        let input = match protocol {
+            Protocol::V1 => Vec::new(),
+            Protocol::V2 => serde_json::to_vec(&Input::new(user, request)).expect("Serialization to vec should never fail"),
+        };
+
+        let owned_app_name = app_name.to_owned();
+        let limits = limits.clone();
+        let sandbox = tokio::task::spawn_blocking(move || Sandbox::prepare(&owned_app_name, limits))
+            .await
+            .map_err(OpenError::TaskJoin)??;
+        sandbox.apply(&mut command, app_name);
+
+        self.run(command, entry_point_path, app_name, protocol, input, timeout.unwrap_or(self.default_timeout)).await
+    }
+
+    async fn run(&self, command: std::process::Command, entry_point_path: String, app_name: &str, protocol: Protocol, input: Vec<u8>, timeout: Duration) -> Result<Response, OpenError> {
+        let _slot = tokio::time::timeout(timeout, self.slots.acquire())
+            .await
+            .map_err(|_| OpenError::TooManyEntryPoints { app: app_name.to_owned(), })?;
+
+        let child = spawn(command, protocol).map_err(|error| OpenError::EntryPointExec { entry_point_path, error, })?;
+        match protocol {
+            Protocol::V1 => {
+                let outputs = finish(child, &[], MAX_LINE + 1, app_name, timeout).await?;
+                finish_v1(outputs, app_name)
+            },
+            Protocol::V2 => {
+                let outputs = finish(child, &input, MAX_RESPONSE + 1, app_name, timeout).await?;
+                finish_v2(outputs, app_name, SystemTime::now())
+            },
+        }
+    }
+}
+
+/// Puts the process into its own process group and connects the standard streams to pipes
+///
+/// Standard input is only connected for protocols that use it.
+fn spawn(mut command: std::process::Command, protocol: Protocol) -> io::Result<tokio::process::Child> {
+    use std::os::unix::process::CommandExt;
+
+    let stdin = match protocol {
+        Protocol::V1 => std::process::Stdio::null(),
+        Protocol::V2 => std::process::Stdio::piped(),
+    };
+
+    command
+        .stdin(stdin)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    // setpgid is async-signal-safe
+    unsafe {
+        command.pre_exec(|| if libc::setpgid(0, 0) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        });
+    }
+
+    tokio::process::Command::from(command)
+        .kill_on_drop(true)
+        .spawn()
+}
+
+/// Writes the input and closes standard input so that the process sees the end of it
+async fn write_input(stdin: Option<tokio::process::ChildStdin>, input: &[u8]) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    if let Some(mut stdin) = stdin {
+        match stdin.write_all(input).await {
+            // the entry point doesn't have to read the input
+            Err(error) if error.kind() == io::ErrorKind::BrokenPipe => (),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads everything so that the process doesn't block but keeps only the first `max` bytes
+async fn read_capped<R: AsyncRead + Unpin>(mut reader: R, max: usize) -> io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut output = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(output);
+        }
+        let remaining = max.saturating_sub(output.len());
+        output.extend_from_slice(&buf[..len.min(remaining)]);
+    }
+}
+
+/// Unlike the outputs of a process the body doesn't have to be read until the end
+async fn read_body(mut body: hyper::Body, max: usize) -> Result<Vec<u8>, hyper::Error> {
+    use futures::StreamExt;
+
+    let mut output = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        let remaining = max.saturating_sub(output.len());
+        output.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+        if output.len() >= max {
+            break;
+        }
+    }
+    Ok(output)
+}
+
+fn first_line(output: io::Result<Vec<u8>>) -> io::Result<String> {
+    use crate::io::BufReadExt;
+
+    (&*output?).read_line_max(MAX_LINE)
+}
+
+struct Outputs {
+    status: std::process::ExitStatus,
+    stdout: io::Result<Vec<u8>>,
+    stderr: io::Result<Vec<u8>>,
+}
+
+/// Feeds the input to the process and collects its outputs
+///
+/// `stdout_max` should be one more byte than allowed so that longer output is detected.
+async fn finish(mut child: tokio::process::Child, input: &[u8], stdout_max: usize, app_name: &str, timeout: Duration) -> Result<Outputs, OpenError> {
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let process_group = child.id();
+
+    let output = tokio::time::timeout(timeout, async move {
+        futures::join!(write_input(stdin, input), read_capped(stdout, stdout_max), read_capped(stderr, MAX_LINE + 1), child)
+    }).await;
+
+    let (written, stdout, stderr, status) = match output {
+        Ok(output) => output,
+        Err(_) => {
+            // The process may have exited already but something it spawned keeps the pipes
+            // open. The process itself is also killed when the child is dropped.
+            unsafe {
+                libc::kill(-(process_group as libc::pid_t), libc::SIGKILL);
+            }
+            return Err(OpenError::EntryPointTimedOut { app: app_name.to_owned(), timeout, });
+        },
+    };
+
+    let status = status.map_err(|error| OpenError::EntryPointWaitFailed { app: app_name.to_owned(), error, })?;
+    written.map_err(|error| OpenError::WritingStdinFailed { app: app_name.to_owned(), error, })?;
+
+    Ok(Outputs { status, stdout, stderr, })
+}
+
+fn failure(exit_code: Option<i32>, stderr_line: io::Result<String>) -> OpenError {
+    match (exit_code, stderr_line) {
+        (Some(exit_code), Ok(message)) => OpenError::EntryPointFailedWithMessage { message, exit_code, },
+        (Some(exit_code), Err(_)) => OpenError::EntryPointFailedWithInvalidMessage { exit_code, },
+        (None, Ok(message)) => OpenError::EntryPointKilledWithMessage { message },
+        (None, Err(_)) => OpenError::EntryPointKilledWithInvalidMessage,
+    }
+}
+
+fn finish_v1(outputs: Outputs, app_name: &str) -> Result<Response, OpenError> {
+    let stdout_line = first_line(outputs.stdout);
+    let stderr_line = first_line(outputs.stderr);
+
+    if !outputs.status.success() {
+        return Err(match (outputs.status.code(), stderr_line) {
+            (Some(1), Ok(message)) => OpenError::RejectedWithMessage(message),
+            (Some(1), Err(_)) => OpenError::RejectedWithInvalidMessage,
+            (exit_code, stderr_line) => failure(exit_code, stderr_line),
+        });
+    }
+
+    let path = stdout_line.map_err(|error| OpenError::ReadingStdoutFailed { app: app_name.to_owned(), error, })?;
+    Ok(Response { path, cookies: Vec::new(), headers: Vec::new(), })
+}
+
+fn finish_v2(outputs: Outputs, app_name: &str, now: SystemTime) -> Result<Response, OpenError> {
+    if !outputs.status.success() {
+        return Err(failure(outputs.status.code(), first_line(outputs.stderr)));
+    }
+
+    let stdout = outputs.stdout.map_err(|error| OpenError::ReadingStdoutFailed { app: app_name.to_owned(), error, })?;
+    parse_response(&stdout, app_name, now)
+}
+
+/// Reduces the `Location` of a redirect to the path and query, which are appended to the root path
+/// of the app
+///
+/// Only absolute paths and URLs with the origin of the callback are accepted, anything else would
+/// send the user to a different host.
+fn redirect_path(location: &str, callback_url: &url::Url) -> Option<String> {
+    if location.is_empty() || !location.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return None;
+    }
+    let url = if location.starts_with('/') && !location.starts_with("//") {
+        callback_url.join(location).ok()?
+    } else {
+        url::Url::parse(location).ok()?
+    };
+    // also catches `/\host` which URL parsers treat as protocol-relative
+    if url.origin() != callback_url.origin() {
+        return None;
+    }
+
+    let mut path = url.path().to_owned();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    Some(path)
+}
+
+fn finish_http(status: http::StatusCode, headers: &http::HeaderMap, body: Vec<u8>, callback_url: &url::Url, app_name: &str, now: SystemTime) -> Result<Response, OpenError> {
+    let failed = |error| OpenError::HttpEntryPointFailed { app: app_name.to_owned(), error, };
+
+    if status.is_redirection() {
+        let path = headers
+            .get(http::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| redirect_path(location, callback_url))
+            .ok_or_else(|| failed(CallbackError::InvalidRedirect))?;
+        return Ok(Response { path, cookies: Vec::new(), headers: Vec::new(), });
+    }
+    if status == http::StatusCode::FORBIDDEN {
+        let message = first_line(Ok(body)).ok().filter(|message| !message.is_empty());
+        return Err(OpenError::Refused { message, });
+    }
+    if !status.is_success() {
+        return Err(failed(CallbackError::Status(status.as_u16())));
+    }
+
+    parse_response(&body, app_name, now)
+}
+
+/// Parses the JSON response which is `MAX_RESPONSE + 1` bytes long at most
+fn parse_response(response: &[u8], app_name: &str, now: SystemTime) -> Result<Response, OpenError> {
+    let invalid = |error| OpenError::InvalidResponse { app: app_name.to_owned(), error, };
+    if response.len() > MAX_RESPONSE {
+        return Err(invalid(ResponseError::TooLong));
+    }
+    let output = serde_json::from_slice::<Output>(response).map_err(|error| invalid(error.into()))?;
+
+    match check_output(output, now).map_err(invalid)? {
+        Ok(response) => Ok(response),
+        Err(message) => Err(OpenError::Refused { message, }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use super::{CallbackError, Limits, Protocol, RequestInfo, Response, ResponseError, Runner};
+    use crate::app::{OpenError, config::CallbackMethod, sandbox::{ResourceLimits, Sandbox}};
+    use crate::user::{self, types::SessionId};
+
+    fn runner(max_processes: usize) -> Runner {
+        Runner::new(Limits {
+            default_timeout: Duration::from_secs(5),
+            max_processes,
+        })
+    }
+
+    fn shell(script: &str) -> std::process::Command {
+        let mut command = std::process::Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    async fn run(runner: &Runner, script: &str, timeout: Duration) -> Result<String, OpenError> {
+        runner.run(shell(script), "/bin/sh".to_owned(), "test", Protocol::V1, Vec::new(), timeout).await.map(|response| response.path)
+    }
+
+    async fn run_v2(runner: &Runner, script: &str, input: &str) -> Result<Response, OpenError> {
+        runner.run(shell(script), "/bin/sh".to_owned(), "test", Protocol::V2, input.into(), Duration::from_secs(5)).await
+    }
+
+    #[test]
+    fn zero_processes() {
+        let limits = |max_processes| Limits { default_timeout: Duration::from_secs(5), max_processes, };
+        assert!(limits(0).check().is_err());
+        assert!(limits(1).check().is_ok());
+    }
+
+    #[test]
+    fn outputs() {
+        let runner = runner(4);
+        let open = |script| tokio_test::block_on(run(&runner, script, Duration::from_secs(5)));
+
+        assert_eq!(open("echo /login; echo ignored").unwrap(), "/login");
+        match open("echo 'not today' >&2; exit 1") {
+            Err(OpenError::RejectedWithMessage(message)) => assert_eq!(message, "not today"),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match open("exit 2") {
+            Err(OpenError::EntryPointFailedWithMessage { exit_code: 2, .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // would deadlock if the outputs were read one after another
+        assert_eq!(open("head -c 1000000 /dev/zero >&2; echo /ok").unwrap(), "/ok");
+        match open("head -c 2000 /dev/zero | tr '\\0' a") {
+            Err(OpenError::ReadingStdoutFailed { .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn protocol_v2() {
+        let runner = runner(4);
+        let open = |script, input| tokio_test::block_on(run_v2(&runner, script, input));
+
+        // the input is echoed back as the URL
+        let response = open("printf '{\"url\": \"/%s\"}' \"$(cat)\"", "hello").unwrap();
+        assert_eq!(response.path, "/hello");
+
+        let response = open("echo '{\"url\": \"/\", \"cookies\": [{\"name\": \"session\", \"value\": \"abc\"}], \"headers\": {\"X-Frame-Options\": \"DENY\"}}'", "").unwrap();
+        assert_eq!(response.cookies[0].header_value("/btcpay"), "session=abc; Path=/btcpay; HttpOnly; SameSite=Lax");
+        assert_eq!(response.headers, [("x-frame-options".to_owned(), "DENY".to_owned())]);
+
+        match open("echo '{\"message\": \"ask the admin\"}'", "") {
+            Err(OpenError::Refused { message: Some(message) }) => assert_eq!(message, "ask the admin"),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // exit code 1 isn't special
+        match open("echo 'not today' >&2; exit 1", "") {
+            Err(OpenError::EntryPointFailedWithMessage { exit_code: 1, .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // the input is not required to be read
+        let input = "x".repeat(1_000_000);
+        let response = open("echo '{\"url\": \"/\"}'", &input).unwrap();
+        assert_eq!(response.path, "/");
+        match open("echo '{\"url\": \"/\", \"cookies\": [{\"name\": \"auth_token\", \"value\": \"x\"}]}'", "") {
+            Err(OpenError::InvalidResponse { error: ResponseError::ReservedCookie(_), .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match open("echo '{\"url\": \"/\", \"headers\": {\"Location\": \"https://example.com\"}}'", "") {
+            Err(OpenError::InvalidResponse { error: ResponseError::ForbiddenHeader(_), .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match open("echo not json", "") {
+            Err(OpenError::InvalidResponse { error: ResponseError::Json(_), .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn check_output() {
+        let check = |json: &str| {
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+            super::check_output(serde_json::from_str(json).unwrap(), now)
+        };
+
+        let response = check("{\"url\": \"/\", \"expires\": 1060, \"cookies\": [{\"name\": \"a\", \"value\": \"1\"}, {\"name\": \"b\", \"value\": \"2\", \"max_age\": 5}]}").unwrap().unwrap();
+        assert_eq!(response.cookies[0].max_age, Some(60));
+        assert_eq!(response.cookies[1].max_age, Some(5));
+        assert_eq!(check("{\"url\": \"/\", \"expires\": 10, \"cookies\": [{\"name\": \"a\", \"value\": \"1\"}]}").unwrap().unwrap().cookies[0].max_age, Some(0));
+
+        match check(&format!("{{\"url\": \"/\", \"expires\": {}}}", u64::max_value())) {
+            Err(ResponseError::InvalidExpiration(_)) => (),
+            _ => panic!("overflowing expiration accepted"),
+        }
+        match check("{\"url\": \"/a b\"}") {
+            Err(ResponseError::InvalidUrl(_)) => (),
+            _ => panic!("URL with a space accepted"),
+        }
+        match check("{\"url\": \"/\", \"cookies\": [{\"name\": \"a\", \"value\": \"1; Domain=example.com\"}]}") {
+            Err(ResponseError::InvalidCookie(_)) => (),
+            _ => panic!("cookie attribute injected"),
+        }
+        match check("{\"url\": \"/\", \"headers\": {\"X-Test\": \"a\\r\\nSet-Cookie: a=1\"}}") {
+            Err(ResponseError::InvalidHeader(_)) => (),
+            _ => panic!("header injected"),
+        }
+    }
+
+    #[test]
+    fn preferred_locale() {
+        assert_eq!(super::preferred_locale("sk-SK,sk;q=0.9,en;q=0.8").as_deref(), Some("sk-SK"));
+        assert_eq!(super::preferred_locale("en;q=0.8").as_deref(), Some("en"));
+        assert_eq!(super::preferred_locale("*"), None);
+        assert_eq!(super::preferred_locale(""), None);
+    }
+
+    #[test]
+    fn protocol_version() {
+        #[derive(serde_derive::Deserialize)]
+        struct Meta {
+            #[serde(default)]
+            entry_point_protocol: Protocol,
+        }
+
+        assert_eq!(toml::from_str::<Meta>("").unwrap().entry_point_protocol, Protocol::V1);
+        assert_eq!(toml::from_str::<Meta>("entry_point_protocol = 2").unwrap().entry_point_protocol, Protocol::V2);
+        assert!(toml::from_str::<Meta>("entry_point_protocol = 3").is_err());
+    }
+
+    /// Answers one request with the response and returns the head of the request
+    fn serve_once(response: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/login", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                if len == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn redirect_path() {
+        let callback_url = url::Url::parse("http://127.0.0.1:3000/login").unwrap();
+        let path = |location| super::redirect_path(location, &callback_url);
+
+        assert_eq!(path("/sso?token=abc").as_deref(), Some("/sso?token=abc"));
+        assert_eq!(path("/a/../sso#top").as_deref(), Some("/sso"));
+        assert_eq!(path("http://127.0.0.1:3000/sso?token=abc").as_deref(), Some("/sso?token=abc"));
+        assert_eq!(path("http://127.0.0.1:3001/sso"), None);
+        assert_eq!(path("https://evil.com/sso"), None);
+        assert_eq!(path("//evil.com/sso"), None);
+        assert_eq!(path("/\\evil.com/sso"), None);
+        assert_eq!(path("sso"), None);
+        assert_eq!(path("javascript:alert(1)"), None);
+        assert_eq!(path("/sso token"), None);
+        assert_eq!(path(""), None);
+    }
+
+    #[test]
+    fn http_callback() {
+        let runner = runner(1);
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        let groups = vec![user::Group::try_from("admins".to_owned()).unwrap()];
+        let user = user::Authenticated::user_logged_in(name, SessionId::random(), groups);
+        let request = RequestInfo {
+            locale: Some("en-US".to_owned()),
+            ..Default::default()
+        };
+        let call = |url: &str, timeout| tokio_test::block_on(runner.call("test", url, CallbackMethod::Get, Some(timeout), &user, &request));
+
+        let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"url\": \"/home\"}");
+        assert_eq!(call(&url, Duration::from_secs(5)).unwrap().path, "/home");
+        let request_head = server.join().unwrap();
+        assert!(request_head.starts_with("GET /login?user=satoshi&groups=admins&locale=en-US HTTP/1.1\r\n"), "{}", request_head);
+
+        let (url, server) = serve_once("HTTP/1.1 303 See Other\r\nLocation: /sso?token=abc\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert_eq!(call(&url, Duration::from_secs(5)).unwrap().path, "/sso?token=abc");
+        server.join().unwrap();
+
+        let (url, server) = serve_once("HTTP/1.1 302 Found\r\nLocation: //evil.com/sso\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        match call(&url, Duration::from_secs(5)) {
+            Err(OpenError::HttpEntryPointFailed { error: CallbackError::InvalidRedirect, .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        server.join().unwrap();
+
+        let (url, server) = serve_once("HTTP/1.1 403 Forbidden\r\nContent-Length: 13\r\nConnection: close\r\n\r\nask the admin");
+        match call(&url, Duration::from_secs(5)) {
+            Err(OpenError::Refused { message: Some(message) }) => assert_eq!(message, "ask the admin"),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        server.join().unwrap();
+
+        let (url, server) = serve_once("HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        match call(&url, Duration::from_secs(5)) {
+            Err(OpenError::HttpEntryPointFailed { error: CallbackError::Status(500), .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        server.join().unwrap();
+
+        // the connection is accepted by the kernel but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/login", listener.local_addr().unwrap());
+        match call(&url, Duration::from_millis(200)) {
+            Err(OpenError::EntryPointTimedOut { .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn sandbox() {
+        let runner = runner(4);
+        let work_dir = std::env::temp_dir().canonicalize().unwrap();
+        // dup doesn't set close-on-exec
+        let inherited = unsafe { libc::dup(2) };
+        assert!(inherited >= 0);
+
+        let script = format!("[ -e /proc/self/fd/{} ] && echo /leaked || echo \"$PWD/${{CARGO_MANIFEST_DIR:-clean}}/$(ulimit -t)/$(grep NoNewPrivs /proc/self/status | tr -dc 0-9)\"", inherited);
+        let mut command = shell(&script);
+        Sandbox::unprivileged(ResourceLimits { cpu_seconds: 5, ..Default::default() }, work_dir.clone()).apply(&mut command, "test");
+        let result = tokio_test::block_on(runner.run(command, "/bin/sh".to_owned(), "test", Protocol::V1, Vec::new(), Duration::from_secs(5)));
+        unsafe {
+            libc::close(inherited);
+        }
+
+        // working directory, environment, CPU limit and no_new_privs
+        assert_eq!(result.unwrap().path, format!("{}/clean/5/1", work_dir.display()));
+    }
+
+    #[test]
+    fn timeout_kills_process_group() {
+        let runner = runner(4);
+        let start = Instant::now();
+
+        // the background process keeps stdout open after the shell exits
+        match tokio_test::block_on(run(&runner, "sleep 30 & echo /late", Duration::from_millis(200))) {
+            Err(OpenError::EntryPointTimedOut { .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        let runner = runner(1);
+
+        let (first, second) = tokio_test::block_on(async {
+            futures::join!(run(&runner, "sleep 1; echo /first", Duration::from_secs(5)), run(&runner, "echo /second", Duration::from_millis(100)))
+        });
+        assert_eq!(first.unwrap(), "/first");
+        match second {
+            Err(OpenError::TooManyEntryPoints { .. }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+}
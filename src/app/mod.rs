@@ -1,6 +1,9 @@
+use std::time::Duration;
 use crate::user;
-use crate::primitives::Stringly;
-use std::future::Future;
+
+pub mod entry_point;
+pub mod sandbox;
+pub mod template;
 
 pub mod api {
     #[derive(serde_derive::Serialize)]
@@ -27,7 +30,37 @@ pub mod config {
     pub enum EntryPoint {
         #[non_exhaustive]
         Static { url: String, },
+        /// Like `Static` but with placeholders filled in for the user, see `template`
+        #[non_exhaustive]
+        Template { url: super::template::UrlTemplate, },
         Dynamic,
+        /// The app is asked over HTTP, see `entry_point::Runner::call`
+        #[non_exhaustive]
+        Http {
+            /// Local `http` URL of the endpoint
+            url: String,
+            #[serde(default)]
+            method: CallbackMethod,
+            /// Seconds, `entry_point_timeout_seconds` or the global default is used if missing
+            timeout: Option<u64>,
+        },
+    }
+
+    /// How the user context is sent to an HTTP entry point
+    #[derive(Copy, Clone, serde_derive::Deserialize)]
+    pub enum CallbackMethod {
+        /// Query arguments
+        #[serde(rename = "GET")]
+        Get,
+        /// JSON body
+        #[serde(rename = "POST")]
+        Post,
+    }
+
+    impl Default for CallbackMethod {
+        fn default() -> Self {
+            CallbackMethod::Post
+        }
     }
 
     #[derive(serde_derive::Deserialize)]
@@ -36,12 +69,40 @@ pub mod config {
         pub root_path: String,
     }
 
+    /// Registration of the app as a client of the OpenID Connect provider, the client ID is the
+    /// name of the app
+    #[derive(serde_derive::Deserialize)]
+    #[non_exhaustive]
+    pub struct OidcClient {
+        /// Allowed values of `redirect_uri`, they have to match exactly
+        pub redirect_uris: Vec<String>,
+        /// Hex-encoded SHA-256 of the client secret, clients without a secret have to use PKCE
+        pub client_secret_sha256: Option<crate::login::oidc::ClientSecretHash>,
+    }
+
     #[derive(serde_derive::Deserialize)]
     #[non_exhaustive]
     pub struct AppInfo {
         pub user_friendly_name: String,
         pub admin_only: bool,
         pub entry_point: EntryPoint,
+        /// If either `allowed_users` or `allowed_groups` is present only the listed users and
+        /// members of the listed groups may open the app. Administrators are NOT exempt.
+        pub allowed_users: Option<Vec<crate::user::Name>>,
+        pub allowed_groups: Option<Vec<crate::user::Group>>,
+        /// These users can never open the app, this takes precedence over everything else
+        #[serde(default)]
+        pub denied_users: Vec<crate::user::Name>,
+        /// Present if the app can log users in using OpenID Connect
+        pub oidc: Option<OidcClient>,
+        /// How long the dynamic entry point may run, the global default is used if missing
+        pub entry_point_timeout_seconds: Option<u64>,
+        /// Version of the protocol spoken by the dynamic entry point
+        #[serde(default)]
+        pub entry_point_protocol: super::entry_point::Protocol,
+        /// Resources available to the dynamic entry point process
+        #[serde(default)]
+        pub entry_point_limits: super::sandbox::ResourceLimits,
     }
 
     pub type Apps = HashMap<String, super::App>;
@@ -50,6 +111,8 @@ pub mod config {
         pub app_info: &'static str,
         pub app_icons: &'static str,
         pub app_entry_points: &'static str,
+        /// Contains private working directories of entry points named after the apps
+        pub entry_point_work_dirs: &'static str,
         pub selfhost_apps: &'static str,
     }
 
@@ -58,6 +121,7 @@ pub mod config {
         app_info: "/etc/selfhost-dashboard/apps",
         app_icons: "/usr/share/selfhost-dashboard/apps/icons",
         app_entry_points: "/usr/lib/selfhost-dashboard/apps/entry_points",
+        entry_point_work_dirs: "/var/lib/selfhost-dashboard/entry_points",
         selfhost_apps: "/etc/selfhost/apps",
     };
 
@@ -66,6 +130,7 @@ pub mod config {
         app_info: "./test_data/etc/selfhost-dashboard/apps",
         app_icons: "./test_data/usr/share/selfhost-dashboard/apps/icons",
         app_entry_points: "./test_data/usr/lib/selfhost-dashboard/apps/entry_points",
+        entry_point_work_dirs: "./test_data/var/lib/selfhost-dashboard/entry_points",
         selfhost_apps: "./test_data/etc/selfhost/apps",
     };
 
@@ -115,12 +180,85 @@ pub mod config {
         BadEntryPointPerm(u32),
         #[error("empty root path")]
         EmptyRootPath,
+        #[error("the lists of allowed users and groups are empty, nobody could open the app")]
+        EmptyAllowList,
+        #[error("user {0} is both allowed and denied")]
+        ConflictingAcl(String),
+        #[error("the OpenID Connect client has no redirect URIs")]
+        EmptyRedirectUris,
+        #[error("invalid OpenID Connect redirect URI {0}")]
+        InvalidRedirectUri(String),
+        #[error("the URL of the HTTP entry point {0} is not a local http URL")]
+        InvalidEntryPointUrl(String),
+    }
+
+    pub(super) fn check_acl(app_info: &AppInfo) -> Result<(), LoadAppError> {
+        let allowed_users = app_info.allowed_users.as_ref().map(Vec::as_slice);
+        let allowed_groups = app_info.allowed_groups.as_ref().map(Vec::as_slice);
+
+        match (allowed_users, allowed_groups) {
+            (Some([]), None) | (None, Some([])) | (Some([]), Some([])) => return Err(LoadAppError::EmptyAllowList),
+            _ => (),
+        }
+
+        let conflicting = app_info
+            .denied_users
+            .iter()
+            .find(|denied| allowed_users.unwrap_or(&[]).contains(denied));
+
+        match conflicting {
+            Some(user) => Err(LoadAppError::ConflictingAcl(user.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn check_oidc_client(client: &OidcClient) -> Result<(), LoadAppError> {
+        if client.redirect_uris.is_empty() {
+            return Err(LoadAppError::EmptyRedirectUris);
+        }
+
+        // Fragments are forbidden by RFC 6749, the URI is also put into the Location header as-is
+        let invalid = client
+            .redirect_uris
+            .iter()
+            .find(|uri| !uri.bytes().all(|byte| byte.is_ascii_graphic()) || url::Url::parse(uri).map(|url| url.fragment().is_some() || url.cannot_be_a_base()).unwrap_or(true));
+
+        match invalid {
+            Some(uri) => Err(LoadAppError::InvalidRedirectUri(uri.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// The entry point is called without TLS so it has to stay on this machine
+    pub(super) fn check_entry_point_url(url: &str) -> Result<(), LoadAppError> {
+        let is_local = match url::Url::parse(url) {
+            Ok(parsed) if parsed.scheme() == "http" => match parsed.host() {
+                Some(url::Host::Domain(domain)) => domain == "localhost",
+                Some(url::Host::Ipv4(address)) => address.is_loopback(),
+                Some(url::Host::Ipv6(address)) => address.is_loopback(),
+                None => false,
+            },
+            _ => false,
+        };
+
+        if is_local {
+            Ok(())
+        } else {
+            Err(LoadAppError::InvalidEntryPointUrl(url.to_owned()))
+        }
     }
 
     /// Loads app info and does sanity checking of associated files
     fn load_and_check_app(name: &str) -> Result<super::App, LoadAppError> {
         let app_info_path = Path::new(DIRS.app_info).join(name).join("meta.toml");
         let app_info = load_toml::<AppInfo, _>(app_info_path).map_err(LoadAppError::Toml)?;
+        check_acl(&app_info)?;
+        if let Some(client) = &app_info.oidc {
+            check_oidc_client(client)?;
+        }
+        if let EntryPoint::Http { url, .. } = &app_info.entry_point {
+            check_entry_point_url(url)?;
+        }
         let main_icon_file = Path::new(DIRS.app_icons).join(name).join("entry_main.png");
         if !main_icon_file.exists() {
             return Err(LoadAppError::MissingIcon);
@@ -206,16 +344,14 @@ pub mod config {
 }
 
 pub fn get_apps<S: crate::webserver::Server>(user: &user::Authenticated, prefix: &str, app_info: &config::Apps) -> S::ResponseBuilder {
-    use crate::webserver::ResponseBuilder;
-
     let apps = app_info
         .iter()
-        .filter(|(_, app)| user.is_admin() || !app.app_info.admin_only)
+        .filter(|(_, app)| app.check_access(user).is_ok())
         .map(|(k, v)| {
             let icon = format!("/icons/{}/entry_main.png", k);
             let url = match &v.app_info.entry_point {
                 config::EntryPoint::Static { url, } => format!("{}{}", v.root_path, url),
-                config::EntryPoint::Dynamic => format!("{}/open-app/{}", prefix, k),
+                config::EntryPoint::Template { .. } | config::EntryPoint::Dynamic | config::EntryPoint::Http { .. } => format!("{}/open-app/{}", prefix, k),
             };
 
             api::App {
@@ -230,12 +366,7 @@ pub fn get_apps<S: crate::webserver::Server>(user: &user::Authenticated, prefix:
         apps,
     };
 
-    let serialized_response = serde_json::to_string(&resp).expect("Serialization to string should never fail");
-    let mut builder = S::ResponseBuilder::with_status(200);
-    builder.set_content_type("application/json");
-    builder.set_body(serialized_response.into());
-
-    builder
+    crate::webserver::json_response(&resp)
 }
 
 str_char_whitelist_newtype!(Name, NameError, "application name", |c| c != '-' && !('a'..='z').contains(&c));
@@ -246,65 +377,143 @@ pub struct App {
 }
 
 impl App {
-    fn open_dynamic<'a, Str: Stringly>(app_name: &'a Name<Str>, user: &'a user::Authenticated) -> impl Future<Output=Result<String, OpenError>> + 'a {
-        #[cfg(not(feature = "mock_system"))]
-        use std::os::unix::process::CommandExt;
-        use crate::io::BufReadExt;
-
-        let entry_point_path = format!("{}/{}/open", self::config::DIRS.app_entry_points, app_name);
-        #[cfg(not(feature = "mock_system"))]
-        let owned_app_name = String::from(&**app_name);
-        let owned_user_name = user.name().to_owned();
-
-        async move {
-            let mut child = tokio::task::spawn_blocking(move || -> Result<_, _> {
-                #[cfg(not(feature = "mock_system"))]
-                let system_user = users::get_user_by_name(&owned_app_name).ok_or(OpenError::SystemUserNotFound)?;
-                let mut command = std::process::Command::new(&entry_point_path);
-                #[cfg(not(feature = "mock_system"))]
-                command
-                        .uid(system_user.uid())
-                        .gid(system_user.primary_group_id());
-                command
-                    .arg(owned_user_name)
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::piped())
-                    .spawn()
-                    .map_err(move |error| OpenError::EntryPointExec { entry_point_path, error, })
-            }).await.map_err(OpenError::TaskJoin)??;
-
-            let stdout_line = std::io::BufReader::new(child.stdout.take().expect("std Command API is retarded")).read_line_max(1024);
-            let stderr_line = std::io::BufReader::new(child.stderr.take().expect("std Command API is retarded")).read_line_max(1024);
-
-            let status = child.wait().map_err(|error| OpenError::EntryPointWaitFailed { app: (&**app_name).to_owned(), error, })?;
-
-            if !status.success() {
-                return Err(match (status.code(), stderr_line) {
-                    (Some(1), Ok(message)) => OpenError::RejectedWithMessage(message),
-                    (Some(1), Err(_)) => OpenError::RejectedWithInvalidMessage,
-                    (Some(exit_code), Ok(message)) => OpenError::EntryPointFailedWithMessage { message, exit_code, },
-                    (Some(exit_code), Err(_)) => OpenError::EntryPointFailedWithInvalidMessage { exit_code, },
-                    (None, Ok(message)) => OpenError::EntryPointKilledWithMessage { message },
-                    (None, Err(_)) => OpenError::EntryPointKilledWithInvalidMessage,
-                });
-            }
+    /// Returns the registration of the app in the OpenID Connect provider if it has one
+    pub fn oidc_client(&self) -> Option<&config::OidcClient> {
+        self.app_info.oidc.as_ref()
+    }
 
-            stdout_line.map_err(|error| OpenError::ReadingStdoutFailed { app: (&**app_name).to_owned(), error, })
+    /// Creates the app from the contents of `meta.toml` without checking it
+    #[cfg(test)]
+    pub fn from_toml(app_info: &str) -> Self {
+        App {
+            app_info: toml::from_str(app_info).expect("invalid app info"),
+            root_path: String::new(),
         }
     }
 
-    pub async fn get_open_url(&self, app_name: &Name, user: &user::Authenticated) -> Result<String, OpenError> {
-        if self.app_info.admin_only && !user.is_admin() {
+    /// Returns `true` if the path is the root path of the app or below it
+    fn contains_path(&self, path: &str) -> bool {
+        let root_path = self.root_path.trim_end_matches('/');
+        path.starts_with(root_path) && (path.len() == root_path.len() || path[root_path.len()..].starts_with('/'))
+    }
+
+    /// Checks whether the user may see and open the app
+    pub fn check_access(&self, user: &user::Authenticated) -> Result<(), OpenError> {
+        let app_info = &self.app_info;
+
+        if app_info.denied_users.iter().any(|denied| **denied == *user.name()) {
+            return Err(OpenError::NotAllowed);
+        }
+
+        if app_info.admin_only && !user.is_admin() {
             return Err(OpenError::NonAdmin);
         }
 
-        Ok(match &self.app_info.entry_point {
-            config::EntryPoint::Static { url, } => format!("{}{}", self.root_path, url),
-            config::EntryPoint::Dynamic => format!("{}{}", self.root_path, App::open_dynamic(&app_name, &user).await?),
+        let allowed_user = app_info.allowed_users.as_ref().map(|users| users.iter().any(|allowed| **allowed == *user.name()));
+        let allowed_group = app_info.allowed_groups.as_ref().map(|groups| groups.iter().any(|group| user.is_member(group)));
+
+        match (allowed_user, allowed_group) {
+            (None, None) | (Some(true), _) | (_, Some(true)) => Ok(()),
+            _ => Err(OpenError::NotAllowed),
+        }
+    }
+
+    /// Checks access of the user and finds out where to send them
+    pub async fn open(&self, app_name: &Name, user: &user::Authenticated, request: &entry_point::RequestInfo, entry_points: &entry_point::Runner) -> Result<Opened, OpenError> {
+        self.check_access(user)?;
+
+        let timeout = self.app_info.entry_point_timeout_seconds.map(Duration::from_secs);
+        let response = match &self.app_info.entry_point {
+            config::EntryPoint::Static { url, } => return Ok(Opened {
+                url: format!("{}{}", self.root_path, url),
+                headers: Vec::new(),
+            }),
+            config::EntryPoint::Template { url, } => return Ok(Opened {
+                url: format!("{}{}", self.root_path, url.render(user, app_name, request)),
+                headers: Vec::new(),
+            }),
+            config::EntryPoint::Dynamic => entry_points.open(app_name, user, request, self.app_info.entry_point_protocol, timeout, &self.app_info.entry_point_limits).await?,
+            config::EntryPoint::Http { url, method, timeout: http_timeout, } => {
+                let timeout = http_timeout.map(Duration::from_secs).or(timeout);
+                entry_points.call(app_name, url, *method, timeout, user, request).await?
+            },
+        };
+
+        let mut headers = response.headers;
+        headers.extend(response.cookies.iter().map(|cookie| ("set-cookie".to_owned(), cookie.header_value(&self.root_path))));
+
+        Ok(Opened {
+            url: format!("{}{}", self.root_path, response.path),
+            headers,
         })
     }
 }
 
+/// Redirect to the app after it was opened successfully
+pub struct Opened {
+    pub url: String,
+    /// Extra headers of the redirect including cookies set by the entry point
+    pub headers: Vec<(String, String)>,
+}
+
+/// Finds the app serving the path, the most specific root path wins
+pub fn find_by_path<'a>(apps: &'a config::Apps, path: &str) -> Option<(&'a str, &'a App)> {
+    apps
+        .iter()
+        .filter(|(_, app)| app.contains_path(path))
+        .max_by_key(|(_, app)| app.root_path.len())
+        .map(|(name, app)| (&**name, app))
+}
+
+/// Decodes the path the same way the reverse proxy does before matching locations
+///
+/// Percent-encoded bytes are decoded, empty and `.` segments are removed and `..` removes the
+/// previous segment. Returns `None` for invalid encoding or a path escaping the root.
+fn normalize_path(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = char::from(bytes.next()?).to_digit(16)?;
+            let low = char::from(bytes.next()?).to_digit(16)?;
+            decoded.push((high * 16 + low) as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+    if decoded.contains('\0') {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => { segments.pop()?; },
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Finds the app serving the URI of a request forwarded by the reverse proxy
+///
+/// Returns `None` if there's none or the path is invalid.
+pub fn find_by_uri<'a>(apps: &'a config::Apps, uri: &str) -> Option<(&'a str, &'a App)> {
+    let path = uri.split(|c| c == '?' || c == '#').next()?;
+    find_by_path(apps, &normalize_path(path)?)
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum OpenError {
@@ -314,6 +523,8 @@ pub enum OpenError {
     TaskJoin(tokio::task::JoinError),
     #[error("the user is not an administrator")]
     NonAdmin,
+    #[error("the user is not allowed to open the application by its access list")]
+    NotAllowed,
     #[error("failed to execute entry point {entry_point_path}")]
     EntryPointExec { entry_point_path: String, #[source] error: std::io::Error, },
     #[error("failed to wait for entry point process of application {app}")]
@@ -332,6 +543,20 @@ pub enum OpenError {
     EntryPointKilledWithInvalidMessage,
     #[error("reading of the resulting URL of application {app} failed")]
     ReadingStdoutFailed { app: String, #[source] error: std::io::Error, },
+    #[error("entry point of application {app} didn't finish within {timeout:?}")]
+    EntryPointTimedOut { app: String, timeout: Duration, },
+    #[error("too many entry points are running, application {app} couldn't be opened")]
+    TooManyEntryPoints { app: String, },
+    #[error("user is not allowed to open the application by its entry point")]
+    Refused { message: Option<String>, },
+    #[error("entry point of application {app} returned an invalid response")]
+    InvalidResponse { app: String, #[source] error: entry_point::ResponseError, },
+    #[error("writing the request to the entry point of application {app} failed")]
+    WritingStdinFailed { app: String, #[source] error: std::io::Error, },
+    #[error("failed to prepare the working directory {path} of the entry point")]
+    WorkDir { path: std::path::PathBuf, #[source] error: std::io::Error, },
+    #[error("calling the HTTP entry point of application {app} failed")]
+    HttpEntryPointFailed { app: String, #[source] error: entry_point::CallbackError, },
 }
 
 #[cfg(test)]
@@ -344,4 +569,168 @@ mod tests {
     test_str_val_err!(app_name_dot, Name, "foo.bar");
     test_str_val_err!(app_name_space, Name, "foo bar");
     test_str_val_err!(app_name_slash, Name, "foo/bar");
+
+    use super::{App, OpenError, config::{self, AppInfo}};
+    use crate::user::{self, types::SessionId};
+    use std::convert::TryFrom;
+
+    fn app(acl: &str) -> App {
+        let toml = format!("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = \"Dynamic\"\n{}", acl);
+        let app_info = toml::from_str::<AppInfo>(&toml).expect("invalid app info");
+        config::check_acl(&app_info).expect("invalid ACL");
+        App {
+            app_info,
+            root_path: String::new(),
+        }
+    }
+
+    fn user(name: &str, groups: &[&str]) -> user::Authenticated {
+        let name = user::Name::try_from(name.to_owned()).unwrap();
+        let groups = groups.iter().map(|group| user::Group::try_from((*group).to_owned()).unwrap()).collect();
+        user::Authenticated::user_logged_in(name, SessionId::random(), groups)
+    }
+
+    #[test]
+    fn acl_default_allows_everyone() {
+        let app = app("");
+        app.check_access(&user("foo", &[])).unwrap();
+        app.check_access(&user("admin", &["admins"])).unwrap();
+    }
+
+    #[test]
+    fn acl_allowed() {
+        let app = app("allowed_users = [\"foo\"]\nallowed_groups = [\"family\"]");
+        app.check_access(&user("foo", &[])).unwrap();
+        app.check_access(&user("bar", &["family"])).unwrap();
+        match app.check_access(&user("baz", &["friends"])) {
+            Err(OpenError::NotAllowed) => (),
+            _ => panic!("user not in the allow list was allowed"),
+        }
+        // admins are not exempt
+        match app.check_access(&user("admin", &["admins"])) {
+            Err(OpenError::NotAllowed) => (),
+            _ => panic!("admin not in the allow list was allowed"),
+        }
+    }
+
+    #[test]
+    fn acl_denied() {
+        let app = app("allowed_groups = [\"family\"]\ndenied_users = [\"foo\"]");
+        match app.check_access(&user("foo", &["family"])) {
+            Err(OpenError::NotAllowed) => (),
+            _ => panic!("denied user was allowed"),
+        }
+        app.check_access(&user("bar", &["family"])).unwrap();
+    }
+
+    #[test]
+    fn find_by_path() {
+        let mut apps = config::Apps::new();
+        for (name, root_path) in &[("btcpay", "/btcpay"), ("btcpay-admin", "/btcpay/admin/"), ("rtl", "/rtl")] {
+            let mut app = app("");
+            app.root_path = (*root_path).to_owned();
+            apps.insert((*name).to_owned(), app);
+        }
+
+        let found = |path| super::find_by_path(&apps, path).map(|(name, _)| name);
+        assert_eq!(found("/btcpay"), Some("btcpay"));
+        assert_eq!(found("/btcpay/invoices"), Some("btcpay"));
+        assert_eq!(found("/btcpay/admin"), Some("btcpay-admin"));
+        assert_eq!(found("/btcpay/admin/users"), Some("btcpay-admin"));
+        assert_eq!(found("/btcpayserver"), None);
+        assert_eq!(found("/dashboard"), None);
+    }
+
+    #[test]
+    fn normalize_path() {
+        use super::normalize_path;
+
+        assert_eq!(normalize_path("/wallet/x").as_deref(), Some("/wallet/x"));
+        assert_eq!(normalize_path("//wallet//x/").as_deref(), Some("/wallet/x"));
+        assert_eq!(normalize_path("/%77allet/x").as_deref(), Some("/wallet/x"));
+        assert_eq!(normalize_path("/wallet/./../wallet").as_deref(), Some("/wallet"));
+        assert_eq!(normalize_path("/rtl/..%2Fwallet").as_deref(), Some("/wallet"));
+        assert_eq!(normalize_path("").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/.."), None);
+        assert_eq!(normalize_path("/wallet%2"), None);
+        assert_eq!(normalize_path("/wallet%zz"), None);
+        assert_eq!(normalize_path("/%ff"), None);
+        assert_eq!(normalize_path("/wallet%00"), None);
+    }
+
+    #[test]
+    fn find_by_uri() {
+        let mut apps = config::Apps::new();
+        for (name, root_path) in &[("wallet", "/wallet"), ("rtl", "/rtl")] {
+            let mut app = app("");
+            app.root_path = (*root_path).to_owned();
+            apps.insert((*name).to_owned(), app);
+        }
+
+        let found = |uri| super::find_by_uri(&apps, uri).map(|(name, _)| name);
+        assert_eq!(found("/wallet/x?a=b"), Some("wallet"));
+        assert_eq!(found("//wallet/x"), Some("wallet"));
+        assert_eq!(found("/%77allet/x"), Some("wallet"));
+        assert_eq!(found("/wallet/../wallet"), Some("wallet"));
+        assert_eq!(found("/rtl/../wallet"), Some("wallet"));
+        assert_eq!(found("/wallet/../other"), None);
+        assert_eq!(found("/other/x"), None);
+        assert_eq!(found("/../wallet"), None);
+    }
+
+    #[test]
+    fn acl_invalid() {
+        assert!(toml::from_str::<AppInfo>("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = \"Dynamic\"\nallowed_users = [\"foo bar\"]").is_err());
+
+        let app_info = toml::from_str::<AppInfo>("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = \"Dynamic\"\nallowed_users = []").unwrap();
+        assert!(config::check_acl(&app_info).is_err());
+
+        let app_info = toml::from_str::<AppInfo>("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = \"Dynamic\"\nallowed_users = [\"foo\"]\ndenied_users = [\"foo\"]").unwrap();
+        assert!(config::check_acl(&app_info).is_err());
+    }
+
+    #[test]
+    fn oidc_client() {
+        let client = |redirect_uris: &str| {
+            let toml = format!("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = \"Dynamic\"\n[oidc]\nredirect_uris = {}", redirect_uris);
+            let app_info = toml::from_str::<AppInfo>(&toml).expect("invalid app info");
+            config::check_oidc_client(app_info.oidc.as_ref().expect("missing client"))
+        };
+
+        client("[\"https://example.com/callback?provider=dashboard\"]").unwrap();
+        assert!(client("[]").is_err());
+        assert!(client("[\"/callback\"]").is_err());
+        assert!(client("[\"https://example.com/#callback\"]").is_err());
+        assert!(client("[\"https://example.com/call back\"]").is_err());
+        assert!(client("[\"mailto:admin@example.com\"]").is_err());
+    }
+
+    #[test]
+    fn template_entry_point() {
+        let toml = "user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = { Template = { url = \"/u/{user}/\" } }";
+        match toml::from_str::<AppInfo>(toml).expect("invalid app info").entry_point {
+            config::EntryPoint::Template { .. } => (),
+            _ => panic!("unexpected entry point"),
+        }
+
+        // invalid templates prevent the app from loading
+        assert!(toml::from_str::<AppInfo>("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = { Template = { url = \"/u/{name}/\" } }").is_err());
+    }
+
+    #[test]
+    fn entry_point_url() {
+        let app_info = toml::from_str::<AppInfo>("user_friendly_name = \"Test\"\nadmin_only = false\nentry_point = { Http = { url = \"http://127.0.0.1:3000/login\", method = \"GET\" } }").expect("invalid app info");
+        match app_info.entry_point {
+            config::EntryPoint::Http { method: config::CallbackMethod::Get, timeout: None, .. } => (),
+            _ => panic!("unexpected entry point"),
+        }
+
+        config::check_entry_point_url("http://127.0.0.1:3000/login").unwrap();
+        config::check_entry_point_url("http://localhost/login").unwrap();
+        config::check_entry_point_url("http://[::1]:8080/").unwrap();
+        assert!(config::check_entry_point_url("https://127.0.0.1/login").is_err());
+        assert!(config::check_entry_point_url("http://example.com/login").is_err());
+        assert!(config::check_entry_point_url("http://192.168.1.1/login").is_err());
+        assert!(config::check_entry_point_url("/login").is_err());
+    }
 }
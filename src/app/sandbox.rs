@@ -0,0 +1,171 @@
+//! Confinement of entry point processes
+//!
+//! The process gets a clean environment, a working directory private to the app and no file
+//! descriptors except the standard ones. Before exec it drops to the system user of the app
+//! including its supplementary groups, its resources are limited and it can't gain privileges
+//! through setuid binaries.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use super::OpenError;
+
+/// `PATH` of the entry point, nothing else is inherited from the dashboard
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Descriptors above this are left alone if the kernel doesn't support `close_range`
+const MAX_SCANNED_FD: libc::c_int = 65536;
+
+/// Set in `[entry_point_limits]` of `meta.toml`, zero means unlimited
+///
+/// All limits are opt-in because existing entry points didn't have any, runtimes like the JVM,
+/// Node.js or Go reserve large address spaces and the process limit depends on the app.
+#[derive(Debug, Clone, Default, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// CPU time, unlike the timeout this doesn't include waiting
+    pub cpu_seconds: u64,
+    /// Address space of each process
+    pub memory_mib: u64,
+    /// Counts all processes and threads of the system user including the app itself
+    pub processes: u64,
+}
+
+struct Credentials {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+pub struct Sandbox {
+    /// `None` keeps the user of the dashboard, only with `mock_system` and in tests
+    credentials: Option<Credentials>,
+    limits: ResourceLimits,
+    work_dir: PathBuf,
+}
+
+impl Sandbox {
+    /// Looks up the system user of the app and creates its working directory, this blocks
+    pub fn prepare(app_name: &str, limits: ResourceLimits) -> Result<Self, OpenError> {
+        #[cfg(not(feature = "mock_system"))]
+        let credentials = {
+            let system_user = users::get_user_by_name(app_name).ok_or(OpenError::SystemUserNotFound)?;
+            let gid = system_user.primary_group_id();
+            // fewer groups are safe if they can't be listed
+            let groups = users::get_user_groups(app_name, gid)
+                .map(|groups| groups.iter().map(|group| group.gid()).collect())
+                .unwrap_or_else(|| vec![gid]);
+
+            Some(Credentials { uid: system_user.uid(), gid, groups, })
+        };
+        #[cfg(feature = "mock_system")]
+        let credentials = None;
+
+        let work_dir = Path::new(super::config::DIRS.entry_point_work_dirs).join(app_name);
+        create_work_dir(&work_dir, credentials.as_ref()).map_err(|error| OpenError::WorkDir { path: work_dir.clone(), error, })?;
+
+        Ok(Sandbox { credentials, limits, work_dir, })
+    }
+
+    #[cfg(test)]
+    pub fn unprivileged(limits: ResourceLimits, work_dir: PathBuf) -> Self {
+        Sandbox {
+            credentials: None,
+            limits,
+            work_dir,
+        }
+    }
+
+    pub fn apply(self, command: &mut std::process::Command, app_name: &str) {
+        use std::os::unix::process::CommandExt;
+
+        command
+            .env_clear()
+            .env("PATH", PATH)
+            .env("HOME", &self.work_dir)
+            .env("USER", app_name)
+            .current_dir(&self.work_dir);
+
+        let Sandbox { credentials, limits, .. } = self;
+        // Only async-signal-safe calls, everything is allocated before fork
+        unsafe {
+            command.pre_exec(move || {
+                mark_inherited_fds_cloexec();
+                if let Some(limit) = rlimit(limits.cpu_seconds) {
+                    check(libc::setrlimit(libc::RLIMIT_CPU, &limit))?;
+                }
+                if let Some(limit) = rlimit(limits.memory_mib.saturating_mul(1024 * 1024)) {
+                    check(libc::setrlimit(libc::RLIMIT_AS, &limit))?;
+                }
+                if let Some(limit) = rlimit(limits.processes) {
+                    check(libc::setrlimit(libc::RLIMIT_NPROC, &limit))?;
+                }
+                // groups have to be changed while still privileged
+                if let Some(credentials) = &credentials {
+                    check(libc::setgroups(credentials.groups.len(), credentials.groups.as_ptr()))?;
+                    check(libc::setgid(credentials.gid))?;
+                    check(libc::setuid(credentials.uid))?;
+                }
+                let (enable, unused): (libc::c_ulong, libc::c_ulong) = (1, 0);
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, enable, unused, unused, unused))
+            });
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn rlimit(value: u64) -> Option<libc::rlimit> {
+    if value == 0 {
+        return None;
+    }
+    Some(libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    })
+}
+
+/// The descriptors can't be closed right away because the standard library reports a failed exec
+/// through one of them
+unsafe fn mark_inherited_fds_cloexec() {
+    const SYS_CLOSE_RANGE: libc::c_long = 436;
+    const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+
+    let first: libc::c_uint = 3;
+    if libc::syscall(SYS_CLOSE_RANGE, first, libc::c_uint::max_value(), CLOSE_RANGE_CLOEXEC) == 0 {
+        return;
+    }
+    for fd in 3..MAX_SCANNED_FD {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags >= 0 {
+            libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+        }
+    }
+}
+
+/// The parent is owned by root so the directory can't be replaced by a symlink
+fn create_work_dir(path: &Path, credentials: Option<&Credentials>) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::DirBuilder::new().mode(0o700).create(path) {
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => (),
+        result => result?,
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))?;
+
+    if let Some(credentials) = credentials {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        check(unsafe { libc::chown(path.as_ptr(), credentials.uid, credentials.gid) })?;
+    }
+    Ok(())
+}
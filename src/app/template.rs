@@ -0,0 +1,202 @@
+//! URL templates of static entry points
+//!
+//! Placeholders are written as `{name}`, `{{` and `}}` stand for literal braces. The values are
+//! percent-encoded so they can't change the structure of the URL, the groups are separated by
+//! commas. Unknown values (`host`, `locale`) are replaced by an empty string.
+
+use std::convert::TryFrom;
+use crate::user;
+use super::entry_point::RequestInfo;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Placeholder {
+    User,
+    Groups,
+    App,
+    Host,
+    Locale,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "user" => Some(Placeholder::User),
+            "groups" => Some(Placeholder::Groups),
+            "app" => Some(Placeholder::App),
+            "host" => Some(Placeholder::Host),
+            "locale" => Some(Placeholder::Locale),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Parsed template, it's checked when `meta.toml` is loaded
+#[derive(Debug, serde_derive::Deserialize)]
+#[serde(try_from = "String")]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("unknown placeholder {{{0}}}")]
+    UnknownPlaceholder(String),
+    #[error("unclosed placeholder")]
+    Unclosed,
+    #[error("unmatched }}, use }}}} for a literal brace")]
+    UnmatchedBrace,
+    #[error("invalid character {0:?}, only printable ASCII is allowed")]
+    InvalidChar(char),
+}
+
+impl TryFrom<String> for UrlTemplate {
+    type Error = TemplateError;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '}' => return Err(TemplateError::UnmatchedBrace),
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(TemplateError::Unclosed),
+                        }
+                    }
+                    let placeholder = Placeholder::from_name(&name).ok_or(TemplateError::UnknownPlaceholder(name))?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::replace(&mut literal, String::new())));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                },
+                c if c.is_ascii_graphic() => literal.push(c),
+                c => return Err(TemplateError::InvalidChar(c)),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(UrlTemplate { parts, })
+    }
+}
+
+/// Keeps only the unreserved characters of RFC 3986
+fn percent_encode(value: &str, output: &mut String) {
+    use std::fmt::Write;
+
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            output.push(char::from(byte));
+        } else {
+            write!(output, "%{:02X}", byte).expect("writing to string never fails");
+        }
+    }
+}
+
+impl UrlTemplate {
+    /// Returns the URL for the user, it's appended to the root path of the app
+    pub fn render(&self, user: &user::Authenticated, app_name: &str, request: &RequestInfo) -> String {
+        let mut url = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => url.push_str(literal),
+                Part::Placeholder(Placeholder::User) => percent_encode(user.name(), &mut url),
+                Part::Placeholder(Placeholder::Groups) => {
+                    for (i, group) in user.groups().iter().enumerate() {
+                        if i > 0 {
+                            url.push(',');
+                        }
+                        percent_encode(group, &mut url);
+                    }
+                },
+                Part::Placeholder(Placeholder::App) => percent_encode(app_name, &mut url),
+                Part::Placeholder(Placeholder::Host) => percent_encode(request.host.as_ref().map(String::as_str).unwrap_or(""), &mut url),
+                Part::Placeholder(Placeholder::Locale) => percent_encode(request.locale.as_ref().map(String::as_str).unwrap_or(""), &mut url),
+            }
+        }
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use super::{TemplateError, UrlTemplate};
+    use crate::app::entry_point::RequestInfo;
+    use crate::user::{self, types::SessionId};
+
+    fn render(template: &str, request: &RequestInfo) -> String {
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        let groups = vec![user::Group::try_from("admins".to_owned()).unwrap(), user::Group::try_from("family".to_owned()).unwrap()];
+        let user = user::Authenticated::user_logged_in(name, SessionId::random(), groups);
+        UrlTemplate::try_from(template.to_owned()).unwrap().render(&user, "btcpay", request)
+    }
+
+    #[test]
+    fn placeholders() {
+        let request = RequestInfo {
+            host: Some("node.example.com".to_owned()),
+            locale: Some("en-US".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(render("/u/{user}/", &request), "/u/satoshi/");
+        assert_eq!(render("/{app}?lang={locale}&groups={groups}", &request), "/btcpay?lang=en-US&groups=admins,family");
+        assert_eq!(render("/?return=https://{host}/", &request), "/?return=https://node.example.com/");
+        assert_eq!(render("/{{user}}", &request), "/{user}");
+        assert_eq!(render("?lang={locale}", &RequestInfo::default()), "?lang=");
+    }
+
+    #[test]
+    fn values_are_encoded() {
+        let request = RequestInfo {
+            host: Some("evil.com/?a=b&c".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(render("/{host}", &request), "/evil.com%2F%3Fa%3Db%26c");
+    }
+
+    #[test]
+    fn invalid() {
+        let parse = |template: &str| UrlTemplate::try_from(template.to_owned());
+
+        match parse("/{name}") {
+            Err(TemplateError::UnknownPlaceholder(name)) => assert_eq!(name, "name"),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match parse("/{user") {
+            Err(TemplateError::Unclosed) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match parse("/user}") {
+            Err(TemplateError::UnmatchedBrace) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match parse("/u/{user} /") {
+            Err(TemplateError::InvalidChar(' ')) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+}
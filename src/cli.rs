@@ -0,0 +1,347 @@
+//! Subcommands for managing users without the web server
+//!
+//! They access the database directly so they can be used to recover from a forgotten
+//! administrator password.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use crate::user;
+use crate::user::password;
+
+pub const USAGE: &str = "Subcommands:
+    reset-password USER           set a new password and revoke all sessions of the user
+    create-user USER [GROUP...]   create a user belonging to the given groups
+    delete-user USER              delete the user and all their sessions
+    list-users                    print all users with their groups
+    revoke-sessions USER          log the user out everywhere
+    audit-log [USER]              print the audit log, newest first, optionally only of the user
+
+The password is read from the standard input. Without a subcommand the web server is started.";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    ResetPassword { name: user::Name },
+    CreateUser { name: user::Name, groups: Vec<user::Group> },
+    DeleteUser { name: user::Name },
+    ListUsers,
+    RevokeSessions { name: user::Name },
+    AuditLog { name: Option<user::Name> },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("unknown subcommand {0}")]
+    UnknownCommand(String),
+    #[error("the subcommand {0} requires a user name")]
+    MissingUserName(&'static str),
+    #[error("unexpected argument {0}")]
+    UnexpectedArgument(String),
+    #[error("the argument {0:?} is not valid UTF-8")]
+    NotUnicode(OsString),
+    #[error("invalid user name")]
+    InvalidUserName(#[from] user::types::UserNameError),
+    #[error("invalid group name")]
+    InvalidGroupName(#[from] user::types::GroupNameError),
+}
+
+fn next_user_name<I: Iterator<Item=String>>(args: &mut I, command: &'static str) -> Result<user::Name, ParseError> {
+    let name = args.next().ok_or(ParseError::MissingUserName(command))?;
+    Ok(user::Name::try_from(name)?)
+}
+
+fn no_more_args<I: Iterator<Item=String>>(mut args: I) -> Result<(), ParseError> {
+    match args.next() {
+        Some(arg) => Err(ParseError::UnexpectedArgument(arg)),
+        None => Ok(()),
+    }
+}
+
+impl Command {
+    /// Parses the positional arguments left after parsing the configuration
+    ///
+    /// Returns `None` if there's no subcommand and the web server should be started.
+    pub fn parse<I>(args: I) -> Result<Option<Self>, ParseError> where I: IntoIterator, I::Item: Into<OsString> {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.into().into_string().map_err(ParseError::NotUnicode))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut args = args.into_iter();
+
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+
+        let command = match &*command {
+            "reset-password" => Command::ResetPassword { name: next_user_name(&mut args, "reset-password")?, },
+            "create-user" => {
+                let name = next_user_name(&mut args, "create-user")?;
+                let groups = args
+                    .by_ref()
+                    .map(user::Group::try_from)
+                    .collect::<Result<_, _>>()?;
+                Command::CreateUser { name, groups, }
+            },
+            "delete-user" => Command::DeleteUser { name: next_user_name(&mut args, "delete-user")?, },
+            "list-users" => Command::ListUsers,
+            "revoke-sessions" => Command::RevokeSessions { name: next_user_name(&mut args, "revoke-sessions")?, },
+            "audit-log" => Command::AuditLog { name: args.next().map(user::Name::try_from).transpose()?, },
+            _ => return Err(ParseError::UnknownCommand(command)),
+        };
+
+        no_more_args(args)?;
+        Ok(Some(command))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RunError<GetUser: 'static + std::error::Error, InsertUser: 'static + std::error::Error, UpdateUser: 'static + std::error::Error, Session: 'static + std::error::Error> {
+    #[error("the user {0} doesn't exist")]
+    UserNotFound(user::Name),
+    #[error("the user {0} already exists")]
+    UserExists(user::Name),
+    #[error("the password is empty")]
+    EmptyPassword,
+    #[error("failed to read the password")]
+    ReadPassword(#[source] io::Error),
+    #[error("failed to write the output")]
+    Output(#[source] io::Error),
+    #[error("failed to get user")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to insert the user")]
+    DbInsertUserError(#[source] InsertUser),
+    #[error("failed to update the user")]
+    DbUpdateUserError(#[source] UpdateUser),
+    #[error("failed to revoke sessions")]
+    DbSessionError(#[source] Session),
+    #[error("failed to retrieve the audit log")]
+    DbAuditLogError(#[source] UpdateUser),
+}
+
+type DbRunError<Db> = RunError<<Db as user::Db>::GetUserError, <Db as user::Db>::InsertUserError, <Db as user::Db>::UpdateUserError, <Db as user::Db>::SessionError>;
+
+fn read_new_password<A, B, C, D, F>(read_password: F) -> Result<String, RunError<A, B, C, D>> where A: std::error::Error, B: std::error::Error, C: std::error::Error, D: std::error::Error, F: FnOnce() -> io::Result<String> {
+    let password = read_password().map_err(RunError::ReadPassword)?;
+    if password.is_empty() {
+        return Err(RunError::EmptyPassword);
+    }
+    Ok(password)
+}
+
+/// Executes the command, `read_password` is called by commands that need a new password.
+pub async fn run<Db, F, W>(database: &mut Db, command: Command, params: &password::Params, read_password: F, mut output: W) -> Result<(), DbRunError<Db>>
+where Db: user::Db, Db::GetUserError: 'static, Db::InsertUserError: 'static, Db::UpdateUserError: 'static, Db::SessionError: 'static, F: FnOnce() -> io::Result<String>, W: Write {
+    match command {
+        Command::ResetPassword { name } => {
+            database
+                .get_user(name.clone())
+                .await
+                .map_err(RunError::DbGetUserError)?
+                .ok_or_else(|| RunError::UserNotFound(name.clone()))?;
+
+            let password = read_new_password(read_password)?;
+
+            database
+                .set_password(name.clone(), user::PasswordHash::new(&password, params))
+                .await
+                .map_err(RunError::DbUpdateUserError)?;
+
+            // whoever knew the old password could be still logged in
+            database
+                .revoke_all_sessions(name, None)
+                .await
+                .map_err(RunError::DbSessionError)
+        },
+        Command::CreateUser { name, groups } => {
+            let password = read_new_password(read_password)?;
+
+            let request = crate::login::SignupRequest {
+                name: name.clone(),
+                password,
+                groups,
+            };
+
+            match crate::login::signup(database, request, params).await {
+                Ok(()) => Ok(()),
+                Err(user::InsertError::UserExists) => Err(RunError::UserExists(name)),
+                Err(user::InsertError::DatabaseError(error)) => Err(RunError::DbInsertUserError(error)),
+            }
+        },
+        Command::DeleteUser { name } => {
+            let deleted = database
+                .delete_user(name.clone())
+                .await
+                .map_err(RunError::DbUpdateUserError)?;
+
+            if deleted {
+                Ok(())
+            } else {
+                Err(RunError::UserNotFound(name))
+            }
+        },
+        Command::ListUsers => {
+            let users = database
+                .list_users()
+                .await
+                .map_err(RunError::DbGetUserError)?;
+
+            for user in users {
+                let groups = user.groups.iter().map(|group| &**group).collect::<Vec<&str>>().join(",");
+                let mut flags = String::new();
+                if user.disabled {
+                    flags.push_str(" disabled");
+                }
+                if user.totp_secret.is_some() {
+                    flags.push_str(" totp");
+                }
+                writeln!(output, "{}\t{}\t{}", user.name, groups, flags.trim_start()).map_err(RunError::Output)?;
+            }
+            Ok(())
+        },
+        Command::RevokeSessions { name } => {
+            database
+                .get_user(name.clone())
+                .await
+                .map_err(RunError::DbGetUserError)?
+                .ok_or_else(|| RunError::UserNotFound(name.clone()))?;
+
+            database
+                .revoke_all_sessions(name, None)
+                .await
+                .map_err(RunError::DbSessionError)
+        },
+        Command::AuditLog { name } => {
+            let page_size = crate::login::audit::MAX_PAGE_SIZE;
+            let mut offset = 0;
+            loop {
+                let query = user::db::AuditQuery {
+                    user_name: name.clone(),
+                    offset,
+                    limit: page_size,
+                };
+                let records = database
+                    .list_audit_events(query)
+                    .await
+                    .map_err(RunError::DbAuditLogError)?;
+
+                for record in &records {
+                    writeln!(output, "{}", format_audit_record(record)).map_err(RunError::Output)?;
+                }
+                if records.len() < page_size as usize {
+                    break Ok(());
+                }
+                offset += page_size;
+            }
+        },
+    }
+}
+
+/// Formats the record as tab-separated line, missing values are replaced by `-`
+fn format_audit_record(record: &user::DbAuditRecord) -> String {
+    let address = record.client_address.map(|address| address.to_string());
+    let optional = |value: &Option<String>| value.as_ref().map_or("-", String::as_str).replace(&['\t', '\n'][..], " ");
+
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        humantime::format_rfc3339_seconds(record.time),
+        record.event,
+        record.user_name,
+        optional(&address),
+        optional(&record.app),
+        record.outcome,
+        optional(&record.message),
+        optional(&record.user_agent),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::{TryFrom, TryInto};
+    use super::{Command, ParseError, RunError};
+    use crate::mock_db::Db;
+    use crate::user::Db as _;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse(Vec::<String>::new()).unwrap(), None);
+        assert_eq!(Command::parse(vec!["list-users"]).unwrap(), Some(Command::ListUsers));
+        assert_eq!(Command::parse(vec!["audit-log"]).unwrap(), Some(Command::AuditLog { name: None, }));
+        assert_eq!(Command::parse(vec!["create-user", "satoshi", "admins", "family"]).unwrap(), Some(Command::CreateUser {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            groups: vec!["admins".to_owned().try_into().unwrap(), "family".to_owned().try_into().unwrap()],
+        }));
+
+        match Command::parse(vec!["reset-password"]) {
+            Err(ParseError::MissingUserName("reset-password")) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match Command::parse(vec!["delete-user", "satoshi", "dorian"]) {
+            Err(ParseError::UnexpectedArgument(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match Command::parse(vec!["revoke-sessions", "Satoshi"]) {
+            Err(ParseError::InvalidUserName(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        match Command::parse(vec!["serve"]) {
+            Err(ParseError::UnknownCommand(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn manage_users() {
+        let mut db = Db::default();
+        let params = crate::user::password::test_params();
+        let run = |db: &mut Db, args: &[&str], password: &'static str| {
+            let mut output = Vec::new();
+            let command = Command::parse(args.iter().copied()).unwrap().expect("missing subcommand");
+            tokio_test::block_on(super::run(db, command, &params, || Ok(password.to_owned()), &mut output)).map(|()| String::from_utf8(output).unwrap())
+        };
+
+        run(&mut db, &["create-user", "satoshi", "admins"], "nbusr123").unwrap();
+        match run(&mut db, &["create-user", "satoshi"], "nbusr123") {
+            Err(RunError::UserExists(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(run(&mut db, &["list-users"], "").unwrap(), "satoshi\tadmins\t\n");
+
+        match run(&mut db, &["reset-password", "satoshi"], "") {
+            Err(RunError::EmptyPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        run(&mut db, &["reset-password", "satoshi"], "correct horse").unwrap();
+        let user = tokio_test::block_on(db.get_user(crate::user::Name::<&str>::try_from("satoshi").unwrap())).unwrap().unwrap();
+        assert!(user.password_hash.verify("correct horse"));
+
+        run(&mut db, &["delete-user", "satoshi"], "").unwrap();
+        match run(&mut db, &["revoke-sessions", "satoshi"], "") {
+            Err(RunError::UserNotFound(_)) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn audit_log() {
+        use crate::user::types::{AuditEvent, AuditOutcome};
+
+        let mut db = Db::default();
+        let record = crate::user::DbAuditRecord {
+            time: std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_231_006_505),
+            event: AuditEvent::OpenApp,
+            user_name: "satoshi".to_owned().try_into().unwrap(),
+            client_address: Some([127, 0, 0, 1].into()),
+            user_agent: None,
+            app: Some("btcpayserver".to_owned()),
+            outcome: AuditOutcome::Rejected,
+            message: Some("not\tnow".to_owned()),
+        };
+        tokio_test::block_on(db.log_audit_event(record, std::time::SystemTime::UNIX_EPOCH)).unwrap();
+
+        let mut output = Vec::new();
+        let command = Command::parse(vec!["audit-log", "satoshi"]).unwrap().expect("missing subcommand");
+        tokio_test::block_on(super::run(&mut db, command, &Default::default(), || unreachable!(), &mut output)).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "2009-01-03T18:15:05Z\topen_app\tsatoshi\t127.0.0.1\tbtcpayserver\trejected\tnot now\t-\n");
+    }
+}
@@ -32,10 +32,10 @@ impl crate::webserver::ResponseBuilder for ResponseBuilder {
         self.inner.headers_mut().expect("http API is retarded").insert(http::header::CONTENT_TYPE, http::header::HeaderValue::try_from(content_type).expect("Invalid value for content type"));
     }
 
-    fn set_cookie(&mut self, key: &str, value: &str, expires_after_seconds: Option<u64>) {
+    fn set_cookie(&mut self, key: &str, value: &str, path: &str, expires_after_seconds: Option<u64>) {
         let cookie_string = match expires_after_seconds {
-            Some(max_age) => format!("{}={}; Max-Age={}; HttpOnly; SameSite=Lax", key, value, max_age),
-            None => format!("{}={}; HttpOnly; SameSite=Lax", key, value)
+            Some(max_age) => format!("{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax", key, value, path, max_age),
+            None => format!("{}={}; Path={}; HttpOnly; SameSite=Lax", key, value, path)
         };
         let cookie = cookie_string
             .try_into()
@@ -43,6 +43,13 @@ impl crate::webserver::ResponseBuilder for ResponseBuilder {
         self.inner.headers_mut().expect("http API is retarded").append(http::header::SET_COOKIE, cookie);
     }
 
+    fn set_csrf_cookie(&mut self, token: &crate::webserver::CsrfToken, path: &str) {
+        let cookie = format!("{}={}; Path={}; SameSite=Strict", crate::webserver::CSRF_COOKIE, token, path)
+            .try_into()
+            .expect("invalid Set-Cookie header");
+        self.inner.headers_mut().expect("http API is retarded").append(http::header::SET_COOKIE, cookie);
+    }
+
     fn redirect(url: &str, kind: crate::webserver::RedirectKind) -> Self {
         use crate::webserver::RedirectKind;
 
@@ -56,4 +63,15 @@ impl crate::webserver::ResponseBuilder for ResponseBuilder {
         builer.inner.headers_mut().expect("http API is retarded").insert(http::header::LOCATION, url.try_into().expect("invalid value for redirect URL"));
         builer
     }
+
+    fn set_header(&mut self, name: &'static str, value: &str) {
+        let value = http::header::HeaderValue::try_from(value).expect("invalid header value");
+        self.inner.headers_mut().expect("http API is retarded").insert(name, value);
+    }
+
+    fn append_header(&mut self, name: &str, value: &str) {
+        let name = http::header::HeaderName::try_from(name).expect("invalid header name");
+        let value = http::header::HeaderValue::try_from(value).expect("invalid header value");
+        self.inner.headers_mut().expect("http API is retarded").append(name, value);
+    }
 }
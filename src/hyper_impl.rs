@@ -8,6 +8,7 @@ pub struct ParsedRequest {
     //request: http::Request<hyper::Body>,
     request_parts: http::request::Parts,
     form_data: HashMap<String, String>,
+    query_args: HashMap<String, String>,
     cookies: HashMap<String, String>,
 }
 
@@ -28,6 +29,12 @@ impl ParsedRequest {
             }))
             .collect();
 
+        let query_args = request
+            .uri()
+            .query()
+            .map(|query| url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+
         let (parts, body) = request.into_parts();
         let body_bytes = body
             .scan(0usize, move |len, item| {
@@ -53,6 +60,7 @@ impl ParsedRequest {
         ParsedRequest {
             request_parts: parts,
             form_data,
+            query_args,
             cookies,
         }
     }
@@ -77,9 +85,17 @@ impl crate::webserver::Request for ParsedRequest {
         Ok(self.form_data.get(key).map(AsRef::as_ref))
     }
 
+    fn query_arg(&self, key: &str) -> Option<&str> {
+        self.query_args.get(key).map(AsRef::as_ref)
+    }
+
     fn get_cookie(&self, key: &str) -> Option<&str> {
         self.cookies.get(key).map(AsRef::as_ref)
     }
+
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.request_parts.headers.get(name).and_then(|value| value.to_str().ok())
+    }
 }
 
 impl<T> crate::webserver::Server for hyper::server::Builder<T> where T: 'static + hyper::server::accept::Accept, T::Conn: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static, T::Error: std::error::Error + Send + Sync {
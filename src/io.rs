@@ -32,6 +32,19 @@ pub trait BufReadExt: BufRead {
 
 impl<T: io::BufRead> BufReadExt for T {}
 
+/// Creates a file readable only by the owner, fails if it already exists
+pub fn create_private_file(path: &std::path::Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
 #[cfg(test)]
 mod tests {
     use super::BufReadExt;
@@ -0,0 +1,248 @@
+//! Personal API tokens letting scripts use the dashboard without the session cookies
+//!
+//! Each token is limited to the scopes chosen when creating it and expires after a chosen time.
+//! Routes that don't require any scope only accept sessions, so tokens can't be used to manage
+//! the account or to create more tokens.
+
+use std::time::{Duration, SystemTime};
+use crate::user;
+use crate::user::types::{ApiScope, ApiScopeError, ApiToken, ApiTokenId};
+use super::SessionConfig;
+
+/// Created token, only its hash is stored so it has to be handed over right away
+pub struct Created {
+    pub id: ApiTokenId,
+    pub token: ApiToken,
+    pub expires: SystemTime,
+}
+
+pub struct CreateRequest {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub lifetime: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CreateError<Session: 'static + std::error::Error> {
+    #[error("the token name is empty")]
+    EmptyName,
+    #[error("the token has no scopes")]
+    NoScopes,
+    #[error("failed to store the token")]
+    DbSessionError(#[source] Session),
+}
+
+/// Parses scopes separated by commas or whitespace, duplicates are removed
+pub fn parse_scopes(scopes: &str) -> Result<Vec<ApiScope>, ApiScopeError> {
+    let mut result = Vec::new();
+    for scope in scopes.split(|c: char| c == ',' || c.is_whitespace()).filter(|scope| !scope.is_empty()) {
+        let scope = scope.parse()?;
+        if !result.contains(&scope) {
+            result.push(scope);
+        }
+    }
+    Ok(result)
+}
+
+pub async fn create<Db: user::Db>(database: &mut Db, sessions: &SessionConfig, user: &user::Authenticated, request: CreateRequest) -> Result<Created, CreateError<Db::SessionError>> {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(CreateError::EmptyName);
+    }
+    if request.scopes.is_empty() {
+        return Err(CreateError::NoScopes);
+    }
+
+    let now = SystemTime::now();
+    let token = ApiToken::random();
+    let record = user::DbApiToken {
+        id: ApiTokenId::random(),
+        token_hash: token.hash(&sessions.token_key),
+        user_name: user.user_name().clone(),
+        name: super::truncate_session_info(name.to_owned()),
+        scopes: request.scopes,
+        created: now,
+        expires: now + request.lifetime,
+        last_used: None,
+    };
+    let created = Created {
+        id: record.id,
+        token,
+        expires: record.expires,
+    };
+
+    database.create_api_token(record).await.map_err(CreateError::DbSessionError)?;
+
+    Ok(created)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError<GetUser: 'static + std::error::Error, Session: 'static + std::error::Error> {
+    #[error("the API token doesn't exist, expired or its user is disabled")]
+    InvalidToken,
+    #[error("the API token doesn't allow {0}")]
+    MissingScope(ApiScope),
+    #[error("failed to get the user")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to access the API token")]
+    DbSessionError(#[source] Session),
+}
+
+/// Checks the token sent in the `Authorization` header and that it allows the operation
+pub async fn authenticate<Db: user::Db>(database: &mut Db, sessions: &SessionConfig, token: &str, scope: &ApiScope) -> Result<user::Authenticated, AuthError<Db::GetUserError, Db::SessionError>> where Db::GetUserError: 'static {
+    let token = token.parse::<ApiToken>().map_err(|_| AuthError::InvalidToken)?;
+    let now = SystemTime::now();
+
+    let record = database
+        .get_api_token(token.hash(&sessions.token_key))
+        .await
+        .map_err(AuthError::DbSessionError)?
+        .filter(|record| record.expires > now)
+        .ok_or(AuthError::InvalidToken)?;
+
+    if !record.scopes.contains(scope) {
+        return Err(AuthError::MissingScope(scope.clone()));
+    }
+
+    let user = database
+        .get_user(record.user_name)
+        .await
+        .map_err(AuthError::DbGetUserError)?
+        .filter(|user| !user.disabled)
+        .ok_or(AuthError::InvalidToken)?;
+
+    database
+        .touch_api_token(record.id, now)
+        .await
+        .map_err(AuthError::DbSessionError)?;
+
+    Ok(user::Authenticated::token_authenticated(user.name, user.groups))
+}
+
+pub mod api {
+    #[derive(serde_derive::Serialize)]
+    pub struct CreatedToken {
+        pub id: String,
+        /// Value of the `Authorization: Bearer` header
+        pub token: String,
+        /// Seconds since UNIX epoch
+        pub expires: u64,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct Token {
+        pub id: String,
+        pub name: String,
+        pub scopes: Vec<String>,
+        /// Seconds since UNIX epoch
+        pub created: u64,
+        /// Seconds since UNIX epoch
+        pub expires: u64,
+        /// Seconds since UNIX epoch
+        pub last_used: Option<u64>,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct TokensResponse {
+        pub tokens: Vec<Token>,
+    }
+}
+
+pub fn created<S: crate::webserver::Server>(created: &Created) -> S::ResponseBuilder {
+    let resp = api::CreatedToken {
+        id: created.id.to_string(),
+        token: created.token.to_string(),
+        expires: super::unix_timestamp(created.expires),
+    };
+
+    crate::webserver::json_response(&resp)
+}
+
+pub fn get_tokens<S: crate::webserver::Server>(tokens: Vec<user::DbApiToken>) -> S::ResponseBuilder {
+    let tokens = tokens
+        .into_iter()
+        .map(|token| api::Token {
+            id: token.id.to_string(),
+            name: token.name,
+            scopes: token.scopes.iter().map(ToString::to_string).collect(),
+            created: super::unix_timestamp(token.created),
+            expires: super::unix_timestamp(token.expires),
+            last_used: token.last_used.map(super::unix_timestamp),
+        })
+        .collect();
+
+    crate::webserver::json_response(&api::TokensResponse { tokens, })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::{TryFrom, TryInto};
+    use std::time::Duration;
+    use super::{AuthError, CreateRequest};
+    use crate::mock_db::Db;
+    use crate::user::{self, types::ApiScope, Db as _};
+
+    #[test]
+    fn parse_scopes() {
+        let scopes = super::parse_scopes("list-apps, open-app:thunderhub open-app:thunderhub").unwrap();
+        assert_eq!(scopes, vec![ApiScope::ListApps, ApiScope::OpenApp("thunderhub".to_owned().try_into().unwrap())]);
+        assert_eq!(scopes[1].to_string(), "open-app:thunderhub");
+        assert!(super::parse_scopes("admin").is_err());
+        assert!(super::parse_scopes("open-app:Thunderhub").is_err());
+    }
+
+    #[test]
+    fn authenticate() {
+        let mut db = Db::default();
+        let params = crate::user::password::test_params();
+        let sessions = crate::login::test_sessions();
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        tokio_test::block_on(crate::login::signup(&mut db, crate::login::SignupRequest { name: name.clone(), password: "nbusr123".to_owned(), groups: Vec::new(), }, &params)).unwrap();
+        let user = user::Authenticated::user_logged_in(name.clone(), crate::user::types::SessionId::random(), Vec::new());
+        let open_app = ApiScope::OpenApp("thunderhub".to_owned().try_into().unwrap());
+
+        let create = |db: &mut Db, lifetime| {
+            let request = CreateRequest {
+                name: "backup script".to_owned(),
+                scopes: vec![ApiScope::ListApps],
+                lifetime,
+            };
+            tokio_test::block_on(super::create(db, &sessions, &user, request)).unwrap()
+        };
+        let authenticate = |db: &mut Db, token: &str, scope: &ApiScope| tokio_test::block_on(super::authenticate(db, &sessions, token, scope));
+
+        let created = create(&mut db, Duration::from_secs(3600));
+        let authenticated = authenticate(&mut db, &created.token.to_string(), &ApiScope::ListApps).unwrap();
+        assert_eq!(authenticated.name(), "satoshi");
+        assert_eq!(authenticated.session_id(), None);
+        assert!(tokio_test::block_on(db.list_api_tokens(name.clone())).unwrap()[0].last_used.is_some());
+
+        match authenticate(&mut db, &created.token.to_string(), &open_app) {
+            Err(AuthError::MissingScope(_)) => (),
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+        match authenticate(&mut db, "not a token", &ApiScope::ListApps) {
+            Err(AuthError::InvalidToken) => (),
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+
+        let expired = create(&mut db, Duration::from_secs(0));
+        match authenticate(&mut db, &expired.token.to_string(), &ApiScope::ListApps) {
+            Err(AuthError::InvalidToken) => (),
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+
+        tokio_test::block_on(db.set_disabled(name.clone(), true)).unwrap();
+        match authenticate(&mut db, &created.token.to_string(), &ApiScope::ListApps) {
+            Err(AuthError::InvalidToken) => (),
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+        tokio_test::block_on(db.set_disabled(name.clone(), false)).unwrap();
+
+        assert!(tokio_test::block_on(db.revoke_api_token(name, created.id)).unwrap());
+        match authenticate(&mut db, &created.token.to_string(), &ApiScope::ListApps) {
+            Err(AuthError::InvalidToken) => (),
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Persistent log of logins, logouts and opened apps
+//!
+//! Failing to store a record is logged but doesn't fail the request, the user shouldn't be locked
+//! out because of a database hiccup. Records older than the retention period are removed whenever
+//! a new one is stored.
+
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use slog::error;
+use crate::app::OpenError;
+use crate::user::{self, types::{AuditEvent, AuditOutcome}};
+use super::LoginError;
+
+/// Maximum number of records returned at once
+pub const MAX_PAGE_SIZE: u32 = 1000;
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Who made the request
+pub struct Client {
+    pub address: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl Client {
+    /// Creates a record of an event happening now, `app` and `message` are left empty
+    pub fn record(&self, event: AuditEvent, user_name: user::Name, outcome: AuditOutcome) -> user::DbAuditRecord {
+        user::DbAuditRecord {
+            time: SystemTime::now(),
+            event,
+            user_name,
+            client_address: self.address,
+            user_agent: self.user_agent.clone(),
+            app: None,
+            outcome,
+            message: None,
+        }
+    }
+}
+
+/// Returns the time before which records are removed, zero retention keeps them forever
+pub fn retain_since(now: SystemTime, retention: Duration) -> SystemTime {
+    if retention == Duration::from_secs(0) {
+        return SystemTime::UNIX_EPOCH;
+    }
+    now.checked_sub(retention).unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+pub async fn log<Db: user::Db>(database: &mut Db, retention: Duration, record: user::DbAuditRecord, logger: &slog::Logger) {
+    let retain_since = retain_since(record.time, retention);
+    if let Err(error) = database.log_audit_event(record, retain_since).await {
+        error!(logger, "failed to store the audit record"; "error" => #error);
+    }
+}
+
+pub fn login_outcome<T, A, B, C, D>(result: &Result<T, LoginError<A, B, C, D>>) -> AuditOutcome {
+    match result {
+        Ok(_) => AuditOutcome::Success,
+        Err(LoginError::BadUserPassword) => AuditOutcome::BadCredentials,
+        Err(LoginError::UserDisabled) => AuditOutcome::Disabled,
+        Err(LoginError::SecondFactorRequired(_)) => AuditOutcome::SecondFactorRequired,
+        Err(_) => AuditOutcome::Failed,
+    }
+}
+
+/// Returns the outcome of opening an app together with the explanation
+pub fn open_app_outcome<T>(result: &Result<T, OpenError>) -> (AuditOutcome, Option<String>) {
+    match result {
+        Ok(_) => (AuditOutcome::Success, None),
+        Err(OpenError::NonAdmin) => (AuditOutcome::NonAdmin, None),
+        Err(OpenError::NotAllowed) => (AuditOutcome::NotAllowed, None),
+        Err(OpenError::RejectedWithMessage(message)) => (AuditOutcome::Rejected, Some(message.clone())),
+        Err(OpenError::RejectedWithInvalidMessage) => (AuditOutcome::Rejected, None),
+        Err(OpenError::Refused { message, }) => (AuditOutcome::Rejected, message.clone()),
+        Err(error) => (AuditOutcome::Failed, Some(error.to_string())),
+    }
+}
+
+pub mod api {
+    #[derive(serde_derive::Serialize)]
+    pub struct Record {
+        /// Seconds since UNIX epoch
+        pub time: u64,
+        pub event: crate::user::types::AuditEvent,
+        pub user_name: String,
+        pub client_address: Option<std::net::IpAddr>,
+        pub user_agent: Option<String>,
+        pub app: Option<String>,
+        pub outcome: crate::user::types::AuditOutcome,
+        pub message: Option<String>,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct LogResponse {
+        pub records: Vec<Record>,
+        /// Offset of the next page, missing on the last page
+        pub next_offset: Option<u32>,
+    }
+}
+
+/// `query` is the one used to retrieve the records, it's needed for the offset of the next page
+pub fn get_log<S: crate::webserver::Server>(records: Vec<user::DbAuditRecord>, query: &user::db::AuditQuery) -> S::ResponseBuilder {
+    let next_offset = if records.len() < query.limit as usize {
+        None
+    } else {
+        Some(query.offset.saturating_add(query.limit))
+    };
+
+    let records = records
+        .into_iter()
+        .map(|record| api::Record {
+            time: super::unix_timestamp(record.time),
+            event: record.event,
+            user_name: record.user_name.into_inner(),
+            client_address: record.client_address,
+            user_agent: record.user_agent,
+            app: record.app,
+            outcome: record.outcome,
+            message: record.message,
+        })
+        .collect();
+
+    crate::webserver::json_response(&api::LogResponse { records, next_offset, })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime};
+    use crate::app::OpenError;
+    use crate::mock_db::Db;
+    use crate::user::{self, db::AuditQuery, types::{AuditEvent, AuditOutcome}, Db as _};
+
+    #[test]
+    fn open_app_outcome() {
+        assert_eq!(super::open_app_outcome(&Ok("https://example.com".to_owned())), (AuditOutcome::Success, None));
+        assert_eq!(super::open_app_outcome::<()>(&Err(OpenError::NotAllowed)), (AuditOutcome::NotAllowed, None));
+        assert_eq!(super::open_app_outcome::<()>(&Err(OpenError::RejectedWithMessage("not today".to_owned()))), (AuditOutcome::Rejected, Some("not today".to_owned())));
+        assert_eq!(super::open_app_outcome::<()>(&Err(OpenError::Refused { message: None, })), (AuditOutcome::Rejected, None));
+        let (outcome, message) = super::open_app_outcome::<()>(&Err(OpenError::EntryPointFailedWithMessage { message: "oops".to_owned(), exit_code: 2, }));
+        assert_eq!(outcome, AuditOutcome::Failed);
+        assert!(message.unwrap().contains("oops"));
+    }
+
+    #[test]
+    fn retention_and_paging() {
+        let mut db = Db::default();
+        let client = super::Client {
+            address: Some([127, 0, 0, 1].into()),
+            user_agent: None,
+        };
+        let satoshi = user::Name::try_from("satoshi".to_owned()).unwrap();
+        let hal = user::Name::try_from("hal".to_owned()).unwrap();
+        let now = SystemTime::now();
+        let retention = Duration::from_secs(60);
+        let log = |db: &mut Db, name: &user::Name, time: SystemTime, outcome: AuditOutcome| {
+            let record = user::DbAuditRecord { time, ..client.record(AuditEvent::Login, name.clone(), outcome) };
+            tokio_test::block_on(db.log_audit_event(record, super::retain_since(now, retention))).unwrap();
+        };
+        let list = |db: &mut Db, user_name: Option<&user::Name>, offset, limit| {
+            let query = AuditQuery { user_name: user_name.cloned(), offset, limit, };
+            tokio_test::block_on(db.list_audit_events(query)).unwrap().into_iter().map(|record| record.outcome).collect::<Vec<_>>()
+        };
+
+        log(&mut db, &satoshi, now - Duration::from_secs(120), AuditOutcome::Throttled);
+        log(&mut db, &satoshi, now, AuditOutcome::BadCredentials);
+        log(&mut db, &hal, now, AuditOutcome::Disabled);
+        log(&mut db, &satoshi, now, AuditOutcome::Success);
+
+        // the first record expired when the second was stored
+        assert_eq!(list(&mut db, None, 0, 10), [AuditOutcome::Success, AuditOutcome::Disabled, AuditOutcome::BadCredentials]);
+        assert_eq!(list(&mut db, None, 1, 1), [AuditOutcome::Disabled]);
+        assert_eq!(list(&mut db, Some(&satoshi), 0, 10), [AuditOutcome::Success, AuditOutcome::BadCredentials]);
+        assert!(list(&mut db, Some(&satoshi), 2, 10).is_empty());
+    }
+
+    #[test]
+    fn zero_retention_keeps_everything() {
+        assert_eq!(super::retain_since(SystemTime::now(), Duration::from_secs(0)), SystemTime::UNIX_EPOCH);
+    }
+}
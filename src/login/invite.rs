@@ -0,0 +1,236 @@
+//! Invitations letting new users choose their own name and password
+//!
+//! An administrator creates a single-use link which expires after a configured time. The groups
+//! of the new user are chosen by the administrator when creating the invite.
+
+use std::time::{Duration, SystemTime};
+use crate::user;
+use crate::user::password;
+use crate::user::types::{InviteId, InviteToken};
+use super::{LoginSuccessful, SessionConfig, SessionInfo, SignupRequest};
+
+/// Created invite, the token is never stored so it has to be handed over right away
+pub struct Invite {
+    pub id: InviteId,
+    pub token: InviteToken,
+    pub expires: SystemTime,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CreateError<UpdateUser: 'static + std::error::Error> {
+    #[error("the invite lifetime {0:?} is too long")]
+    LifetimeTooLong(Duration),
+    #[error("failed to store the invite")]
+    DbInviteError(#[source] UpdateUser),
+}
+
+pub async fn create<Db: user::Db>(database: &mut Db, sessions: &SessionConfig, admin: &user::Authenticated, groups: Vec<user::Group>, lifetime: Duration) -> Result<Invite, CreateError<Db::UpdateUserError>> where Db::UpdateUserError: 'static {
+    let now = SystemTime::now();
+    let expires = now.checked_add(lifetime).ok_or(CreateError::LifetimeTooLong(lifetime))?;
+    let token = InviteToken::random();
+    let record = user::DbInvite {
+        id: InviteId::random(),
+        token_hash: token.hash(&sessions.token_key),
+        created_by: admin.user_name().clone(),
+        created: now,
+        expires,
+        groups,
+    };
+    let invite = Invite {
+        id: record.id,
+        token,
+        expires: record.expires,
+    };
+
+    database.create_invite(record).await.map_err(CreateError::DbInviteError)?;
+
+    Ok(invite)
+}
+
+/// Returns invites that can still be accepted
+pub async fn list_pending<Db: user::Db>(database: &mut Db) -> Result<Vec<user::DbInvite>, Db::UpdateUserError> {
+    let now = SystemTime::now();
+    let mut invites = database.list_invites().await?;
+    invites.retain(|invite| invite.expires > now);
+    Ok(invites)
+}
+
+/// Checks whether the invite can be accepted without consuming it
+pub async fn is_valid<Db: user::Db>(database: &mut Db, sessions: &SessionConfig, token: &InviteToken) -> Result<bool, Db::UpdateUserError> {
+    let invite = database.get_invite(token.hash(&sessions.token_key)).await?;
+    Ok(invite.map(|invite| invite.expires > SystemTime::now()).unwrap_or(false))
+}
+
+pub struct AcceptRequest {
+    pub token: InviteToken,
+    pub name: user::Name,
+    pub password: String,
+    pub session: SessionInfo,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AcceptError<InsertUser: 'static + std::error::Error, UpdateUser: 'static + std::error::Error, Session: 'static + std::error::Error> {
+    #[error("the invite doesn't exist, expired or was already used")]
+    InvalidInvite,
+    #[error("the password is empty")]
+    EmptyPassword,
+    #[error("the user already exists")]
+    UserExists,
+    #[error("failed to access the invite")]
+    DbInviteError(#[source] UpdateUser),
+    #[error("failed to insert the user")]
+    DbInsertUserError(#[source] InsertUser),
+    #[error("failed to create session")]
+    DbSessionError(#[source] Session),
+}
+
+/// Creates the user with the groups chosen by the administrator and logs them in.
+///
+/// The invite is consumed before creating the user so that it can't be used twice concurrently.
+/// If the name is already taken the invite is restored so that the invitee can pick another one.
+pub async fn accept<Db: user::Db>(database: &mut Db, params: &password::Params, sessions: &SessionConfig, request: AcceptRequest) -> Result<LoginSuccessful, AcceptError<Db::InsertUserError, Db::UpdateUserError, Db::SessionError>> where Db::InsertUserError: 'static {
+    if request.password.is_empty() {
+        return Err(AcceptError::EmptyPassword);
+    }
+
+    let invite = database
+        .take_invite(request.token.hash(&sessions.token_key))
+        .await
+        .map_err(AcceptError::DbInviteError)?
+        .ok_or(AcceptError::InvalidInvite)?;
+
+    if invite.expires <= SystemTime::now() {
+        return Err(AcceptError::InvalidInvite);
+    }
+
+    let signup_request = SignupRequest {
+        name: request.name.clone(),
+        password: request.password,
+        groups: invite.groups.clone(),
+    };
+
+    match super::signup(database, signup_request, params).await {
+        Ok(()) => (),
+        Err(user::InsertError::UserExists) => {
+            database
+                .create_invite(invite)
+                .await
+                .map_err(AcceptError::DbInviteError)?;
+            return Err(AcceptError::UserExists);
+        },
+        Err(user::InsertError::DatabaseError(error)) => return Err(AcceptError::DbInsertUserError(error)),
+    }
+
+    super::create_session(database, sessions, request.name, request.session)
+        .await
+        .map_err(AcceptError::DbSessionError)
+}
+
+pub mod api {
+    #[derive(serde_derive::Serialize)]
+    pub struct CreatedInvite {
+        pub id: String,
+        /// Path of the invitation page relative to the host
+        pub path: String,
+        /// Seconds since UNIX epoch
+        pub expires: u64,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct Invite {
+        pub id: String,
+        pub created_by: String,
+        /// Seconds since UNIX epoch
+        pub created: u64,
+        /// Seconds since UNIX epoch
+        pub expires: u64,
+        pub groups: Vec<String>,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct InvitesResponse {
+        pub invites: Vec<Invite>,
+    }
+}
+
+pub fn created<S: crate::webserver::Server>(prefix: &str, invite: &Invite) -> S::ResponseBuilder {
+    let resp = api::CreatedInvite {
+        id: invite.id.to_string(),
+        path: format!("{}/invite/{}", prefix, invite.token),
+        expires: super::unix_timestamp(invite.expires),
+    };
+
+    crate::webserver::json_response(&resp)
+}
+
+pub fn get_invites<S: crate::webserver::Server>(invites: Vec<user::DbInvite>) -> S::ResponseBuilder {
+    let invites = invites
+        .into_iter()
+        .map(|invite| api::Invite {
+            id: invite.id.to_string(),
+            created_by: invite.created_by.into_inner(),
+            created: super::unix_timestamp(invite.created),
+            expires: super::unix_timestamp(invite.expires),
+            groups: invite.groups.into_iter().map(user::Group::into_inner).collect(),
+        })
+        .collect();
+
+    crate::webserver::json_response(&api::InvitesResponse { invites, })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::time::Duration;
+    use super::{AcceptRequest, AcceptError, CreateError};
+    use crate::login::SessionInfo;
+    use crate::mock_db::Db;
+    use crate::user::{self, Db as _};
+
+    #[test]
+    fn accept() {
+        let mut db = Db::default();
+        let params = crate::user::password::test_params();
+        let sessions = crate::login::test_sessions();
+        let admin = user::Authenticated::user_logged_in("admin".to_owned().try_into().unwrap(), crate::user::types::SessionId::random(), vec![user::Group::ADMINS.into_owned()]);
+        tokio_test::block_on(crate::login::signup(&mut db, crate::login::SignupRequest { name: "dorian".to_owned().try_into().unwrap(), password: "nbusr123".to_owned(), groups: Vec::new(), }, &params)).unwrap();
+
+        let groups = vec!["family".to_owned().try_into().unwrap()];
+        let invite = tokio_test::block_on(super::create(&mut db, &sessions, &admin, groups, Duration::from_secs(3600))).unwrap();
+        assert_eq!(tokio_test::block_on(super::list_pending(&mut db)).unwrap().len(), 1);
+        assert!(tokio_test::block_on(super::is_valid(&mut db, &sessions, &invite.token)).unwrap());
+
+        let request = |name: &str| AcceptRequest {
+            token: invite.token,
+            name: name.to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            session: SessionInfo::default(),
+        };
+
+        // the invite survives choosing a taken name
+        match tokio_test::block_on(super::accept(&mut db, &params, &sessions, request("dorian"))) {
+            Err(AcceptError::UserExists) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let success = tokio_test::block_on(super::accept(&mut db, &params, &sessions, request("satoshi"))).expect("failed to accept invite");
+        assert_eq!(&*success.name, "satoshi");
+        let user = tokio_test::block_on(db.get_user(success.name)).unwrap().unwrap();
+        assert_eq!(&*user.groups[0], "family");
+
+        match tokio_test::block_on(super::accept(&mut db, &params, &sessions, request("hal"))) {
+            Err(AcceptError::InvalidInvite) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(tokio_test::block_on(super::list_pending(&mut db)).unwrap().is_empty());
+
+        let invite = tokio_test::block_on(super::create(&mut db, &sessions, &admin, Vec::new(), Duration::from_secs(0))).unwrap();
+        assert!(!tokio_test::block_on(super::is_valid(&mut db, &sessions, &invite.token)).unwrap());
+        assert!(tokio_test::block_on(db.revoke_invite(invite.id)).unwrap());
+
+        match tokio_test::block_on(super::create(&mut db, &sessions, &admin, Vec::new(), Duration::from_secs(u64::max_value()))) {
+            Err(CreateError::LifetimeTooLong(_)) => (),
+            _ => panic!("overflowing lifetime accepted"),
+        }
+    }
+}
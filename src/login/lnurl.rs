@@ -0,0 +1,654 @@
+//! Login with a Lightning wallet using LNURL-auth (LUD-04)
+//!
+//! The browser asks for a challenge (k1) and shows it as a QR code. The wallet signs k1 with the
+//! key it derived for this server and calls the callback, which maps the key to the user who
+//! linked it before. Meanwhile the browser polls the status of the challenge and finishes the
+//! login once it's verified. Keys are linked the same way by a logged-in user, except that the
+//! browser shows the fingerprint of the key that signed and the user has to confirm it. Anyone
+//! can sign the challenge in the QR code, the confirmation makes sure it was their wallet.
+//!
+//! Challenges live only in memory, they expire quickly and a restart just makes the user scan a
+//! new code.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use crate::user;
+use crate::user::types::{LnurlK1, LnurlK1Error, LnurlSecret, LinkingKey, LinkingKeyError};
+use super::{LoginSuccessful, SecondFactorChallenge, SessionConfig, SessionInfo};
+
+/// How long the user has to scan the code
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Cookie containing the `LnurlSecret` of the challenge displayed by the browser
+pub const SECRET_COOKIE: &str = "lnurl_secret";
+
+/// Expired challenges are removed when the number of pending ones exceeds this, if none expired new
+/// challenges are refused
+const MAX_CHALLENGES: usize = 10_000;
+
+/// What happens after the wallet signs the challenge
+#[derive(Debug, Clone)]
+pub enum Action {
+    Login,
+    /// Links the key to the account of the user
+    Link(user::Name),
+}
+
+impl Action {
+    /// The value of the `action` parameter of LUD-04, wallets show it to the user
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Login => "login",
+            Action::Link(_) => "link",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Pending,
+    /// The wallet signed the challenge, contains the user the key belongs to or who is linking it
+    Verified { user_name: user::Name, key: LinkingKey },
+    /// The signature was valid but the key couldn't be used
+    Failed,
+}
+
+struct Challenge {
+    action: Action,
+    secret: LnurlSecret,
+    created: Instant,
+    state: State,
+}
+
+impl Challenge {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.created) >= CHALLENGE_LIFETIME
+    }
+}
+
+/// Challenge to be displayed by the browser
+pub struct Created {
+    pub k1: LnurlK1,
+    /// Has to be stored in `SECRET_COOKIE`
+    pub secret: LnurlSecret,
+    /// Bech32-encoded callback URL scanned by the wallet
+    pub lnurl: String,
+}
+
+/// Status of the challenge reported to the browser
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Pending,
+    Verified,
+    Failed,
+    /// Also returned for unknown challenges and challenges of other browsers
+    Expired,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InitError {
+    #[error("the LNURL-auth URL {url} must be an HTTP(S) URL ending with the root path {root_path}")]
+    InvalidUrl { url: String, root_path: String },
+    #[error("failed to encode the callback URL")]
+    Bech32(#[source] bech32::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("too many pending LNURL-auth challenges")]
+pub struct TooManyChallenges;
+
+/// Errors are reported to the wallet which displays them to the user
+#[derive(Debug, thiserror::Error)]
+pub enum CallbackError<E: 'static + std::error::Error> {
+    #[error("invalid challenge")]
+    InvalidK1(#[source] LnurlK1Error),
+    #[error("invalid linking key")]
+    InvalidKey(#[source] LinkingKeyError),
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("the challenge expired, please reload the page")]
+    UnknownChallenge,
+    #[error("the challenge was already used")]
+    AlreadyUsed,
+    #[error("this key is not linked to any account, log in with your password and link it first")]
+    NotLinked,
+    #[error("this key is already linked to an account")]
+    AlreadyLinked,
+    #[error("failed to access linked keys")]
+    DbUpdateUserError(#[source] E),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FinishError<GetUser: 'static + std::error::Error, Session: 'static + std::error::Error> {
+    #[error("the user owning the key doesn't exist")]
+    UserNotFound,
+    #[error("the user is disabled")]
+    UserDisabled,
+    #[error("the user has to enter the second factor")]
+    SecondFactorRequired(SecondFactorChallenge),
+    #[error("failed to get user")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to create session")]
+    DbCreateSessionError(#[source] Session),
+}
+
+pub struct Provider {
+    /// External URL of the callback called by wallets
+    callback_url: String,
+    secp: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    challenges: Mutex<HashMap<LnurlK1, Challenge>>,
+}
+
+impl Provider {
+    /// Checks the external URL of the dashboard, the wallet has to be able to reach it
+    pub fn init(url: String, root_path: &str) -> Result<Self, InitError> {
+        let url = url.trim_end_matches('/').to_owned();
+        let valid = url::Url::parse(&url)
+            .map(|parsed| (parsed.scheme() == "https" || parsed.scheme() == "http") && parsed.query().is_none() && parsed.fragment().is_none() && parsed.path().trim_end_matches('/') == root_path.trim_end_matches('/'))
+            .unwrap_or(false);
+        if !valid {
+            return Err(InitError::InvalidUrl { url, root_path: root_path.to_owned(), });
+        }
+
+        let provider = Provider::with_callback(format!("{}/lnurl-auth/callback", url));
+        // fail early rather than on each login
+        provider.lnurl(&LnurlK1::random(), &Action::Login).map_err(InitError::Bech32)?;
+        Ok(provider)
+    }
+
+    fn with_callback(callback_url: String) -> Self {
+        Provider {
+            callback_url,
+            secp: secp256k1::Secp256k1::verification_only(),
+            challenges: Default::default(),
+        }
+    }
+
+    fn lnurl(&self, k1: &LnurlK1, action: &Action) -> Result<String, bech32::Error> {
+        use bech32::ToBase32;
+
+        let url = format!("{}?tag=login&k1={}&action={}", self.callback_url, k1, action.as_str());
+        // upper case makes the QR code smaller
+        bech32::encode("lnurl", url.as_bytes().to_base32()).map(|lnurl| lnurl.to_ascii_uppercase())
+    }
+
+    pub fn create(&self, action: Action, now: Instant) -> Result<Created, TooManyChallenges> {
+        let k1 = LnurlK1::random();
+        let lnurl = self.lnurl(&k1, &action).expect("the URL was checked by init");
+        let secret = LnurlSecret::random();
+
+        let mut challenges = self.challenges.lock().expect("LNURL-auth mutex poisoned");
+        if challenges.len() >= MAX_CHALLENGES {
+            challenges.retain(|_, challenge| !challenge.is_expired(now));
+            if challenges.len() >= MAX_CHALLENGES {
+                return Err(TooManyChallenges);
+            }
+        }
+        challenges.insert(k1, Challenge { action, secret, created: now, state: State::Pending, });
+
+        Ok(Created { k1, secret, lnurl, })
+    }
+
+    /// Returns the action of the pending challenge if the signature by the key is valid
+    fn verify<E: 'static + std::error::Error>(&self, k1: &LnurlK1, signature: &str, key: &LinkingKey, now: Instant) -> Result<Action, CallbackError<E>> {
+        let signature = hex::decode(signature).map_err(|_| CallbackError::InvalidSignature)?;
+        let mut signature = secp256k1::Signature::from_der(&signature).map_err(|_| CallbackError::InvalidSignature)?;
+        // libsecp256k1 only accepts low S but wallets don't have to produce it
+        signature.normalize_s();
+        let message = secp256k1::Message::from_slice(k1.as_bytes()).expect("k1 has the length of a hash");
+
+        let challenges = self.challenges.lock().expect("LNURL-auth mutex poisoned");
+        let challenge = challenges
+            .get(k1)
+            .filter(|challenge| !challenge.is_expired(now))
+            .ok_or(CallbackError::UnknownChallenge)?;
+        match challenge.state {
+            State::Pending => (),
+            State::Verified { .. } | State::Failed => return Err(CallbackError::AlreadyUsed),
+        }
+
+        self.secp
+            .verify(&message, &signature, key.as_public_key())
+            .map_err(|_| CallbackError::InvalidSignature)?;
+
+        Ok(challenge.action.clone())
+    }
+
+    /// Moves the pending challenge to `state`, returns `false` if another callback finished first
+    fn set_state(&self, k1: &LnurlK1, state: State) -> bool {
+        let mut challenges = self.challenges.lock().expect("LNURL-auth mutex poisoned");
+        match challenges.get_mut(k1) {
+            Some(challenge @ Challenge { state: State::Pending, .. }) => {
+                challenge.state = state;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Handles the request of the wallet, the parameters are passed as received
+    ///
+    /// Keys of link challenges are only checked, they're linked once the user confirms them.
+    pub async fn callback<Db: user::Db>(&self, database: &mut Db, k1: &str, signature: &str, key: &str, now: Instant) -> Result<(), CallbackError<Db::UpdateUserError>> {
+        let k1 = k1.parse::<LnurlK1>().map_err(CallbackError::InvalidK1)?;
+        let key = key.parse::<LinkingKey>().map_err(CallbackError::InvalidKey)?;
+
+        let action = self.verify(&k1, signature, &key, now)?;
+
+        let result = match action {
+            Action::Login => database
+                .get_lnurl_key(key)
+                .await
+                .map_err(CallbackError::DbUpdateUserError)?
+                .map(|record| record.user_name)
+                .ok_or(CallbackError::NotLinked),
+            Action::Link(user_name) => match database.get_lnurl_key(key).await.map_err(CallbackError::DbUpdateUserError)? {
+                Some(_) => Err(CallbackError::AlreadyLinked),
+                None => Ok(user_name),
+            },
+        };
+
+        // The challenge was pending when verified but the lock isn't held while the database is
+        // accessed, a concurrent callback mustn't replace the key the browser may already show.
+        let state = match &result {
+            Ok(user_name) => State::Verified { user_name: user_name.clone(), key, },
+            Err(_) => State::Failed,
+        };
+        if !self.set_state(&k1, state) {
+            return Err(CallbackError::AlreadyUsed);
+        }
+        result.map(drop)
+    }
+
+    pub fn status(&self, k1: &LnurlK1, secret: &LnurlSecret, now: Instant) -> Status {
+        let challenges = self.challenges.lock().expect("LNURL-auth mutex poisoned");
+        match challenges.get(k1).filter(|challenge| challenge.secret == *secret && !challenge.is_expired(now)) {
+            Some(challenge) => match challenge.state {
+                State::Pending => Status::Pending,
+                State::Verified { .. } => Status::Verified,
+                State::Failed => Status::Failed,
+            },
+            None => Status::Expired,
+        }
+    }
+
+    /// Returns the key that signed the verified link challenge so that the user can check it
+    pub fn link_key(&self, k1: &LnurlK1, secret: &LnurlSecret, now: Instant) -> Option<LinkingKey> {
+        let challenges = self.challenges.lock().expect("LNURL-auth mutex poisoned");
+        match challenges.get(k1).filter(|challenge| challenge.secret == *secret && !challenge.is_expired(now)) {
+            Some(Challenge { action: Action::Link(_), state: State::Verified { key, .. }, .. }) => Some(*key),
+            _ => None,
+        }
+    }
+
+    /// Removes the verified challenge if `accept` allows its action and key
+    fn take(&self, k1: &LnurlK1, secret: &LnurlSecret, now: Instant, accept: impl Fn(&Action, &LinkingKey) -> bool) -> Option<(user::Name, LinkingKey)> {
+        let mut challenges = self.challenges.lock().expect("LNURL-auth mutex poisoned");
+        let valid = match challenges.get(k1) {
+            Some(challenge) if challenge.secret == *secret && !challenge.is_expired(now) => match &challenge.state {
+                State::Verified { key, .. } => accept(&challenge.action, key),
+                _ => false,
+            },
+            _ => false,
+        };
+        if !valid {
+            return None;
+        }
+        match challenges.remove(k1).map(|challenge| challenge.state) {
+            Some(State::Verified { user_name, key, }) => Some((user_name, key)),
+            _ => None,
+        }
+    }
+
+    /// Removes the verified login challenge and returns the user who signed it
+    pub fn take_verified(&self, k1: &LnurlK1, secret: &LnurlSecret, now: Instant) -> Option<user::Name> {
+        self
+            .take(k1, secret, now, |action, _| match action {
+                Action::Login => true,
+                Action::Link(_) => false,
+            })
+            .map(|(user_name, _)| user_name)
+    }
+
+    /// Removes the verified link challenge of the user and returns the key that signed it
+    ///
+    /// `fingerprint` is the one the user confirmed, the challenge is kept if it doesn't match.
+    pub fn take_verified_link(&self, k1: &LnurlK1, secret: &LnurlSecret, user_name: &user::Name, fingerprint: &str, now: Instant) -> Option<LinkingKey> {
+        self
+            .take(k1, secret, now, |action, key| match action {
+                Action::Link(linking_user) => linking_user == user_name && key.fingerprint() == fingerprint,
+                Action::Login => false,
+            })
+            .map(|(_, key)| key)
+    }
+}
+
+/// Links the key confirmed by the user, returns `false` if it's already linked
+pub async fn link<Db: user::Db>(database: &mut Db, user_name: user::Name, key: LinkingKey) -> Result<bool, Db::UpdateUserError> {
+    let record = user::DbLnurlKey {
+        key,
+        user_name,
+        created: SystemTime::now(),
+    };
+    database.link_lnurl_key(record).await
+}
+
+/// Logs in the user who signed the challenge, the key already replaced the password
+pub async fn finish_login<Db: user::Db>(database: &mut Db, sessions: &SessionConfig, name: user::Name, session: SessionInfo) -> Result<LoginSuccessful, FinishError<Db::GetUserError, Db::SessionError>> where Db::GetUserError: 'static {
+    let user = database
+        .get_user(name)
+        .await
+        .map_err(FinishError::DbGetUserError)?
+        .ok_or(FinishError::UserNotFound)?;
+
+    if user.disabled {
+        return Err(FinishError::UserDisabled);
+    }
+
+    if user.totp_secret.is_some() {
+        let challenge = super::totp::create_challenge(database, user.name, session)
+            .await
+            .map_err(FinishError::DbCreateSessionError)?;
+        return Err(FinishError::SecondFactorRequired(challenge));
+    }
+
+    super::create_session(database, sessions, user.name, session)
+        .await
+        .map_err(FinishError::DbCreateSessionError)
+}
+
+pub mod api {
+    #[derive(serde_derive::Serialize)]
+    pub struct Challenge {
+        pub k1: String,
+        pub lnurl: String,
+        /// The LNURL encoded as a QR code
+        pub qr_svg: String,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct Status {
+        pub status: super::Status,
+        /// Fingerprint of the key that signed a link challenge, the user has to confirm it
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub key_fingerprint: Option<String>,
+    }
+
+    /// Response to the wallet as defined by LUD-04
+    #[derive(serde_derive::Serialize)]
+    #[serde(tag = "status", rename_all = "UPPERCASE")]
+    pub enum CallbackResponse {
+        Ok,
+        Error { reason: String },
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct Key {
+        pub key: String,
+        /// Seconds since UNIX epoch
+        pub created: u64,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct KeysResponse {
+        pub keys: Vec<Key>,
+    }
+}
+
+/// Sends the challenge to the browser and binds it to it using the cookie
+pub fn created<S: crate::webserver::Server>(created: &Created, prefix: &str) -> S::ResponseBuilder {
+    use crate::webserver::ResponseBuilder;
+
+    let qr_svg = qrcode::QrCode::new(created.lnurl.as_bytes())
+        .expect("LNURL fits into a QR code")
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(256, 256)
+        .build();
+
+    let resp = api::Challenge {
+        k1: created.k1.to_string(),
+        lnurl: created.lnurl.clone(),
+        qr_svg,
+    };
+
+    let mut builder: S::ResponseBuilder = crate::webserver::json_response(&resp);
+    builder.set_cookie(SECRET_COOKIE, &created.secret.to_string(), crate::webserver::cookie_path(prefix), Some(CHALLENGE_LIFETIME.as_secs()));
+    builder
+}
+
+pub fn status<S: crate::webserver::Server>(status: Status, link_key: Option<&LinkingKey>) -> S::ResponseBuilder {
+    let key_fingerprint = link_key.map(LinkingKey::fingerprint);
+    crate::webserver::json_response(&api::Status { status, key_fingerprint, })
+}
+
+pub fn callback_response<S: crate::webserver::Server, E: 'static + std::error::Error>(result: &Result<(), CallbackError<E>>) -> S::ResponseBuilder {
+    let resp = match result {
+        Ok(()) => api::CallbackResponse::Ok,
+        Err(CallbackError::DbUpdateUserError(_)) => api::CallbackResponse::Error { reason: "internal server error".to_owned(), },
+        Err(error) => api::CallbackResponse::Error { reason: error.to_string(), },
+    };
+
+    crate::webserver::json_response(&resp)
+}
+
+pub fn get_keys<S: crate::webserver::Server>(keys: Vec<user::DbLnurlKey>) -> S::ResponseBuilder {
+    let keys = keys
+        .into_iter()
+        .map(|key| api::Key {
+            key: key.key.to_string(),
+            created: super::unix_timestamp(key.created),
+        })
+        .collect();
+
+    crate::webserver::json_response(&api::KeysResponse { keys, })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Instant;
+    use super::{Action, CallbackError, Provider, Status};
+    use crate::mock_db::Db;
+    use crate::user::{self, types::{LinkingKey, LnurlK1, LnurlSecret}, Db as _};
+
+    struct Wallet {
+        secp: secp256k1::Secp256k1<secp256k1::All>,
+        secret_key: secp256k1::SecretKey,
+    }
+
+    impl Wallet {
+        fn new(byte: u8) -> Self {
+            Wallet {
+                secp: secp256k1::Secp256k1::new(),
+                secret_key: secp256k1::SecretKey::from_slice(&[byte; 32]).unwrap(),
+            }
+        }
+
+        fn key(&self) -> String {
+            LinkingKey::from(secp256k1::PublicKey::from_secret_key(&self.secp, &self.secret_key)).to_string()
+        }
+
+        fn sign(&self, k1: &LnurlK1) -> String {
+            let message = secp256k1::Message::from_slice(k1.as_bytes()).unwrap();
+            hex::encode(&*self.secp.sign(&message, &self.secret_key).serialize_der())
+        }
+    }
+
+    fn provider() -> Provider {
+        Provider::init("https://example.com/dashboard/".to_owned(), "/dashboard").unwrap()
+    }
+
+    #[test]
+    fn init() {
+        assert!(Provider::init("https://example.com/dashboard".to_owned(), "/dashboard").is_ok());
+        assert!(Provider::init("https://example.com/".to_owned(), "/dashboard").is_err());
+        assert!(Provider::init("ftp://example.com/dashboard".to_owned(), "/dashboard").is_err());
+        assert!(Provider::init("https://example.com/dashboard?foo=bar".to_owned(), "/dashboard").is_err());
+    }
+
+    #[test]
+    fn lnurl_encoding() {
+        use bech32::FromBase32;
+
+        let provider = provider();
+        let k1 = "0000000000000000000000000000000000000000000000000000000000000000".parse().unwrap();
+        let lnurl = provider.lnurl(&k1, &Action::Login).unwrap();
+        assert!(lnurl.starts_with("LNURL1"));
+
+        let (hrp, data) = bech32::decode(&lnurl).unwrap();
+        assert_eq!(hrp, "lnurl");
+        let url = String::from_utf8(Vec::<u8>::from_base32(&data).unwrap()).unwrap();
+        assert_eq!(url, format!("https://example.com/dashboard/lnurl-auth/callback?tag=login&k1={}&action=login", k1));
+    }
+
+    #[test]
+    fn link_and_login() {
+        let mut db = Db::default();
+        let params = crate::user::password::test_params();
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        tokio_test::block_on(crate::login::signup(&mut db, crate::login::SignupRequest { name: name.clone(), password: "nbusr123".to_owned(), groups: Vec::new(), }, &params)).unwrap();
+        let provider = provider();
+        let wallet = Wallet::new(0xcd);
+        let now = Instant::now();
+        let callback = |db: &mut Db, k1: &LnurlK1, signature: &str, key: &str| tokio_test::block_on(provider.callback(db, &k1.to_string(), signature, key, now));
+
+        // the key is not linked yet
+        let login = provider.create(Action::Login, now).unwrap();
+        match callback(&mut db, &login.k1, &wallet.sign(&login.k1), &wallet.key()) {
+            Err(CallbackError::NotLinked) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(provider.status(&login.k1, &login.secret, now), Status::Failed);
+
+        let link = provider.create(Action::Link(name.clone()), now).unwrap();
+        match callback(&mut db, &link.k1, &wallet.sign(&login.k1), &wallet.key()) {
+            Err(CallbackError::InvalidSignature) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(provider.status(&link.k1, &link.secret, now), Status::Pending);
+        callback(&mut db, &link.k1, &wallet.sign(&link.k1), &wallet.key()).expect("failed to verify the key");
+        assert_eq!(provider.status(&link.k1, &link.secret, now), Status::Verified);
+        let key = wallet.key().parse::<LinkingKey>().unwrap();
+        assert_eq!(provider.link_key(&link.k1, &link.secret, now), Some(key));
+        // the key is only linked after the user confirms it
+        assert!(tokio_test::block_on(db.get_lnurl_key(key)).unwrap().is_none());
+        // link challenges can't be used to log in
+        assert!(provider.take_verified(&link.k1, &link.secret, now).is_none());
+        let other_user = user::Name::try_from("dorian".to_owned()).unwrap();
+        let fingerprint = key.fingerprint();
+        assert!(provider.take_verified_link(&link.k1, &link.secret, &other_user, &fingerprint, now).is_none());
+        assert!(provider.take_verified_link(&link.k1, &link.secret, &name, &Wallet::new(0xab).key().parse::<LinkingKey>().unwrap().fingerprint(), now).is_none());
+        assert_eq!(provider.take_verified_link(&link.k1, &link.secret, &name, &fingerprint, now), Some(key));
+        assert!(provider.take_verified_link(&link.k1, &link.secret, &name, &fingerprint, now).is_none());
+        assert!(tokio_test::block_on(super::link(&mut db, name.clone(), key)).unwrap());
+
+        let other = provider.create(Action::Link(name.clone()), now).unwrap();
+        match callback(&mut db, &other.k1, &wallet.sign(&other.k1), &wallet.key()) {
+            Err(CallbackError::AlreadyLinked) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let login = provider.create(Action::Login, now).unwrap();
+        assert_eq!(provider.status(&login.k1, &link.secret, now), Status::Expired);
+        callback(&mut db, &login.k1, &wallet.sign(&login.k1), &wallet.key()).expect("login failed");
+        match callback(&mut db, &login.k1, &wallet.sign(&login.k1), &wallet.key()) {
+            Err(CallbackError::AlreadyUsed) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // the challenge is bound to the browser which requested it
+        assert!(provider.take_verified(&login.k1, &link.secret, now).is_none());
+        assert_eq!(provider.take_verified(&login.k1, &login.secret, now), Some(name.clone()));
+        assert!(provider.take_verified(&login.k1, &login.secret, now).is_none());
+
+        let expired = provider.create(Action::Login, now).unwrap();
+        match tokio_test::block_on(provider.callback(&mut db, &expired.k1.to_string(), &wallet.sign(&expired.k1), &wallet.key(), now + super::CHALLENGE_LIFETIME)) {
+            Err(CallbackError::UnknownChallenge) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        assert_eq!(tokio_test::block_on(db.list_lnurl_keys(name.clone())).unwrap().len(), 1);
+        assert!(tokio_test::block_on(db.unlink_lnurl_key(name, key)).unwrap());
+        let login = provider.create(Action::Login, now).unwrap();
+        match callback(&mut db, &login.k1, &wallet.sign(&login.k1), &wallet.key()) {
+            Err(CallbackError::NotLinked) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn foreign_key_needs_confirmation() {
+        let mut db = Db::default();
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        let provider = provider();
+        let attacker = Wallet::new(0xab);
+        let now = Instant::now();
+
+        // the QR code is public, anyone can sign it before the victim's wallet does
+        let link = provider.create(Action::Link(name.clone()), now).unwrap();
+        tokio_test::block_on(provider.callback(&mut db, &link.k1.to_string(), &attacker.sign(&link.k1), &attacker.key(), now)).expect("failed to verify the key");
+        let key = attacker.key().parse::<LinkingKey>().unwrap();
+        assert!(tokio_test::block_on(db.get_lnurl_key(key)).unwrap().is_none());
+        // without the secret of the victim's browser it can't be confirmed
+        assert!(provider.take_verified_link(&link.k1, &LnurlSecret::random(), &name, &key.fingerprint(), now).is_none());
+        assert_eq!(provider.link_key(&link.k1, &link.secret, now), Some(key));
+    }
+
+    #[test]
+    fn second_callback_keeps_verified_key() {
+        let mut db = Db::default();
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        let provider = provider();
+        let wallet = Wallet::new(0xcd);
+        let attacker = Wallet::new(0xab);
+        let now = Instant::now();
+
+        let link = provider.create(Action::Link(name.clone()), now).unwrap();
+        tokio_test::block_on(provider.callback(&mut db, &link.k1.to_string(), &wallet.sign(&link.k1), &wallet.key(), now)).expect("failed to verify the key");
+        let key = wallet.key().parse::<LinkingKey>().unwrap();
+        assert_eq!(provider.link_key(&link.k1, &link.secret, now), Some(key));
+
+        match tokio_test::block_on(provider.callback(&mut db, &link.k1.to_string(), &attacker.sign(&link.k1), &attacker.key(), now)) {
+            Err(CallbackError::AlreadyUsed) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // a callback that passed the check before the first one finished can't replace the key
+        let attacker_key = attacker.key().parse::<LinkingKey>().unwrap();
+        assert!(!provider.set_state(&link.k1, super::State::Verified { user_name: name.clone(), key: attacker_key, }));
+        assert!(!provider.set_state(&link.k1, super::State::Failed));
+        assert_eq!(provider.status(&link.k1, &link.secret, now), Status::Verified);
+        assert_eq!(provider.link_key(&link.k1, &link.secret, now), Some(key));
+        assert!(provider.take_verified_link(&link.k1, &link.secret, &name, &attacker_key.fingerprint(), now).is_none());
+        assert_eq!(provider.take_verified_link(&link.k1, &link.secret, &name, &key.fingerprint(), now), Some(key));
+    }
+
+    #[test]
+    fn high_s_signature() {
+        let mut db = Db::default();
+        let provider = provider();
+        let wallet = Wallet::new(0xcd);
+        let login = provider.create(Action::Login, Instant::now()).unwrap();
+
+        // negating S keeps the signature valid but libsecp256k1 rejects it unless normalized
+        let message = secp256k1::Message::from_slice(login.k1.as_bytes()).unwrap();
+        let compact = wallet.secp.sign(&message, &wallet.secret_key).serialize_compact();
+        let order = hex::decode("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141").unwrap();
+        let mut s = [0u8; 32];
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let difference = i16::from(order[i]) - i16::from(compact[32 + i]) - borrow;
+            s[i] = (difference & 0xff) as u8;
+            borrow = if difference < 0 { 1 } else { 0 };
+        }
+        let mut high_s = [0u8; 64];
+        high_s[..32].copy_from_slice(&compact[..32]);
+        high_s[32..].copy_from_slice(&s);
+        let signature = secp256k1::Signature::from_compact(&high_s).unwrap().serialize_der();
+
+        match tokio_test::block_on(provider.callback(&mut db, &login.k1.to_string(), &hex::encode(&*signature), &wallet.key(), Instant::now())) {
+            Err(CallbackError::NotLinked) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+}
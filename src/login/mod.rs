@@ -1,114 +1,444 @@
 use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
 use slog::{error, debug, trace};
+use void::Void;
 use crate::user;
-use crate::user::types::{Salt, AuthToken, HardenedPassword};
+use crate::user::types::{AuthToken, AuthTokenHash, ForwardAuthToken, SessionId, LoginChallenge, TokenKey};
+use crate::user::password::{self, PasswordHash};
+use crate::user::backend::Backend;
+
+pub mod totp;
+pub mod throttle;
+pub mod setup;
+pub mod invite;
+pub mod oidc;
+pub mod api_token;
+pub mod lnurl;
+pub mod audit;
+
+/// Maximum number of characters of user agent and session label stored in the database
+const MAX_SESSION_INFO_LEN: usize = 256;
+
+/// Cookie for the whole host that the reverse proxy forwards to `/auth/verify`
+///
+/// The session cookies are limited to the dashboard so that other apps don't receive them, this
+/// one only authenticates forward auth requests.
+pub const FORWARD_AUTH_COOKIE: &str = "forward_auth";
 
 #[derive(Debug)]
 pub struct LoginRequest {
     pub name: user::Name,
     pub password: String,
+    pub session: SessionInfo,
+}
+
+/// Information about the device used to log in, presented to the user when listing sessions
+#[derive(Debug, Default)]
+pub struct SessionInfo {
+    pub user_agent: Option<String>,
+    pub label: Option<String>,
+    /// The cookie should survive closing the browser
+    pub remember: bool,
 }
 
 #[derive(Debug)]
 pub struct LoginSuccessful {
     pub name: user::Name,
+    pub session_id: SessionId,
     pub cookie: AuthToken,
+    pub remember: bool,
+}
+
+/// Server-side settings of sessions
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Maximum time since login
+    pub absolute: Duration,
+    /// Maximum time since the last request, each request extends the session up to `absolute`
+    pub idle: Duration,
+    /// Key of the hashes of authentication tokens stored in the database
+    pub token_key: TokenKey,
+}
+
+/// Fixed key so that tests can compute hashes of tokens
+#[cfg(test)]
+pub fn test_sessions() -> SessionConfig {
+    SessionConfig {
+        absolute: Duration::from_secs(3600 * 24 * 30),
+        idle: Duration::from_secs(3600 * 24),
+        token_key: "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f".parse().unwrap(),
+    }
+}
+
+/// Returns the value of `FORWARD_AUTH_COOKIE` for the new session
+pub fn forward_auth_cookie(success: &LoginSuccessful, config: &SessionConfig) -> String {
+    let token = success.cookie.hash(&config.token_key).forward_auth_token(&config.token_key);
+    format!("{}.{}", success.name, token)
+}
+
+impl SessionConfig {
+    fn is_expired(&self, session: &user::DbSession, now: SystemTime) -> bool {
+        // lifetimes too long to be represented never end
+        let ended = |since: SystemTime, lifetime| since.checked_add(lifetime).map_or(false, |end| end <= now);
+        ended(session.created, self.absolute) || ended(session.last_seen, self.idle)
+    }
+}
+
+/// Login attempt of a user with two-factor authentication enabled, finish it with
+/// `totp::check_second_factor`
+#[derive(Debug)]
+pub struct SecondFactorChallenge {
+    pub name: user::Name,
+    pub token: LoginChallenge,
 }
 
 #[derive(Debug)]
-pub enum LoginError<GetUser, SetCookie> {
+pub enum LoginError<GetUser, Session, UpdateUser, InsertUser> {
     BadUserPassword,
+    /// Only returned if the password was correct
+    UserDisabled,
+    /// Only returned if the password was correct
+    SecondFactorRequired(SecondFactorChallenge),
     DbGetUserError(GetUser),
-    DbSetCookieError(SetCookie),
+    DbCreateSessionError(Session),
+    /// Failed to upgrade the hash of the password to current parameters
+    DbUpdateUserError(UpdateUser),
+    /// Failed to create the record of a user logging in for the first time using external backend
+    DbInsertUserError(InsertUser),
+    BackendError(user::backend::Error),
 }
 
 pub struct SignupRequest {
     pub name: user::Name,
     pub password: String,
+    pub groups: Vec<user::Group>,
 }
 
-pub async fn signup<Db: user::Db>(database: &mut Db, request: SignupRequest) -> Result<AuthToken, user::InsertError<Db::InsertUserError>> {
-    let salt = Salt::random();
-    let hardened_password = HardenedPassword::harden(&request.password, &salt);
-    let cookie = AuthToken::random();
+/// Only creates the user, call `create_session` to log them in
+pub async fn signup<Db: user::Db>(database: &mut Db, request: SignupRequest, params: &password::Params) -> Result<(), user::InsertError<Db::InsertUserError>> {
     let record = user::DbRecord {
         name: request.name,
-        hardened_password,
-        salt,
-        cookie: Some(cookie),
+        password_hash: PasswordHash::new(&request.password, params),
+        disabled: false,
+        groups: request.groups,
+        totp_secret: None,
+    };
+
+    database.insert_new_user(record).await
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenKeyFileError {
+    #[error("can't read token key file {path}")]
+    Read { path: std::path::PathBuf, #[source] error: std::io::Error },
+    #[error("can't create token key file {path}")]
+    Create { path: std::path::PathBuf, #[source] error: std::io::Error },
+    #[error("invalid token key in {path}")]
+    Invalid { path: std::path::PathBuf, #[source] error: user::types::TokenKeyError },
+}
+
+/// Loads the key of token hashes or generates it on the first start.
+///
+/// Losing the key only logs everyone out.
+pub fn load_or_create_token_key(path: &std::path::Path) -> Result<TokenKey, TokenKeyFileError> {
+    match std::fs::read_to_string(path) {
+        Ok(key) => key.trim().parse().map_err(|error| TokenKeyFileError::Invalid { path: path.into(), error, }),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            let key = TokenKey::random();
+            crate::io::create_private_file(path, &format!("{}\n", key))
+                .map_err(|error| TokenKeyFileError::Create { path: path.into(), error, })?;
+            Ok(key)
+        },
+        Err(error) => Err(TokenKeyFileError::Read { path: path.into(), error, }),
+    }
+}
+
+fn truncate_session_info(mut value: String) -> String {
+    if let Some((pos, _)) = value.char_indices().nth(MAX_SESSION_INFO_LEN) {
+        value.truncate(pos);
+    }
+    value
+}
+
+/// Creates a new session for already authenticated user
+pub async fn create_session<Db: user::Db>(database: &mut Db, config: &SessionConfig, name: user::Name, info: SessionInfo) -> Result<LoginSuccessful, Db::SessionError> {
+    let now = SystemTime::now();
+    let cookie = AuthToken::random();
+    let record = user::DbSession {
+        id: SessionId::random(),
+        user_name: name.clone(),
+        auth_token_hash: cookie.hash(&config.token_key),
+        created: now,
+        last_seen: now,
+        user_agent: info.user_agent.map(truncate_session_info),
+        label: info.label.map(truncate_session_info),
     };
+    let session_id = record.id;
+
+    database.create_session(record).await?;
 
-    database.insert_new_user(record).await?;
-    Ok(cookie)
+    Ok(LoginSuccessful { name, session_id, cookie, remember: info.remember, })
 }
 
-pub async fn check_login<Db: user::Db>(database: &mut Db, request: LoginRequest) -> Result<LoginSuccessful, LoginError<Db::GetUserError, Db::SetCookieError>> {
+/// Where the password of the user was checked
+enum Verified {
+    /// The hash stored in the database matched
+    Local(user::DbRecord),
+    /// The backend accepted the password, users logging in for the first time don't have a record
+    External(Option<user::DbRecord>),
+}
+
+/// Checks the password of the user and returns their record if it matches.
+///
+/// The hash in the database is checked first even with an external backend so that local
+/// accounts keep working. Records created for users of the backend have a random hash which
+/// never matches.
+async fn verify_password<Db: user::Db, E, F, G>(database: &mut Db, backend: &Backend, name: user::Name, password: &str, params: &password::Params) -> Result<Verified, LoginError<Db::GetUserError, E, F, G>> {
     let user = database
-        .get_user(request.name.clone())
+        .get_user(name.clone())
         .await
         .map_err(LoginError::DbGetUserError)?;
 
-    // This function is supposed to be constant time with respect to the user entry existing
-    // That's why it looks "weird" - it hashes the password with constant salt
-    let salt = user.as_ref().map_or(&Salt::EMPTY, |user| &user.salt);
-    let hardened_password = HardenedPassword::harden(&request.password, &salt);
-    let user = user.ok_or(LoginError::BadUserPassword)?;
+    match backend {
+        // This function is supposed to be constant time with respect to the user entry existing
+        // That's why it looks "weird" - it hashes the password even if there's no user
+        Backend::Local => match user {
+            Some(user) if user.password_hash.verify(password) => Ok(Verified::Local(user)),
+            Some(_) => Err(LoginError::BadUserPassword),
+            None => {
+                PasswordHash::new(password, params);
+                Err(LoginError::BadUserPassword)
+            },
+        },
+        Backend::External { authenticator, .. } => match user {
+            Some(user) if user.password_hash.verify(password) => Ok(Verified::Local(user)),
+            user => {
+                if authenticator.verify(&name, password).await.map_err(LoginError::BackendError)? {
+                    Ok(Verified::External(user))
+                } else {
+                    Err(LoginError::BadUserPassword)
+                }
+            },
+        },
+    }
+}
+
+/// Checks the password and creates a session.
+///
+/// If the password was hashed with outdated algorithm or parameters it's rehashed with the current
+/// ones. Users of external backends logging in for the first time get a record without any groups
+/// if the backend creates users, otherwise they are rejected.
+pub async fn check_login<Db: user::Db>(database: &mut Db, backend: &Backend, request: LoginRequest, params: &password::Params, sessions: &SessionConfig) -> Result<LoginSuccessful, LoginError<Db::GetUserError, Db::SessionError, Db::UpdateUserError, Db::InsertUserError>> {
+    let (user, local) = match verify_password(database, backend, request.name.clone(), &request.password, params).await? {
+        Verified::Local(user) => (user, true),
+        Verified::External(Some(user)) => (user, false),
+        Verified::External(None) if !backend.creates_users() => return Err(LoginError::BadUserPassword),
+        Verified::External(None) => {
+            // The password is checked by the backend, the stored one only has to be unguessable
+            let record = user::DbRecord {
+                name: request.name,
+                password_hash: PasswordHash::new(&AuthToken::random().to_string(), params),
+                disabled: false,
+                groups: Vec::new(),
+                totp_secret: None,
+            };
+            let user = match database.insert_new_user(record.clone()).await {
+                Ok(()) => record,
+                // Another login of the same user was faster
+                Err(user::InsertError::UserExists) => database
+                    .get_user(record.name)
+                    .await
+                    .map_err(LoginError::DbGetUserError)?
+                    .ok_or(LoginError::BadUserPassword)?,
+                Err(user::InsertError::DatabaseError(error)) => return Err(LoginError::DbInsertUserError(error)),
+            };
+            (user, false)
+        },
+    };
 
-    if hardened_password == user.hardened_password {
-        let cookie = AuthToken::random();
+    if user.disabled {
+        return Err(LoginError::UserDisabled);
+    }
 
+    if local && user.password_hash.needs_rehash(params) {
         database
-            .set_cookie(user.name, Some(cookie))
+            .set_password(user.name.clone(), PasswordHash::new(&request.password, params))
+            .await
+            .map_err(LoginError::DbUpdateUserError)?;
+    }
+
+    if user.totp_secret.is_some() {
+        let challenge = totp::create_challenge(database, user.name, request.session)
             .await
-            .map_err(LoginError::DbSetCookieError)?;
+            .map_err(LoginError::DbCreateSessionError)?;
+        return Err(LoginError::SecondFactorRequired(challenge));
+    }
+
+    create_session(database, sessions, user.name, request.session)
+        .await
+        .map_err(LoginError::DbCreateSessionError)
+}
+
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChangePasswordError<GetUser: 'static + std::error::Error, UpdateUser: 'static + std::error::Error, Session: 'static + std::error::Error> {
+    #[error("the current password is invalid")]
+    BadPassword,
+    #[error("the new password is empty")]
+    EmptyPassword,
+    #[error("the password is managed by the authentication backend")]
+    ExternalPassword,
+    #[error("failed to get user")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to store the new password")]
+    DbUpdateUserError(#[source] UpdateUser),
+    #[error("failed to revoke other sessions")]
+    DbSessionError(#[source] Session),
+    #[error("failed to verify the password")]
+    BackendError(#[source] user::backend::Error),
+}
 
-        Ok(LoginSuccessful { name: request.name, cookie, })
-    } else {
-        Err(LoginError::BadUserPassword)
+/// Changes the password of the user and logs out all their other sessions
+///
+/// Only passwords stored in the database can be changed, the backend manages the others.
+pub async fn change_password<Db: user::Db>(database: &mut Db, backend: &Backend, user: &user::Authenticated, request: ChangePasswordRequest, params: &password::Params) -> Result<(), ChangePasswordError<Db::GetUserError, Db::UpdateUserError, Db::SessionError>> where Db::GetUserError: 'static {
+    if request.new_password.is_empty() {
+        return Err(ChangePasswordError::EmptyPassword);
     }
+
+    match verify_password::<_, Void, Void, Void>(database, backend, user.user_name().clone(), &request.current_password, params).await {
+        Ok(Verified::Local(_)) => (),
+        Ok(Verified::External(_)) => return Err(ChangePasswordError::ExternalPassword),
+        Err(LoginError::BadUserPassword) | Err(LoginError::UserDisabled) => return Err(ChangePasswordError::BadPassword),
+        Err(LoginError::DbGetUserError(error)) => return Err(ChangePasswordError::DbGetUserError(error)),
+        Err(LoginError::DbCreateSessionError(never)) | Err(LoginError::DbUpdateUserError(never)) | Err(LoginError::DbInsertUserError(never)) => match never {},
+        Err(LoginError::BackendError(error)) => return Err(ChangePasswordError::BackendError(error)),
+        Err(LoginError::SecondFactorRequired(_)) => unreachable!("password verification doesn't check the second factor"),
+    }
+
+    let password_hash = PasswordHash::new(&request.new_password, params);
+
+    database
+        .set_password(user.user_name().clone(), password_hash)
+        .await
+        .map_err(ChangePasswordError::DbUpdateUserError)?;
+
+    database
+        .revoke_all_sessions(user.user_name().clone(), user.session_id())
+        .await
+        .map_err(ChangePasswordError::DbSessionError)
 }
 
 pub struct AuthRequest {
     user_name: user::Name,
-    auth_token: String,
+    token: RequestToken,
+}
+
+enum RequestToken {
+    /// `auth_token` cookie of the dashboard
+    Session(String),
+    /// Value of `FORWARD_AUTH_COOKIE` after the user name
+    ForwardAuth(String),
+}
+
+/// What a stored session has to match
+enum ExpectedToken {
+    Hash(AuthTokenHash),
+    ForwardAuth(ForwardAuthToken),
+}
+
+impl ExpectedToken {
+    fn matches(&self, stored: &AuthTokenHash, key: &TokenKey) -> bool {
+        match self {
+            ExpectedToken::Hash(hash) => stored == hash,
+            ExpectedToken::ForwardAuth(token) => stored.forward_auth_token(key) == *token,
+        }
+    }
 }
 
 #[must_use]
 pub enum AuthStatus {
-    LoggedIn(user::Name),
+    LoggedIn(user::Authenticated),
     NotLoggedIn,
 }
 
 #[derive(Debug, thiserror::Error)]
-pub enum AuthError<GetUserError: 'static + std::error::Error> {
+pub enum AuthError<GetUserError: 'static + std::error::Error, SessionError: 'static + std::error::Error> {
     #[error("failed to get user")]
     GetUser(#[source] GetUserError),
+    #[error("failed to get sessions of the user")]
+    GetSessions(#[source] SessionError),
+    #[error("failed to update the last activity of the session")]
+    TouchSession(#[source] SessionError),
+    #[error("failed to revoke expired session")]
+    RevokeExpired(#[source] SessionError),
     #[error("invalid authentication token")]
     InvalidAuthToken(#[source] user::types::AuthTokenError),
+    #[error("invalid forward auth token")]
+    InvalidForwardAuthToken(#[source] user::types::ForwardAuthTokenError),
 }
 
-async fn check_cookie<Db: user::Db>(database: &mut Db, request: AuthRequest, logger: slog::Logger) -> Result<AuthStatus, AuthError<Db::GetUserError>> where Db::GetUserError: 'static {
-    let req_cookie = request.auth_token.parse::<AuthToken>().map_err(AuthError::InvalidAuthToken)?;
+async fn check_cookie<'a, Db: user::Db>(database: &'a mut Db, config: &'a SessionConfig, request: AuthRequest, logger: slog::Logger) -> Result<AuthStatus, AuthError<Db::GetUserError, Db::SessionError>> where Db::GetUserError: 'static {
+    let expected = match &request.token {
+        RequestToken::Session(token) => ExpectedToken::Hash(token.parse::<AuthToken>().map_err(AuthError::InvalidAuthToken)?.hash(&config.token_key)),
+        RequestToken::ForwardAuth(token) => ExpectedToken::ForwardAuth(token.parse().map_err(AuthError::InvalidForwardAuthToken)?),
+    };
 
     debug!(logger, "retrieving user"; "user" => &request.user_name);
 
     let user = database
-        .get_user(request.user_name)
+        .get_user(request.user_name.clone())
         .await
         .map_err(AuthError::GetUser)?;
 
-    let (user_name, db_cookie) = match user {
-        Some(user::DbRecord { name, cookie: Some (cookie), .. }) => (name, cookie),
-        _ => return Ok(AuthStatus::NotLoggedIn),
+    let groups = match user {
+        Some(user) if !user.disabled => user.groups,
+        Some(_) => {
+            debug!(logger, "user is disabled");
+            return Ok(AuthStatus::NotLoggedIn);
+        },
+        None => return Ok(AuthStatus::NotLoggedIn),
+    };
+
+    debug!(logger, "retrieving sessions"; "user" => &request.user_name);
+
+    let sessions = database
+        .get_sessions(request.user_name)
+        .await
+        .map_err(AuthError::GetSessions)?;
+
+    // All sessions are compared so that timing doesn't reveal the position of the matching one
+    let session = sessions
+        .into_iter()
+        .fold(None, |found, session| if expected.matches(&session.auth_token_hash, &config.token_key) { Some(session) } else { found });
+
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(AuthStatus::NotLoggedIn),
     };
 
-    trace!(logger, "checking cookie"; "db_cookie" => &db_cookie, "user_cookie" => &request.auth_token);
+    trace!(logger, "session found"; "session_id" => &session.id);
 
-    if req_cookie == db_cookie {
-        Ok(AuthStatus::LoggedIn(user_name))
-    } else {
-        Ok(AuthStatus::NotLoggedIn)
+    let now = SystemTime::now();
+    if config.is_expired(&session, now) {
+        debug!(logger, "session expired"; "session_id" => &session.id);
+        database
+            .revoke_session(session.user_name, session.id)
+            .await
+            .map_err(AuthError::RevokeExpired)?;
+        return Ok(AuthStatus::NotLoggedIn);
     }
+
+    database
+        .touch_session(session.id, now)
+        .await
+        .map_err(AuthError::TouchSession)?;
+
+    Ok(AuthStatus::LoggedIn(user::Authenticated::user_logged_in(session.user_name, session.id, groups)))
 }
 
 pub enum RequestError {
@@ -117,23 +447,58 @@ pub enum RequestError {
     NoUserRegistered,
     InternalError,
     InvalidUserName,
+    BadApiToken,
+    InsufficientScope,
+}
+
+/// Extracts the token from the `Authorization` header
+pub fn bearer_token(header: &str) -> Option<&str> {
+    if header.len() < 7 || !header[..7].eq_ignore_ascii_case("bearer ") {
+        return None;
+    }
+
+    Some(header[7..].trim())
 }
 
+/// Authenticates the request using the session cookies.
+///
+/// If the route allows API tokens it passes the scope the token needs, otherwise the
+/// `Authorization` header is ignored, which also lets the reverse proxy forward the headers of
+/// apps.
 // Can't be async fn because of https://github.com/rust-lang/rust/issues/63033
-pub async fn auth_request<Db: user::Db, S: crate::webserver::Server>(database: &mut Db, request: S::Request, logger: slog::Logger) -> Result<user::Authenticated, RequestError> where Db::GetUserError: 'static {
+pub async fn auth_request<'a, Db: user::Db, S: crate::webserver::Server>(database: &'a mut Db, sessions: &'a SessionConfig, request: S::Request, scope: Option<&'a user::types::ApiScope>, logger: slog::Logger) -> Result<user::Authenticated, RequestError> where Db::GetUserError: 'static {
     use crate::webserver::Request;
 
+    let token = scope.and_then(|scope| request.get_header("authorization").and_then(bearer_token).map(|token| (scope, token)));
+    if let Some((scope, token)) = token {
+        return match api_token::authenticate(database, sessions, token, scope).await {
+            Ok(user) => Ok(user),
+            Err(api_token::AuthError::InvalidToken) => {
+                debug!(logger, "invalid API token");
+                Err(RequestError::BadApiToken)
+            },
+            Err(error @ api_token::AuthError::MissingScope(_)) => {
+                debug!(logger, "API token rejected"; "error" => #error);
+                Err(RequestError::InsufficientScope)
+            },
+            Err(error) => {
+                error!(logger, "Failed to check API token"; "error" => #error);
+                Err(RequestError::InternalError)
+            },
+        };
+    }
+
     let user_name = request.get_cookie("user_name").map(ToOwned::to_owned).map(TryInto::try_into).transpose().map_err(|error| { error!(logger, "invalid user name"; "error" => #error); RequestError::InvalidUserName })?;
     let auth_token = request.get_cookie("auth_token");
 
     let auth_request = match (user_name, auth_token) {
-        (Some(user_name), Some(auth_token)) => AuthRequest { user_name, auth_token: auth_token.to_owned(), },
+        (Some(user_name), Some(auth_token)) => AuthRequest { user_name, token: RequestToken::Session(auth_token.to_owned()), },
         (None, None) => {
-            return Err(match database.get_user(user::Name::ADMIN).await {
-                Ok(Some(_)) => RequestError::MissingCookies,
-                Ok(None) => RequestError::NoUserRegistered,
+            return Err(match database.has_users().await {
+                Ok(false) => RequestError::NoUserRegistered,
+                Ok(true) => RequestError::MissingCookies,
                 Err(error) => {
-                    error!(logger, "failed to check presence of the admin user"; "error" => #error);
+                    error!(logger, "failed to check presence of users"; "error" => #error);
                     RequestError::InternalError
                 },
             })
@@ -141,18 +506,43 @@ pub async fn auth_request<Db: user::Db, S: crate::webserver::Server>(database: &
         _ => return Err(RequestError::MissingCookies),
     };
 
+    authenticate(database, sessions, auth_request, logger).await
+}
+
+/// Authenticates a request to another app forwarded by the reverse proxy using
+/// `FORWARD_AUTH_COOKIE`, the session cookies aren't sent with it
+pub async fn forward_auth_request<'a, Db: user::Db, S: crate::webserver::Server>(database: &'a mut Db, sessions: &'a SessionConfig, request: S::Request, logger: slog::Logger) -> Result<user::Authenticated, RequestError> where Db::GetUserError: 'static {
+    use crate::webserver::Request;
+
+    let cookie = request.get_cookie(FORWARD_AUTH_COOKIE).ok_or(RequestError::MissingCookies)?;
+    let mut parts = cookie.splitn(2, '.');
+    let user_name = parts.next().unwrap_or_default().to_owned().try_into().map_err(|error| { error!(logger, "invalid user name"; "error" => #error); RequestError::InvalidUserName })?;
+    let token = parts.next().ok_or_else(|| { error!(logger, "forward auth cookie without token"); RequestError::BadCookies })?;
+    let auth_request = AuthRequest {
+        user_name,
+        token: RequestToken::ForwardAuth(token.to_owned()),
+    };
+
+    authenticate(database, sessions, auth_request, logger).await
+}
+
+async fn authenticate<'a, Db: user::Db>(database: &'a mut Db, sessions: &'a SessionConfig, auth_request: AuthRequest, logger: slog::Logger) -> Result<user::Authenticated, RequestError> where Db::GetUserError: 'static {
     let logger = logger.new(slog::o!("user_name" => auth_request.user_name.clone()));
 
     debug!(logger, "authenticating user");
 
-    let result = check_cookie(database, auth_request, logger.clone()).await;
+    let result = check_cookie(database, sessions, auth_request, logger.clone()).await;
     match result {
         Ok(AuthStatus::NotLoggedIn) => Err(RequestError::BadCookies),
-        Ok(AuthStatus::LoggedIn(user_name)) => Ok(user::Authenticated::user_logged_in(user_name)),
+        Ok(AuthStatus::LoggedIn(user)) => Ok(user),
         Err(AuthError::InvalidAuthToken(error)) => {
             error!(logger, "Invalid authentication token"; "error" => #error);
             Err(RequestError::BadCookies)
         },
+        Err(AuthError::InvalidForwardAuthToken(error)) => {
+            error!(logger, "Invalid forward auth token"; "error" => #error);
+            Err(RequestError::BadCookies)
+        },
         Err(error) => {
             error!(logger, "Failed to check cookie"; "error" => #error);
             Err(RequestError::InternalError)
@@ -160,6 +550,52 @@ pub async fn auth_request<Db: user::Db, S: crate::webserver::Server>(database: &
     }
 }
 
+pub mod api {
+    #[derive(serde_derive::Serialize)]
+    pub struct Session {
+        pub id: String,
+        /// Seconds since UNIX epoch
+        pub created: u64,
+        /// Seconds since UNIX epoch
+        pub last_seen: u64,
+        pub user_agent: Option<String>,
+        pub label: Option<String>,
+        pub current: bool,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct SessionsResponse {
+        pub sessions: Vec<Session>,
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn get_sessions<S: crate::webserver::Server>(user: &user::Authenticated, sessions: Vec<user::DbSession>) -> S::ResponseBuilder {
+    let sessions = sessions
+        .into_iter()
+        .map(|session| api::Session {
+            id: session.id.to_string(),
+            created: unix_timestamp(session.created),
+            last_seen: unix_timestamp(session.last_seen),
+            user_agent: session.user_agent,
+            label: session.label,
+            current: Some(session.id) == user.session_id(),
+        })
+        .collect();
+
+    let resp = api::SessionsResponse {
+        sessions,
+    };
+
+    crate::webserver::json_response(&resp)
+}
+
 #[cfg(test)]
 mod tests {
     use hmap::hmap;
@@ -167,21 +603,26 @@ mod tests {
     use crate::mock_db::Db;
     use std::convert::TryInto;
 
+    use crate::user::password::test_params as params;
+    use super::test_sessions as sessions;
+
     #[test]
     fn missing_user() {
         let mut db = Db::from(hmap!("satoshi".to_owned() => user::DbRecord {
             name: "satoshi".to_owned().try_into().unwrap(),
-            hardened_password: "000000000000000000000000000000000000000000000000000775f05a074000".parse().unwrap(),
-            salt: "0000000000000000000775f05a074000".parse().unwrap(),
-            cookie: None,
+            password_hash: "$scrypt$ln=15,r=8,p=1$AAAAAAAAAAAAB3XwWgdAAA$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAd18FoHQAA".parse().unwrap(),
+            disabled: false,
+            groups: Vec::new(),
+            totp_secret: None,
         }));
 
         let request = LoginRequest {
             name: "dorian".to_owned().try_into().unwrap(),
             password: "I'm not Satoshi".to_owned(),
+            session: SessionInfo::default(),
         };
 
-        let result = tokio_test::block_on(super::check_login(&mut db, request));
+        let result = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions()));
         let err = result.unwrap_err();
 
         match err {
@@ -194,17 +635,19 @@ mod tests {
     fn bad_password() {
         let mut db = Db::from(hmap!("satoshi".to_owned() => user::DbRecord {
             name: "satoshi".to_owned().try_into().unwrap(),
-            hardened_password: "000000000000000000000000000000000000000000000000000775f05a074000".parse().unwrap(),
-            salt: "0000000000000000000775f05a074000".parse().unwrap(),
-            cookie: None,
+            password_hash: "$scrypt$ln=15,r=8,p=1$AAAAAAAAAAAAB3XwWgdAAA$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAd18FoHQAA".parse().unwrap(),
+            disabled: false,
+            groups: Vec::new(),
+            totp_secret: None,
         }));
 
         let request = LoginRequest {
             name: "satoshi".to_owned().try_into().unwrap(),
             password: "shitcoin".to_owned(),
+            session: SessionInfo::default(),
         };
 
-        let result = tokio_test::block_on(super::check_login(&mut db, request));
+        let result = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions()));
         let err = result.unwrap_err();
 
         match err {
@@ -217,46 +660,83 @@ mod tests {
     fn login_success() {
         let mut db = Db::from(hmap!("satoshi".to_owned() => user::DbRecord {
             name: "satoshi".to_owned().try_into().unwrap(),
-            hardened_password: "78e78942ef998339bf975422c27d0be88edd4601f4bee1d544b8af12bcd5b7f7".parse().unwrap(),
-            salt: "0000000000000000000775f05a074000".parse().unwrap(),
-            cookie: None,
+            password_hash: "$scrypt$ln=15,r=8,p=1$AAAAAAAAAAAAB3XwWgdAAA$eOeJQu+Zgzm/l1Qiwn0L6I7dRgH0vuHVRLivErzVt/c".parse().unwrap(),
+            disabled: false,
+            groups: Vec::new(),
+            totp_secret: None,
         }));
 
         let request = LoginRequest {
             name: "satoshi".to_owned().try_into().unwrap(),
             password: "If you don't believe me or don't get it I don't have the time to explain".to_owned(),
+            session: SessionInfo::default(),
         };
 
-        let result = tokio_test::block_on(super::check_login(&mut db, request)).expect("login failed");
+        let result = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions())).expect("login failed");
 
         assert_eq!(&*result.name, "satoshi");
     }
 
+    #[test]
+    fn rehash_on_login() {
+        use crate::user::Db as _;
+
+        let mut db = Db::from(hmap!("satoshi".to_owned() => user::DbRecord {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password_hash: "$scrypt$ln=15,r=8,p=1$AAAAAAAAAAAAB3XwWgdAAA$eOeJQu+Zgzm/l1Qiwn0L6I7dRgH0vuHVRLivErzVt/c".parse().unwrap(),
+            disabled: false,
+            groups: Vec::new(),
+            totp_secret: None,
+        }));
+        let password = "If you don't believe me or don't get it I don't have the time to explain";
+
+        let login = |db: &mut Db| {
+            let request = LoginRequest {
+                name: "satoshi".to_owned().try_into().unwrap(),
+                password: password.to_owned(),
+                session: SessionInfo::default(),
+            };
+
+            tokio_test::block_on(super::check_login(db, &Backend::Local, request, &params(), &sessions())).expect("login failed")
+        };
+
+        login(&mut db);
+
+        let record = tokio_test::block_on(db.get_user("satoshi".to_owned().try_into().unwrap())).unwrap().unwrap();
+        assert!(record.password_hash.as_str().starts_with("$argon2id$"));
+        assert!(!record.password_hash.needs_rehash(&params()));
+
+        login(&mut db);
+    }
+
     #[test]
     fn signup() {
         let mut db = Db::default();
         let request = SignupRequest {
             name: "admin".to_owned().try_into().unwrap(),
             password: "nbusr123".to_owned(),
+            groups: Vec::new(),
         };
 
-        tokio_test::block_on(super::signup(&mut db, request)).expect("Signup failed");
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
 
         let request = LoginRequest {
             name: "admin".to_owned().try_into().unwrap(),
             password: "nbusr123".to_owned(),
+            session: SessionInfo::default(),
         };
 
-        let result = tokio_test::block_on(super::check_login(&mut db, request)).expect("login failed");
+        let result = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions())).expect("login failed");
 
         assert_eq!(&*result.name, "admin");
 
         let request = LoginRequest {
             name: "admin".to_owned().try_into().unwrap(),
             password: "government".to_owned(),
+            session: SessionInfo::default(),
         };
 
-        let result = tokio_test::block_on(super::check_login(&mut db, request));
+        let result = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions()));
         let err = result.unwrap_err();
 
         match err {
@@ -267,12 +747,487 @@ mod tests {
         let request = SignupRequest {
             name: "admin".to_owned().try_into().unwrap(),
             password: "scam".to_owned(),
+            groups: Vec::new(),
         };
 
-        match tokio_test::block_on(super::signup(&mut db, request)) {
+        match tokio_test::block_on(super::signup(&mut db, request, &params())) {
             Err(crate::user::db::InsertUserError::UserExists) => (),
             x => panic!("Unexpected result: {:?}", x),
         }
+    }
+
+    #[test]
+    fn concurrent_sessions() {
+        use crate::user::Db as _;
+
+        let mut db = Db::default();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let request = SignupRequest {
+            name: "admin".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let mut login = |user_agent: &str| {
+            let request = LoginRequest {
+                name: "admin".to_owned().try_into().unwrap(),
+                password: "nbusr123".to_owned(),
+                session: SessionInfo { user_agent: Some(user_agent.to_owned()), label: None, remember: false, },
+            };
+
+            tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions())).expect("login failed")
+        };
+
+        let laptop = login("laptop");
+        let phone = login("phone");
+        assert_ne!(laptop.session_id, phone.session_id);
+
+        let check = |db: &mut Db, login: &LoginSuccessful| {
+            let request = AuthRequest {
+                user_name: login.name.clone(),
+                token: RequestToken::Session(login.cookie.to_string()),
+            };
+
+            match tokio_test::block_on(check_cookie(db, &sessions(), request, logger.clone())).expect("failed to check cookie") {
+                AuthStatus::LoggedIn(user) => user.session_id(),
+                AuthStatus::NotLoggedIn => None,
+            }
+        };
+
+        assert_eq!(check(&mut db, &laptop), Some(laptop.session_id));
+        assert_eq!(check(&mut db, &phone), Some(phone.session_id));
+
+        let sessions = tokio_test::block_on(db.get_sessions(laptop.name.clone())).unwrap();
+        assert_eq!(sessions.len(), 2);
+
+        assert!(tokio_test::block_on(db.revoke_session(laptop.name.clone(), laptop.session_id)).unwrap());
+        assert_eq!(check(&mut db, &laptop), None);
+        assert_eq!(check(&mut db, &phone), Some(phone.session_id));
+
+        tokio_test::block_on(db.revoke_all_sessions(phone.name.clone(), None)).unwrap();
+        assert_eq!(check(&mut db, &phone), None);
+    }
+
+    #[test]
+    fn forward_auth() {
+        let mut db = Db::default();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let request = SignupRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let request = LoginRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            session: SessionInfo::default(),
+        };
+        let login = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, request, &params(), &sessions())).expect("login failed");
+        let cookie = forward_auth_cookie(&login, &sessions());
+        assert!(cookie.starts_with("satoshi."));
+        let token = &cookie["satoshi.".len()..];
+
+        let mut check = |token: RequestToken| {
+            let request = AuthRequest {
+                user_name: login.name.clone(),
+                token,
+            };
+            match tokio_test::block_on(check_cookie(&mut db, &sessions(), request, logger.clone())) {
+                Ok(AuthStatus::LoggedIn(user)) => user.session_id(),
+                _ => None,
+            }
+        };
+
+        assert_eq!(check(RequestToken::ForwardAuth(token.to_owned())), Some(login.session_id));
+        // the tokens aren't interchangeable
+        assert_eq!(check(RequestToken::Session(token.to_owned())), None);
+        assert_eq!(check(RequestToken::ForwardAuth(login.cookie.to_string())), None);
+        assert_eq!(check(RequestToken::ForwardAuth(ForwardAuthToken::random().to_string())), None);
+    }
+
+    #[test]
+    fn unbounded_session_lifetime() {
+        let config = SessionConfig {
+            absolute: Duration::from_secs(u64::max_value()),
+            idle: Duration::from_secs(u64::max_value()),
+            ..sessions()
+        };
+        let now = SystemTime::now();
+        let session = user::DbSession {
+            id: SessionId::random(),
+            user_name: "satoshi".to_owned().try_into().unwrap(),
+            auth_token_hash: AuthToken::random().hash(&config.token_key),
+            created: now,
+            last_seen: now,
+            user_agent: None,
+            label: None,
+        };
+        assert!(!config.is_expired(&session, now));
+    }
+
+    #[test]
+    fn session_expiry() {
+        use crate::user::Db as _;
+
+        let mut db = Db::default();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let request = SignupRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let now = SystemTime::now();
+        let day = Duration::from_secs(3600 * 24);
+        let mut session = |created: SystemTime, last_seen: SystemTime| {
+            let auth_token = AuthToken::random();
+            let record = user::DbSession {
+                id: SessionId::random(),
+                user_name: "satoshi".to_owned().try_into().unwrap(),
+                auth_token_hash: auth_token.hash(&sessions().token_key),
+                created,
+                last_seen,
+                user_agent: None,
+                label: None,
+            };
+            tokio_test::block_on(db.create_session(record)).unwrap();
+            auth_token
+        };
+
+        let active = session(now - day * 29, now - day / 2);
+        let idle = session(now - day * 2, now - day * 2);
+        let old = session(now - day * 31, now);
+
+        let check = |db: &mut Db, auth_token: AuthToken| {
+            let request = AuthRequest {
+                user_name: "satoshi".to_owned().try_into().unwrap(),
+                token: RequestToken::Session(auth_token.to_string()),
+            };
+
+            match tokio_test::block_on(check_cookie(db, &sessions(), request, logger.clone())).expect("failed to check cookie") {
+                AuthStatus::LoggedIn(_) => true,
+                AuthStatus::NotLoggedIn => false,
+            }
+        };
+
+        assert!(check(&mut db, active));
+        assert!(!check(&mut db, idle));
+        assert!(!check(&mut db, old));
+
+        // activity renews the idle timeout
+        let name: user::Name = "satoshi".to_owned().try_into().unwrap();
+        let sessions = tokio_test::block_on(db.get_sessions(name)).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].last_seen >= now);
+    }
+
+    #[test]
+    fn change_password() {
+        use crate::user::Db as _;
+
+        let mut db = Db::default();
+        let request = SignupRequest {
+            name: "admin".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let login = |db: &mut Db, password: &str| {
+            let request = LoginRequest {
+                name: "admin".to_owned().try_into().unwrap(),
+                password: password.to_owned(),
+                session: SessionInfo::default(),
+            };
+
+            tokio_test::block_on(super::check_login(db, &Backend::Local, request, &params(), &sessions()))
+        };
+
+        let current = login(&mut db, "nbusr123").expect("login failed");
+        let other = login(&mut db, "nbusr123").expect("login failed");
+        let user = user::Authenticated::user_logged_in(current.name.clone(), current.session_id, Vec::new());
+
+        let request = ChangePasswordRequest {
+            current_password: "government".to_owned(),
+            new_password: "scam".to_owned(),
+        };
+
+        match tokio_test::block_on(super::change_password(&mut db, &Backend::Local, &user, request, &params())) {
+            Err(ChangePasswordError::BadPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let request = ChangePasswordRequest {
+            current_password: "nbusr123".to_owned(),
+            new_password: "correct horse battery staple".to_owned(),
+        };
+
+        tokio_test::block_on(super::change_password(&mut db, &Backend::Local, &user, request, &params())).expect("failed to change password");
+
+        match login(&mut db, "nbusr123") {
+            Err(LoginError::BadUserPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        login(&mut db, "correct horse battery staple").expect("login failed");
+
+        let sessions = tokio_test::block_on(db.get_sessions(current.name.clone())).unwrap();
+        assert!(sessions.iter().any(|session| session.id == current.session_id));
+        assert!(sessions.iter().all(|session| session.id != other.session_id));
+    }
+
+    #[test]
+    fn external_backend() {
+        use crate::user::Db as _;
+        use crate::user::backend::{Authenticator, VerifyFuture};
+
+        struct Satoshi;
+
+        impl Authenticator for Satoshi {
+            fn verify<'a>(&'a self, name: &'a str, password: &'a str) -> VerifyFuture<'a> {
+                let result = name == "satoshi" && password == "nbusr123";
+                Box::pin(async move { Ok(result) })
+            }
+        }
+
+        let mut db = Db::default();
+        let backend = Backend::External { authenticator: Box::new(Satoshi), create_users: true, };
+        let login_with = |db: &mut Db, backend: &Backend, name: &str, password: &str| {
+            let request = LoginRequest {
+                name: name.to_owned().try_into().unwrap(),
+                password: password.to_owned(),
+                session: SessionInfo::default(),
+            };
+
+            tokio_test::block_on(super::check_login(db, backend, request, &params(), &sessions()))
+        };
+        let login = |db: &mut Db, name: &str, password: &str| login_with(db, &backend, name, password);
+
+        match login(&mut db, "satoshi", "nbusr124") {
+            Err(LoginError::BadUserPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        let name: user::Name = "satoshi".to_owned().try_into().unwrap();
+        assert!(tokio_test::block_on(db.get_user(name.clone())).unwrap().is_none());
+
+        let no_new_users = Backend::External { authenticator: Box::new(Satoshi), create_users: false, };
+        match login_with(&mut db, &no_new_users, "satoshi", "nbusr123") {
+            Err(LoginError::BadUserPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert!(tokio_test::block_on(db.get_user(name)).unwrap().is_none());
+
+        let session = login(&mut db, "satoshi", "nbusr123").expect("login failed");
+        let record = tokio_test::block_on(db.get_user(session.name.clone())).unwrap().expect("user not created");
+        assert!(record.groups.is_empty());
+        login(&mut db, "satoshi", "nbusr123").expect("second login failed");
+
+        let user = user::Authenticated::user_logged_in(session.name, session.session_id, Vec::new());
+        let request = ChangePasswordRequest {
+            current_password: "nbusr123".to_owned(),
+            new_password: "correct horse battery staple".to_owned(),
+        };
+        match tokio_test::block_on(super::change_password(&mut db, &backend, &user, request, &params())) {
+            Err(ChangePasswordError::ExternalPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        tokio_test::block_on(db.set_disabled(user.user_name().clone(), true)).unwrap();
+        match login(&mut db, "satoshi", "nbusr123") {
+            Err(LoginError::UserDisabled) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        login_with(&mut db, &no_new_users, "satoshi", "nbusr123").err().expect("disabled user logged in");
+    }
+
+    #[test]
+    fn local_user_with_external_backend() {
+        use crate::user::backend::{Authenticator, VerifyFuture};
+
+        struct Nobody;
+
+        impl Authenticator for Nobody {
+            fn verify<'a>(&'a self, _name: &'a str, _password: &'a str) -> VerifyFuture<'a> {
+                Box::pin(async { Ok(false) })
+            }
+        }
+
+        let mut db = Db::default();
+        let request = SignupRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let backend = Backend::External { authenticator: Box::new(Nobody), create_users: true, };
+        let login = |db: &mut Db, password: &str| {
+            let request = LoginRequest {
+                name: "satoshi".to_owned().try_into().unwrap(),
+                password: password.to_owned(),
+                session: SessionInfo::default(),
+            };
+
+            tokio_test::block_on(super::check_login(db, &backend, request, &params(), &sessions()))
+        };
+
+        let session = login(&mut db, "nbusr123").expect("login failed");
+        match login(&mut db, "nbusr124") {
+            Err(LoginError::BadUserPassword) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let user = user::Authenticated::user_logged_in(session.name, session.session_id, Vec::new());
+        let request = ChangePasswordRequest {
+            current_password: "nbusr123".to_owned(),
+            new_password: "correct horse battery staple".to_owned(),
+        };
+        tokio_test::block_on(super::change_password(&mut db, &backend, &user, request, &params())).expect("failed to change password");
+        login(&mut db, "correct horse battery staple").expect("login with the new password failed");
+    }
+
+    #[test]
+    fn disabled_user() {
+        use crate::user::Db as _;
+
+        let mut db = Db::default();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let request = SignupRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let login = |db: &mut Db| {
+            let request = LoginRequest {
+                name: "satoshi".to_owned().try_into().unwrap(),
+                password: "nbusr123".to_owned(),
+                session: SessionInfo::default(),
+            };
+
+            tokio_test::block_on(super::check_login(db, &Backend::Local, request, &params(), &sessions()))
+        };
+
+        let session = login(&mut db).expect("login failed");
+        let name = session.name.clone();
+
+        tokio_test::block_on(db.set_disabled(name.clone(), true)).unwrap();
+
+        match login(&mut db) {
+            Err(LoginError::UserDisabled) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
 
+        // The session was not revoked but it must be rejected anyway
+        let request = AuthRequest {
+            user_name: name.clone(),
+            token: RequestToken::Session(session.cookie.to_string()),
+        };
+
+        match tokio_test::block_on(check_cookie(&mut db, &sessions(), request, logger)).expect("failed to check cookie") {
+            AuthStatus::NotLoggedIn => (),
+            AuthStatus::LoggedIn(_) => panic!("disabled user is logged in"),
+        }
+
+        tokio_test::block_on(db.set_disabled(name, false)).unwrap();
+        login(&mut db).expect("login failed");
+    }
+
+    #[test]
+    fn two_factor() {
+        use super::totp::{EnableTotpRequest, SecondFactorRequest, SecondFactorError};
+        use crate::user::types::{TotpSecret, TOTP_STEP_SECONDS};
+
+        let mut db = Db::default();
+        let request = SignupRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            groups: Vec::new(),
+        };
+
+        tokio_test::block_on(super::signup(&mut db, request, &params())).expect("Signup failed");
+
+        let login = |db: &mut Db| {
+            let request = LoginRequest {
+                name: "satoshi".to_owned().try_into().unwrap(),
+                password: "nbusr123".to_owned(),
+                session: SessionInfo::default(),
+            };
+
+            match tokio_test::block_on(super::check_login(db, &Backend::Local, request, &params(), &sessions())) {
+                Err(LoginError::SecondFactorRequired(challenge)) => challenge,
+                x => panic!("Unexpected result: {:?}", x),
+            }
+        };
+
+        let second_factor = |db: &mut Db, challenge: SecondFactorChallenge, code: &str| {
+            let request = SecondFactorRequest {
+                name: challenge.name,
+                challenge: challenge.token,
+                code: code.to_owned(),
+                user_agent: None,
+            };
+
+            tokio_test::block_on(super::totp::check_second_factor(db, &sessions(), request))
+        };
+
+        let session = tokio_test::block_on(super::check_login(&mut db, &Backend::Local, LoginRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            session: SessionInfo::default(),
+        }, &params(), &sessions())).expect("login failed");
+        let user = user::Authenticated::user_logged_in(session.name, session.session_id, Vec::new());
+
+        let secret = TotpSecret::random();
+        let step = unix_timestamp(SystemTime::now()) / TOTP_STEP_SECONDS;
+        let request = EnableTotpRequest {
+            password: "nbusr123".to_owned(),
+            secret,
+            code: format!("{:06}", secret.code(step)),
+        };
+        let recovery_codes = tokio_test::block_on(super::totp::enable(&mut db, &Backend::Local, &user, request, &params())).expect("failed to enable TOTP");
+
+        // the code used for enabling can't be replayed
+        let challenge = login(&mut db);
+        match second_factor(&mut db, challenge, &format!("{:06}", secret.code(step))) {
+            Err(SecondFactorError::BadCode) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let challenge = login(&mut db);
+        let token = challenge.token;
+        second_factor(&mut db, challenge, &format!("{:06}", secret.code(step + 1))).expect("login failed");
+
+        // challenges are single-use
+        let challenge = SecondFactorChallenge { name: "satoshi".to_owned().try_into().unwrap(), token, };
+        match second_factor(&mut db, challenge, &format!("{:06}", secret.code(step + 1))) {
+            Err(SecondFactorError::InvalidChallenge) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let challenge = login(&mut db);
+        second_factor(&mut db, challenge, &recovery_codes[0].to_uppercase()).expect("login failed");
+
+        let challenge = login(&mut db);
+        match second_factor(&mut db, challenge, &recovery_codes[0]) {
+            Err(SecondFactorError::BadCode) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        tokio_test::block_on(super::totp::disable(&mut db, &Backend::Local, &user, "nbusr123", &params())).expect("failed to disable TOTP");
+        tokio_test::block_on(super::check_login(&mut db, &Backend::Local, LoginRequest {
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            session: SessionInfo::default(),
+        }, &params(), &sessions())).expect("login failed");
     }
 }
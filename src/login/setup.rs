@@ -0,0 +1,200 @@
+//! First-run setup of the administrator account
+//!
+//! While there's no active administrator a one-time token is generated at startup. It's written to
+//! a file readable only by the owner and to the log so only someone with access to the machine
+//! can create the administrator.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::user;
+use crate::user::password;
+use super::{LoginSuccessful, SessionConfig, SessionInfo, SignupRequest};
+
+token_newtype!(SetupToken, 16, SetupTokenError);
+
+#[derive(Debug, thiserror::Error)]
+pub enum InitError<ListUsers: 'static + std::error::Error> {
+    #[error("failed to list users")]
+    DbListUsers(#[source] ListUsers),
+    #[error("can't remove old setup token file {path}")]
+    RemoveFile { path: PathBuf, #[source] error: std::io::Error },
+    #[error("can't create setup token file {path}")]
+    CreateFile { path: PathBuf, #[source] error: std::io::Error },
+}
+
+pub struct Setup {
+    token: Mutex<Option<SetupToken>>,
+    file: PathBuf,
+}
+
+fn remove_file(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl Setup {
+    /// Generates the token if there's no active administrator and returns it so it can be logged
+    pub async fn init<Db: user::Db>(database: &mut Db, file: PathBuf) -> Result<(Self, Option<SetupToken>), InitError<Db::GetUserError>> where Db::GetUserError: 'static {
+        // a token left from previous run must not be usable
+        remove_file(&file).map_err(|error| InitError::RemoveFile { path: file.clone(), error, })?;
+
+        let users = database
+            .list_users()
+            .await
+            .map_err(InitError::DbListUsers)?;
+
+        let token = if users.iter().any(user::DbRecord::is_active_admin) {
+            None
+        } else {
+            let token = SetupToken::random();
+            crate::io::create_private_file(&file, &format!("{}\n", token))
+                .map_err(|error| InitError::CreateFile { path: file.clone(), error, })?;
+            Some(token)
+        };
+
+        let setup = Setup {
+            token: Mutex::new(token),
+            file,
+        };
+
+        Ok((setup, token))
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.token.lock().expect("setup mutex poisoned").is_some()
+    }
+
+    fn check_token(&self, token: &SetupToken) -> bool {
+        *self.token.lock().expect("setup mutex poisoned") == Some(*token)
+    }
+
+    fn finish(&self) {
+        *self.token.lock().expect("setup mutex poisoned") = None;
+    }
+
+    /// Removes the token file after the setup was finished
+    pub fn remove_file(&self) -> std::io::Result<()> {
+        remove_file(&self.file)
+    }
+}
+
+pub struct SetupRequest {
+    pub token: String,
+    pub name: user::Name,
+    pub password: String,
+    pub session: SessionInfo,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SetupError<GetUser: 'static + std::error::Error, InsertUser: 'static + std::error::Error, Session: 'static + std::error::Error> {
+    #[error("the setup was already finished")]
+    Finished,
+    #[error("invalid setup token")]
+    BadToken,
+    #[error("the password is empty")]
+    EmptyPassword,
+    #[error("the user already exists")]
+    UserExists,
+    #[error("failed to list users")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to insert the user")]
+    DbInsertUserError(#[source] InsertUser),
+    #[error("failed to create session")]
+    DbSessionError(#[source] Session),
+}
+
+/// Creates the administrator and logs them in.
+///
+/// The token stays valid if the setup fails so that it can be retried.
+pub async fn setup<Db: user::Db>(database: &mut Db, state: &Setup, params: &password::Params, sessions: &SessionConfig, request: SetupRequest) -> Result<LoginSuccessful, SetupError<Db::GetUserError, Db::InsertUserError, Db::SessionError>> where Db::GetUserError: 'static, Db::InsertUserError: 'static {
+    if !state.is_pending() {
+        return Err(SetupError::Finished);
+    }
+
+    let token = request.token.trim().parse::<SetupToken>().map_err(|_| SetupError::BadToken)?;
+    if !state.check_token(&token) {
+        return Err(SetupError::BadToken);
+    }
+
+    if request.password.is_empty() {
+        return Err(SetupError::EmptyPassword);
+    }
+
+    let users = database
+        .list_users()
+        .await
+        .map_err(SetupError::DbGetUserError)?;
+
+    // someone could have created an administrator using the command line in the meantime
+    if users.iter().any(user::DbRecord::is_active_admin) {
+        state.finish();
+        return Err(SetupError::Finished);
+    }
+
+    let signup_request = SignupRequest {
+        name: request.name.clone(),
+        password: request.password,
+        groups: vec![user::Group::ADMINS.into_owned()],
+    };
+
+    match super::signup(database, signup_request, params).await {
+        Ok(()) => (),
+        Err(user::InsertError::UserExists) => return Err(SetupError::UserExists),
+        Err(user::InsertError::DatabaseError(error)) => return Err(SetupError::DbInsertUserError(error)),
+    }
+
+    state.finish();
+
+    super::create_session(database, sessions, request.name, request.session)
+        .await
+        .map_err(SetupError::DbSessionError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use super::{Setup, SetupRequest, SetupError};
+    use crate::login::SessionInfo;
+    use crate::mock_db::Db;
+
+    #[test]
+    fn setup() {
+        let mut db = Db::default();
+        let file = std::env::temp_dir().join(format!("selfhost-dashboard-setup-test-{}", std::process::id()));
+        let (state, token) = tokio_test::block_on(Setup::init(&mut db, file.clone())).unwrap();
+        let token = token.expect("missing setup token");
+        assert_eq!(std::fs::read_to_string(&file).unwrap().trim(), token.to_string());
+
+        let request = |token: String| SetupRequest {
+            token,
+            name: "satoshi".to_owned().try_into().unwrap(),
+            password: "nbusr123".to_owned(),
+            session: SessionInfo::default(),
+        };
+        let params = crate::user::password::test_params();
+        let sessions = crate::login::test_sessions();
+
+        match tokio_test::block_on(super::setup(&mut db, &state, &params, &sessions, request(super::SetupToken::random().to_string()))) {
+            Err(SetupError::BadToken) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        let success = tokio_test::block_on(super::setup(&mut db, &state, &params, &sessions, request(token.to_string()))).expect("setup failed");
+        assert_eq!(&*success.name, "satoshi");
+        state.remove_file().unwrap();
+        assert!(!file.exists());
+
+        match tokio_test::block_on(super::setup(&mut db, &state, &params, &sessions, request(token.to_string()))) {
+            Err(SetupError::Finished) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        // no token is generated once there's an administrator
+        let (state, token) = tokio_test::block_on(Setup::init(&mut db, file.clone())).unwrap();
+        assert!(token.is_none());
+        assert!(!state.is_pending());
+        assert!(!file.exists());
+    }
+}
@@ -0,0 +1,384 @@
+//! Protection against guessing passwords
+//!
+//! Failed login attempts are counted per user name and per client address. After a few free
+//! attempts each further one has to wait exponentially longer and eventually the user name or
+//! address gets locked out. User names are tracked whether they exist or not so that the
+//! responses don't reveal existence of accounts.
+//!
+//! Checking the password is slow so an attempt counts as a failure from the moment it's admitted
+//! until its result is known. Otherwise parallel requests would all pass the check before any of
+//! them failed.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Expired entries are removed when the number of tracked keys exceeds this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub free_attempts: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    pub lockout_attempts: u32,
+    pub lockout: Duration,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    /// Admitted attempts that didn't finish yet
+    pending: u32,
+    /// When the last pending attempt was admitted
+    reserved: Instant,
+}
+
+impl Failures {
+    fn new(now: Instant) -> Self {
+        Failures {
+            count: 0,
+            last: now,
+            pending: 0,
+            reserved: now,
+        }
+    }
+
+    /// Counts pending attempts as if they failed when they were admitted
+    fn with_pending(&self) -> Self {
+        if self.pending == 0 {
+            return *self;
+        }
+        Failures {
+            count: self.count.saturating_add(self.pending),
+            last: std::cmp::max(self.last, self.reserved),
+            ..*self
+        }
+    }
+
+    fn is_expired(&self, limits: &Limits, now: Instant) -> bool {
+        now.saturating_duration_since(self.last) >= limits.lockout
+    }
+
+    /// Returns the instant after which another attempt is allowed, `None` if it's too far in the
+    /// future to be represented, which is treated as locked out
+    fn allowed_after(&self, limits: &Limits) -> Option<Instant> {
+        if self.count >= limits.lockout_attempts {
+            self.last.checked_add(limits.lockout)
+        } else if self.count >= limits.free_attempts {
+            let exponent = self.count - limits.free_attempts;
+            let multiplier = 1u32.checked_shl(exponent).unwrap_or(u32::max_value());
+            let delay = limits.backoff_base.checked_mul(multiplier).unwrap_or(limits.backoff_max);
+            self.last.checked_add(std::cmp::min(delay, limits.backoff_max))
+        } else {
+            Some(self.last)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttled {
+    pub retry_after: Duration,
+    pub locked_out: bool,
+}
+
+/// Reports which keys got locked out by the failed attempt
+#[derive(Debug, Default)]
+pub struct NewLockouts {
+    pub user_name: bool,
+    pub address: bool,
+}
+
+#[derive(Default)]
+struct State {
+    user_names: HashMap<String, Failures>,
+    addresses: HashMap<IpAddr, Failures>,
+}
+
+pub struct Throttle {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+fn check_entry(failures: Option<&Failures>, limits: &Limits, now: Instant) -> Option<Throttled> {
+    let failures = failures.map(Failures::with_pending).filter(|failures| !failures.is_expired(limits, now))?;
+    match failures.allowed_after(limits) {
+        Some(allowed_after) if allowed_after > now => Some(Throttled {
+            retry_after: allowed_after - now,
+            locked_out: failures.count >= limits.lockout_attempts,
+        }),
+        Some(_) => None,
+        None => Some(Throttled {
+            retry_after: limits.lockout,
+            locked_out: true,
+        }),
+    }
+}
+
+/// Returns `true` if the entry just reached the lockout limit
+fn record_failure(failures: &mut Failures, limits: &Limits, now: Instant) -> bool {
+    if failures.is_expired(limits, now) {
+        failures.count = 0;
+    }
+    failures.count = failures.count.saturating_add(1);
+    failures.last = now;
+    failures.count == limits.lockout_attempts
+}
+
+fn reserve(failures: &mut Failures, limits: &Limits, now: Instant) {
+    // stale failures must not add up with the new attempt
+    if failures.pending == 0 && failures.is_expired(limits, now) {
+        failures.count = 0;
+    }
+    failures.pending = failures.pending.saturating_add(1);
+    failures.reserved = now;
+}
+
+fn release<K, Q>(map: &mut HashMap<K, Failures>, key: &Q) where K: Borrow<Q> + Hash + Eq, Q: Hash + Eq + ?Sized {
+    if let Some(failures) = map.get_mut(key) {
+        failures.pending = failures.pending.saturating_sub(1);
+        if failures.count == 0 && failures.pending == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+fn prune<K: Hash + Eq>(map: &mut HashMap<K, Failures>, limits: &Limits, now: Instant) {
+    if map.len() > PRUNE_THRESHOLD {
+        map.retain(|_, failures| failures.pending > 0 || !failures.is_expired(limits, now));
+    }
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        Throttle {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    /// Must be called before checking the password so that throttled attempts are cheap
+    ///
+    /// The admitted attempt counts as a failure until it's resolved.
+    pub fn check(&self, user_name: &str, address: Option<IpAddr>, now: Instant) -> Result<Attempt<'_>, Throttled> {
+        let mut state = self.state.lock().expect("throttle mutex poisoned");
+        let limits = &self.limits;
+        let by_name = check_entry(state.user_names.get(user_name), limits, now);
+        let by_address = address.and_then(|address| check_entry(state.addresses.get(&address), limits, now));
+
+        match (by_name, by_address) {
+            (None, None) => (),
+            (Some(throttled), None) | (None, Some(throttled)) => return Err(throttled),
+            (Some(a), Some(b)) => return Err(if a.retry_after > b.retry_after { a } else { b }),
+        }
+
+        prune(&mut state.user_names, limits, now);
+        reserve(state.user_names.entry(user_name.to_owned()).or_insert_with(|| Failures::new(now)), limits, now);
+        if let Some(address) = address {
+            prune(&mut state.addresses, limits, now);
+            reserve(state.addresses.entry(address).or_insert_with(|| Failures::new(now)), limits, now);
+        }
+
+        Ok(Attempt {
+            throttle: self,
+            user_name: user_name.to_owned(),
+            address,
+            resolved: false,
+        })
+    }
+}
+
+/// Login attempt admitted by `Throttle::check`
+///
+/// Dropping it without calling `failed` or `succeeded` releases it without counting it, which is
+/// used when the attempt ends with an internal error.
+pub struct Attempt<'a> {
+    throttle: &'a Throttle,
+    user_name: String,
+    address: Option<IpAddr>,
+    resolved: bool,
+}
+
+impl<'a> Attempt<'a> {
+    pub fn failed(mut self, now: Instant) -> NewLockouts {
+        self.resolved = true;
+        let mut state = self.throttle.state.lock().expect("throttle mutex poisoned");
+        let limits = &self.throttle.limits;
+
+        let user_name = {
+            let failures = state.user_names.entry(self.user_name.clone()).or_insert_with(|| Failures::new(now));
+            failures.pending = failures.pending.saturating_sub(1);
+            record_failure(failures, limits, now)
+        };
+        let address = match self.address {
+            Some(address) => {
+                let failures = state.addresses.entry(address).or_insert_with(|| Failures::new(now));
+                failures.pending = failures.pending.saturating_sub(1);
+                record_failure(failures, limits, now)
+            },
+            None => false,
+        };
+
+        NewLockouts {
+            user_name,
+            address,
+        }
+    }
+
+    /// Forgets failed attempts of the user.
+    ///
+    /// The address is intentionally kept so that an attacker can't reset its counter by logging
+    /// into their own account.
+    pub fn succeeded(mut self) {
+        self.resolved = true;
+        let mut state = self.throttle.state.lock().expect("throttle mutex poisoned");
+        state.user_names.remove(&self.user_name);
+        if let Some(address) = &self.address {
+            release(&mut state.addresses, address);
+        }
+    }
+}
+
+impl<'a> Drop for Attempt<'a> {
+    fn drop(&mut self) {
+        if self.resolved {
+            return;
+        }
+        let mut state = self.throttle.state.lock().expect("throttle mutex poisoned");
+        release(&mut state.user_names, self.user_name.as_str());
+        if let Some(address) = &self.address {
+            release(&mut state.addresses, address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Throttle, Limits};
+    use std::time::{Duration, Instant};
+
+    fn throttle() -> Throttle {
+        Throttle::new(Limits {
+            free_attempts: 2,
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(4),
+            lockout_attempts: 6,
+            lockout: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn backoff_and_lockout() {
+        let throttle = throttle();
+        let start = Instant::now();
+        let address = Some("192.0.2.1".parse().unwrap());
+
+        for _ in 0..2 {
+            throttle.check("satoshi", address, start).unwrap().failed(start);
+        }
+        let throttled = throttle.check("satoshi", address, start).err().expect("not throttled");
+        assert_eq!(throttled.retry_after, Duration::from_secs(1));
+        assert!(!throttled.locked_out);
+        assert!(throttle.check("satoshi", address, start + Duration::from_secs(1)).is_ok());
+
+        let mut now = start;
+        for delay in &[1, 2, 4] {
+            now += Duration::from_secs(*delay);
+            assert!(!throttle.check("satoshi", address, now).unwrap().failed(now).user_name);
+        }
+        // backoff is capped
+        assert_eq!(throttle.check("satoshi", address, now).err().expect("not throttled").retry_after, Duration::from_secs(4));
+
+        now += Duration::from_secs(4);
+        let lockouts = throttle.check("satoshi", address, now).unwrap().failed(now);
+        assert!(lockouts.user_name && lockouts.address);
+        let throttled = throttle.check("satoshi", None, now + Duration::from_secs(30)).err().expect("not throttled");
+        assert!(throttled.locked_out);
+        // other users from the same address are locked out too
+        assert!(throttle.check("dorian", address, now).err().expect("not throttled").locked_out);
+        assert!(throttle.check("dorian", None, now).is_ok());
+
+        // failures are forgotten after the lockout
+        let later = now + Duration::from_secs(60);
+        throttle.check("satoshi", address, later).unwrap().failed(later);
+        assert!(throttle.check("satoshi", address, later).is_ok());
+    }
+
+    #[test]
+    fn unrepresentable_lockout() {
+        let throttle = Throttle::new(Limits {
+            free_attempts: 1,
+            backoff_base: Duration::from_secs(u64::max_value()),
+            backoff_max: Duration::from_secs(u64::max_value()),
+            lockout_attempts: 2,
+            lockout: Duration::from_secs(u64::max_value()),
+        });
+        let now = Instant::now();
+
+        throttle.check("satoshi", None, now).unwrap().failed(now);
+        assert!(throttle.check("satoshi", None, now).err().expect("not throttled").locked_out);
+    }
+
+    #[test]
+    fn success_resets_user_name() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let address = Some("192.0.2.1".parse().unwrap());
+
+        for _ in 0..2 {
+            throttle.check("satoshi", address, now).unwrap().failed(now);
+        }
+        throttle.check("satoshi", None, now + Duration::from_secs(1)).unwrap().succeeded();
+        assert!(throttle.check("satoshi", None, now).is_ok());
+        assert!(throttle.check("satoshi", address, now).is_err());
+    }
+
+    #[test]
+    fn pending_attempts_count() {
+        let throttle = throttle();
+        let now = Instant::now();
+
+        let first = throttle.check("satoshi", None, now).unwrap();
+        let second = throttle.check("satoshi", None, now).unwrap();
+        assert!(throttle.check("satoshi", None, now).is_err());
+        // an attempt ending without a result frees its slot
+        drop(second);
+        let second = throttle.check("satoshi", None, now).unwrap();
+        first.failed(now);
+        second.failed(now);
+        assert!(throttle.check("satoshi", None, now).is_err());
+    }
+
+    #[test]
+    fn parallel_attempts() {
+        use std::sync::{Arc, Barrier};
+
+        let throttle = Arc::new(throttle());
+        let now = Instant::now();
+        let checked = Arc::new(Barrier::new(8));
+
+        let threads = (0..8)
+            .map(|_| {
+                let throttle = Arc::clone(&throttle);
+                let checked = Arc::clone(&checked);
+                std::thread::spawn(move || {
+                    let attempt = throttle.check("satoshi", None, now).ok();
+                    // all checks happen before any password would be verified
+                    checked.wait();
+                    let admitted = attempt.is_some();
+                    if let Some(attempt) = attempt {
+                        attempt.failed(now);
+                    }
+                    admitted
+                })
+            })
+            .collect::<Vec<_>>();
+        let admitted = threads.into_iter().map(|thread| thread.join().unwrap()).filter(|admitted| *admitted).count();
+        assert_eq!(admitted, 2);
+        assert!(throttle.check("satoshi", None, now).is_err());
+    }
+}
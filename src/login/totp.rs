@@ -0,0 +1,350 @@
+//! Two-factor authentication using RFC 6238 TOTP codes with one-time recovery codes
+
+use std::time::{Duration, SystemTime};
+use void::Void;
+use crate::user;
+use crate::user::password;
+use crate::user::backend::Backend;
+use crate::user::db::LoginChallengeRecord;
+use crate::user::types::{TotpSecret, RecoveryCodeHash, LoginChallenge, TOTP_STEP_SECONDS};
+use super::{LoginError, LoginSuccessful, SecondFactorChallenge, SessionInfo, Verified};
+
+/// How long the user has to enter the code after entering the correct password
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Number of steps the clock of the authenticator may differ from ours
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Displayed by authenticator apps next to the user name
+const ISSUER: &str = "selfhost-dashboard";
+
+fn current_step(now: SystemTime) -> u64 {
+    super::unix_timestamp(now) / TOTP_STEP_SECONDS
+}
+
+/// Returns the time step the code belongs to or `None` if the code is invalid
+fn find_step(secret: &TotpSecret, code: &str, now: SystemTime) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != 6 || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let step = current_step(now);
+
+    // All steps are checked so that timing doesn't reveal which one matched
+    (step.saturating_sub(ALLOWED_DRIFT_STEPS)..=(step + ALLOWED_DRIFT_STEPS))
+        .fold(None, |found, step| if secret.code(step) == code { Some(step) } else { found })
+}
+
+/// Generates recovery codes to be shown to the user and their hashes to be stored
+fn generate_recovery_codes() -> (Vec<String>, Vec<RecoveryCodeHash>) {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let bytes = rand::random::<[u8; 10]>();
+            let encoded = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_ascii_lowercase();
+            let code = format!("{}-{}-{}-{}", &encoded[0..4], &encoded[4..8], &encoded[8..12], &encoded[12..16]);
+            let hash = RecoveryCodeHash::hash(&code);
+            (code, hash)
+        })
+        .unzip()
+}
+
+pub fn otpauth_uri(secret: &TotpSecret, user_name: &str) -> String {
+    format!("otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period={period}",
+        issuer = ISSUER,
+        user = user_name,
+        secret = secret.to_base32(),
+        period = TOTP_STEP_SECONDS,
+    )
+}
+
+/// Time before which challenges expired, they're removed whenever a challenge is created or used
+fn valid_since(now: SystemTime) -> SystemTime {
+    now.checked_sub(CHALLENGE_LIFETIME).unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+pub(super) async fn create_challenge<Db: user::Db>(database: &mut Db, name: user::Name, session: SessionInfo) -> Result<SecondFactorChallenge, Db::SessionError> {
+    let now = SystemTime::now();
+    let record = LoginChallengeRecord {
+        token: LoginChallenge::random(),
+        user_name: name.clone(),
+        created: now,
+        label: session.label.map(super::truncate_session_info),
+        remember: session.remember,
+    };
+    let token = record.token;
+
+    database.create_login_challenge(record, valid_since(now)).await?;
+
+    Ok(SecondFactorChallenge { name, token, })
+}
+
+pub struct SecondFactorRequest {
+    pub name: user::Name,
+    pub challenge: LoginChallenge,
+    /// Either the six-digit TOTP code or one of the recovery codes
+    pub code: String,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SecondFactorError<GetUser: 'static + std::error::Error, Session: 'static + std::error::Error, UpdateUser: 'static + std::error::Error> {
+    #[error("the login challenge doesn't exist or expired")]
+    InvalidChallenge,
+    #[error("the code is invalid or was already used")]
+    BadCode,
+    #[error("the user is disabled")]
+    UserDisabled,
+    #[error("failed to get user")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to access the login challenge or to create session")]
+    DbSessionError(#[source] Session),
+    #[error("failed to mark the code as used")]
+    DbUpdateUserError(#[source] UpdateUser),
+}
+
+/// Finishes the login started by `check_login`.
+///
+/// The challenge is consumed even if the code is wrong so guessing requires entering the password
+/// again every time.
+pub async fn check_second_factor<Db: user::Db>(database: &mut Db, sessions: &super::SessionConfig, request: SecondFactorRequest) -> Result<LoginSuccessful, SecondFactorError<Db::GetUserError, Db::SessionError, Db::UpdateUserError>> where Db::GetUserError: 'static {
+    let now = SystemTime::now();
+
+    let challenge = database
+        .take_login_challenge(request.name.clone(), request.challenge, valid_since(now))
+        .await
+        .map_err(SecondFactorError::DbSessionError)?
+        .ok_or(SecondFactorError::InvalidChallenge)?;
+
+    if challenge.created < valid_since(now) {
+        return Err(SecondFactorError::InvalidChallenge);
+    }
+
+    let user = database
+        .get_user(request.name)
+        .await
+        .map_err(SecondFactorError::DbGetUserError)?
+        .ok_or(SecondFactorError::InvalidChallenge)?;
+
+    if user.disabled {
+        return Err(SecondFactorError::UserDisabled);
+    }
+
+    // two-factor authentication was disabled in the meantime
+    let secret = user.totp_secret.ok_or(SecondFactorError::InvalidChallenge)?;
+
+    let accepted = match find_step(&secret, &request.code, now) {
+        Some(step) => database.use_totp_step(user.name.clone(), step).await,
+        // the hash ignores case, spaces and dashes like the user may type the code
+        None => database.use_recovery_code(user.name.clone(), RecoveryCodeHash::hash(&request.code)).await,
+    };
+
+    if !accepted.map_err(SecondFactorError::DbUpdateUserError)? {
+        return Err(SecondFactorError::BadCode);
+    }
+
+    let session_info = SessionInfo {
+        user_agent: request.user_agent,
+        label: challenge.label,
+        remember: challenge.remember,
+    };
+
+    super::create_session(database, sessions, user.name, session_info)
+        .await
+        .map_err(SecondFactorError::DbSessionError)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManageTotpError<GetUser: 'static + std::error::Error, UpdateUser: 'static + std::error::Error> {
+    #[error("the password is invalid")]
+    BadPassword,
+    #[error("the code doesn't match the secret")]
+    BadCode,
+    #[error("two-factor authentication is not enabled")]
+    NotEnabled,
+    #[error("failed to get user")]
+    DbGetUserError(#[source] GetUser),
+    #[error("failed to store two-factor authentication settings")]
+    DbUpdateUserError(#[source] UpdateUser),
+    #[error("failed to verify the password")]
+    BackendError(#[source] user::backend::Error),
+}
+
+async fn verify_password<Db: user::Db>(database: &mut Db, backend: &Backend, user: &user::Authenticated, password: &str, params: &password::Params) -> Result<user::DbRecord, ManageTotpError<Db::GetUserError, Db::UpdateUserError>> where Db::GetUserError: 'static {
+    match super::verify_password::<_, Void, Void, Void>(database, backend, user.user_name().clone(), password, params).await {
+        Ok(Verified::Local(record)) | Ok(Verified::External(Some(record))) => Ok(record),
+        Ok(Verified::External(None)) | Err(LoginError::BadUserPassword) | Err(LoginError::UserDisabled) => Err(ManageTotpError::BadPassword),
+        Err(LoginError::DbGetUserError(error)) => Err(ManageTotpError::DbGetUserError(error)),
+        Err(LoginError::BackendError(error)) => Err(ManageTotpError::BackendError(error)),
+        Err(LoginError::DbCreateSessionError(never)) | Err(LoginError::DbUpdateUserError(never)) | Err(LoginError::DbInsertUserError(never)) => match never {},
+        Err(LoginError::SecondFactorRequired(_)) => unreachable!("password verification doesn't check the second factor"),
+    }
+}
+
+pub struct EnableTotpRequest {
+    pub password: String,
+    /// The secret previously generated by `TotpSecret::random` and shown to the user
+    pub secret: TotpSecret,
+    /// Code generated by the authenticator proving it was set up correctly
+    pub code: String,
+}
+
+/// Enables two-factor authentication or replaces the secret and returns new recovery codes.
+pub async fn enable<Db: user::Db>(database: &mut Db, backend: &Backend, user: &user::Authenticated, request: EnableTotpRequest, params: &password::Params) -> Result<Vec<String>, ManageTotpError<Db::GetUserError, Db::UpdateUserError>> where Db::GetUserError: 'static {
+    verify_password(database, backend, user, &request.password, params).await?;
+
+    let step = find_step(&request.secret, &request.code, SystemTime::now()).ok_or(ManageTotpError::BadCode)?;
+    let (codes, hashes) = generate_recovery_codes();
+
+    database
+        .set_totp(user.user_name().clone(), Some(request.secret), hashes)
+        .await
+        .map_err(ManageTotpError::DbUpdateUserError)?;
+
+    // The code is visible to the user so it must not be usable for logging in
+    database
+        .use_totp_step(user.user_name().clone(), step)
+        .await
+        .map_err(ManageTotpError::DbUpdateUserError)?;
+
+    Ok(codes)
+}
+
+/// Disables two-factor authentication and deletes all recovery codes
+pub async fn disable<Db: user::Db>(database: &mut Db, backend: &Backend, user: &user::Authenticated, password: &str, params: &password::Params) -> Result<(), ManageTotpError<Db::GetUserError, Db::UpdateUserError>> where Db::GetUserError: 'static {
+    verify_password(database, backend, user, password, params).await?;
+
+    database
+        .set_totp(user.user_name().clone(), None, Vec::new())
+        .await
+        .map_err(ManageTotpError::DbUpdateUserError)?;
+
+    Ok(())
+}
+
+/// Invalidates all existing recovery codes and returns new ones
+pub async fn regenerate_recovery_codes<Db: user::Db>(database: &mut Db, backend: &Backend, user: &user::Authenticated, password: &str, params: &password::Params) -> Result<Vec<String>, ManageTotpError<Db::GetUserError, Db::UpdateUserError>> where Db::GetUserError: 'static {
+    let record = verify_password(database, backend, user, password, params).await?;
+    let secret = record.totp_secret.ok_or(ManageTotpError::NotEnabled)?;
+    let (codes, hashes) = generate_recovery_codes();
+
+    database
+        .set_totp(user.user_name().clone(), Some(secret), hashes)
+        .await
+        .map_err(ManageTotpError::DbUpdateUserError)?;
+
+    Ok(codes)
+}
+
+pub mod api {
+    #[derive(serde_derive::Serialize)]
+    pub struct Enrollment {
+        /// Has to be sent back when enabling
+        pub secret: String,
+        /// For manual entry into the authenticator
+        pub key: String,
+        pub uri: String,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    pub struct RecoveryCodes {
+        pub recovery_codes: Vec<String>,
+    }
+}
+
+/// Generates a new secret for the user to scan, nothing is stored until `enable` is called
+pub fn enroll<S: crate::webserver::Server>(user: &user::Authenticated) -> S::ResponseBuilder {
+    let secret = TotpSecret::random();
+    let resp = api::Enrollment {
+        secret: secret.to_string(),
+        key: secret.to_base32(),
+        uri: otpauth_uri(&secret, user.name()),
+    };
+
+    crate::webserver::json_response(&resp)
+}
+
+pub fn recovery_codes<S: crate::webserver::Server>(recovery_codes: Vec<String>) -> S::ResponseBuilder {
+    crate::webserver::json_response(&api::RecoveryCodes { recovery_codes, })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime};
+    use crate::login::{SessionInfo, SignupRequest};
+    use crate::mock_db::Db;
+    use crate::user::{self, types::{LoginChallenge, TotpSecret}, db::LoginChallengeRecord, Db as _};
+    use super::{SecondFactorError, SecondFactorRequest};
+
+    #[test]
+    fn find_step() {
+        let secret = TotpSecret::random();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let step = 1_600_000_000 / 30;
+
+        assert_eq!(super::find_step(&secret, &format!("{:06}", secret.code(step)), now), Some(step));
+        assert_eq!(super::find_step(&secret, &format!("{:06}", secret.code(step - 1)), now), Some(step - 1));
+        assert_eq!(super::find_step(&secret, &format!("{:06}", secret.code(step + 1)), now), Some(step + 1));
+        assert_eq!(super::find_step(&secret, &format!("{:06}", secret.code(step + 2)), now).filter(|found| *found == step + 2), None);
+        assert_eq!(super::find_step(&secret, "12345", now), None);
+        assert_eq!(super::find_step(&secret, "+12345", now), None);
+    }
+
+    #[test]
+    fn recovery_codes() {
+        let (codes, hashes) = super::generate_recovery_codes();
+        assert_eq!(codes.len(), super::RECOVERY_CODE_COUNT);
+        assert_eq!(codes[0].len(), 19);
+        assert_eq!(crate::user::types::RecoveryCodeHash::hash(&codes[0]), hashes[0]);
+    }
+
+    #[test]
+    fn recovery_code_as_typed() {
+        let mut db = Db::default();
+        let params = user::password::test_params();
+        let sessions = crate::login::test_sessions();
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        tokio_test::block_on(crate::login::signup(&mut db, SignupRequest { name: name.clone(), password: "nbusr123".to_owned(), groups: Vec::new(), }, &params)).unwrap();
+        let (codes, hashes) = super::generate_recovery_codes();
+        assert!(tokio_test::block_on(db.set_totp(name.clone(), Some(TotpSecret::random()), hashes)).unwrap());
+
+        let mut login = |code: String| {
+            let challenge = tokio_test::block_on(super::create_challenge(&mut db, name.clone(), SessionInfo::default())).unwrap();
+            let request = SecondFactorRequest { name: name.clone(), challenge: challenge.token, code, user_agent: None, };
+            tokio_test::block_on(super::check_second_factor(&mut db, &sessions, request))
+        };
+
+        // pasted with a line break, typed in capitals or without dashes
+        login(format!(" {}\n", codes[0])).expect("recovery code with whitespace rejected");
+        login(codes[1].to_ascii_uppercase()).expect("upper case recovery code rejected");
+        login(codes[2].replace('-', " ")).expect("recovery code with spaces rejected");
+        match login(codes[0].clone()) {
+            Err(SecondFactorError::BadCode) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn expired_challenges_are_removed() {
+        let mut db = Db::default();
+        let params = user::password::test_params();
+        let name = user::Name::try_from("satoshi".to_owned()).unwrap();
+        tokio_test::block_on(crate::login::signup(&mut db, SignupRequest { name: name.clone(), password: "nbusr123".to_owned(), groups: Vec::new(), }, &params)).unwrap();
+
+        let now = SystemTime::now();
+        let expired = LoginChallengeRecord {
+            token: LoginChallenge::random(),
+            user_name: name.clone(),
+            created: now - super::CHALLENGE_LIFETIME - Duration::from_secs(1),
+            label: None,
+            remember: false,
+        };
+        let expired_token = expired.token;
+        tokio_test::block_on(db.create_login_challenge(expired, super::valid_since(now))).unwrap();
+        tokio_test::block_on(super::create_challenge(&mut db, name.clone(), SessionInfo::default())).unwrap();
+        assert!(tokio_test::block_on(db.take_login_challenge(name, expired_token, super::valid_since(now))).unwrap().is_none());
+    }
+}
@@ -25,6 +25,7 @@ mod postgres_impl;
 mod hyper_impl;
 mod http_impl;
 mod slog_impl;
+mod cli;
 #[cfg(any(test, feature = "mock_system"))]
 mod mock_db;
 
@@ -91,6 +92,18 @@ impl configure_me::parse_arg::ParseArgFromStr for LogLevel {
     }
 }
 
+impl configure_me::parse_arg::ParseArgFromStr for user::password::Algorithm {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "one of: argon2id, scrypt")
+    }
+}
+
+impl configure_me::parse_arg::ParseArgFromStr for user::backend::Kind {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "one of: local, pam, htpasswd, ldap")
+    }
+}
+
 trait LogResultExt {
     type Item;
 
@@ -113,7 +126,12 @@ async fn main() {
     use sloggers::Build;
     use crate::webserver::{self, Request};
 
-    let (config, _) = Config::including_optional_config_files(&["/etc/selfhost-dashboard/interface.conf", "/etc/selfhost-dashboard/database"]).unwrap_or_exit();
+    let (config, args) = Config::including_optional_config_files(&["/etc/selfhost-dashboard/interface.conf", "/etc/selfhost-dashboard/database"]).unwrap_or_exit();
+
+    let command = cli::Command::parse(args).unwrap_or_else(|error| {
+        eprintln!("Error: {}\n\n{}", error, cli::USAGE);
+        std::process::exit(1)
+    });
 
     let logger = sloggers::terminal::TerminalLoggerBuilder::new()
         .destination(sloggers::terminal::Destination::Stderr)
@@ -123,6 +141,8 @@ async fn main() {
 
     let request_logger = logger.clone();
 
+    let token_key = login::load_or_create_token_key(config.token_key_file.as_ref()).die_on_error(&logger, "Failed to load the token key");
+
     #[cfg(not(feature = "mock_system"))]
     let (db_client, db_connection) = postgres_impl::ArcDatabase::connect(&config.pg_uri, tokio_postgres::tls::NoTls)
         .await
@@ -136,20 +156,118 @@ async fn main() {
 
     #[cfg(not(feature = "mock_system"))]
     db_client
-        .init_tables()
+        .init_tables(token_key)
         .await
         .die_on_error(&logger, "Failed to initialize tables in the database");
 
+    let password_params = user::password::Params {
+        algorithm: config.password_hash_algorithm,
+        argon2_memory_kib: config.argon2_memory_kib,
+        argon2_iterations: config.argon2_iterations,
+        argon2_parallelism: config.argon2_parallelism,
+        scrypt_log_n: config.scrypt_log_n,
+        scrypt_r: config.scrypt_r,
+        scrypt_p: config.scrypt_p,
+    };
+    password_params.check().die_on_error(&logger, "invalid password hashing parameters");
+
+    // The commands must not touch the state of a server that may be running
+    if let Some(command) = command {
+        cli::run(&mut db_client.clone(), command, &password_params, || rpassword::prompt_password_stderr("New password: "), std::io::stdout())
+            .await
+            .die_on_error(&logger, "Command failed");
+        return;
+    }
+
     let root_path: Arc<str> = config.root_path.into();
 
+    let (setup, setup_token) = login::setup::Setup::init(&mut db_client.clone(), config.setup_token_file.clone().into())
+        .await
+        .die_on_error(&logger, "Failed to prepare the setup");
+    if let Some(token) = setup_token {
+        slog::warn!(logger, "No administrator exists, open the setup page and enter the setup token"; "url" => format!("{}/setup", root_path), "setup_token" => token, "setup_token_file" => &config.setup_token_file);
+    }
+    let setup = Arc::new(setup);
+
+    let throttle_limits = login::throttle::Limits {
+        free_attempts: config.login_free_attempts,
+        backoff_base: std::time::Duration::from_secs(config.login_backoff_base_seconds),
+        backoff_max: std::time::Duration::from_secs(config.login_backoff_max_seconds),
+        lockout_attempts: config.login_lockout_attempts,
+        lockout: std::time::Duration::from_secs(config.login_lockout_seconds),
+    };
+    let login_throttle = Arc::new(login::throttle::Throttle::new(throttle_limits));
+    let client_address_header: Arc<str> = config.client_address_header.into();
+
+    let password_params = Arc::new(password_params);
+
+    let backend_settings = user::backend::Settings {
+        pam_service: config.pam_service,
+        htpasswd_file: config.htpasswd_file,
+        ldap_url: config.ldap_url,
+        ldap_user_dn: config.ldap_user_dn,
+        create_users: config.auth_backend_create_users,
+    };
+    let auth_backend = user::backend::Backend::configure(config.auth_backend, backend_settings).die_on_error(&logger, "invalid authentication backend configuration");
+    let auth_backend = Arc::new(auth_backend);
+
+    let session_config = Arc::new(login::SessionConfig {
+        absolute: std::time::Duration::from_secs(config.session_lifetime_seconds),
+        idle: std::time::Duration::from_secs(config.session_idle_timeout_seconds),
+        token_key,
+    });
+
+    let invite_lifetime = std::time::Duration::from_secs(config.invite_lifetime_seconds);
+    let api_token_max_lifetime = std::time::Duration::from_secs(config.api_token_max_lifetime_days.saturating_mul(24 * 60 * 60));
+    let audit_retention = std::time::Duration::from_secs(config.audit_log_retention_days.saturating_mul(24 * 60 * 60));
+
+    let oidc_key_file = &config.oidc_key_file;
+    let oidc_token_lifetime = std::time::Duration::from_secs(config.oidc_token_lifetime_seconds);
+    let oidc = config
+        .oidc_issuer
+        .map(|issuer| login::oidc::Provider::init(issuer, &root_path, oidc_key_file.as_ref(), oidc_token_lifetime))
+        .transpose()
+        .die_on_error(&logger, "Failed to initialize the OpenID Connect provider")
+        .map(Arc::new);
+
+    let lnurl = config
+        .lnurl_auth_url
+        .map(|url| login::lnurl::Provider::init(url, &root_path))
+        .transpose()
+        .die_on_error(&logger, "Failed to initialize LNURL-auth")
+        .map(Arc::new);
+
     let apps = app::config::load_and_check_apps(logger.clone()).die_on_error(&logger, "failed to load apps");
     let apps = Arc::new(apps);
 
+    let entry_point_limits = app::entry_point::Limits {
+        default_timeout: std::time::Duration::from_secs(config.entry_point_timeout_seconds),
+        max_processes: config.max_entry_point_processes as usize,
+    };
+    entry_point_limits.check().die_on_error(&logger, "invalid entry point limits");
+    let entry_points = Arc::new(app::entry_point::Runner::new(entry_point_limits));
+
     let server = hyper::Server::bind(&([127, 0, 0, 1], config.bind_port).into());
 
     let server = webserver::Server::serve(server, move |request| {
         slog::info!(request_logger, "received request"; "path" => request.path(), "method" => ?request.method());
-        route::route::<hyper::server::Builder<hyper::server::conn::AddrIncoming>, _>(Arc::clone(&root_path), db_client.clone(), Arc::clone(&apps), request, request_logger.clone())
+        let context = route::Context {
+            prefix: Arc::clone(&root_path),
+            apps: Arc::clone(&apps),
+            login_throttle: Arc::clone(&login_throttle),
+            password_params: Arc::clone(&password_params),
+            auth_backend: Arc::clone(&auth_backend),
+            sessions: Arc::clone(&session_config),
+            setup: Arc::clone(&setup),
+            invite_lifetime,
+            client_address_header: Arc::clone(&client_address_header),
+            api_token_max_lifetime,
+            oidc: oidc.clone(),
+            lnurl: lnurl.clone(),
+            audit_retention,
+            entry_points: Arc::clone(&entry_points),
+        };
+        route::route::<hyper::server::Builder<hyper::server::conn::AddrIncoming>, _>(context, db_client.clone(), request, request_logger.clone())
     });
 
     let server = async {
@@ -1,37 +1,56 @@
 use std::collections::HashMap;
-use std::pin::Pin;
+use std::time::SystemTime;
 use void::Void as Never;
-use std::future::Future;
-use crate::user::{self, types::AuthToken};
+use crate::user::{self, types::{SessionId, TotpSecret, RecoveryCodeHash, LoginChallenge, InviteId, InviteTokenHash, OidcCodeHash, OidcAccessTokenHash, ApiTokenId, ApiTokenHash, LinkingKey}, db::{DbFuture, LoginChallengeRecord, AuditQuery}};
 use std::sync::{Arc, RwLock};
 use crate::primitives::Stringly;
 
+#[derive(Default)]
+struct State {
+    users: HashMap<String, user::DbRecord>,
+    sessions: Vec<user::DbSession>,
+    last_totp_steps: HashMap<String, u64>,
+    recovery_codes: HashMap<String, Vec<RecoveryCodeHash>>,
+    login_challenges: Vec<LoginChallengeRecord>,
+    invites: Vec<user::DbInvite>,
+    oidc_codes: Vec<user::DbOidcCode>,
+    oidc_tokens: Vec<user::DbOidcToken>,
+    api_tokens: Vec<user::DbApiToken>,
+    lnurl_keys: Vec<user::DbLnurlKey>,
+    /// Oldest first
+    audit_log: Vec<user::DbAuditRecord>,
+}
+
 #[derive(Default, Clone)]
-pub struct Db(Arc<RwLock<HashMap<String, user::DbRecord>>>);
+pub struct Db(Arc<RwLock<State>>);
 
 impl From<HashMap<String, user::DbRecord>> for Db {
     fn from(value: HashMap<String, user::DbRecord>) -> Self {
-        Db(Arc::new(RwLock::new(value)))
+        let state = State {
+            users: value,
+            ..Default::default()
+        };
+        Db(Arc::new(RwLock::new(state)))
     }
 }
 
 impl user::Db for Db {
     type GetUserError = Never;
-    type GetUserFuture = Pin<Box<dyn 'static + Send + Future<Output=Result<Option<user::DbRecord>, Self::GetUserError>>>>;
+    type GetUserFuture = DbFuture<Option<user::DbRecord>, Self::GetUserError>;
     type InsertUserError = Never;
-    type InsertUserFuture = Pin<Box<dyn 'static + Send + Future<Output=Result<(), user::InsertError<Self::InsertUserError>>>>>;
+    type InsertUserFuture = DbFuture<(), user::InsertError<Self::InsertUserError>>;
     // We don't care about error handling in tests, just panic
-    type SetCookieError = Never;
-    type SetCookieFuture = Pin<Box<dyn 'static + Send + Future<Output=Result<(), Self::SetCookieError>>>>;
+    type UpdateUserError = Never;
+    type SessionError = Never;
 
     fn get_user<S: 'static + Stringly>(&mut self, name: user::Name<S>) -> Self::GetUserFuture {
-        let result = self.0.read().unwrap().get(&*name).map(Clone::clone);
+        let result = self.0.read().unwrap().users.get(&*name).map(Clone::clone);
         Box::pin(async move { Ok(result)} )
     }
 
     fn insert_new_user(&mut self, record: user::DbRecord) -> Self::InsertUserFuture {
         let mut inserted = false;
-        self.0.write().unwrap().entry((*record.name).to_owned()).or_insert_with(|| { inserted = true; record });
+        self.0.write().unwrap().users.entry((*record.name).to_owned()).or_insert_with(|| { inserted = true; record });
         let result = if inserted {
             Ok(())
         } else {
@@ -41,9 +60,309 @@ impl user::Db for Db {
         Box::pin(async move { result })
     }
 
-    fn set_cookie<S: 'static + Stringly>(&mut self, name: user::Name<S>, value: Option<AuthToken>) -> Self::SetCookieFuture {
-        let result = self.0.write().unwrap().get_mut(&*name).expect("User doesn't exist").cookie = value;
+    fn list_users(&mut self) -> DbFuture<Vec<user::DbRecord>, Self::GetUserError> {
+        let mut result = self.0.read().unwrap().users.values().cloned().collect::<Vec<_>>();
+        result.sort_by(|a, b| (*a.name).cmp(&*b.name));
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn has_users(&mut self) -> DbFuture<bool, Self::GetUserError> {
+        let result = !self.0.read().unwrap().users.is_empty();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn set_disabled<S: 'static + Stringly>(&mut self, name: user::Name<S>, disabled: bool) -> DbFuture<bool, Self::UpdateUserError> {
+        let result = match self.0.write().unwrap().users.get_mut(&*name) {
+            Some(user) => {
+                user.disabled = disabled;
+                true
+            },
+            None => false,
+        };
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn delete_user<S: 'static + Stringly>(&mut self, name: user::Name<S>) -> DbFuture<bool, Self::UpdateUserError> {
+        let mut state = self.0.write().unwrap();
+        let result = state.users.remove(&*name).is_some();
+        state.sessions.retain(|session| *session.user_name != *name);
+        state.last_totp_steps.remove(&*name);
+        state.recovery_codes.remove(&*name);
+        state.login_challenges.retain(|challenge| *challenge.user_name != *name);
+        state.oidc_codes.retain(|code| *code.user_name != *name);
+        state.oidc_tokens.retain(|token| *token.user_name != *name);
+        state.api_tokens.retain(|token| *token.user_name != *name);
+        state.lnurl_keys.retain(|key| *key.user_name != *name);
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn set_groups<S: 'static + Stringly>(&mut self, name: user::Name<S>, groups: Vec<user::Group>) -> DbFuture<bool, Self::UpdateUserError> {
+        let result = match self.0.write().unwrap().users.get_mut(&*name) {
+            Some(user) => {
+                user.groups = groups;
+                true
+            },
+            None => false,
+        };
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn set_password<S: 'static + Stringly>(&mut self, name: user::Name<S>, password_hash: user::PasswordHash) -> DbFuture<(), Self::UpdateUserError> {
+        let mut state = self.0.write().unwrap();
+        let user = state.users.get_mut(&*name).expect("User doesn't exist");
+        user.password_hash = password_hash;
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn create_session(&mut self, record: user::DbSession) -> DbFuture<(), Self::SessionError> {
+        let mut state = self.0.write().unwrap();
+        assert!(state.users.contains_key(&*record.user_name), "User doesn't exist");
+        state.sessions.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn get_sessions<S: 'static + Stringly>(&mut self, user_name: user::Name<S>) -> DbFuture<Vec<user::DbSession>, Self::SessionError> {
+        let result = self.0
+            .read()
+            .unwrap()
+            .sessions
+            .iter()
+            .filter(|session| *session.user_name == *user_name)
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn touch_session(&mut self, id: SessionId, last_seen: SystemTime) -> DbFuture<(), Self::SessionError> {
+        if let Some(session) = self.0.write().unwrap().sessions.iter_mut().find(|session| session.id == id) {
+            session.last_seen = last_seen;
+        }
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn revoke_session<S: 'static + Stringly>(&mut self, user_name: user::Name<S>, id: SessionId) -> DbFuture<bool, Self::SessionError> {
+        let sessions = &mut self.0.write().unwrap().sessions;
+        let count = sessions.len();
+        sessions.retain(|session| !(session.id == id && *session.user_name == *user_name));
+        let result = sessions.len() != count;
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn revoke_all_sessions<S: 'static + Stringly>(&mut self, user_name: user::Name<S>, except: Option<SessionId>) -> DbFuture<(), Self::SessionError> {
+        self.0.write().unwrap().sessions.retain(|session| *session.user_name != *user_name || Some(session.id) == except);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn set_totp<S: 'static + Stringly>(&mut self, name: user::Name<S>, secret: Option<TotpSecret>, recovery_codes: Vec<RecoveryCodeHash>) -> DbFuture<bool, Self::UpdateUserError> {
+        let mut state = self.0.write().unwrap();
+        let result = match state.users.get_mut(&*name) {
+            Some(user) => {
+                user.totp_secret = secret;
+                true
+            },
+            None => false,
+        };
+        if result {
+            state.recovery_codes.insert((*name).to_owned(), recovery_codes);
+        }
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn use_totp_step<S: 'static + Stringly>(&mut self, name: user::Name<S>, step: u64) -> DbFuture<bool, Self::UpdateUserError> {
+        let mut state = self.0.write().unwrap();
+        let last_step = state.last_totp_steps.entry((*name).to_owned()).or_insert(0);
+        let result = step > *last_step;
+        if result {
+            *last_step = step;
+        }
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn use_recovery_code<S: 'static + Stringly>(&mut self, name: user::Name<S>, code: RecoveryCodeHash) -> DbFuture<bool, Self::UpdateUserError> {
+        let result = match self.0.write().unwrap().recovery_codes.get_mut(&*name) {
+            Some(codes) => {
+                let count = codes.len();
+                codes.retain(|stored| *stored != code);
+                codes.len() != count
+            },
+            None => false,
+        };
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn create_login_challenge(&mut self, record: LoginChallengeRecord, created_since: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let mut state = self.0.write().unwrap();
+        assert!(state.users.contains_key(&*record.user_name), "User doesn't exist");
+        state.login_challenges.retain(|challenge| challenge.created >= created_since);
+        state.login_challenges.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn take_login_challenge<S: 'static + Stringly>(&mut self, user_name: user::Name<S>, token: LoginChallenge, created_since: SystemTime) -> DbFuture<Option<LoginChallengeRecord>, Self::SessionError> {
+        let challenges = &mut self.0.write().unwrap().login_challenges;
+        let result = challenges
+            .iter()
+            .position(|challenge| challenge.token == token && *challenge.user_name == *user_name)
+            .map(|pos| challenges.remove(pos));
+        challenges.retain(|challenge| challenge.created >= created_since);
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn create_invite(&mut self, record: user::DbInvite) -> DbFuture<(), Self::UpdateUserError> {
+        self.0.write().unwrap().invites.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn list_invites(&mut self) -> DbFuture<Vec<user::DbInvite>, Self::UpdateUserError> {
+        let result = self.0.read().unwrap().invites.clone();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn get_invite(&mut self, token_hash: InviteTokenHash) -> DbFuture<Option<user::DbInvite>, Self::UpdateUserError> {
+        let result = self.0.read().unwrap().invites.iter().find(|invite| invite.token_hash == token_hash).cloned();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn take_invite(&mut self, token_hash: InviteTokenHash) -> DbFuture<Option<user::DbInvite>, Self::UpdateUserError> {
+        let invites = &mut self.0.write().unwrap().invites;
+        let result = invites
+            .iter()
+            .position(|invite| invite.token_hash == token_hash)
+            .map(|pos| invites.remove(pos));
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn revoke_invite(&mut self, id: InviteId) -> DbFuture<bool, Self::UpdateUserError> {
+        let invites = &mut self.0.write().unwrap().invites;
+        let count = invites.len();
+        invites.retain(|invite| invite.id != id);
+        let result = invites.len() != count;
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn create_oidc_code(&mut self, record: user::DbOidcCode, created_since: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let mut state = self.0.write().unwrap();
+        assert!(state.users.contains_key(&*record.user_name), "User doesn't exist");
+        state.oidc_codes.retain(|code| code.created >= created_since);
+        state.oidc_codes.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn take_oidc_code(&mut self, code_hash: OidcCodeHash) -> DbFuture<Option<user::DbOidcCode>, Self::SessionError> {
+        let codes = &mut self.0.write().unwrap().oidc_codes;
+        let result = codes
+            .iter()
+            .position(|code| code.code_hash == code_hash)
+            .map(|pos| codes.remove(pos));
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn create_oidc_token(&mut self, record: user::DbOidcToken, now: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let mut state = self.0.write().unwrap();
+        assert!(state.users.contains_key(&*record.user_name), "User doesn't exist");
+        state.oidc_tokens.retain(|token| token.expires > now);
+        state.oidc_tokens.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn get_oidc_token(&mut self, token_hash: OidcAccessTokenHash) -> DbFuture<Option<user::DbOidcToken>, Self::SessionError> {
+        let result = self.0.read().unwrap().oidc_tokens.iter().find(|token| token.token_hash == token_hash).cloned();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn create_api_token(&mut self, record: user::DbApiToken) -> DbFuture<(), Self::SessionError> {
+        let mut state = self.0.write().unwrap();
+        assert!(state.users.contains_key(&*record.user_name), "User doesn't exist");
+        state.api_tokens.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn get_api_token(&mut self, token_hash: ApiTokenHash) -> DbFuture<Option<user::DbApiToken>, Self::SessionError> {
+        let result = self.0.read().unwrap().api_tokens.iter().find(|token| token.token_hash == token_hash).cloned();
         Box::pin(async move { Ok(result) })
     }
-}
 
+    fn list_api_tokens<S: 'static + Stringly>(&mut self, user_name: user::Name<S>) -> DbFuture<Vec<user::DbApiToken>, Self::SessionError> {
+        let result = self.0
+            .read()
+            .unwrap()
+            .api_tokens
+            .iter()
+            .filter(|token| *token.user_name == *user_name)
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn touch_api_token(&mut self, id: ApiTokenId, last_used: SystemTime) -> DbFuture<(), Self::SessionError> {
+        if let Some(token) = self.0.write().unwrap().api_tokens.iter_mut().find(|token| token.id == id) {
+            token.last_used = Some(last_used);
+        }
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn revoke_api_token<S: 'static + Stringly>(&mut self, user_name: user::Name<S>, id: ApiTokenId) -> DbFuture<bool, Self::SessionError> {
+        let tokens = &mut self.0.write().unwrap().api_tokens;
+        let count = tokens.len();
+        tokens.retain(|token| !(token.id == id && *token.user_name == *user_name));
+        let result = tokens.len() != count;
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn link_lnurl_key(&mut self, record: user::DbLnurlKey) -> DbFuture<bool, Self::UpdateUserError> {
+        let mut state = self.0.write().unwrap();
+        assert!(state.users.contains_key(&*record.user_name), "User doesn't exist");
+        let result = !state.lnurl_keys.iter().any(|linked| linked.key == record.key);
+        if result {
+            state.lnurl_keys.push(record);
+        }
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn get_lnurl_key(&mut self, key: LinkingKey) -> DbFuture<Option<user::DbLnurlKey>, Self::UpdateUserError> {
+        let result = self.0.read().unwrap().lnurl_keys.iter().find(|linked| linked.key == key).cloned();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn list_lnurl_keys<S: 'static + Stringly>(&mut self, user_name: user::Name<S>) -> DbFuture<Vec<user::DbLnurlKey>, Self::UpdateUserError> {
+        let result = self.0
+            .read()
+            .unwrap()
+            .lnurl_keys
+            .iter()
+            .filter(|linked| *linked.user_name == *user_name)
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn unlink_lnurl_key<S: 'static + Stringly>(&mut self, user_name: user::Name<S>, key: LinkingKey) -> DbFuture<bool, Self::UpdateUserError> {
+        let keys = &mut self.0.write().unwrap().lnurl_keys;
+        let count = keys.len();
+        keys.retain(|linked| !(linked.key == key && *linked.user_name == *user_name));
+        let result = keys.len() != count;
+        Box::pin(async move { Ok(result) })
+    }
+
+    fn log_audit_event(&mut self, record: user::DbAuditRecord, retain_since: SystemTime) -> DbFuture<(), Self::UpdateUserError> {
+        let log = &mut self.0.write().unwrap().audit_log;
+        log.retain(|logged| logged.time >= retain_since);
+        log.push(record);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn list_audit_events(&mut self, query: AuditQuery) -> DbFuture<Vec<user::DbAuditRecord>, Self::UpdateUserError> {
+        let result = self.0
+            .read()
+            .unwrap()
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|logged| query.user_name.as_ref().map_or(true, |name| *logged.user_name == **name))
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(result) })
+    }
+}
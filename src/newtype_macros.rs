@@ -223,6 +223,13 @@ macro_rules! str_char_whitelist_newtype {
             }
         }
 
+        impl<'de> serde::Deserialize<'de> for $name<String> {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = String::deserialize(deserializer)?;
+                core::convert::TryFrom::try_from(value).map_err(serde::de::Error::custom)
+            }
+        }
+
         /*
         impl<S> core::convert::TryFrom<S> for $name<S> where S: $crate::primitives::Stringly {
             type Error = $error_name;
@@ -4,7 +4,8 @@
 use std::borrow::Borrow;
 use std::pin::Pin;
 use std::future::Future;
-use crate::user::{self, types::AuthToken};
+use std::time::SystemTime;
+use crate::user::{self, types::{AuthToken, SessionId, TokenKey, TotpSecret, RecoveryCodeHash, LoginChallenge, InviteId, InviteTokenHash, OidcCodeHash, OidcAccessTokenHash, ApiTokenId, ApiTokenHash, LinkingKey}, db::{DbFuture, LoginChallengeRecord, AuditQuery}};
 use crate::primitives::Stringly;
 
 macro_rules! deser_row {
@@ -15,12 +16,16 @@ macro_rules! deser_row {
     };
 }
 
+/// Queries share the client, updates of several tables lock it exclusively so that they can run
+/// in a transaction without picking up queries of other requests
+pub type SharedClient = tokio::sync::RwLock<tokio_postgres::Client>;
+
 #[derive(Debug, Clone)]
-pub struct Database<T> where T: Borrow<tokio_postgres::Client> {
+pub struct Database<T> where T: Borrow<SharedClient> {
     client: T,
 }
 
-impl<T> Database<T> where T: Borrow<tokio_postgres::Client> {
+impl<T> Database<T> where T: Borrow<SharedClient> {
     pub fn new(client: T) -> Self {
         Database {
             client,
@@ -28,36 +33,291 @@ impl<T> Database<T> where T: Borrow<tokio_postgres::Client> {
     }
 }
 
-impl<C> Database<C> where C: Borrow<tokio_postgres::Client> + From<tokio_postgres::Client> {
+impl<C> Database<C> where C: Borrow<SharedClient> + From<SharedClient> {
     pub async fn connect<T>(connection_string: &str, tls: T) -> Result<(Self, tokio_postgres::Connection<tokio_postgres::Socket, T::Stream>), tokio_postgres::Error> where T: tokio_postgres::tls::MakeTlsConnect<tokio_postgres::Socket> {
         tokio_postgres::connect(connection_string, tls)
             .await
-            .map(|(client, conn)| (Database::new(client.into()), conn))
+            .map(|(client, conn)| (Database::new(SharedClient::new(client).into()), conn))
     }
 }
 
-impl<T> Database<T> where T: 'static + Borrow<tokio_postgres::Client> + Clone + Send + Sync {
-    pub fn init_tables(&self) -> impl Future<Output=Result<(), tokio_postgres::Error>> {
+// Older versions stored a single authentication token in the users table, these are moved to
+// sessions so that nobody gets logged out by the upgrade.
+//
+// Sessions used to store authentication tokens instead of their hashes, these are hashed by
+// `init_tables` because the key is not available to SQL.
+//
+// Even older versions stored raw scrypt hashes with fixed parameters, these are converted to PHC
+// strings and upgraded to the configured algorithm when the user logs in.
+const INIT_TABLES: &str = "
+CREATE TABLE IF NOT EXISTS users (name VARCHAR PRIMARY KEY, password_hash VARCHAR NOT NULL);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash VARCHAR;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE IF NOT EXISTS sessions (
+    id BYTEA PRIMARY KEY,
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    auth_token_hash BYTEA NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+    user_agent VARCHAR,
+    label VARCHAR
+);
+CREATE INDEX IF NOT EXISTS sessions_user_name ON sessions (user_name);
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS auth_token_hash BYTEA;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret BYTEA;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT NOT NULL DEFAULT 0;
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    code_hash BYTEA NOT NULL,
+    PRIMARY KEY (user_name, code_hash)
+);
+CREATE TABLE IF NOT EXISTS login_challenges (
+    token BYTEA PRIMARY KEY,
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    label VARCHAR
+);
+ALTER TABLE login_challenges ADD COLUMN IF NOT EXISTS remember BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE IF NOT EXISTS invites (
+    id BYTEA PRIMARY KEY,
+    token_hash BYTEA NOT NULL UNIQUE,
+    created_by VARCHAR NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL,
+    groups VARCHAR[] NOT NULL
+);
+CREATE TABLE IF NOT EXISTS oidc_codes (
+    code_hash BYTEA PRIMARY KEY,
+    client_id VARCHAR NOT NULL,
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    groups VARCHAR[] NOT NULL,
+    redirect_uri VARCHAR NOT NULL,
+    code_challenge VARCHAR,
+    nonce VARCHAR,
+    scopes VARCHAR[] NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL
+);
+CREATE TABLE IF NOT EXISTS oidc_tokens (
+    token_hash BYTEA PRIMARY KEY,
+    client_id VARCHAR NOT NULL,
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    scopes VARCHAR[] NOT NULL,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL
+);
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id BYTEA PRIMARY KEY,
+    token_hash BYTEA NOT NULL UNIQUE,
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    scopes VARCHAR[] NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_used TIMESTAMP WITH TIME ZONE
+);
+CREATE TABLE IF NOT EXISTS lnurl_keys (
+    key BYTEA PRIMARY KEY,
+    user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    created TIMESTAMP WITH TIME ZONE NOT NULL
+);
+CREATE INDEX IF NOT EXISTS lnurl_keys_user_name ON lnurl_keys (user_name);
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    time TIMESTAMP WITH TIME ZONE NOT NULL,
+    event VARCHAR NOT NULL,
+    user_name VARCHAR NOT NULL,
+    client_address INET,
+    user_agent VARCHAR,
+    app VARCHAR,
+    outcome VARCHAR NOT NULL,
+    message VARCHAR
+);
+CREATE INDEX IF NOT EXISTS audit_log_time ON audit_log (time);
+CREATE INDEX IF NOT EXISTS audit_log_user_name ON audit_log (user_name);
+DO $$
+BEGIN
+    -- Before groups existed the user called admin was the administrator
+    IF NOT EXISTS (SELECT 1 FROM information_schema.tables WHERE table_name = 'user_groups') THEN
+        CREATE TABLE user_groups (
+            user_name VARCHAR NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+            group_name VARCHAR NOT NULL,
+            PRIMARY KEY (user_name, group_name)
+        );
+        INSERT INTO user_groups (user_name, group_name) SELECT name, 'admins' FROM users WHERE name = 'admin';
+    END IF;
+    IF EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'users' AND column_name = 'auth_token') THEN
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS auth_token BYTEA, ALTER COLUMN auth_token_hash DROP NOT NULL;
+        INSERT INTO sessions (id, user_name, auth_token, created, last_seen)
+            SELECT decode(md5(random()::text || name), 'hex'), name, auth_token, now(), now() FROM users WHERE auth_token IS NOT NULL;
+        ALTER TABLE users DROP COLUMN auth_token;
+    END IF;
+    IF EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = 'users' AND column_name = 'hardened_password') THEN
+        UPDATE users SET password_hash = '$scrypt$ln=15,r=8,p=1$' || rtrim(encode(salt, 'base64'), '=') || '$' || rtrim(encode(hardened_password, 'base64'), '=')
+            WHERE password_hash IS NULL;
+        ALTER TABLE users DROP COLUMN hardened_password, DROP COLUMN salt, ALTER COLUMN password_hash SET NOT NULL;
+    END IF;
+END $$;
+";
+
+const HAS_RAW_AUTH_TOKENS: &str = "SELECT 1 FROM information_schema.columns WHERE table_name = 'sessions' AND column_name = 'auth_token'";
+
+impl<T> Database<T> where T: 'static + Borrow<SharedClient> + Clone + Send + Sync {
+    pub fn init_tables(&self, token_key: TokenKey) -> impl Future<Output=Result<(), tokio_postgres::Error>> {
         let this = self.clone();
         async move {
-            this
-                .client
-                .borrow()
-                .batch_execute("CREATE TABLE IF NOT EXISTS users (name VARCHAR PRIMARY KEY, hardened_password BYTEA, salt BYTEA, auth_token BYTEA)")
-                .await
+            let client = this.client.borrow().read().await;
+
+            client.batch_execute(INIT_TABLES).await?;
+
+            if client.query_opt(HAS_RAW_AUTH_TOKENS, &[]).await?.is_some() {
+                // Rows that were already hashed are skipped so this can be resumed if interrupted
+                for row in client.query("SELECT id, auth_token FROM sessions WHERE auth_token_hash IS NULL", &[]).await? {
+                    deser_row!(row, id: SessionId, auth_token: AuthToken);
+
+                    client
+                        .execute("UPDATE sessions SET auth_token_hash = $1 WHERE id = $2", &[&auth_token.hash(&token_key), &id])
+                        .await?;
+                }
+
+                client
+                    .batch_execute("ALTER TABLE sessions DROP COLUMN auth_token, ALTER COLUMN auth_token_hash SET NOT NULL")
+                    .await?;
+            }
+
+            Ok(())
         }
     }
 }
 
+const SELECT_USERS: &str = "SELECT name, password_hash, disabled, totp_secret, ARRAY(SELECT group_name FROM user_groups WHERE user_name = users.name ORDER BY group_name) AS groups FROM users";
+
+fn deser_user(row: tokio_postgres::Row) -> Result<user::DbRecord, tokio_postgres::Error> {
+    deser_row!(row, name, password_hash, disabled, groups, totp_secret);
+
+    Ok(user::DbRecord {
+        name,
+        password_hash,
+        disabled,
+        groups,
+        totp_secret,
+    })
+}
+
+fn deser_login_challenge(row: tokio_postgres::Row) -> Result<LoginChallengeRecord, tokio_postgres::Error> {
+    deser_row!(row, token, user_name, created, label, remember);
+
+    Ok(LoginChallengeRecord {
+        token,
+        user_name,
+        created,
+        label,
+        remember,
+    })
+}
+
+fn deser_session(row: tokio_postgres::Row) -> Result<user::DbSession, tokio_postgres::Error> {
+    deser_row!(row, id, user_name, auth_token_hash, created, last_seen, user_agent, label);
+
+    Ok(user::DbSession {
+        id,
+        user_name,
+        auth_token_hash,
+        created,
+        last_seen,
+        user_agent,
+        label,
+    })
+}
+
+fn deser_invite(row: tokio_postgres::Row) -> Result<user::DbInvite, tokio_postgres::Error> {
+    deser_row!(row, id, token_hash, created_by, created, expires, groups);
+
+    Ok(user::DbInvite {
+        id,
+        token_hash,
+        created_by,
+        created,
+        expires,
+        groups,
+    })
+}
+
+fn deser_oidc_code(row: tokio_postgres::Row) -> Result<user::DbOidcCode, tokio_postgres::Error> {
+    deser_row!(row, code_hash, client_id, user_name, groups, redirect_uri, code_challenge, nonce, scopes, created);
+
+    Ok(user::DbOidcCode {
+        code_hash,
+        client_id,
+        user_name,
+        groups,
+        redirect_uri,
+        code_challenge,
+        nonce,
+        scopes,
+        created,
+    })
+}
+
+fn deser_oidc_token(row: tokio_postgres::Row) -> Result<user::DbOidcToken, tokio_postgres::Error> {
+    deser_row!(row, token_hash, client_id, user_name, scopes, expires);
+
+    Ok(user::DbOidcToken {
+        token_hash,
+        client_id,
+        user_name,
+        scopes,
+        expires,
+    })
+}
+
+fn deser_api_token(row: tokio_postgres::Row) -> Result<user::DbApiToken, tokio_postgres::Error> {
+    deser_row!(row, id, token_hash, user_name, name, scopes, created, expires, last_used);
+
+    Ok(user::DbApiToken {
+        id,
+        token_hash,
+        user_name,
+        name,
+        scopes,
+        created,
+        expires,
+        last_used,
+    })
+}
+
+fn deser_lnurl_key(row: tokio_postgres::Row) -> Result<user::DbLnurlKey, tokio_postgres::Error> {
+    deser_row!(row, key, user_name, created);
+
+    Ok(user::DbLnurlKey {
+        key,
+        user_name,
+        created,
+    })
+}
+
+fn deser_audit_record(row: tokio_postgres::Row) -> Result<user::DbAuditRecord, tokio_postgres::Error> {
+    deser_row!(row, time, event, user_name, client_address, user_agent, app, outcome, message);
+
+    Ok(user::DbAuditRecord {
+        time,
+        event,
+        user_name,
+        client_address,
+        user_agent,
+        app,
+        outcome,
+        message,
+    })
+}
+
 type PinnedSendFutureResult<T, E> = Pin<Box<dyn Future<Output=Result<T, E>> + Send>>;
 
-impl<T> user::Db for Database<T> where T: 'static + Borrow<tokio_postgres::Client> + Clone + Send + Sync {
+impl<T> user::Db for Database<T> where T: 'static + Borrow<SharedClient> + Clone + Send + Sync {
     type GetUserError = tokio_postgres::Error;
     type InsertUserError = tokio_postgres::Error;
-    type SetCookieError = tokio_postgres::Error;
+    type UpdateUserError = tokio_postgres::Error;
+    type SessionError = tokio_postgres::Error;
     type GetUserFuture = PinnedSendFutureResult<Option<user::DbRecord>, Self::GetUserError>;
     type InsertUserFuture = PinnedSendFutureResult<(), user::InsertError<Self::InsertUserError>>;
-    type SetCookieFuture = PinnedSendFutureResult<(), Self::SetCookieError>;
 
     fn get_user<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>) -> Self::GetUserFuture {
         let this = self.clone();
@@ -66,20 +326,13 @@ impl<T> user::Db for Database<T> where T: 'static + Borrow<tokio_postgres::Clien
             let row = this
                 .client
                 .borrow()
-                .query_opt("SELECT * FROM users WHERE name = $1", &[&name])
+                .read()
+                .await
+                .query_opt(&*format!("{} WHERE name = $1", SELECT_USERS), &[&name])
                 .await?;
 
             row
-                .map(|row| {
-                    deser_row!(row, name, hardened_password, salt, auth_token);
-
-                    Ok(user::DbRecord {
-                        name,
-                        hardened_password,
-                        salt,
-                        cookie: auth_token,
-                    })
-                })
+                .map(deser_user)
                 .transpose()
         })
     }
@@ -87,29 +340,630 @@ impl<T> user::Db for Database<T> where T: 'static + Borrow<tokio_postgres::Clien
     fn insert_new_user(&mut self, record: user::DbRecord) -> Self::InsertUserFuture {
         let this = self.clone();
 
+        Box::pin(async move {
+            let mut client = this.client.borrow().write().await;
+            // a user without the groups could log in as an ordinary user in the meantime
+            let transaction = client.transaction().await.map_err(user::InsertError::DatabaseError)?;
+            let inserted = transaction
+                .execute("INSERT INTO users (name, password_hash, disabled, totp_secret) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING", &[&record.name, &record.password_hash, &record.disabled, &record.totp_secret])
+                .await
+                .map_err(user::InsertError::DatabaseError)?;
+            if inserted == 0 {
+                return Err(user::InsertError::UserExists);
+            }
+            transaction
+                .execute("INSERT INTO user_groups (user_name, group_name) SELECT $1, unnest($2::VARCHAR[])", &[&record.name, &record.groups])
+                .await
+                .map_err(user::InsertError::DatabaseError)?;
+            transaction.commit().await.map_err(user::InsertError::DatabaseError)
+        })
+    }
+
+    fn list_users(&mut self) -> DbFuture<Vec<user::DbRecord>, Self::GetUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let rows = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query(&*format!("{} ORDER BY name", SELECT_USERS), &[])
+                .await?;
+
+            rows
+                .into_iter()
+                .map(deser_user)
+                .collect()
+        })
+    }
+
+    fn has_users(&mut self) -> DbFuture<bool, Self::GetUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_one("SELECT EXISTS(SELECT 1 FROM users)", &[])
+                .await?;
+
+            row.try_get(0)
+        })
+    }
+
+    fn set_disabled<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, disabled: bool) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let updated = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("UPDATE users SET disabled = $1 WHERE name = $2", &[&disabled, &name])
+                .await?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn delete_user<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            // sessions are deleted by ON DELETE CASCADE
+            let deleted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM users WHERE name = $1", &[&name])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn set_groups<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, groups: Vec<user::Group>) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let mut client = this.client.borrow().write().await;
+            let transaction = client.transaction().await?;
+            let exists = transaction
+                .query_opt("SELECT 1 FROM users WHERE name = $1", &[&name])
+                .await?
+                .is_some();
+            if !exists {
+                return Ok(false);
+            }
+            transaction
+                .execute("DELETE FROM user_groups WHERE user_name = $1 AND group_name <> ALL($2::VARCHAR[])", &[&name, &groups])
+                .await?;
+            transaction
+                .execute("INSERT INTO user_groups (user_name, group_name) SELECT $1, unnest($2::VARCHAR[]) ON CONFLICT DO NOTHING", &[&name, &groups])
+                .await?;
+            transaction.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn set_password<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, password_hash: user::PasswordHash) -> DbFuture<(), Self::UpdateUserError> {
+        let this = self.clone();
+
         Box::pin(async move {
             this
                 .client
                 .borrow()
-                .query("INSERT INTO users (name, hardened_password, salt, auth_token) VALUES ($1, $2, $3, $4)", &[&record.name, &record.hardened_password, &record.salt, &record.cookie])
+                .read()
                 .await
-                .map_err(user::InsertError::DatabaseError)?;
+                .execute("UPDATE users SET password_hash = $1 WHERE name = $2", &[&password_hash, &name])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn create_session(&mut self, record: user::DbSession) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("INSERT INTO sessions (id, user_name, auth_token_hash, created, last_seen, user_agent, label) VALUES ($1, $2, $3, $4, $5, $6, $7)", &[&record.id, &record.user_name, &record.auth_token_hash, &record.created, &record.last_seen, &record.user_agent, &record.label])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_sessions<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>) -> DbFuture<Vec<user::DbSession>, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let rows = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query("SELECT * FROM sessions WHERE user_name = $1 ORDER BY created", &[&user_name])
+                .await?;
+
+            rows
+                .into_iter()
+                .map(deser_session)
+                .collect()
+        })
+    }
+
+    fn touch_session(&mut self, id: SessionId, last_seen: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("UPDATE sessions SET last_seen = $1 WHERE id = $2", &[&last_seen, &id])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn revoke_session<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>, id: SessionId) -> DbFuture<bool, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let deleted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM sessions WHERE id = $1 AND user_name = $2", &[&id, &user_name])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn revoke_all_sessions<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>, except: Option<SessionId>) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM sessions WHERE user_name = $1 AND id IS DISTINCT FROM $2", &[&user_name, &except])
+                .await?;
             Ok(())
         })
     }
 
-    fn set_cookie<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, value: Option<AuthToken>) -> Self::SetCookieFuture {
+    fn set_totp<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, secret: Option<TotpSecret>, recovery_codes: Vec<RecoveryCodeHash>) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let mut client = this.client.borrow().write().await;
+            // the old recovery codes mustn't outlive the secret they belong to
+            let transaction = client.transaction().await?;
+            let updated = transaction
+                .execute("UPDATE users SET totp_secret = $1 WHERE name = $2", &[&secret, &name])
+                .await?;
+            if updated == 0 {
+                return Ok(false);
+            }
+            transaction
+                .execute("DELETE FROM recovery_codes WHERE user_name = $1", &[&name])
+                .await?;
+            transaction
+                .execute("INSERT INTO recovery_codes (user_name, code_hash) SELECT $1, unnest($2::BYTEA[])", &[&name, &recovery_codes])
+                .await?;
+            transaction.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn use_totp_step<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, step: u64) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+        let step = step as i64;
+
+        Box::pin(async move {
+            let updated = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("UPDATE users SET totp_last_step = $1 WHERE name = $2 AND totp_last_step < $1", &[&step, &name])
+                .await?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn use_recovery_code<S: 'static + Stringly + Send + Sync>(&mut self, name: user::Name<S>, code: RecoveryCodeHash) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let deleted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM recovery_codes WHERE user_name = $1 AND code_hash = $2", &[&name, &code])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn create_login_challenge(&mut self, record: LoginChallengeRecord, created_since: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let client = this.client.borrow().read().await;
+            client
+                .execute("DELETE FROM login_challenges WHERE created < $1", &[&created_since])
+                .await?;
+            client
+                .execute("INSERT INTO login_challenges (token, user_name, created, label, remember) VALUES ($1, $2, $3, $4, $5)", &[&record.token, &record.user_name, &record.created, &record.label, &record.remember])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take_login_challenge<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>, token: LoginChallenge, created_since: SystemTime) -> DbFuture<Option<LoginChallengeRecord>, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let client = this.client.borrow().read().await;
+            let row = client
+                .query_opt("DELETE FROM login_challenges WHERE token = $1 AND user_name = $2 RETURNING *", &[&token, &user_name])
+                .await?;
+            client
+                .execute("DELETE FROM login_challenges WHERE created < $1", &[&created_since])
+                .await?;
+
+            row
+                .map(deser_login_challenge)
+                .transpose()
+        })
+    }
+
+    fn create_invite(&mut self, record: user::DbInvite) -> DbFuture<(), Self::UpdateUserError> {
         let this = self.clone();
 
         Box::pin(async move {
             this
                 .client
                 .borrow()
-                .query("UPDATE users SET auth_token = $1 WHERE name = $2", &[&value, &name])
+                .read()
+                .await
+                .execute("INSERT INTO invites (id, token_hash, created_by, created, expires, groups) VALUES ($1, $2, $3, $4, $5, $6)", &[&record.id, &record.token_hash, &record.created_by, &record.created, &record.expires, &record.groups])
                 .await?;
             Ok(())
         })
     }
+
+    fn list_invites(&mut self) -> DbFuture<Vec<user::DbInvite>, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let rows = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query("SELECT * FROM invites ORDER BY created", &[])
+                .await?;
+
+            rows
+                .into_iter()
+                .map(deser_invite)
+                .collect()
+        })
+    }
+
+    fn get_invite(&mut self, token_hash: InviteTokenHash) -> DbFuture<Option<user::DbInvite>, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_opt("SELECT * FROM invites WHERE token_hash = $1", &[&token_hash])
+                .await?;
+
+            row
+                .map(deser_invite)
+                .transpose()
+        })
+    }
+
+    fn take_invite(&mut self, token_hash: InviteTokenHash) -> DbFuture<Option<user::DbInvite>, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_opt("DELETE FROM invites WHERE token_hash = $1 RETURNING *", &[&token_hash])
+                .await?;
+
+            row
+                .map(deser_invite)
+                .transpose()
+        })
+    }
+
+    fn revoke_invite(&mut self, id: InviteId) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let deleted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM invites WHERE id = $1", &[&id])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn create_oidc_code(&mut self, record: user::DbOidcCode, created_since: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let client = this.client.borrow().read().await;
+            client
+                .execute("DELETE FROM oidc_codes WHERE created < $1", &[&created_since])
+                .await?;
+            client
+                .execute("INSERT INTO oidc_codes (code_hash, client_id, user_name, groups, redirect_uri, code_challenge, nonce, scopes, created) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)", &[&record.code_hash, &record.client_id, &record.user_name, &record.groups, &record.redirect_uri, &record.code_challenge, &record.nonce, &record.scopes, &record.created])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take_oidc_code(&mut self, code_hash: OidcCodeHash) -> DbFuture<Option<user::DbOidcCode>, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_opt("DELETE FROM oidc_codes WHERE code_hash = $1 RETURNING *", &[&code_hash])
+                .await?;
+
+            row
+                .map(deser_oidc_code)
+                .transpose()
+        })
+    }
+
+    fn create_oidc_token(&mut self, record: user::DbOidcToken, now: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let client = this.client.borrow().read().await;
+            client
+                .execute("DELETE FROM oidc_tokens WHERE expires <= $1", &[&now])
+                .await?;
+            client
+                .execute("INSERT INTO oidc_tokens (token_hash, client_id, user_name, scopes, expires) VALUES ($1, $2, $3, $4, $5)", &[&record.token_hash, &record.client_id, &record.user_name, &record.scopes, &record.expires])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_oidc_token(&mut self, token_hash: OidcAccessTokenHash) -> DbFuture<Option<user::DbOidcToken>, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_opt("SELECT * FROM oidc_tokens WHERE token_hash = $1", &[&token_hash])
+                .await?;
+
+            row
+                .map(deser_oidc_token)
+                .transpose()
+        })
+    }
+
+    fn create_api_token(&mut self, record: user::DbApiToken) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("INSERT INTO api_tokens (id, token_hash, user_name, name, scopes, created, expires, last_used) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)", &[&record.id, &record.token_hash, &record.user_name, &record.name, &record.scopes, &record.created, &record.expires, &record.last_used])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_api_token(&mut self, token_hash: ApiTokenHash) -> DbFuture<Option<user::DbApiToken>, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_opt("SELECT * FROM api_tokens WHERE token_hash = $1", &[&token_hash])
+                .await?;
+
+            row
+                .map(deser_api_token)
+                .transpose()
+        })
+    }
+
+    fn list_api_tokens<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>) -> DbFuture<Vec<user::DbApiToken>, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let rows = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query("SELECT * FROM api_tokens WHERE user_name = $1 ORDER BY created", &[&user_name])
+                .await?;
+
+            rows
+                .into_iter()
+                .map(deser_api_token)
+                .collect()
+        })
+    }
+
+    fn touch_api_token(&mut self, id: ApiTokenId, last_used: SystemTime) -> DbFuture<(), Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("UPDATE api_tokens SET last_used = $1 WHERE id = $2", &[&last_used, &id])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn revoke_api_token<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>, id: ApiTokenId) -> DbFuture<bool, Self::SessionError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let deleted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM api_tokens WHERE id = $1 AND user_name = $2", &[&id, &user_name])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn link_lnurl_key(&mut self, record: user::DbLnurlKey) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let inserted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("INSERT INTO lnurl_keys (key, user_name, created) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING", &[&record.key, &record.user_name, &record.created])
+                .await?;
+            Ok(inserted > 0)
+        })
+    }
+
+    fn get_lnurl_key(&mut self, key: LinkingKey) -> DbFuture<Option<user::DbLnurlKey>, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let row = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query_opt("SELECT * FROM lnurl_keys WHERE key = $1", &[&key])
+                .await?;
+
+            row
+                .map(deser_lnurl_key)
+                .transpose()
+        })
+    }
+
+    fn list_lnurl_keys<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>) -> DbFuture<Vec<user::DbLnurlKey>, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let rows = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query("SELECT * FROM lnurl_keys WHERE user_name = $1 ORDER BY created", &[&user_name])
+                .await?;
+
+            rows
+                .into_iter()
+                .map(deser_lnurl_key)
+                .collect()
+        })
+    }
+
+    fn unlink_lnurl_key<S: 'static + Stringly + Send + Sync>(&mut self, user_name: user::Name<S>, key: LinkingKey) -> DbFuture<bool, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let deleted = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .execute("DELETE FROM lnurl_keys WHERE key = $1 AND user_name = $2", &[&key, &user_name])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn log_audit_event(&mut self, record: user::DbAuditRecord, retain_since: SystemTime) -> DbFuture<(), Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let client = this.client.borrow().read().await;
+            client
+                .execute("DELETE FROM audit_log WHERE time < $1", &[&retain_since])
+                .await?;
+            client
+                .execute("INSERT INTO audit_log (time, event, user_name, client_address, user_agent, app, outcome, message) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)", &[&record.time, &record.event, &record.user_name, &record.client_address, &record.user_agent, &record.app, &record.outcome, &record.message])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn list_audit_events(&mut self, query: AuditQuery) -> DbFuture<Vec<user::DbAuditRecord>, Self::UpdateUserError> {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let rows = this
+                .client
+                .borrow()
+                .read()
+                .await
+                .query("SELECT * FROM audit_log WHERE $1::VARCHAR IS NULL OR user_name = $1 ORDER BY id DESC OFFSET $2 LIMIT $3", &[&query.user_name, &i64::from(query.offset), &i64::from(query.limit)])
+                .await?;
+
+            rows
+                .into_iter()
+                .map(deser_audit_record)
+                .collect()
+        })
+    }
 }
 
-pub type ArcDatabase = Database<std::sync::Arc<tokio_postgres::Client>>;
+pub type ArcDatabase = Database<std::sync::Arc<SharedClient>>;
@@ -3,7 +3,7 @@ use std::path::Path;
 use crate::webserver::{Request, HttpMethod};
 use std::future::Future;
 use std::sync::Arc;
-use slog::{error, info, debug, trace};
+use slog::{error, warn, info, debug, trace};
 use crate::user;
 use crate::app;
 use crate::primitives::Stringly;
@@ -14,12 +14,16 @@ const STATIC_DIR: &str = "/usr/share/selfhost-dashboard/static";
 #[cfg(feature = "mock_system")]
 const STATIC_DIR: &'static str = "./static";
 
-const COOKIE_LIFETIME_SECONDS: u64 = 3600 * 24 * 365; // one year
 
 enum LoginReason {
     LoggedOut,
     BadCredentials,
     BadInput,
+    Disabled,
+    SecondFactorFailed,
+    Throttled,
+    InvalidInvite,
+    LnurlFailed,
 }
 
 impl LoginReason {
@@ -28,18 +32,64 @@ impl LoginReason {
             LoginReason::LoggedOut => "",
             LoginReason::BadCredentials => "#failure=credentials",
             LoginReason::BadInput => "#failure=input",
+            LoginReason::Disabled => "#failure=disabled",
+            LoginReason::SecondFactorFailed => "#failure=second_factor",
+            LoginReason::Throttled => "#failure=throttled",
+            LoginReason::InvalidInvite => "#failure=invite",
+            LoginReason::LnurlFailed => "#failure=lnurl",
+        }
+    }
+}
+
+enum SetupReason {
+    Pending,
+    BadToken,
+    BadInput,
+    UserExists,
+}
+
+impl SetupReason {
+    fn suffix(&self) -> &'static str {
+        match self {
+            SetupReason::Pending => "",
+            SetupReason::BadToken => "#failure=token",
+            SetupReason::BadInput => "#failure=input",
+            SetupReason::UserExists => "#failure=exists",
+        }
+    }
+}
+
+enum InviteReason {
+    BadInput,
+    UserExists,
+}
+
+impl InviteReason {
+    fn suffix(&self) -> &'static str {
+        match self {
+            InviteReason::BadInput => "#failure=input",
+            InviteReason::UserExists => "#failure=exists",
         }
     }
 }
 
 enum Error {
     NotAuthorized,
+    /// Like `NotAuthorized` but tells the reverse proxy where to send the user to log in
+    LoginRequired,
     Forbidden(&'static str),
+    /// Like `Forbidden` but the message comes from an entry point
+    ForbiddenMessage(String),
     InvalidData(&'static str),
     NotFound,
     Internal,
     RedirectToLogin(LoginReason),
-    RedirectToRegistration,
+    RedirectToSetup(SetupReason),
+    RedirectToInvite(user::types::InviteToken, InviteReason),
+    /// Error of the OpenID Connect token or userinfo endpoint reported to the app
+    OAuth(crate::login::oidc::ErrorCode),
+    /// Sends the user to log in and remembers the authorization request to continue afterwards
+    LoginToAuthorize(String),
 }
 
 impl From<&'_ DirectoryTraversalError> for Error {
@@ -54,10 +104,15 @@ impl From<&'_ app::OpenError> for Error {
 
         match value {
             OpenError::NonAdmin => Error::Forbidden("Non-admins are not authorized to open admin-only apps"),
-            OpenError::RejectedWithMessage(_) | OpenError::RejectedWithInvalidMessage => Error::Forbidden("You are not allowed to open this application"),
+            OpenError::NotAllowed => Error::Forbidden("You are not allowed to open this application"),
+            OpenError::RejectedWithMessage(_) | OpenError::RejectedWithInvalidMessage | OpenError::Refused { message: None, } => Error::Forbidden("You are not allowed to open this application"),
+            OpenError::Refused { message: Some(message), } => Error::ForbiddenMessage(message.clone()),
             OpenError::EntryPointExec { .. } | OpenError::EntryPointFailedWithMessage { .. } |  OpenError::EntryPointFailedWithInvalidMessage { .. } |
             OpenError::SystemUserNotFound | OpenError::TaskJoin(_) | OpenError::EntryPointKilledWithMessage { .. } |
-            OpenError::EntryPointKilledWithInvalidMessage | OpenError::EntryPointWaitFailed { .. } | OpenError::ReadingStdoutFailed { .. } => Error::Internal,
+            OpenError::EntryPointKilledWithInvalidMessage | OpenError::EntryPointWaitFailed { .. } | OpenError::ReadingStdoutFailed { .. } |
+            OpenError::EntryPointTimedOut { .. } | OpenError::TooManyEntryPoints { .. } | OpenError::InvalidResponse { .. } |
+            OpenError::WritingStdinFailed { .. } | OpenError::HttpEntryPointFailed { .. } |
+            OpenError::WorkDir { .. } => Error::Internal,
         }
     }
 }
@@ -79,6 +134,8 @@ fn api_auth(error: crate::login::RequestError) -> Error {
         RequestError::NoUserRegistered => Error::NotAuthorized,
         RequestError::InternalError => Error::Internal,
         RequestError::InvalidUserName => Error::InvalidData("invalid user name"),
+        RequestError::BadApiToken => Error::NotAuthorized,
+        RequestError::InsufficientScope => Error::Forbidden("the API token doesn't allow this"),
     }
 }
 
@@ -88,9 +145,26 @@ fn view_auth(error: crate::login::RequestError) -> Error {
     match error {
         RequestError::MissingCookies => Error::RedirectToLogin(LoginReason::LoggedOut),
         RequestError::BadCookies => Error::RedirectToLogin(LoginReason::LoggedOut),
-        RequestError::NoUserRegistered => Error::RedirectToRegistration,
+        RequestError::NoUserRegistered => Error::RedirectToSetup(SetupReason::Pending),
         RequestError::InternalError => Error::Internal,
         RequestError::InvalidUserName => Error::RedirectToLogin(LoginReason::BadInput),
+        // scripts using tokens can't log in
+        RequestError::BadApiToken => Error::NotAuthorized,
+        RequestError::InsufficientScope => Error::Forbidden("the API token doesn't allow this"),
+    }
+}
+
+fn forward_auth(error: crate::login::RequestError) -> Error {
+    use crate::login::RequestError;
+
+    match error {
+        RequestError::MissingCookies => Error::LoginRequired,
+        RequestError::BadCookies => Error::LoginRequired,
+        RequestError::NoUserRegistered => Error::LoginRequired,
+        RequestError::InternalError => Error::Internal,
+        RequestError::InvalidUserName => Error::LoginRequired,
+        RequestError::BadApiToken => Error::LoginRequired,
+        RequestError::InsufficientScope => Error::Forbidden("the API token doesn't allow this"),
     }
 }
 
@@ -104,11 +178,23 @@ impl Error {
                 builder.set_body("Not authorized".to_owned().into());
                 builder
             },
+            Error::LoginRequired => {
+                let mut builder = S::ResponseBuilder::with_status(401);
+                builder.set_header("location", &format!("{}/login", prefix));
+                builder.set_body("Not authorized".to_owned().into());
+                builder
+            },
             Error::Forbidden(message) => {
                 let mut builder = S::ResponseBuilder::with_status(403);
                 builder.set_body(format!("Forbidden: {}", message).into());
                 builder
             },
+            Error::ForbiddenMessage(message) => {
+                let mut builder = S::ResponseBuilder::with_status(403);
+                builder.set_content_type("text/plain; charset=utf-8");
+                builder.set_body(format!("Forbidden: {}", message).into());
+                builder
+            },
             Error::InvalidData(message) => {
                 let mut builder = S::ResponseBuilder::with_status(400);
                 builder.set_body(format!("Invalid request: {}", message).into());
@@ -125,7 +211,14 @@ impl Error {
                 builder
             },
             Error::RedirectToLogin(reason) => S::ResponseBuilder::redirect(&format!("{}/login{}", prefix, reason.suffix()), crate::webserver::RedirectKind::SeeOther),
-            Error::RedirectToRegistration => S::ResponseBuilder::redirect(&format!("{}/login#uninitialized=true", prefix), crate::webserver::RedirectKind::SeeOther),
+            Error::RedirectToSetup(reason) => S::ResponseBuilder::redirect(&format!("{}/setup{}", prefix, reason.suffix()), crate::webserver::RedirectKind::SeeOther),
+            Error::RedirectToInvite(token, reason) => S::ResponseBuilder::redirect(&format!("{}/invite/{}{}", prefix, token, reason.suffix()), crate::webserver::RedirectKind::SeeOther),
+            Error::OAuth(code) => crate::login::oidc::error_response::<S>(code),
+            Error::LoginToAuthorize(authorization_request) => {
+                let mut builder = S::ResponseBuilder::redirect(&format!("{}/login", prefix), crate::webserver::RedirectKind::SeeOther);
+                builder.set_cookie(crate::login::oidc::PENDING_REQUEST_COOKIE, &authorization_request, crate::webserver::cookie_path(prefix), Some(crate::login::oidc::PENDING_REQUEST_LIFETIME.as_secs()));
+                builder
+            },
         }
     }
 }
@@ -281,20 +374,271 @@ fn not_found<S: crate::webserver::Server>() -> S::ResponseBuilder {
     builder
 }
 
-pub async fn route<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix: Arc<str>, user_db: Db, apps: Arc<app::config::Apps>, request: S::Request, logger: slog::Logger) -> S::ResponseBuilder where S::Request: Send + Sync, Db::SetCookieFuture: Send, Db::GetUserFuture: Send, Db::GetUserError: Send, Db::SetCookieError: Send, Db::InsertUserFuture: Send {
-    match route_raw::<S, _>(Arc::clone(&prefix), user_db, apps, request, logger).await {
+enum UserUpdate {
+    Disable,
+    Enable,
+    Delete,
+    SetGroups(Vec<user::Group>),
+}
+
+impl UserUpdate {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UserUpdate::Disable => "disable",
+            UserUpdate::Enable => "enable",
+            UserUpdate::Delete => "delete",
+            UserUpdate::SetGroups(_) => "set groups",
+        }
+    }
+
+    /// Returns `true` if the update takes administrator rights from the user
+    fn removes_admin(&self) -> bool {
+        match self {
+            UserUpdate::Disable | UserUpdate::Delete => true,
+            UserUpdate::Enable => false,
+            UserUpdate::SetGroups(groups) => !groups.iter().any(|group| group.as_ref() == user::Group::ADMINS),
+        }
+    }
+}
+
+enum TotpAction {
+    Enroll,
+    Enable { secret: user::types::TotpSecret, code: String, },
+    Disable,
+    RegenerateRecoveryCodes,
+}
+
+impl TotpAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TotpAction::Enroll => "enroll",
+            TotpAction::Enable { .. } => "enable",
+            TotpAction::Disable => "disable",
+            TotpAction::RegenerateRecoveryCodes => "regenerate recovery codes",
+        }
+    }
+}
+
+/// Returns `true` if the user is the only one who can administer the dashboard
+fn is_last_admin(users: &[user::DbRecord], name: &str) -> bool {
+    let (target, others) = users.iter().partition::<Vec<_>, _>(|user| *user.name == *name);
+    target.into_iter().any(user::DbRecord::is_active_admin) && !others.into_iter().any(user::DbRecord::is_active_admin)
+}
+
+fn parse_groups(groups: &str, logger: &slog::Logger) -> Result<Vec<user::Group>, Error> {
+    let mut groups = groups
+        .split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(|group| user::Group::try_from(group.to_owned()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(e(Error::InvalidData("group name contains invalid character"), "invalid group name", logger))?;
+    groups.sort_by(|a, b| (**a).cmp(&**b));
+    groups.dedup();
+    Ok(groups)
+}
+
+fn require_admin(user: &user::Authenticated, logger: &slog::Logger) -> Result<(), Error> {
+    if user.is_admin() {
+        Ok(())
+    } else {
+        error!(logger, "non-admin attempted to access administration"; "user_name" => user.name());
+        Err(Error::Forbidden("only administrators are allowed to do this"))
+    }
+}
+
+fn parse_user_name(name: &str, logger: &slog::Logger) -> Result<user::Name, Error> {
+    if name.is_empty() {
+        error!(logger, "empty user name");
+        return Err(Error::InvalidData("user name must not be empty"));
+    }
+
+    user::Name::try_from(name.to_owned()).map_err(e(Error::InvalidData("user name contains invalid character"), "invalid user name", logger))
+}
+
+/// Sets the session cookies, these are persistent only if the user asked to be remembered
+///
+/// Persistent cookies live as long as the session can, the server enforces the idle timeout.
+/// Older versions set the session cookies for the whole host which sent them to other apps
+fn expire_host_session_cookies<B: crate::webserver::ResponseBuilder>(builder: &mut B, prefix: &str) {
+    if crate::webserver::cookie_path(prefix) != "/" {
+        builder.set_cookie("user_name", "", "/", Some(0));
+        builder.set_cookie("auth_token", "", "/", Some(0));
+    }
+}
+
+fn logged_in<S: crate::webserver::Server>(prefix: &str, target: &str, success: &crate::login::LoginSuccessful, sessions: &crate::login::SessionConfig) -> S::ResponseBuilder {
+    use crate::webserver::ResponseBuilder;
+
+    let max_age = if success.remember {
+        Some(sessions.absolute.as_secs())
+    } else {
+        None
+    };
+    let path = crate::webserver::cookie_path(prefix);
+
+    let mut builder = S::ResponseBuilder::redirect(target, crate::webserver::RedirectKind::SeeOther);
+    expire_host_session_cookies(&mut builder, prefix);
+    builder.set_cookie("user_name", &success.name, path, max_age);
+    builder.set_cookie("auth_token", &success.cookie.to_string(), path, max_age);
+    builder.set_cookie(crate::login::FORWARD_AUTH_COOKIE, &crate::login::forward_auth_cookie(success, sessions), "/", max_age);
+    builder
+}
+
+/// Returns where to send the user after logging in, an app may be waiting for the authorization
+fn after_login<R: Request>(request: &R, prefix: &str) -> String {
+    request
+        .get_cookie(crate::login::oidc::PENDING_REQUEST_COOKIE)
+        .and_then(|saved| crate::login::oidc::resume_path(prefix, saved))
+        .unwrap_or_else(|| prefix.to_owned())
+}
+
+/// Tells the reverse proxy who the user is so that it can pass it to the app
+fn forward_auth_ok<S: crate::webserver::Server>(user: &user::Authenticated) -> S::ResponseBuilder {
+    use crate::webserver::ResponseBuilder;
+
+    let groups = user.groups().iter().map(|group| &**group).collect::<Vec<&str>>().join(",");
+
+    let mut builder = S::ResponseBuilder::with_status(200);
+    builder.set_header("x-remote-user", user.name());
+    builder.set_header("x-remote-groups", &groups);
+    builder
+}
+
+/// Returns the URI of the original request as reported by the reverse proxy
+///
+/// nginx has to be configured to send `X-Original-URI`, Caddy and Traefik send `X-Forwarded-Uri`.
+fn original_uri<R: Request>(request: &R) -> Option<&str> {
+    request
+        .get_header("x-original-uri")
+        .or_else(|| request.get_header("x-forwarded-uri"))
+}
+
+fn second_factor_required<S: crate::webserver::Server>(prefix: &str, challenge: &crate::login::SecondFactorChallenge) -> S::ResponseBuilder {
+    use crate::webserver::ResponseBuilder;
+
+    let mut builder = S::ResponseBuilder::redirect(&format!("{}/login#second_factor=true", prefix), crate::webserver::RedirectKind::SeeOther);
+    builder.set_cookie("user_name", &challenge.name, crate::webserver::cookie_path(prefix), Some(crate::login::totp::CHALLENGE_LIFETIME.as_secs()));
+    builder.set_cookie("login_challenge", &challenge.token.to_string(), crate::webserver::cookie_path(prefix), Some(crate::login::totp::CHALLENGE_LIFETIME.as_secs()));
+    builder
+}
+
+fn logged_out<S: crate::webserver::Server>(prefix: &str) -> S::ResponseBuilder {
+    use crate::webserver::ResponseBuilder;
+
+    let mut builder = S::ResponseBuilder::redirect(&format!("{}/login", prefix), crate::webserver::RedirectKind::SeeOther);
+    let path = crate::webserver::cookie_path(prefix);
+    builder.set_cookie("user_name", "", path, Some(0));
+    builder.set_cookie("auth_token", "", path, Some(0));
+    builder.set_cookie(crate::login::FORWARD_AUTH_COOKIE, "", "/", Some(0));
+    expire_host_session_cookies(&mut builder, prefix);
+    builder
+}
+
+/// State shared by all requests
+#[derive(Clone)]
+pub struct Context {
+    pub prefix: Arc<str>,
+    pub apps: Arc<app::config::Apps>,
+    pub login_throttle: Arc<crate::login::throttle::Throttle>,
+    /// Parameters for hashing new passwords
+    pub password_params: Arc<user::password::Params>,
+    /// Verifies passwords when logging in
+    pub auth_backend: Arc<user::backend::Backend>,
+    pub sessions: Arc<crate::login::SessionConfig>,
+    pub setup: Arc<crate::login::setup::Setup>,
+    /// How long new invites are valid
+    pub invite_lifetime: std::time::Duration,
+    /// Name of the header containing the client address set by the reverse proxy
+    pub client_address_header: Arc<str>,
+    /// Longest lifetime users can choose for their API tokens
+    pub api_token_max_lifetime: std::time::Duration,
+    /// Present if the OpenID Connect provider is enabled
+    pub oidc: Option<Arc<crate::login::oidc::Provider>>,
+    /// Present if login with Lightning wallets is enabled
+    pub lnurl: Option<Arc<crate::login::lnurl::Provider>>,
+    /// How long records of the audit log are kept, zero keeps them forever
+    pub audit_retention: std::time::Duration,
+    /// Runs dynamic entry points, limiting how many run at once
+    pub entry_points: Arc<app::entry_point::Runner>,
+}
+
+/// Returns the address of the client as reported by the reverse proxy
+///
+/// The last address is used because the previous ones are controlled by the client.
+fn client_address<R: Request>(request: &R, header: &str) -> Option<std::net::IpAddr> {
+    request
+        .get_header(header)?
+        .rsplit(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn audit_client<R: Request>(request: &R, context: &Context) -> crate::login::audit::Client {
+    crate::login::audit::Client {
+        address: client_address(request, &context.client_address_header),
+        user_agent: request.get_header("user-agent").map(ToOwned::to_owned),
+    }
+}
+
+/// Rejects the attempt early if there were too many failures so that it doesn't cost a password hash
+///
+/// The returned attempt must be resolved once the result is known, dropping it releases it.
+fn check_throttle<'a>(context: &'a Context, name: &str, address: Option<std::net::IpAddr>, logger: &slog::Logger) -> Result<crate::login::throttle::Attempt<'a>, Error> {
+    context
+        .login_throttle
+        .check(name, address, std::time::Instant::now())
+        .map_err(|throttled| {
+            info!(logger, "login attempt throttled"; "user_name" => name, "client_address" => ?address, "retry_after_seconds" => throttled.retry_after.as_secs(), "locked_out" => throttled.locked_out);
+            Error::RedirectToLogin(LoginReason::Throttled)
+        })
+}
+
+fn login_failed(attempt: crate::login::throttle::Attempt<'_>, name: &str, address: Option<std::net::IpAddr>, logger: &slog::Logger) {
+    let lockouts = attempt.failed(std::time::Instant::now());
+    if lockouts.user_name {
+        warn!(logger, "user name locked out after too many failed login attempts"; "user_name" => name);
+    }
+    if lockouts.address {
+        warn!(logger, "client address locked out after too many failed login attempts"; "client_address" => ?address);
+    }
+}
+
+pub async fn route<S: crate::webserver::Server, Db: 'static + user::Db + Send>(context: Context, user_db: Db, request: S::Request, logger: slog::Logger) -> S::ResponseBuilder where S::Request: Send + Sync, Db::GetUserFuture: Send, Db::GetUserError: 'static + Send, Db::InsertUserError: Send, Db::InsertUserFuture: Send {
+    use crate::webserver::ResponseBuilder;
+
+    let prefix = Arc::clone(&context.prefix);
+    // Every page gets a token so that its forms can be submitted
+    let new_csrf_token = match request.csrf_cookie() {
+        Some(_) => None,
+        None => Some(crate::webserver::CsrfToken::random()),
+    };
+
+    let mut response = match route_raw::<S, _>(context, user_db, request, logger).await {
         Ok(response) => response,
         Err(error) => error.response::<S>(&prefix),
+    };
+
+    if let Some(token) = new_csrf_token {
+        response.set_csrf_cookie(&token, crate::webserver::cookie_path(&prefix));
     }
+    response
 }
 
-fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix: Arc<str>, mut user_db: Db, apps: Arc<app::config::Apps>, request: S::Request, logger: slog::Logger) -> impl Future<Output=Result<S::ResponseBuilder, Error>> + Send where S::Request: Send + Sync, Db::SetCookieFuture: Send, Db::GetUserFuture: Send, Db::GetUserError: Send, Db::SetCookieError: Send, Db::InsertUserFuture: Send {
+// A flat match over all routes, the arms don't depend on each other
+#[allow(clippy::cognitive_complexity)]
+fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(context: Context, mut user_db: Db, request: S::Request, logger: slog::Logger) -> impl Future<Output=Result<S::ResponseBuilder, Error>> + Send where S::Request: Send + Sync, Db::GetUserFuture: Send, Db::GetUserError: 'static + Send, Db::InsertUserError: Send, Db::InsertUserFuture: Send {
     use crate::webserver::ResponseBuilder;
     use crate::login::SignupRequest;
 
     let logger = logger.new(slog::o!("path" => request.path().to_owned(), "method" => format!("{:?}", request.method())));
 
     async move {
+        let prefix: &str = &context.prefix;
+        let apps: &app::config::Apps = &context.apps;
+
         let path = if request.path().starts_with(&*prefix) {
             &request.path()[prefix.len()..]
         } else {
@@ -316,11 +660,25 @@ fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix:
 
         trace!(logger, "about to route"; "component" => component, "remaining" => remaining);
 
+        // All state-changing routes use POST, the reverse proxy may forward any method to
+        // `/auth/verify` which doesn't change anything. The OpenID Connect endpoints are called by
+        // apps and authenticate them with their own credentials.
+        if let (HttpMethod::Post, false) = (request.method(), component == "/auth" || component == "/oidc") {
+            if let Err(error) = request.check_csrf() {
+                warn!(logger, "rejected request failing CSRF check"; "error" => #error);
+                return Err(match component {
+                    // the redirect sets a new cookie if it was missing
+                    "/login" => Error::RedirectToLogin(LoginReason::BadInput),
+                    _ => Error::Forbidden("invalid CSRF token, please reload the page"),
+                });
+            }
+        }
+
         match (component, request.method()) {
             ("", HttpMethod::Get) | ("/", HttpMethod::Get) => {
                 // There's nothing secret here, but redirecting the user immediately is a better
                 // UX.
-                crate::login::auth_request::<_, S>(&mut user_db, request, logger.clone()).await.map_err(view_auth)?;
+                crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
                 Ok(serve_static::<S, _>(&SafeResourcePath::from_literal("index.html"), Some("text/html"), logger))
             },
             ("/static", HttpMethod::Get) => {
@@ -337,15 +695,441 @@ fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix:
                 Ok(serve_static_abs::<S, _>(&icon_path, None, logger))
             },
             ("/apps", HttpMethod::Get) => {
-                let user = crate::login::auth_request::<_, S>(&mut user_db, request, logger.clone())
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, Some(&user::types::ApiScope::ListApps), logger.clone())
                     .await
                     .map_err(api_auth)?;
 
                 Ok(app::get_apps::<S>(&user, &prefix, &apps))
             },
+            ("/auth", _) if remaining == "verify" || remaining.starts_with("verify/") => {
+                // The app is either explicit or found by the path of the original request, access
+                // is denied if there's none so that a misconfiguration doesn't bypass the ACL
+                let (app_name, app) = match &remaining["verify".len()..] {
+                    "" => {
+                        let uri = original_uri(&request).ok_or_else(|| { warn!(logger, "the reverse proxy didn't send the original URI"); Error::Forbidden("unknown application") })?;
+                        app::find_by_uri(apps, uri).ok_or_else(|| { warn!(logger, "no application serves the original URI"; "uri" => uri); Error::Forbidden("unknown application") })?
+                    },
+                    app_name => {
+                        let app_name = &app_name[1..];
+                        match apps.get_key_value(app_name) {
+                            Some((name, app)) => (&**name, app),
+                            None => {
+                                error!(logger, "application not found"; "app" => app_name);
+                                return Err(Error::NotFound);
+                            },
+                        }
+                    },
+                };
+
+                let user = crate::login::forward_auth_request::<_, S>(&mut user_db, &context.sessions, request, logger.clone())
+                    .await
+                    .map_err(forward_auth)?;
+
+                let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "app" => app_name.to_owned()));
+                app.check_access(&user).map_err(log_and_convert(&logger))?;
+
+                Ok(forward_auth_ok::<S>(&user))
+            },
+            ("/.well-known", HttpMethod::Get) if remaining == "openid-configuration" => {
+                let provider = context.oidc.as_deref().ok_or(Error::NotFound)?;
+
+                Ok(provider.discovery::<S>())
+            },
+            ("/oidc", _) => {
+                use crate::login::oidc::{self, AuthorizationRequest, ErrorCode, TokenRequest, UserInfoError};
+
+                let provider = match context.oidc.as_deref() {
+                    Some(provider) => provider,
+                    None => {
+                        error!(logger, "the OpenID Connect provider is not enabled");
+                        return Err(Error::NotFound);
+                    },
+                };
+
+                match (request.method(), remaining) {
+                    (HttpMethod::Get, "authorize") => {
+                        let query_arg = |name| request.query_arg(name).map(ToOwned::to_owned);
+                        let client_id = query_arg("client_id").ok_or_else(|| { error!(logger, "missing client ID"); Error::InvalidData("missing client ID") })?;
+                        let redirect_uri = query_arg("redirect_uri").ok_or_else(|| { error!(logger, "missing redirect URI"); Error::InvalidData("missing redirect URI") })?;
+                        let authorization_request = AuthorizationRequest {
+                            client_id,
+                            redirect_uri,
+                            response_type: query_arg("response_type"),
+                            scope: query_arg("scope"),
+                            state: query_arg("state"),
+                            nonce: query_arg("nonce"),
+                            code_challenge: query_arg("code_challenge"),
+                            code_challenge_method: query_arg("code_challenge_method"),
+                        };
+                        let logger = logger.new(slog::o!("app" => authorization_request.client_id.clone()));
+
+                        // The redirect URI can't be trusted until the client is verified
+                        let (app, client) = oidc::check_client(apps, &authorization_request.client_id, &authorization_request.redirect_uri)
+                            .map_err(e(Error::InvalidData("unknown client or redirect URI"), "invalid authorization request", &logger))?;
+                        let pending = request.get_cookie(oidc::PENDING_REQUEST_COOKIE).is_some();
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone())
+                            .await
+                            .map_err(|error| match view_auth(error) {
+                                Error::RedirectToLogin(_) => Error::LoginToAuthorize(authorization_request.to_query()),
+                                error => error,
+                            })?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+
+                        let url = match oidc::authorize(&mut user_db, &context.sessions.token_key, app, client, &user, &authorization_request).await {
+                            Ok(url) => {
+                                info!(logger, "authorization code issued");
+                                url
+                            },
+                            Err(error) => {
+                                let code = error.code();
+                                if code == ErrorCode::ServerError {
+                                    error!(logger, "failed to authorize the app"; "error" => #error);
+                                } else {
+                                    info!(logger, "authorization request rejected"; "error" => #error);
+                                }
+                                oidc::error_redirect(&authorization_request, code)
+                            },
+                        };
+
+                        let mut builder = S::ResponseBuilder::redirect(&url, crate::webserver::RedirectKind::SeeOther);
+                        if pending {
+                            builder.set_cookie(oidc::PENDING_REQUEST_COOKIE, "", crate::webserver::cookie_path(prefix), Some(0));
+                        }
+                        Ok(builder)
+                    },
+                    (HttpMethod::Post, "token") => {
+                        let form_arg = |name| request
+                            .post_form_arg(name)
+                            .map(|value| value.map(ToOwned::to_owned))
+                            .map_err(e(Error::OAuth(ErrorCode::InvalidRequest), "failed to decode form data", &logger));
+                        let (client_id, client_secret) = match request.get_header("authorization") {
+                            Some(header) => {
+                                let (client_id, client_secret) = oidc::basic_credentials(header)
+                                    .ok_or_else(|| { error!(logger, "invalid client credentials"); Error::OAuth(ErrorCode::InvalidClient) })?;
+                                (Some(client_id), Some(client_secret))
+                            },
+                            None => (form_arg("client_id")?, form_arg("client_secret")?),
+                        };
+                        let logger = logger.new(slog::o!("app" => client_id.clone()));
+
+                        let token_request = TokenRequest {
+                            grant_type: form_arg("grant_type")?,
+                            code: form_arg("code")?,
+                            redirect_uri: form_arg("redirect_uri")?,
+                            code_verifier: form_arg("code_verifier")?,
+                            client_id,
+                            client_secret,
+                        };
+
+                        match oidc::exchange_code(&mut user_db, provider, &context.sessions.token_key, apps, token_request).await {
+                            Ok(tokens) => {
+                                info!(logger, "tokens issued");
+                                Ok(oidc::tokens::<S>(&tokens))
+                            },
+                            Err(error) if error.code() == ErrorCode::ServerError => {
+                                error!(logger, "failed to issue tokens"; "error" => #error);
+                                Err(Error::Internal)
+                            },
+                            Err(error) => {
+                                let code = error.code();
+                                info!(logger, "token request rejected"; "error" => #error);
+                                Err(Error::OAuth(code))
+                            },
+                        }
+                    },
+                    (_, "userinfo") => {
+                        let access_token = request
+                            .get_header("authorization")
+                            .and_then(crate::login::bearer_token)
+                            .ok_or_else(|| { info!(logger, "missing access token"); Error::OAuth(ErrorCode::InvalidToken) })?;
+
+                        match oidc::user_info(&mut user_db, &context.sessions.token_key, access_token).await {
+                            Ok(claims) => Ok(crate::webserver::json_response(&claims)),
+                            Err(UserInfoError::InvalidToken) => {
+                                info!(logger, "invalid or expired access token");
+                                Err(Error::OAuth(ErrorCode::InvalidToken))
+                            },
+                            Err(error) => {
+                                error!(logger, "failed to get user info"; "error" => #error);
+                                Err(Error::Internal)
+                            },
+                        }
+                    },
+                    (HttpMethod::Get, "jwks") => Ok(provider.jwks::<S>()),
+                    _ => Err(Error::NotFound),
+                }
+            },
+            ("/lnurl-auth", _) => {
+                use crate::login::lnurl::{self, Action, FinishError};
+                use user::types::{AuditEvent, AuditOutcome};
+
+                let provider = match context.lnurl.as_deref() {
+                    Some(provider) => provider,
+                    None => {
+                        error!(logger, "LNURL-auth is not enabled");
+                        return Err(Error::NotFound);
+                    },
+                };
+                let parts = remaining.split('/').collect::<Vec<_>>();
+
+                match (request.method(), parts.as_slice()) {
+                    (HttpMethod::Post, ["login"]) => {
+                        let created = provider
+                            .create(Action::Login, std::time::Instant::now())
+                            .map_err(e(Error::Forbidden("too many pending logins, please try again later"), "failed to create LNURL-auth challenge", &logger))?;
+
+                        Ok(lnurl::created::<S>(&created, &prefix))
+                    },
+                    (HttpMethod::Post, ["link"]) => {
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+
+                        let created = provider
+                            .create(Action::Link(user.user_name().clone()), std::time::Instant::now())
+                            .map_err(e(Error::Forbidden("too many pending logins, please try again later"), "failed to create LNURL-auth challenge", &logger))?;
+
+                        Ok(lnurl::created::<S>(&created, &prefix))
+                    },
+                    // Called by the wallet, the errors are displayed by it
+                    (HttpMethod::Get, ["callback"]) => {
+                        let query_arg = |name| request.query_arg(name).unwrap_or_default();
+                        let logger = logger.new(slog::o!("linking_key" => query_arg("key").to_owned()));
+
+                        let result = provider.callback(&mut user_db, query_arg("k1"), query_arg("sig"), query_arg("key"), std::time::Instant::now()).await;
+                        let response = lnurl::callback_response::<S, _>(&result);
+                        match result {
+                            Ok(()) => info!(logger, "LNURL-auth challenge signed"),
+                            Err(lnurl::CallbackError::DbUpdateUserError(error)) => error!(logger, "failed to access linked keys"; "error" => #error),
+                            Err(error) => info!(logger, "LNURL-auth callback rejected"; "error" => #error),
+                        }
+                        Ok(response)
+                    },
+                    (HttpMethod::Get, ["status", k1]) => {
+                        let k1 = k1
+                            .parse::<user::types::LnurlK1>()
+                            .map_err(e(Error::InvalidData("invalid challenge"), "invalid LNURL-auth challenge", &logger))?;
+                        let secret = request
+                            .get_cookie(lnurl::SECRET_COOKIE)
+                            .and_then(|secret| secret.parse::<user::types::LnurlSecret>().ok());
+
+                        let now = std::time::Instant::now();
+                        let (status, link_key) = match secret {
+                            Some(secret) => (provider.status(&k1, &secret, now), provider.link_key(&k1, &secret, now)),
+                            None => (lnurl::Status::Expired, None),
+                        };
+                        Ok(lnurl::status::<S>(status, link_key.as_ref()))
+                    },
+                    // The user saw the fingerprint of the key that signed and confirms it's theirs
+                    (HttpMethod::Post, ["link", "confirm"]) => {
+                        let k1 = request
+                            .post_form_arg("k1")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .ok_or_else(|| { error!(logger, "missing challenge"); Error::InvalidData("missing challenge") })?
+                            .parse::<user::types::LnurlK1>()
+                            .map_err(e(Error::InvalidData("invalid challenge"), "invalid LNURL-auth challenge", &logger))?;
+                        let fingerprint = request
+                            .post_form_arg("key_fingerprint")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .ok_or_else(|| { error!(logger, "missing key fingerprint"); Error::InvalidData("missing key fingerprint") })?
+                            .to_owned();
+                        let secret = request
+                            .get_cookie(lnurl::SECRET_COOKIE)
+                            .ok_or_else(|| { info!(logger, "missing LNURL-auth secret"); Error::Forbidden("the challenge expired, please scan a new code") })?
+                            .parse::<user::types::LnurlSecret>()
+                            .map_err(e(Error::InvalidData("invalid LNURL-auth secret"), "invalid LNURL-auth secret", &logger))?;
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+
+                        let key = provider
+                            .take_verified_link(&k1, &secret, user.user_name(), &fingerprint, std::time::Instant::now())
+                            .ok_or_else(|| { info!(logger, "LNURL-auth link challenge is not verified, expired, belongs to another browser or was signed by another key"; "key_fingerprint" => &fingerprint); Error::Forbidden("the challenge expired or the key changed, please scan a new code") })?;
+                        let logger = logger.new(slog::o!("linking_key" => key));
+
+                        let linked = lnurl::link(&mut user_db, user.user_name().clone(), key)
+                            .await
+                            .map_err(e(Error::Internal, "failed to link key", &logger))?;
+                        if !linked {
+                            info!(logger, "the key was linked by someone else meanwhile");
+                            return Err(Error::Forbidden("this key is already linked to an account"));
+                        }
+
+                        info!(logger, "Lightning wallet key linked");
+                        let mut builder = S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther);
+                        builder.set_cookie(lnurl::SECRET_COOKIE, "", crate::webserver::cookie_path(prefix), Some(0));
+                        Ok(builder)
+                    },
+                    (HttpMethod::Post, ["finish"]) => {
+                        let k1 = request
+                            .post_form_arg("k1")
+                            .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToLogin(LoginReason::BadInput) })?
+                            .ok_or_else(|| { error!(logger, "missing challenge"); Error::RedirectToLogin(LoginReason::BadInput) })?
+                            .parse::<user::types::LnurlK1>()
+                            .map_err(e(Error::RedirectToLogin(LoginReason::BadInput), "invalid LNURL-auth challenge", &logger))?;
+                        let secret = request
+                            .get_cookie(lnurl::SECRET_COOKIE)
+                            .ok_or_else(|| { info!(logger, "missing LNURL-auth secret"); Error::RedirectToLogin(LoginReason::LnurlFailed) })?
+                            .parse::<user::types::LnurlSecret>()
+                            .map_err(e(Error::RedirectToLogin(LoginReason::BadInput), "invalid LNURL-auth secret", &logger))?;
+                        let name = provider
+                            .take_verified(&k1, &secret, std::time::Instant::now())
+                            .ok_or_else(|| { info!(logger, "LNURL-auth challenge is not verified, expired or belongs to another browser"); Error::RedirectToLogin(LoginReason::LnurlFailed) })?;
+                        let logger = logger.new(slog::o!("user_name" => name.clone()));
+                        let client = audit_client(&request, &context);
+
+                        let session_info = crate::login::SessionInfo {
+                            user_agent: client.user_agent.clone(),
+                            label: None,
+                            remember: request.post_form_arg("remember_me").ok().and_then(std::convert::identity).is_some(),
+                        };
+
+                        let result = lnurl::finish_login(&mut user_db, &context.sessions, name.clone(), session_info).await;
+                        let outcome = match &result {
+                            Ok(_) => AuditOutcome::Success,
+                            Err(FinishError::SecondFactorRequired(_)) => AuditOutcome::SecondFactorRequired,
+                            Err(FinishError::UserDisabled) => AuditOutcome::Disabled,
+                            Err(_) => AuditOutcome::Failed,
+                        };
+                        let record = client.record(AuditEvent::Login, name, outcome);
+                        crate::login::audit::log(&mut user_db, context.audit_retention, record, &logger).await;
+
+                        match result {
+                            Ok(success) => {
+                                info!(logger, "user logged in with a Lightning wallet"; "session_id" => &success.session_id);
+                                let mut builder = logged_in::<S>(&prefix, &after_login(&request, &prefix), &success, &context.sessions);
+                                builder.set_cookie(lnurl::SECRET_COOKIE, "", crate::webserver::cookie_path(prefix), Some(0));
+                                Ok(builder)
+                            },
+                            Err(FinishError::SecondFactorRequired(challenge)) => {
+                                info!(logger, "wallet signature accepted, waiting for the second factor");
+                                Ok(second_factor_required::<S>(&prefix, &challenge))
+                            },
+                            Err(FinishError::UserDisabled) => {
+                                info!(logger, "disabled user attempted to log in");
+                                Err(Error::RedirectToLogin(LoginReason::Disabled))
+                            },
+                            Err(FinishError::UserNotFound) => {
+                                info!(logger, "the user was deleted during login");
+                                Err(Error::RedirectToLogin(LoginReason::LnurlFailed))
+                            },
+                            Err(error) => {
+                                error!(logger, "failed to log in with a Lightning wallet"; "error" => #error);
+                                Err(Error::Internal)
+                            },
+                        }
+                    },
+                    (HttpMethod::Get, ["keys"]) => {
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+
+                        let keys = user_db
+                            .list_lnurl_keys(user.user_name().clone())
+                            .await
+                            .map_err(e(Error::Internal, "failed to retrieve linked keys", &logger))?;
+
+                        Ok(lnurl::get_keys::<S>(keys))
+                    },
+                    (HttpMethod::Post, ["keys", key, "unlink"]) => {
+                        let key = key
+                            .parse::<user::types::LinkingKey>()
+                            .map_err(e(Error::InvalidData("invalid linking key"), "invalid linking key", &logger))?;
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "linking_key" => key));
+
+                        let unlinked = user_db
+                            .unlink_lnurl_key(user.user_name().clone(), key)
+                            .await
+                            .map_err(e(Error::Internal, "failed to unlink key", &logger))?;
+                        if !unlinked {
+                            error!(logger, "linked key not found");
+                            return Err(Error::NotFound);
+                        }
+
+                        info!(logger, "Lightning wallet key unlinked");
+                        Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                    },
+                    _ => Err(Error::NotFound),
+                }
+            },
             ("/login", HttpMethod::Get) => Ok(serve_static::<S, _>(&SafeResourcePath::from_literal("login.html"), Some("text/html"), logger)),
+            ("/login", HttpMethod::Post) if remaining == "second-factor" => {
+                use crate::login::totp::{SecondFactorRequest, SecondFactorError};
+                use crate::login::audit;
+                use user::types::{AuditEvent, AuditOutcome};
+
+                let code = request
+                    .post_form_arg("code")
+                    .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToLogin(LoginReason::BadInput) })?
+                    .ok_or_else(|| { error!(logger, "missing code"); Error::RedirectToLogin(LoginReason::BadInput) })?
+                    .to_owned();
+                let name = request
+                    .get_cookie("user_name")
+                    .ok_or_else(|| { error!(logger, "missing user name"); Error::RedirectToLogin(LoginReason::LoggedOut) })?;
+                let name = user::Name::try_from(name.to_owned()).map_err(e(Error::RedirectToLogin(LoginReason::BadInput), "invalid user name", &logger))?;
+                let challenge = request
+                    .get_cookie("login_challenge")
+                    .ok_or_else(|| { error!(logger, "missing login challenge"); Error::RedirectToLogin(LoginReason::SecondFactorFailed) })?
+                    .parse()
+                    .map_err(e(Error::RedirectToLogin(LoginReason::BadInput), "invalid login challenge", &logger))?;
+                let logger = logger.new(slog::o!("user_name" => name.clone()));
+                let client = audit_client(&request, &context);
+                let address = client.address;
+                let attempt = match check_throttle(&context, &name, address, &logger) {
+                    Ok(attempt) => attempt,
+                    Err(error) => {
+                        audit::log(&mut user_db, context.audit_retention, client.record(AuditEvent::Login, name, AuditOutcome::Throttled), &logger).await;
+                        return Err(error);
+                    },
+                };
+
+                let name_for_throttle = name.clone();
+                let second_factor_request = SecondFactorRequest {
+                    name,
+                    challenge,
+                    code,
+                    user_agent: request.get_header("user-agent").map(ToOwned::to_owned),
+                };
+
+                let result = crate::login::totp::check_second_factor(&mut user_db, &context.sessions, second_factor_request).await;
+                let outcome = match &result {
+                    Ok(_) => AuditOutcome::Success,
+                    Err(SecondFactorError::InvalidChallenge) | Err(SecondFactorError::BadCode) => AuditOutcome::BadSecondFactor,
+                    Err(SecondFactorError::UserDisabled) => AuditOutcome::Disabled,
+                    Err(_) => AuditOutcome::Failed,
+                };
+                audit::log(&mut user_db, context.audit_retention, client.record(AuditEvent::Login, name_for_throttle.clone(), outcome), &logger).await;
+
+                match result {
+                    Ok(success) => {
+                        info!(logger, "user logged in"; "session_id" => &success.session_id);
+                        attempt.succeeded();
+                        let mut builder = logged_in::<S>(&prefix, &after_login(&request, &prefix), &success, &context.sessions);
+                        builder.set_cookie("login_challenge", "", crate::webserver::cookie_path(prefix), Some(0));
+                        Ok(builder)
+                    },
+                    Err(SecondFactorError::InvalidChallenge) => {
+                        info!(logger, "login challenge is invalid or expired");
+                        Err(Error::RedirectToLogin(LoginReason::SecondFactorFailed))
+                    },
+                    Err(SecondFactorError::BadCode) => {
+                        info!(logger, "invalid second factor code");
+                        login_failed(attempt, &name_for_throttle, address, &logger);
+                        Err(Error::RedirectToLogin(LoginReason::SecondFactorFailed))
+                    },
+                    Err(SecondFactorError::UserDisabled) => {
+                        info!(logger, "disabled user attempted to log in");
+                        Err(Error::RedirectToLogin(LoginReason::Disabled))
+                    },
+                    Err(error) => {
+                        error!(logger, "failed to check the second factor"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                }
+            },
             ("/login", HttpMethod::Post) => {
-                use crate::login::LoginError;
+                use crate::login::{audit, LoginError};
+                use user::types::{AuditEvent, AuditOutcome};
 
                 let name = request
                     .post_form_arg("username")
@@ -357,53 +1141,67 @@ fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix:
                     .ok_or_else(|| { error!(logger, "missing user password"); Error::RedirectToLogin(LoginReason::BadInput) })?;
 
                 let name = user::Name::try_from(name.to_owned()).map_err(e(Error::InvalidData("user name contains invalid character"), "invalid user name", &logger))?;
+                let client = audit_client(&request, &context);
+                let address = client.address;
+                let attempt = match check_throttle(&context, &name, address, &logger) {
+                    Ok(attempt) => attempt,
+                    Err(error) => {
+                        audit::log(&mut user_db, context.audit_retention, client.record(AuditEvent::Login, name, AuditOutcome::Throttled), &logger).await;
+                        return Err(error);
+                    },
+                };
+
+                let session_info = crate::login::SessionInfo {
+                    user_agent: client.user_agent.clone(),
+                    label: request.post_form_arg("session_label").ok().and_then(std::convert::identity).filter(|label| !label.is_empty()).map(ToOwned::to_owned),
+                    remember: request.post_form_arg("remember_me").ok().and_then(std::convert::identity).is_some(),
+                };
 
                 let login_request = crate::login::LoginRequest {
                     name: name.clone(),
                     password: password.to_owned(),
+                    session: session_info,
                 };
-                let result = crate::login::check_login(&mut user_db, login_request).await;
+                let result = crate::login::check_login(&mut user_db, &context.auth_backend, login_request, &context.password_params, &context.sessions).await;
+                let record = client.record(AuditEvent::Login, name.clone(), audit::login_outcome(&result));
+                audit::log(&mut user_db, context.audit_retention, record, &logger).await;
 
                 match result {
                     Ok(success) => {
-                        let mut builder = S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther);
-                        builder.set_cookie("user_name", &success.name, Some(COOKIE_LIFETIME_SECONDS));
-                        builder.set_cookie("auth_token", &success.cookie.to_string(), Some(COOKIE_LIFETIME_SECONDS));
-                        Ok(builder)
+                        info!(logger, "user logged in"; "user_name" => &success.name, "session_id" => &success.session_id);
+                        attempt.succeeded();
+                        Ok(logged_in::<S>(&prefix, &after_login(&request, &prefix), &success, &context.sessions))
                     },
                     Err(LoginError::BadUserPassword) => {
-                        if &*name == "admin" {
-                            let signup_request = SignupRequest {
-                                name: name.to_owned(),
-                                password: password.to_owned(),
-                            };
-
-                            match crate::login::signup(&mut user_db, signup_request).await {
-                                Ok(cookie) => {
-                                    let mut builder = S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther);
-                                    builder.set_cookie("user_name", &name, Some(COOKIE_LIFETIME_SECONDS));
-                                    builder.set_cookie("auth_token", &cookie.to_string(), Some(COOKIE_LIFETIME_SECONDS));
-                                    Ok(builder)
-                                },
-                                Err(user::InsertError::UserExists) => {
-                                    error!(logger, "Invalid user name or password");
-                                    Err(Error::RedirectToLogin(LoginReason::BadCredentials))
-                                },
-                                Err(user::InsertError::DatabaseError(error)) => {
-                                    error!(logger, "failed to insert user due to database error"; "error" => #error);
-                                    Err(Error::Internal)
-                                },
-                            }
-                        } else {
-                            Err(Error::RedirectToLogin(LoginReason::BadCredentials))
-                        }
+                        login_failed(attempt, &name, address, &logger);
+                        Err(Error::RedirectToLogin(LoginReason::BadCredentials))
+                    },
+                    Err(LoginError::UserDisabled) => {
+                        info!(logger, "disabled user attempted to log in");
+                        Err(Error::RedirectToLogin(LoginReason::Disabled))
+                    },
+                    Err(LoginError::SecondFactorRequired(challenge)) => {
+                        info!(logger, "password accepted, waiting for the second factor"; "user_name" => &challenge.name);
+                        Ok(second_factor_required::<S>(&prefix, &challenge))
                     },
                     Err(LoginError::DbGetUserError(error)) => {
                         error!(logger, "failed to retrieve the user"; "error" => #error);
                         Err(Error::Internal)
                     },
-                    Err(LoginError::DbSetCookieError(error)) => {
-                        error!(logger, "failed to set authentication cookie"; "error" => #error);
+                    Err(LoginError::DbCreateSessionError(error)) => {
+                        error!(logger, "failed to create session"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                    Err(LoginError::DbUpdateUserError(error)) => {
+                        error!(logger, "failed to upgrade password hash"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                    Err(LoginError::DbInsertUserError(error)) => {
+                        error!(logger, "failed to create the user authenticated by the backend"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                    Err(LoginError::BackendError(error)) => {
+                        error!(logger, "failed to verify the password"; "error" => #error);
                         Err(Error::Internal)
                     },
                 }
@@ -412,8 +1210,11 @@ fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix:
                 let app_name = app::Name::try_from(remaining.to_owned()).map_err(e(Error::InvalidData("invalid application name"), "failed to parse app name", &logger))?;
 
                 let logger = logger.new(slog::o!("app" => app_name.clone()));
+                let scope = user::types::ApiScope::OpenApp(app_name.clone());
+                let client = audit_client(&request, &context);
+                let request_info = app::entry_point::RequestInfo::from_request(&request, client.address);
 
-                let user = crate::login::auth_request::<_, S>(&mut user_db, request, logger.clone())
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, Some(&scope), logger.clone())
                     .await
                     .map_err(view_auth)?;
                 let app = match apps.get(&*app_name) {
@@ -424,21 +1225,658 @@ fn route_raw<S: crate::webserver::Server, Db: 'static + user::Db + Send>(prefix:
                     },
                 };
 
-                let url = app.get_open_url(&app_name, &user).await.map_err(log_and_convert(&logger))?;
+                let result = app.open(&app_name, &user, &request_info, &context.entry_points).await;
+                let (outcome, message) = crate::login::audit::open_app_outcome(&result);
+                let record = user::DbAuditRecord {
+                    app: Some(app_name.to_string()),
+                    message,
+                    ..client.record(user::types::AuditEvent::OpenApp, user.user_name().clone(), outcome)
+                };
+                crate::login::audit::log(&mut user_db, context.audit_retention, record, &logger).await;
+                let opened = result.map_err(log_and_convert(&logger))?;
+
+                let mut builder = S::ResponseBuilder::redirect(&opened.url, crate::webserver::RedirectKind::Temporary);
+                for (name, value) in &opened.headers {
+                    builder.append_header(name, value);
+                }
+                Ok(builder)
+            },
+            ("/setup", HttpMethod::Get) => {
+                if context.setup.is_pending() {
+                    Ok(serve_static::<S, _>(&SafeResourcePath::from_literal("setup.html"), Some("text/html"), logger))
+                } else {
+                    Err(Error::RedirectToLogin(LoginReason::LoggedOut))
+                }
+            },
+            ("/setup", HttpMethod::Post) => {
+                use crate::login::setup::{SetupRequest, SetupError};
+
+                let token = request
+                    .post_form_arg("setup_token")
+                    .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToSetup(SetupReason::BadInput) })?
+                    .ok_or_else(|| { error!(logger, "missing setup token"); Error::RedirectToSetup(SetupReason::BadInput) })?;
+                let name = request
+                    .post_form_arg("username")
+                    .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToSetup(SetupReason::BadInput) })?
+                    .ok_or_else(|| { error!(logger, "missing user name"); Error::RedirectToSetup(SetupReason::BadInput) })?;
+                let password = request
+                    .post_form_arg("password")
+                    .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToSetup(SetupReason::BadInput) })?
+                    .ok_or_else(|| { error!(logger, "missing user password"); Error::RedirectToSetup(SetupReason::BadInput) })?;
+                let name = user::Name::try_from(name.to_owned()).map_err(e(Error::RedirectToSetup(SetupReason::BadInput), "invalid user name", &logger))?;
+
+                let setup_request = SetupRequest {
+                    token: token.to_owned(),
+                    name,
+                    password: password.to_owned(),
+                    session: crate::login::SessionInfo {
+                        user_agent: request.get_header("user-agent").map(ToOwned::to_owned),
+                        label: None,
+                        remember: false,
+                    },
+                };
+
+                match crate::login::setup::setup(&mut user_db, &context.setup, &context.password_params, &context.sessions, setup_request).await {
+                    Ok(success) => {
+                        info!(logger, "administrator created by setup"; "user_name" => &success.name, "session_id" => &success.session_id);
+                        if let Err(error) = context.setup.remove_file() {
+                            warn!(logger, "failed to remove the setup token file"; "error" => #error);
+                        }
+                        Ok(logged_in::<S>(&prefix, &prefix, &success, &context.sessions))
+                    },
+                    Err(SetupError::Finished) => {
+                        info!(logger, "attempted to run already finished setup");
+                        Err(Error::RedirectToLogin(LoginReason::LoggedOut))
+                    },
+                    Err(SetupError::BadToken) => {
+                        warn!(logger, "invalid setup token");
+                        Err(Error::RedirectToSetup(SetupReason::BadToken))
+                    },
+                    Err(SetupError::EmptyPassword) => Err(Error::RedirectToSetup(SetupReason::BadInput)),
+                    Err(SetupError::UserExists) => Err(Error::RedirectToSetup(SetupReason::UserExists)),
+                    Err(error) => {
+                        error!(logger, "setup failed"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                }
+            },
+            ("/invite", HttpMethod::Get) => {
+                let token = remaining
+                    .parse::<user::types::InviteToken>()
+                    .map_err(e(Error::RedirectToLogin(LoginReason::InvalidInvite), "invalid invite token", &logger))?;
+
+                let valid = crate::login::invite::is_valid(&mut user_db, &context.sessions, &token)
+                    .await
+                    .map_err(e(Error::Internal, "failed to retrieve the invite", &logger))?;
+
+                if valid {
+                    Ok(serve_static::<S, _>(&SafeResourcePath::from_literal("invite.html"), Some("text/html"), logger))
+                } else {
+                    info!(logger, "invite doesn't exist or expired");
+                    Err(Error::RedirectToLogin(LoginReason::InvalidInvite))
+                }
+            },
+            ("/invite", HttpMethod::Post) => {
+                use crate::login::invite::{AcceptRequest, AcceptError};
+
+                let token = remaining
+                    .parse::<user::types::InviteToken>()
+                    .map_err(e(Error::RedirectToLogin(LoginReason::InvalidInvite), "invalid invite token", &logger))?;
+                let name = request
+                    .post_form_arg("username")
+                    .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToInvite(token, InviteReason::BadInput) })?
+                    .ok_or_else(|| { error!(logger, "missing user name"); Error::RedirectToInvite(token, InviteReason::BadInput) })?;
+                let password = request
+                    .post_form_arg("password")
+                    .map_err(|error| { error!(logger, "failed to decode form data"; "error" => #error); Error::RedirectToInvite(token, InviteReason::BadInput) })?
+                    .ok_or_else(|| { error!(logger, "missing user password"); Error::RedirectToInvite(token, InviteReason::BadInput) })?;
+                if name.is_empty() {
+                    error!(logger, "empty user name");
+                    return Err(Error::RedirectToInvite(token, InviteReason::BadInput));
+                }
+                let name = user::Name::try_from(name.to_owned()).map_err(e(Error::RedirectToInvite(token, InviteReason::BadInput), "invalid user name", &logger))?;
+                let logger = logger.new(slog::o!("user_name" => name.clone()));
+
+                let accept_request = AcceptRequest {
+                    token,
+                    name,
+                    password: password.to_owned(),
+                    session: crate::login::SessionInfo {
+                        user_agent: request.get_header("user-agent").map(ToOwned::to_owned),
+                        label: None,
+                        remember: false,
+                    },
+                };
 
-                Ok(S::ResponseBuilder::redirect(&url, crate::webserver::RedirectKind::Temporary))
+                match crate::login::invite::accept(&mut user_db, &context.password_params, &context.sessions, accept_request).await {
+                    Ok(success) => {
+                        info!(logger, "user created from invite"; "session_id" => &success.session_id);
+                        Ok(logged_in::<S>(&prefix, &prefix, &success, &context.sessions))
+                    },
+                    Err(AcceptError::InvalidInvite) => {
+                        info!(logger, "invite doesn't exist, expired or was already used");
+                        Err(Error::RedirectToLogin(LoginReason::InvalidInvite))
+                    },
+                    Err(AcceptError::EmptyPassword) => Err(Error::RedirectToInvite(token, InviteReason::BadInput)),
+                    Err(AcceptError::UserExists) => Err(Error::RedirectToInvite(token, InviteReason::UserExists)),
+                    Err(error) => {
+                        error!(logger, "failed to accept invite"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                }
             },
-            ("/logout", HttpMethod::Get) => {
-                let user = crate::login::auth_request::<_, S>(&mut user_db, request, logger.clone()).await.map_err(view_auth)?;
+            ("/logout", HttpMethod::Post) => {
+                let client = audit_client(&request, &context);
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
                 let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
 
                 user.logout(&mut user_db).await.map_err(e(Error::Internal, "failed to log out", &logger))?;
 
                 info!(logger, "user logged out");
-                let mut builder = S::ResponseBuilder::redirect(&format!("{}/login", prefix), crate::webserver::RedirectKind::SeeOther);
-                builder.set_cookie("user_name", "", Some(0));
-                builder.set_cookie("auth_token", "", Some(0));
-                Ok(builder)
+                let record = client.record(user::types::AuditEvent::Logout, user.user_name().clone(), user::types::AuditOutcome::Success);
+                crate::login::audit::log(&mut user_db, context.audit_retention, record, &logger).await;
+                Ok(logged_out::<S>(&prefix))
+            },
+            ("/change-password", HttpMethod::Post) => {
+                use crate::login::ChangePasswordError;
+
+                let current_password = request
+                    .post_form_arg("current_password")
+                    .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                    .ok_or_else(|| { error!(logger, "missing current password"); Error::InvalidData("missing current password") })?
+                    .to_owned();
+                let new_password = request
+                    .post_form_arg("new_password")
+                    .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                    .ok_or_else(|| { error!(logger, "missing new password"); Error::InvalidData("missing new password") })?
+                    .to_owned();
+
+                let address = client_address(&request, &context.client_address_header);
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
+                let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+                let attempt = check_throttle(&context, user.name(), address, &logger).map_err(|_| Error::Forbidden("too many failed attempts, try again later"))?;
+
+                let change_request = crate::login::ChangePasswordRequest {
+                    current_password,
+                    new_password,
+                };
+
+                match crate::login::change_password(&mut user_db, &context.auth_backend, &user, change_request, &context.password_params).await {
+                    Ok(()) => {
+                        info!(logger, "password changed");
+                        attempt.succeeded();
+                        Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                    },
+                    Err(ChangePasswordError::BadPassword) => {
+                        error!(logger, "failed to change password: invalid current password");
+                        login_failed(attempt, user.name(), address, &logger);
+                        Err(Error::Forbidden("invalid current password"))
+                    },
+                    Err(ChangePasswordError::EmptyPassword) => Err(Error::InvalidData("the new password must not be empty")),
+                    Err(ChangePasswordError::ExternalPassword) => Err(Error::Forbidden("the password can't be changed here, it's managed by the authentication backend")),
+                    Err(error) => {
+                        error!(logger, "failed to change password"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                }
+            },
+            ("/sessions", HttpMethod::Get) if remaining.is_empty() => {
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone())
+                    .await
+                    .map_err(api_auth)?;
+
+                let sessions = user_db
+                    .get_sessions(user.user_name().clone())
+                    .await
+                    .map_err(e(Error::Internal, "failed to retrieve sessions", &logger))?;
+
+                Ok(crate::login::get_sessions::<S>(&user, sessions))
+            },
+            ("/sessions", HttpMethod::Post) => {
+                let session_id = match remaining {
+                    "revoke" => {
+                        let session_id = request
+                            .post_form_arg("session_id")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .ok_or_else(|| { error!(logger, "missing session ID"); Error::InvalidData("missing session ID") })?
+                            .parse::<user::types::SessionId>()
+                            .map_err(e(Error::InvalidData("invalid session ID"), "invalid session ID", &logger))?;
+                        Some(session_id)
+                    },
+                    "revoke-all" => None,
+                    _ => return Err(Error::NotFound),
+                };
+
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
+                let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+
+                match session_id {
+                    Some(session_id) => {
+                        let user_name = user.user_name().clone();
+                        let revoked = user_db
+                            .revoke_session(user_name, session_id)
+                            .await
+                            .map_err(e(Error::Internal, "failed to revoke session", &logger))?;
+                        if !revoked {
+                            error!(logger, "session not found"; "session_id" => &session_id);
+                            return Err(Error::NotFound);
+                        }
+
+                        info!(logger, "session revoked"; "session_id" => &session_id);
+                        if Some(session_id) == user.session_id() {
+                            Ok(logged_out::<S>(&prefix))
+                        } else {
+                            Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                        }
+                    },
+                    None => {
+                        user.logout_everywhere(&mut user_db).await.map_err(e(Error::Internal, "failed to log out everywhere", &logger))?;
+
+                        info!(logger, "all sessions of the user revoked");
+                        Ok(logged_out::<S>(&prefix))
+                    },
+                }
+            },
+            ("/api-tokens", HttpMethod::Get) if remaining.is_empty() => {
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone())
+                    .await
+                    .map_err(api_auth)?;
+
+                let tokens = user_db
+                    .list_api_tokens(user.user_name().clone())
+                    .await
+                    .map_err(e(Error::Internal, "failed to retrieve API tokens", &logger))?;
+
+                Ok(crate::login::api_token::get_tokens::<S>(tokens))
+            },
+            ("/api-tokens", HttpMethod::Post) if remaining.is_empty() => {
+                use crate::login::api_token::{CreateRequest, CreateError};
+
+                let name = request
+                    .post_form_arg("name")
+                    .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                    .ok_or_else(|| { error!(logger, "missing token name"); Error::InvalidData("missing token name") })?
+                    .to_owned();
+                let scopes = request
+                    .post_form_arg("scopes")
+                    .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                    .ok_or_else(|| { error!(logger, "missing scopes"); Error::InvalidData("missing scopes") })?;
+                let scopes = crate::login::api_token::parse_scopes(scopes).map_err(e(Error::InvalidData("invalid scope"), "invalid scope", &logger))?;
+                let lifetime = match request.post_form_arg("lifetime_days").map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))? {
+                    Some(days) => {
+                        let days = days.parse::<u64>().map_err(e(Error::InvalidData("invalid lifetime"), "invalid lifetime", &logger))?;
+                        std::time::Duration::from_secs(days.saturating_mul(24 * 60 * 60))
+                    },
+                    None => context.api_token_max_lifetime,
+                };
+                if lifetime.as_secs() == 0 || lifetime > context.api_token_max_lifetime {
+                    error!(logger, "invalid API token lifetime"; "lifetime_seconds" => lifetime.as_secs());
+                    return Err(Error::InvalidData("the lifetime must be at least one day and at most the configured maximum"));
+                }
+
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+
+                let create_request = CreateRequest {
+                    name,
+                    scopes,
+                    lifetime,
+                };
+
+                match crate::login::api_token::create(&mut user_db, &context.sessions, &user, create_request).await {
+                    Ok(created) => {
+                        info!(logger, "API token created"; "token_id" => &created.id);
+                        Ok(crate::login::api_token::created::<S>(&created))
+                    },
+                    Err(CreateError::EmptyName) => Err(Error::InvalidData("the token name must not be empty")),
+                    Err(CreateError::NoScopes) => Err(Error::InvalidData("the token needs at least one scope")),
+                    Err(error) => {
+                        error!(logger, "failed to create API token"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                }
+            },
+            ("/api-tokens", HttpMethod::Post) => {
+                let id = match remaining.split('/').collect::<Vec<_>>().as_slice() {
+                    [id, "revoke"] => id
+                        .parse::<user::types::ApiTokenId>()
+                        .map_err(e(Error::InvalidData("invalid token ID"), "invalid token ID", &logger))?,
+                    _ => return Err(Error::NotFound),
+                };
+
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(view_auth)?;
+                let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "token_id" => id));
+
+                let revoked = user_db
+                    .revoke_api_token(user.user_name().clone(), id)
+                    .await
+                    .map_err(e(Error::Internal, "failed to revoke API token", &logger))?;
+                if !revoked {
+                    error!(logger, "API token not found");
+                    return Err(Error::NotFound);
+                }
+
+                info!(logger, "API token revoked");
+                Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+            },
+            ("/totp", HttpMethod::Post) => {
+                use crate::login::totp::{EnableTotpRequest, ManageTotpError};
+
+                let action = match remaining {
+                    "enroll" => TotpAction::Enroll,
+                    "enable" => {
+                        let secret = request
+                            .post_form_arg("secret")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .ok_or_else(|| { error!(logger, "missing secret"); Error::InvalidData("missing secret") })?
+                            .parse()
+                            .map_err(e(Error::InvalidData("invalid secret"), "invalid secret", &logger))?;
+                        let code = request
+                            .post_form_arg("code")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .ok_or_else(|| { error!(logger, "missing code"); Error::InvalidData("missing code") })?
+                            .to_owned();
+                        TotpAction::Enable { secret, code, }
+                    },
+                    "disable" => TotpAction::Disable,
+                    "recovery-codes" => TotpAction::RegenerateRecoveryCodes,
+                    _ => return Err(Error::NotFound),
+                };
+                let password = match action {
+                    TotpAction::Enroll => String::new(),
+                    _ => request
+                        .post_form_arg("password")
+                        .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                        .ok_or_else(|| { error!(logger, "missing password"); Error::InvalidData("missing password") })?
+                        .to_owned(),
+                };
+
+                let address = client_address(&request, &context.client_address_header);
+                let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "action" => action.as_str()));
+                // enrolling doesn't check the password
+                let attempt = match action {
+                    TotpAction::Enroll => None,
+                    _ => Some(check_throttle(&context, user.name(), address, &logger).map_err(|_| Error::Forbidden("too many failed attempts, try again later"))?),
+                };
+
+                let result = match action {
+                    TotpAction::Enroll => return Ok(crate::login::totp::enroll::<S>(&user)),
+                    TotpAction::Enable { secret, code, } => {
+                        let enable_request = EnableTotpRequest {
+                            password,
+                            secret,
+                            code,
+                        };
+                        crate::login::totp::enable(&mut user_db, &context.auth_backend, &user, enable_request, &context.password_params)
+                            .await
+                            .map(Some)
+                    },
+                    TotpAction::Disable => {
+                        crate::login::totp::disable(&mut user_db, &context.auth_backend, &user, &password, &context.password_params)
+                            .await
+                            .map(|()| None)
+                    },
+                    TotpAction::RegenerateRecoveryCodes => {
+                        crate::login::totp::regenerate_recovery_codes(&mut user_db, &context.auth_backend, &user, &password, &context.password_params)
+                            .await
+                            .map(Some)
+                    },
+                };
+
+                match result {
+                    Ok(Some(recovery_codes)) => {
+                        info!(logger, "two-factor authentication updated");
+                        if let Some(attempt) = attempt {
+                            attempt.succeeded();
+                        }
+                        Ok(crate::login::totp::recovery_codes::<S>(recovery_codes))
+                    },
+                    Ok(None) => {
+                        info!(logger, "two-factor authentication disabled");
+                        if let Some(attempt) = attempt {
+                            attempt.succeeded();
+                        }
+                        Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                    },
+                    Err(ManageTotpError::BadPassword) => {
+                        error!(logger, "failed to update two-factor authentication: invalid password");
+                        if let Some(attempt) = attempt {
+                            login_failed(attempt, user.name(), address, &logger);
+                        }
+                        Err(Error::Forbidden("invalid password"))
+                    },
+                    Err(ManageTotpError::BadCode) => Err(Error::InvalidData("the code doesn't match, check the time on your device")),
+                    Err(ManageTotpError::NotEnabled) => Err(Error::InvalidData("two-factor authentication is not enabled")),
+                    Err(error) => {
+                        error!(logger, "failed to update two-factor authentication"; "error" => #error);
+                        Err(Error::Internal)
+                    },
+                }
+            },
+            ("/admin", _) => {
+                let parts = remaining.split('/').collect::<Vec<_>>();
+
+                match (request.method(), parts.as_slice()) {
+                    (HttpMethod::Get, ["audit-log"]) => {
+                        use crate::login::audit;
+
+                        let parse_number = |name: &str, default: u32| match request.query_arg(name) {
+                            Some(value) => value.parse::<u32>().map_err(e(Error::InvalidData("invalid paging parameter"), "invalid paging parameter", &logger)),
+                            None => Ok(default),
+                        };
+                        let offset = parse_number("offset", 0)?;
+                        let limit = parse_number("limit", audit::DEFAULT_PAGE_SIZE)?.max(1).min(audit::MAX_PAGE_SIZE);
+                        let user_name = request
+                            .query_arg("user")
+                            .map(|name| parse_user_name(name, &logger))
+                            .transpose()?;
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+
+                        let query = user::db::AuditQuery {
+                            user_name,
+                            offset,
+                            limit,
+                        };
+                        let records = user_db
+                            .list_audit_events(query.clone())
+                            .await
+                            .map_err(e(Error::Internal, "failed to retrieve the audit log", &logger))?;
+
+                        Ok(audit::get_log::<S>(records, &query))
+                    },
+                    (HttpMethod::Get, ["users"]) => {
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+
+                        let users = user_db
+                            .list_users()
+                            .await
+                            .map_err(e(Error::Internal, "failed to list users", &logger))?;
+
+                        Ok(user::api::get_users::<S>(users))
+                    },
+                    (HttpMethod::Post, ["users"]) => {
+                        let name = request
+                            .post_form_arg("username")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .ok_or_else(|| { error!(logger, "missing user name"); Error::InvalidData("missing user name") })?;
+                        let name = parse_user_name(name, &logger)?;
+                        let password = request
+                            .post_form_arg("password")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .filter(|password| !password.is_empty())
+                            .ok_or_else(|| { error!(logger, "missing user password"); Error::InvalidData("missing user password") })?
+                            .to_owned();
+                        let groups = request
+                            .post_form_arg("groups")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .map(|groups| parse_groups(groups, &logger))
+                            .transpose()?
+                            .unwrap_or_default();
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "target_user" => name.clone()));
+
+                        let signup_request = SignupRequest {
+                            name,
+                            password,
+                            groups,
+                        };
+
+                        match crate::login::signup(&mut user_db, signup_request, &context.password_params).await {
+                            Ok(()) => {
+                                info!(logger, "user created");
+                                Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                            },
+                            Err(user::InsertError::UserExists) => {
+                                error!(logger, "user already exists");
+                                Err(Error::InvalidData("user already exists"))
+                            },
+                            Err(user::InsertError::DatabaseError(error)) => {
+                                error!(logger, "failed to insert user due to database error"; "error" => #error);
+                                Err(Error::Internal)
+                            },
+                        }
+                    },
+                    (HttpMethod::Post, ["users", name, action]) => {
+                        let name = parse_user_name(name, &logger)?;
+                        let action = match *action {
+                            "disable" => UserUpdate::Disable,
+                            "enable" => UserUpdate::Enable,
+                            "delete" => UserUpdate::Delete,
+                            "groups" => {
+                                let groups = request
+                                    .post_form_arg("groups")
+                                    .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                                    .ok_or_else(|| { error!(logger, "missing groups"); Error::InvalidData("missing groups") })?;
+                                UserUpdate::SetGroups(parse_groups(groups, &logger)?)
+                            },
+                            _ => return Err(Error::NotFound),
+                        };
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "target_user" => name.clone(), "action" => action.as_str()));
+
+                        if *name == *user.name() {
+                            match action {
+                                UserUpdate::Disable | UserUpdate::Delete => {
+                                    error!(logger, "administrator attempted to disable or delete themselves");
+                                    return Err(Error::Forbidden("you can't disable or delete yourself"));
+                                },
+                                UserUpdate::Enable | UserUpdate::SetGroups(_) => (),
+                            }
+                        }
+
+                        // Prevents the administrators from locking everyone out
+                        if action.removes_admin() {
+                            let users = user_db
+                                .list_users()
+                                .await
+                                .map_err(e(Error::Internal, "failed to list users", &logger))?;
+
+                            if is_last_admin(&users, &name) {
+                                error!(logger, "attempted to remove the last administrator");
+                                return Err(Error::Forbidden("at least one administrator must remain"));
+                            }
+                        }
+
+                        let found = match action {
+                            UserUpdate::Disable => {
+                                let found = user_db
+                                    .set_disabled(name.clone(), true)
+                                    .await
+                                    .map_err(e(Error::Internal, "failed to disable user", &logger))?;
+                                user_db
+                                    .revoke_all_sessions(name, None)
+                                    .await
+                                    .map_err(e(Error::Internal, "failed to revoke sessions of disabled user", &logger))?;
+                                found
+                            },
+                            UserUpdate::Enable => {
+                                user_db
+                                    .set_disabled(name, false)
+                                    .await
+                                    .map_err(e(Error::Internal, "failed to enable user", &logger))?
+                            },
+                            UserUpdate::Delete => {
+                                user_db
+                                    .delete_user(name)
+                                    .await
+                                    .map_err(e(Error::Internal, "failed to delete user", &logger))?
+                            },
+                            UserUpdate::SetGroups(groups) => {
+                                user_db
+                                    .set_groups(name, groups)
+                                    .await
+                                    .map_err(e(Error::Internal, "failed to set groups", &logger))?
+                            },
+                        };
+
+                        if !found {
+                            error!(logger, "user not found");
+                            return Err(Error::NotFound);
+                        }
+
+                        info!(logger, "user updated");
+                        Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                    },
+                    (HttpMethod::Get, ["invites"]) => {
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+
+                        let invites = crate::login::invite::list_pending(&mut user_db)
+                            .await
+                            .map_err(e(Error::Internal, "failed to list invites", &logger))?;
+
+                        Ok(crate::login::invite::get_invites::<S>(invites))
+                    },
+                    (HttpMethod::Post, ["invites"]) => {
+                        let groups = request
+                            .post_form_arg("groups")
+                            .map_err(e(Error::InvalidData("failed to decode form data"), "failed to decode form data", &logger))?
+                            .map(|groups| parse_groups(groups, &logger))
+                            .transpose()?
+                            .unwrap_or_default();
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned()));
+
+                        let invite = crate::login::invite::create(&mut user_db, &context.sessions, &user, groups, context.invite_lifetime)
+                            .await
+                            .map_err(e(Error::Internal, "failed to create invite", &logger))?;
+
+                        info!(logger, "invite created"; "invite_id" => &invite.id);
+                        Ok(crate::login::invite::created::<S>(&prefix, &invite))
+                    },
+                    (HttpMethod::Post, ["invites", id, "revoke"]) => {
+                        let id = id
+                            .parse::<user::types::InviteId>()
+                            .map_err(e(Error::InvalidData("invalid invite ID"), "invalid invite ID", &logger))?;
+
+                        let user = crate::login::auth_request::<_, S>(&mut user_db, &context.sessions, request, None, logger.clone()).await.map_err(api_auth)?;
+                        require_admin(&user, &logger)?;
+                        let logger = logger.new(slog::o!("user_name" => user.name().to_owned(), "invite_id" => id));
+
+                        let revoked = user_db
+                            .revoke_invite(id)
+                            .await
+                            .map_err(e(Error::Internal, "failed to revoke invite", &logger))?;
+                        if !revoked {
+                            error!(logger, "invite not found");
+                            return Err(Error::NotFound);
+                        }
+
+                        info!(logger, "invite revoked");
+                        Ok(S::ResponseBuilder::redirect(&prefix, crate::webserver::RedirectKind::SeeOther))
+                    },
+                    _ => Err(Error::NotFound),
+                }
             },
             _ => Err(Error::NotFound),
         }
@@ -457,4 +1895,28 @@ mod tests {
     test_str_val_err!(resource_path_traversal_end, SafeResourcePath, "foo/..");
     test_str_val_err!(resource_path_traversal_middle, SafeResourcePath, "foo/../bar");
     test_str_val_err!(resource_path_traversal_consecutive, SafeResourcePath, "foo/../../bar");
+
+    #[test]
+    fn last_admin() {
+        use std::convert::TryInto;
+        use crate::user;
+
+        let user = |name: &str, disabled, groups: &[&str]| user::DbRecord {
+            name: name.to_owned().try_into().unwrap(),
+            password_hash: "$scrypt$ln=15,r=8,p=1$AAAAAAAAAAAAAAAAAAAAAA$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".parse().unwrap(),
+            disabled,
+            groups: groups.iter().map(|group| (*group).to_owned().try_into().unwrap()).collect(),
+            totp_secret: None,
+        };
+
+        let users = vec![user("admin", false, &["admins"]), user("satoshi", false, &["family"])];
+        assert!(super::is_last_admin(&users, "admin"));
+        assert!(!super::is_last_admin(&users, "satoshi"));
+
+        let users = vec![user("admin", false, &["admins"]), user("satoshi", false, &["admins", "family"])];
+        assert!(!super::is_last_admin(&users, "admin"));
+
+        let users = vec![user("admin", false, &["admins"]), user("satoshi", true, &["admins"])];
+        assert!(super::is_last_admin(&users, "admin"));
+    }
 }
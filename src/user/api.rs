@@ -0,0 +1,30 @@
+#[derive(serde_derive::Serialize)]
+pub struct User {
+    pub name: String,
+    pub disabled: bool,
+    pub groups: Vec<String>,
+    pub two_factor: bool,
+}
+
+#[derive(serde_derive::Serialize)]
+pub struct UsersResponse {
+    pub users: Vec<User>,
+}
+
+pub fn get_users<S: crate::webserver::Server>(users: Vec<super::DbRecord>) -> S::ResponseBuilder {
+    let users = users
+        .into_iter()
+        .map(|user| User {
+            name: user.name.into_inner(),
+            disabled: user.disabled,
+            groups: user.groups.into_iter().map(super::Group::into_inner).collect(),
+            two_factor: user.totp_secret.is_some(),
+        })
+        .collect();
+
+    let resp = UsersResponse {
+        users,
+    };
+
+    crate::webserver::json_response(&resp)
+}
@@ -0,0 +1,185 @@
+//! Passwords stored in a file managed by `htpasswd`
+//!
+//! The file is read on each login so that changes take effect immediately. bcrypt
+//! (`htpasswd -B`), Apache MD5 (the default of `htpasswd`) and SHA-1 (`htpasswd -s`) hashes are
+//! supported.
+
+use std::path::PathBuf;
+use super::{Authenticator, Error, VerifyFuture};
+
+const APR1_PREFIX: &str = "$apr1$";
+const SHA1_PREFIX: &str = "{SHA}";
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+pub struct Htpasswd {
+    path: PathBuf,
+}
+
+impl Htpasswd {
+    pub fn new(path: PathBuf) -> Self {
+        Htpasswd {
+            path,
+        }
+    }
+}
+
+impl Authenticator for Htpasswd {
+    fn verify<'a>(&'a self, name: &'a str, password: &'a str) -> VerifyFuture<'a> {
+        let path = self.path.clone();
+        let name = name.to_owned();
+        let password = password.to_owned();
+
+        Box::pin(async move {
+            // reading the file and bcrypt both block
+            tokio::task::spawn_blocking(move || {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|error| Error::HtpasswdRead { path, error, })?;
+
+                match find_hash(&contents, &name) {
+                    Some(hash) => verify_hash(&name, hash, &password),
+                    None => Ok(false),
+                }
+            })
+            .await
+            .map_err(Error::TaskJoin)?
+        })
+    }
+}
+
+fn find_hash<'a>(contents: &'a str, name: &str) -> Option<&'a str> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.trim_end().splitn(2, ':');
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|(user, _)| *user == name)
+        .map(|(_, hash)| hash)
+}
+
+fn verify_hash(name: &str, hash: &str, password: &str) -> Result<bool, Error> {
+    if hash.starts_with("$2y$") || hash.starts_with("$2b$") || hash.starts_with("$2a$") {
+        // malformed hash can't match any password
+        Ok(bcrypt::verify(password, hash).unwrap_or(false))
+    } else if hash.starts_with(APR1_PREFIX) {
+        let salt = hash[APR1_PREFIX.len()..].split('$').next().unwrap_or_default();
+        Ok(constant_time_eq(apr1(password.as_bytes(), salt.as_bytes()).as_bytes(), hash.as_bytes()))
+    } else if hash.starts_with(SHA1_PREFIX) {
+        use sha1::Digest;
+
+        let expected = base64::encode(sha1::Sha1::digest(password.as_bytes()));
+        Ok(constant_time_eq(expected.as_bytes(), hash[SHA1_PREFIX.len()..].as_bytes()))
+    } else {
+        Err(Error::HtpasswdUnsupportedHash { user: name.to_owned(), })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Computes the Apache variant of MD5-crypt, returns the whole encoded hash including the salt
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    use md5::{Digest, Md5};
+
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain(password)
+        .chain(salt)
+        .chain(password)
+        .finalize();
+
+    let mut context = Md5::new()
+        .chain(password)
+        .chain(APR1_PREFIX)
+        .chain(salt);
+    for chunk in password.chunks(16) {
+        context.update(&alternate[..chunk.len()]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 != 0 {
+            context.update(&[0u8]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut result = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 != 0 {
+            context.update(password);
+        } else {
+            context.update(&result);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 != 0 {
+            context.update(&result);
+        } else {
+            context.update(password);
+        }
+        result = context.finalize();
+    }
+
+    let mut encoded = format!("{}{}$", APR1_PREFIX, String::from_utf8_lossy(salt));
+    let groups = [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)];
+    for &(a, b, c) in &groups {
+        encode_crypt64(&mut encoded, u32::from(result[a]) << 16 | u32::from(result[b]) << 8 | u32::from(result[c]), 4);
+    }
+    encode_crypt64(&mut encoded, u32::from(result[11]), 2);
+    encoded
+}
+
+fn encode_crypt64(output: &mut String, mut value: u32, chars: usize) {
+    for _ in 0..chars {
+        output.push(char::from(CRYPT_ALPHABET[(value & 0x3f) as usize]));
+        value >>= 6;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Authenticator;
+
+    // The MD5 hash is the example from the documentation of Apache (myPassword), the bcrypt one is
+    // the reference vector of OpenBSD (U*U) and the SHA-1 one is of nbusr123
+    const FILE: &str = "# comment
+satoshi:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/
+hal:{SHA}sQ8oqBBQTWEyBX9edv7MgmTCKJo=
+nick:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW
+adam:crypt123456
+";
+
+    #[test]
+    fn apr1_reference() {
+        assert_eq!(super::apr1(b"myPassword", b"r31....."), "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/");
+    }
+
+    #[test]
+    fn verify() {
+        let path = std::env::temp_dir().join(format!("selfhost-dashboard-test-htpasswd-{}", std::process::id()));
+        std::fs::write(&path, FILE).unwrap();
+        let htpasswd = super::Htpasswd::new(path.clone());
+        let verify = |name: &str, password: &str| tokio_test::block_on(htpasswd.verify(name, password));
+
+        assert!(verify("satoshi", "myPassword").unwrap());
+        assert!(!verify("satoshi", "nbusr123").unwrap());
+        assert!(verify("hal", "nbusr123").unwrap());
+        assert!(!verify("hal", "nbusr124").unwrap());
+        assert!(verify("nick", "U*U").unwrap());
+        assert!(!verify("nick", "U*V").unwrap());
+        assert!(!verify("craig", "nbusr123").unwrap());
+        assert!(verify("adam", "nbusr123").is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! Users of an LDAP directory authenticated by binding as them
+//!
+//! The DN of the user is derived from the user name using the configured template, so no search
+//! is needed and the dashboard doesn't need credentials of its own.
+
+use core::future::Future;
+use core::pin::Pin;
+use std::time::Duration;
+use tokio::time::timeout;
+use super::{Authenticator, ConfigError, Error, VerifyFuture};
+
+/// Placeholder in the DN template replaced by the user name
+const USER_PLACEHOLDER: &str = "{}";
+
+pub type BindFuture<'a> = Pin<Box<dyn Future<Output=Result<bool, ldap3::LdapError>> + Send + 'a>>;
+
+/// LDAP server, replaced by a stand-in in tests
+pub trait Directory: Send + Sync {
+    /// Returns `false` if the credentials are invalid
+    fn simple_bind<'a>(&'a self, dn: &'a str, password: &'a str) -> BindFuture<'a>;
+}
+
+pub struct Server {
+    url: String,
+}
+
+impl Server {
+    pub fn new(url: String) -> Self {
+        Server {
+            url,
+        }
+    }
+}
+
+/// Result code of failed bind (RFC 4511)
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Limit of each step so that an unresponsive server doesn't hang the login
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Directory for Server {
+    fn simple_bind<'a>(&'a self, dn: &'a str, password: &'a str) -> BindFuture<'a> {
+        Box::pin(async move {
+            let (connection, mut ldap) = timeout(TIMEOUT, ldap3::LdapConnAsync::new(&self.url)).await??;
+            // Errors of the connection are returned by the requests too
+            tokio::spawn(async move { let _ = connection.drive().await; });
+
+            let result = timeout(TIMEOUT, ldap.simple_bind(dn, password)).await??;
+            // failing to unbind doesn't change the result
+            let _ = timeout(TIMEOUT, ldap.unbind()).await;
+
+            match result.rc {
+                0 => Ok(true),
+                INVALID_CREDENTIALS => Ok(false),
+                _ => result.success().map(|_| true),
+            }
+        })
+    }
+}
+
+pub struct Ldap<D: Directory> {
+    directory: D,
+    user_dn: String,
+}
+
+impl<D: Directory> Ldap<D> {
+    /// `user_dn` is the DN of users with `{}` in place of the user name, e.g.
+    /// `uid={},ou=people,dc=example,dc=com`
+    pub fn new(directory: D, user_dn: String) -> Result<Self, ConfigError> {
+        if !user_dn.contains(USER_PLACEHOLDER) {
+            return Err(ConfigError::InvalidLdapUserDn);
+        }
+
+        Ok(Ldap {
+            directory,
+            user_dn,
+        })
+    }
+}
+
+impl<D: Directory> Authenticator for Ldap<D> {
+    fn verify<'a>(&'a self, name: &'a str, password: &'a str) -> VerifyFuture<'a> {
+        Box::pin(async move {
+            // Binding with empty password is an unauthenticated bind which succeeds
+            if password.is_empty() {
+                return Ok(false);
+            }
+
+            // User names can't contain characters that would need escaping in DN
+            let dn = self.user_dn.replace(USER_PLACEHOLDER, name);
+            self.directory.simple_bind(&dn, password).await.map_err(Error::Ldap)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{BindFuture, Directory, Ldap};
+    use crate::user::backend::Authenticator;
+
+    /// Stand-in for the LDAP server knowing DNs and passwords of its users
+    struct StandIn(HashMap<&'static str, &'static str>);
+
+    impl Directory for StandIn {
+        fn simple_bind<'a>(&'a self, dn: &'a str, password: &'a str) -> BindFuture<'a> {
+            // unauthenticated bind of real servers
+            let result = password.is_empty() || self.0.get(dn) == Some(&password);
+            Box::pin(async move { Ok(result) })
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let mut users = HashMap::new();
+        users.insert("uid=satoshi,ou=people,dc=example,dc=com", "nbusr123");
+        let ldap = Ldap::new(StandIn(users), "uid={},ou=people,dc=example,dc=com".to_owned()).unwrap();
+        let verify = |name: &str, password: &str| tokio_test::block_on(ldap.verify(name, password)).unwrap();
+
+        assert!(verify("satoshi", "nbusr123"));
+        assert!(!verify("satoshi", "nbusr124"));
+        assert!(!verify("satoshi", ""));
+        assert!(!verify("hal", "nbusr123"));
+        assert!(!verify("hal", ""));
+    }
+
+    #[test]
+    fn invalid_template() {
+        assert!(Ldap::new(StandIn(HashMap::new()), "ou=people,dc=example,dc=com".to_owned()).is_err());
+    }
+}
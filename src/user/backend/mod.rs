@@ -0,0 +1,133 @@
+//! Backends verifying passwords of users
+//!
+//! Only the password is checked by the backend, the user record with groups, two-factor
+//! authentication and sessions stays in the database. Users of external backends get a record
+//! the first time they log in if the backend is configured to create them. Users with a password
+//! hash in the database, like the administrator created by `/setup`, can still log in with it.
+
+use core::future::Future;
+use core::pin::Pin;
+
+pub mod htpasswd;
+#[cfg(feature = "ldap3")]
+pub mod ldap;
+#[cfg(feature = "pam")]
+pub mod pam;
+
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output=Result<bool, Error>> + Send + 'a>>;
+
+/// Source of passwords other than the database
+pub trait Authenticator: Send + Sync {
+    /// Returns `false` if the user doesn't exist in the backend or the password doesn't match
+    fn verify<'a>(&'a self, name: &'a str, password: &'a str) -> VerifyFuture<'a>;
+}
+
+pub enum Backend {
+    /// Password hashes stored in the database
+    Local,
+    External {
+        authenticator: Box<dyn Authenticator>,
+        /// Users accepted by the authenticator who don't have a record yet get one, otherwise
+        /// they are rejected
+        create_users: bool,
+    },
+}
+
+impl Backend {
+    pub fn configure(kind: Kind, settings: Settings) -> Result<Self, ConfigError> {
+        let create_users = settings.create_users;
+        let external = |authenticator: Box<dyn Authenticator>| Backend::External { authenticator, create_users, };
+
+        match kind {
+            Kind::Local => Ok(Backend::Local),
+            #[cfg(feature = "pam")]
+            Kind::Pam => Ok(external(Box::new(pam::Pam::new(settings.pam_service)))),
+            #[cfg(not(feature = "pam"))]
+            Kind::Pam => Err(ConfigError::NotCompiled { backend: "pam", feature: "pam" }),
+            Kind::Htpasswd => Ok(external(Box::new(htpasswd::Htpasswd::new(settings.htpasswd_file.into())))),
+            #[cfg(feature = "ldap3")]
+            Kind::Ldap => {
+                let user_dn = settings.ldap_user_dn.ok_or(ConfigError::MissingLdapUserDn)?;
+                let ldap = ldap::Ldap::new(ldap::Server::new(settings.ldap_url), user_dn)?;
+                Ok(external(Box::new(ldap)))
+            },
+            #[cfg(not(feature = "ldap3"))]
+            Kind::Ldap => Err(ConfigError::NotCompiled { backend: "ldap", feature: "ldap3" }),
+        }
+    }
+
+    /// Whether users accepted by the backend get a record on their first login
+    pub fn creates_users(&self) -> bool {
+        match self {
+            Backend::Local => false,
+            Backend::External { create_users, .. } => *create_users,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Local,
+    Pam,
+    Htpasswd,
+    Ldap,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid authentication backend '{input}', expected local, pam, htpasswd or ldap")]
+pub struct InvalidKind {
+    input: String,
+}
+
+impl std::str::FromStr for Kind {
+    type Err = InvalidKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Kind::Local),
+            "pam" => Ok(Kind::Pam),
+            "htpasswd" => Ok(Kind::Htpasswd),
+            "ldap" => Ok(Kind::Ldap),
+            _ => Err(InvalidKind { input: s.to_owned(), }),
+        }
+    }
+}
+
+/// Settings of all backends, only the ones of the chosen backend are used
+pub struct Settings {
+    pub pam_service: String,
+    pub htpasswd_file: String,
+    pub ldap_url: String,
+    pub ldap_user_dn: Option<String>,
+    pub create_users: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[cfg(feature = "ldap3")]
+    #[error("ldap_user_dn is required by the LDAP backend")]
+    MissingLdapUserDn,
+    #[cfg(feature = "ldap3")]
+    #[error("ldap_user_dn must contain {{}} which is replaced by the user name")]
+    InvalidLdapUserDn,
+    #[cfg(not(all(feature = "pam", feature = "ldap3")))]
+    #[error("the {backend} backend isn't available, rebuild with the {feature} feature")]
+    NotCompiled { backend: &'static str, feature: &'static str },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[cfg(feature = "pam")]
+    #[error("failed to start PAM authentication")]
+    Pam(#[source] ::pam::PamError),
+    #[error("can't read htpasswd file {path}")]
+    HtpasswdRead { path: std::path::PathBuf, #[source] error: std::io::Error },
+    #[error("unsupported hash of user {user} in the htpasswd file, use bcrypt")]
+    HtpasswdUnsupportedHash { user: String },
+    #[cfg(feature = "ldap3")]
+    #[error("LDAP request failed")]
+    Ldap(#[source] ldap3::LdapError),
+    #[error("the authentication task failed")]
+    TaskJoin(#[source] tokio::task::JoinError),
+}
@@ -0,0 +1,38 @@
+//! Accounts of the system checked by PAM
+//!
+//! The PAM service has to be configured in `/etc/pam.d`. Checking passwords of other users with
+//! `pam_unix` requires read access to `/etc/shadow`.
+
+use super::{Authenticator, Error, VerifyFuture};
+
+pub struct Pam {
+    service: String,
+}
+
+impl Pam {
+    pub fn new(service: String) -> Self {
+        Pam {
+            service,
+        }
+    }
+}
+
+impl Authenticator for Pam {
+    fn verify<'a>(&'a self, name: &'a str, password: &'a str) -> VerifyFuture<'a> {
+        let service = self.service.clone();
+        let name = name.to_owned();
+        let password = password.to_owned();
+
+        Box::pin(async move {
+            // PAM modules block, often deliberately delaying failures
+            tokio::task::spawn_blocking(move || {
+                let mut authenticator = ::pam::Authenticator::with_password(&service).map_err(Error::Pam)?;
+                authenticator.get_handler().set_credentials(name, password);
+                // unknown users, wrong passwords and failures of the modules all reject the login
+                Ok(authenticator.authenticate().is_ok())
+            })
+            .await
+            .map_err(Error::TaskJoin)?
+        })
+    }
+}
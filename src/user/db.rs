@@ -1,13 +1,145 @@
 use core::future::Future;
-use super::types::AuthToken;
+use core::pin::Pin;
+use std::time::SystemTime;
+use super::types::{AuthTokenHash, SessionId, TotpSecret, RecoveryCodeHash, LoginChallenge, InviteId, InviteTokenHash, OidcCodeHash, OidcAccessTokenHash, ApiTokenId, ApiTokenHash, ApiScope, LinkingKey, AuditEvent, AuditOutcome};
 use crate::primitives::Stringly;
 
+/// Boxed future returned by database methods that don't need a dedicated future type.
+pub type DbFuture<T, E> = Pin<Box<dyn 'static + Send + Future<Output=Result<T, E>>>>;
+
 #[derive(Clone, Debug)]
 pub struct UserRecord {
     pub name: super::Name,
-    pub hardened_password: super::types::HardenedPassword,
-    pub salt: super::types::Salt,
-    pub cookie: Option<AuthToken>,
+    pub password_hash: super::PasswordHash,
+    /// Disabled users can't log in and their existing sessions are rejected
+    pub disabled: bool,
+    pub groups: Vec<super::Group>,
+    /// Present if the user enrolled in two-factor authentication
+    pub totp_secret: Option<TotpSecret>,
+}
+
+impl UserRecord {
+    /// Returns `true` if the user can currently administer the dashboard
+    pub fn is_active_admin(&self) -> bool {
+        !self.disabled && self.groups.iter().any(|group| group.as_ref() == super::Group::ADMINS)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionRecord {
+    pub id: SessionId,
+    pub user_name: super::Name,
+    /// The token itself is only known to the client
+    pub auth_token_hash: AuthTokenHash,
+    pub created: SystemTime,
+    pub last_seen: SystemTime,
+    pub user_agent: Option<String>,
+    pub label: Option<String>,
+}
+
+/// Login attempt with correct password waiting for the second factor
+#[derive(Clone, Debug)]
+pub struct LoginChallengeRecord {
+    pub token: LoginChallenge,
+    pub user_name: super::Name,
+    pub created: SystemTime,
+    /// Label of the session that will be created after successful verification
+    pub label: Option<String>,
+    /// Whether the cookie of the session should be persistent
+    pub remember: bool,
+}
+
+/// Invitation allowing someone to create an account
+#[derive(Clone, Debug)]
+pub struct InviteRecord {
+    pub id: InviteId,
+    /// The token itself is only known to the invitee
+    pub token_hash: InviteTokenHash,
+    pub created_by: super::Name,
+    pub created: SystemTime,
+    pub expires: SystemTime,
+    /// Groups the new user will be member of
+    pub groups: Vec<super::Group>,
+}
+
+/// Authorization code of the OpenID Connect provider waiting to be exchanged for tokens
+#[derive(Clone, Debug)]
+pub struct OidcCodeRecord {
+    /// The code itself is only known to the app
+    pub code_hash: OidcCodeHash,
+    /// Name of the app the code was issued to
+    pub client_id: String,
+    pub user_name: super::Name,
+    /// Groups of the user at the time of authorization, included in the ID token
+    pub groups: Vec<super::Group>,
+    pub redirect_uri: String,
+    /// PKCE challenge, base64url encoded SHA-256 of the verifier
+    pub code_challenge: Option<String>,
+    pub nonce: Option<String>,
+    pub scopes: Vec<String>,
+    pub created: SystemTime,
+}
+
+/// Access token issued by the OpenID Connect provider
+#[derive(Clone, Debug)]
+pub struct OidcTokenRecord {
+    /// The token itself is only known to the app
+    pub token_hash: OidcAccessTokenHash,
+    pub client_id: String,
+    pub user_name: super::Name,
+    pub scopes: Vec<String>,
+    pub expires: SystemTime,
+}
+
+/// Personal API token used by scripts instead of the session cookies
+#[derive(Clone, Debug)]
+pub struct ApiTokenRecord {
+    pub id: ApiTokenId,
+    /// The token itself is only known to the user
+    pub token_hash: ApiTokenHash,
+    pub user_name: super::Name,
+    /// Chosen by the user to recognize the token
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created: SystemTime,
+    pub expires: SystemTime,
+    pub last_used: Option<SystemTime>,
+}
+
+/// Key of a Lightning wallet the user can log in with using LNURL-auth
+#[derive(Clone, Debug)]
+pub struct LnurlKeyRecord {
+    pub key: LinkingKey,
+    pub user_name: super::Name,
+    pub created: SystemTime,
+}
+
+/// Entry of the audit log
+///
+/// The records are kept after the user is deleted.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    pub event: AuditEvent,
+    /// The user name entered by the client, it may not exist
+    pub user_name: super::Name,
+    pub client_address: Option<std::net::IpAddr>,
+    pub user_agent: Option<String>,
+    /// Present if the event concerns an app
+    pub app: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Explanation of the outcome, e.g. the message of the entry point rejecting the user
+    pub message: Option<String>,
+}
+
+/// Selects a page of audit records
+#[derive(Clone, Debug)]
+pub struct AuditQuery {
+    /// Returns only records of this user if present
+    pub user_name: Option<super::Name>,
+    /// Number of newest records to skip
+    pub offset: u32,
+    pub limit: u32,
 }
 
 #[derive(Debug)]
@@ -21,11 +153,78 @@ pub trait UserDb {
     type GetUserFuture: Future<Output=Result<Option<UserRecord>, Self::GetUserError>>;
     type InsertUserError: std::error::Error;
     type InsertUserFuture: Future<Output=Result<(), InsertUserError<Self::InsertUserError>>>;
-    type SetCookieError: std::error::Error;
-    type SetCookieFuture: Future<Output=Result<(), Self::SetCookieError>>;
+    type UpdateUserError: 'static + std::error::Error + Send;
+    type SessionError: 'static + std::error::Error + Send;
 
     fn get_user<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>) -> Self::GetUserFuture;
     /// Must NOT overrite existing user!
     fn insert_new_user(&mut self, record: UserRecord) -> Self::InsertUserFuture;
-    fn set_cookie<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, value: Option<AuthToken>) -> Self::SetCookieFuture;
+    /// Returns all users ordered by name
+    fn list_users(&mut self) -> DbFuture<Vec<UserRecord>, Self::GetUserError>;
+    /// Returns `true` if at least one user exists, cheaper than `list_users`
+    fn has_users(&mut self) -> DbFuture<bool, Self::GetUserError>;
+    /// Returns `false` if the user doesn't exist
+    fn set_disabled<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, disabled: bool) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Deletes the user together with all their sessions, returns `false` if the user doesn't exist
+    fn delete_user<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Replaces the groups the user is member of, returns `false` if the user doesn't exist
+    fn set_groups<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, groups: Vec<super::Group>) -> DbFuture<bool, Self::UpdateUserError>;
+    fn set_password<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, password_hash: super::PasswordHash) -> DbFuture<(), Self::UpdateUserError>;
+    fn create_session(&mut self, record: SessionRecord) -> DbFuture<(), Self::SessionError>;
+    /// Returns all sessions of the user, oldest first
+    fn get_sessions<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>) -> DbFuture<Vec<SessionRecord>, Self::SessionError>;
+    fn touch_session(&mut self, id: SessionId, last_seen: SystemTime) -> DbFuture<(), Self::SessionError>;
+    /// Returns `false` if the user had no such session
+    fn revoke_session<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>, id: SessionId) -> DbFuture<bool, Self::SessionError>;
+    /// Revokes all sessions of the user except the one given
+    fn revoke_all_sessions<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>, except: Option<SessionId>) -> DbFuture<(), Self::SessionError>;
+    /// Replaces the TOTP secret and all recovery codes, `None` disables two-factor authentication.
+    /// Returns `false` if the user doesn't exist
+    fn set_totp<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, secret: Option<TotpSecret>, recovery_codes: Vec<RecoveryCodeHash>) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Atomically records the TOTP time step as used, returns `false` if the same or later step
+    /// was already used, which prevents replaying codes. Steps are never reset because they only
+    /// grow with time.
+    fn use_totp_step<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, step: u64) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Removes the recovery code, returns `false` if the user had no such code
+    fn use_recovery_code<S: 'static + Stringly + Send + Sync>(&mut self, name: super::Name<S>, code: RecoveryCodeHash) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Stores the challenge and removes the ones created before `created_since`
+    fn create_login_challenge(&mut self, record: LoginChallengeRecord, created_since: SystemTime) -> DbFuture<(), Self::SessionError>;
+    /// Removes the challenge and returns it if it existed, so that each challenge is used at most once
+    ///
+    /// The ones created before `created_since` are removed too.
+    fn take_login_challenge<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>, token: LoginChallenge, created_since: SystemTime) -> DbFuture<Option<LoginChallengeRecord>, Self::SessionError>;
+    fn create_invite(&mut self, record: InviteRecord) -> DbFuture<(), Self::UpdateUserError>;
+    /// Returns all invites including expired ones, oldest first
+    fn list_invites(&mut self) -> DbFuture<Vec<InviteRecord>, Self::UpdateUserError>;
+    fn get_invite(&mut self, token_hash: InviteTokenHash) -> DbFuture<Option<InviteRecord>, Self::UpdateUserError>;
+    /// Removes the invite and returns it if it existed, so that each invite is used at most once
+    fn take_invite(&mut self, token_hash: InviteTokenHash) -> DbFuture<Option<InviteRecord>, Self::UpdateUserError>;
+    /// Returns `false` if there's no such invite
+    fn revoke_invite(&mut self, id: InviteId) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Stores the code and removes the ones created before `created_since`, used codes are
+    /// removed by `take_oidc_code`
+    fn create_oidc_code(&mut self, record: OidcCodeRecord, created_since: SystemTime) -> DbFuture<(), Self::SessionError>;
+    /// Removes the code and returns it if it existed, so that each code is used at most once
+    fn take_oidc_code(&mut self, code_hash: OidcCodeHash) -> DbFuture<Option<OidcCodeRecord>, Self::SessionError>;
+    /// Stores the token and removes the ones expired at `now`
+    fn create_oidc_token(&mut self, record: OidcTokenRecord, now: SystemTime) -> DbFuture<(), Self::SessionError>;
+    fn get_oidc_token(&mut self, token_hash: OidcAccessTokenHash) -> DbFuture<Option<OidcTokenRecord>, Self::SessionError>;
+    fn create_api_token(&mut self, record: ApiTokenRecord) -> DbFuture<(), Self::SessionError>;
+    fn get_api_token(&mut self, token_hash: ApiTokenHash) -> DbFuture<Option<ApiTokenRecord>, Self::SessionError>;
+    /// Returns all tokens of the user including expired ones, oldest first
+    fn list_api_tokens<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>) -> DbFuture<Vec<ApiTokenRecord>, Self::SessionError>;
+    fn touch_api_token(&mut self, id: ApiTokenId, last_used: SystemTime) -> DbFuture<(), Self::SessionError>;
+    /// Returns `false` if the user had no such token
+    fn revoke_api_token<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>, id: ApiTokenId) -> DbFuture<bool, Self::SessionError>;
+    /// Returns `false` if the key is already linked to any user
+    fn link_lnurl_key(&mut self, record: LnurlKeyRecord) -> DbFuture<bool, Self::UpdateUserError>;
+    fn get_lnurl_key(&mut self, key: LinkingKey) -> DbFuture<Option<LnurlKeyRecord>, Self::UpdateUserError>;
+    /// Returns all keys of the user, oldest first
+    fn list_lnurl_keys<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>) -> DbFuture<Vec<LnurlKeyRecord>, Self::UpdateUserError>;
+    /// Returns `false` if the user had no such key
+    fn unlink_lnurl_key<S: 'static + Stringly + Send + Sync>(&mut self, user_name: super::Name<S>, key: LinkingKey) -> DbFuture<bool, Self::UpdateUserError>;
+    /// Stores the record and removes the ones older than `retain_since`
+    fn log_audit_event(&mut self, record: AuditRecord, retain_since: SystemTime) -> DbFuture<(), Self::UpdateUserError>;
+    /// Returns the selected records, newest first
+    fn list_audit_events(&mut self, query: AuditQuery) -> DbFuture<Vec<AuditRecord>, Self::UpdateUserError>;
 }
@@ -1,8 +1,20 @@
 pub mod types;
+pub mod password;
 pub mod db;
+pub mod api;
+pub mod backend;
 
 pub use types::UserName as Name;
+pub use types::GroupName as Group;
 pub use db::UserDb as Db;
 pub use db::UserRecord as DbRecord;
+pub use db::SessionRecord as DbSession;
+pub use db::InviteRecord as DbInvite;
+pub use db::OidcCodeRecord as DbOidcCode;
+pub use db::OidcTokenRecord as DbOidcToken;
+pub use db::ApiTokenRecord as DbApiToken;
+pub use db::LnurlKeyRecord as DbLnurlKey;
+pub use db::AuditRecord as DbAuditRecord;
 pub use types::AuthenticatedUser as Authenticated;
+pub use password::PasswordHash;
 pub use db::InsertUserError as InsertError;
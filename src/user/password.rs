@@ -0,0 +1,358 @@
+//! Password hashing
+//!
+//! Hashes are stored as PHC strings which describe the algorithm and its parameters, so that they
+//! can be changed without invalidating existing passwords.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const ARGON2_VERSION: u32 = 0x13;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    Argon2id,
+    Scrypt,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid password hashing algorithm '{input}', expected argon2id or scrypt")]
+pub struct InvalidAlgorithm {
+    input: String,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = InvalidAlgorithm;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2id" => Ok(Algorithm::Argon2id),
+            "scrypt" => Ok(Algorithm::Scrypt),
+            _ => Err(InvalidAlgorithm { input: s.to_owned(), }),
+        }
+    }
+}
+
+/// Parameters used for hashing new passwords
+#[derive(Debug, Clone)]
+pub struct Params {
+    pub algorithm: Algorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub scrypt_log_n: u8,
+    pub scrypt_r: u32,
+    pub scrypt_p: u32,
+}
+
+impl Default for Params {
+    /// Argon2id with parameters recommended by OWASP, scrypt parameters are the ones used by
+    /// older versions
+    fn default() -> Self {
+        Params {
+            algorithm: Algorithm::Argon2id,
+            argon2_memory_kib: 19456,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            scrypt_log_n: 15,
+            scrypt_r: 8,
+            scrypt_p: 1,
+        }
+    }
+}
+
+/// Cheap parameters so that the tests are fast
+#[cfg(test)]
+pub fn test_params() -> Params {
+    Params {
+        argon2_memory_kib: 64,
+        argon2_iterations: 1,
+        ..Default::default()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid password hashing parameters")]
+pub struct InvalidParams;
+
+impl Params {
+    /// Checks that the parameters are accepted by the algorithm so that hashing can't fail later
+    pub fn check(&self) -> Result<(), InvalidParams> {
+        compute(&self.to_hash_params(), b"", &[0; SALT_LEN], HASH_LEN)
+            .map(drop)
+            .ok_or(InvalidParams)
+    }
+
+    fn to_hash_params(&self) -> HashParams {
+        match self.algorithm {
+            Algorithm::Argon2id => HashParams::Argon2id {
+                memory_kib: self.argon2_memory_kib,
+                iterations: self.argon2_iterations,
+                parallelism: self.argon2_parallelism,
+            },
+            Algorithm::Scrypt => HashParams::Scrypt {
+                log_n: self.scrypt_log_n,
+                r: self.scrypt_r,
+                p: self.scrypt_p,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum HashParams {
+    Argon2id { memory_kib: u32, iterations: u32, parallelism: u32, },
+    Scrypt { log_n: u8, r: u32, p: u32, },
+}
+
+/// Returns `None` if the parameters are invalid
+fn compute(params: &HashParams, password: &[u8], salt: &[u8], output_len: usize) -> Option<Vec<u8>> {
+    match *params {
+        HashParams::Argon2id { memory_kib, iterations, parallelism, } => {
+            let config = argon2::Config {
+                variant: argon2::Variant::Argon2id,
+                version: argon2::Version::Version13,
+                mem_cost: memory_kib,
+                time_cost: iterations,
+                lanes: parallelism,
+                hash_length: u32::try_from(output_len).ok()?,
+                ..Default::default()
+            };
+            argon2::hash_raw(password, salt, &config).ok()
+        },
+        HashParams::Scrypt { log_n, r, p, } => {
+            let params = scrypt::ScryptParams::new(log_n, r, p).ok()?;
+            let mut output = vec![0; output_len];
+            scrypt::scrypt(password, salt, &params, &mut output).ok()?;
+            Some(output)
+        },
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("unsupported password hashing algorithm {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("unsupported version of argon2")]
+    UnsupportedVersion,
+    #[error("malformed PHC string")]
+    Malformed,
+    #[error("invalid base64 in PHC string")]
+    Base64(#[from] base64::DecodeError),
+}
+
+/// Self-describing password hash in PHC string format
+#[derive(Clone)]
+pub struct PasswordHash {
+    encoded: String,
+    params: HashParams,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hashes the password with random salt
+    ///
+    /// The parameters must be checked by `Params::check` beforehand.
+    pub fn new(password: &str, params: &Params) -> Self {
+        let salt = rand::random::<[u8; SALT_LEN]>().to_vec();
+        let params = params.to_hash_params();
+        let hash = compute(&params, password.as_bytes(), &salt, HASH_LEN).expect("password hashing parameters were not checked");
+        let encoded = encode(&params, &salt, &hash);
+
+        PasswordHash {
+            encoded,
+            params,
+            salt,
+            hash,
+        }
+    }
+
+    /// Compares the password with the hash in constant time
+    pub fn verify(&self, password: &str) -> bool {
+        match compute(&self.params, password.as_bytes(), &self.salt, self.hash.len()) {
+            Some(hash) => hash
+                .iter()
+                .zip(&self.hash)
+                .fold(0, |acc, (a, b)| acc | (a ^ b)) == 0,
+            None => false,
+        }
+    }
+
+    /// Returns `true` if the hash was created using different algorithm or parameters
+    pub fn needs_rehash(&self, params: &Params) -> bool {
+        self.params != params.to_hash_params() || self.salt.len() != SALT_LEN || self.hash.len() != HASH_LEN
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.encoded
+    }
+}
+
+fn encode(params: &HashParams, salt: &[u8], hash: &[u8]) -> String {
+    let salt = base64::encode_config(salt, base64::STANDARD_NO_PAD);
+    let hash = base64::encode_config(hash, base64::STANDARD_NO_PAD);
+
+    match params {
+        HashParams::Argon2id { memory_kib, iterations, parallelism, } => format!("$argon2id$v={}$m={},t={},p={}${}${}", ARGON2_VERSION, memory_kib, iterations, parallelism, salt, hash),
+        HashParams::Scrypt { log_n, r, p, } => format!("$scrypt$ln={},r={},p={}${}${}", log_n, r, p, salt, hash),
+    }
+}
+
+/// Parses comma-separated `key=value` pairs, all keys must be present exactly once
+fn parse_params<'a>(params: &'a str, keys: &[&str]) -> Result<Vec<&'a str>, ParseError> {
+    let mut values = vec![None; keys.len()];
+    for pair in params.split(',') {
+        let mut pair = pair.splitn(2, '=');
+        let key = pair.next().ok_or(ParseError::Malformed)?;
+        let value = pair.next().ok_or(ParseError::Malformed)?;
+        let pos = keys.iter().position(|known| *known == key).ok_or(ParseError::Malformed)?;
+        if values[pos].replace(value).is_some() {
+            return Err(ParseError::Malformed);
+        }
+    }
+
+    values.into_iter().collect::<Option<_>>().ok_or(ParseError::Malformed)
+}
+
+fn parse_num<T: std::str::FromStr>(value: &str) -> Result<T, ParseError> {
+    value.parse().map_err(|_| ParseError::Malformed)
+}
+
+impl std::str::FromStr for PasswordHash {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split('$').collect::<Vec<_>>();
+        if fields.len() < 2 || !fields[0].is_empty() {
+            return Err(ParseError::Malformed);
+        }
+
+        let (params, salt, hash) = match (fields[1], fields.len()) {
+            ("argon2id", 6) => {
+                if fields[2] != format!("v={}", ARGON2_VERSION) {
+                    return Err(ParseError::UnsupportedVersion);
+                }
+                let values = parse_params(fields[3], &["m", "t", "p"])?;
+                let params = HashParams::Argon2id {
+                    memory_kib: parse_num(values[0])?,
+                    iterations: parse_num(values[1])?,
+                    parallelism: parse_num(values[2])?,
+                };
+                (params, fields[4], fields[5])
+            },
+            ("scrypt", 5) => {
+                let values = parse_params(fields[2], &["ln", "r", "p"])?;
+                let params = HashParams::Scrypt {
+                    log_n: parse_num(values[0])?,
+                    r: parse_num(values[1])?,
+                    p: parse_num(values[2])?,
+                };
+                (params, fields[3], fields[4])
+            },
+            ("argon2id", _) | ("scrypt", _) => return Err(ParseError::Malformed),
+            (algorithm, _) => return Err(ParseError::UnsupportedAlgorithm(algorithm.to_owned())),
+        };
+
+        let salt = base64::decode_config(salt, base64::STANDARD_NO_PAD)?;
+        let hash = base64::decode_config(hash, base64::STANDARD_NO_PAD)?;
+        if hash.is_empty() {
+            return Err(ParseError::Malformed);
+        }
+
+        Ok(PasswordHash {
+            encoded: s.to_owned(),
+            params,
+            salt,
+            hash,
+        })
+    }
+}
+
+// The hash is not secret per se but there's no reason to put it into logs
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PasswordHash({:?})", self.params)
+    }
+}
+
+impl tokio_postgres::types::ToSql for PasswordHash {
+    fn to_sql(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        self.as_str().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+        <&str>::accepts(ty)
+    }
+
+    fn to_sql_checked(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        self.as_str().to_sql_checked(ty, out)
+    }
+}
+
+impl<'a> tokio_postgres::types::FromSql<'a> for PasswordHash {
+    fn from_sql(ty: &tokio_postgres::types::Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        Ok(<&'a str>::from_sql(ty, raw)?.parse()?)
+    }
+
+    fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+        <&'a str>::accepts(ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PasswordHash, Params, Algorithm};
+
+    fn weak_params(algorithm: Algorithm) -> Params {
+        Params {
+            algorithm,
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+            scrypt_log_n: 4,
+            scrypt_r: 8,
+            scrypt_p: 1,
+        }
+    }
+
+    #[test]
+    fn hash_and_verify() {
+        for algorithm in &[Algorithm::Argon2id, Algorithm::Scrypt] {
+            let params = weak_params(*algorithm);
+            params.check().unwrap();
+            let hash = PasswordHash::new("nbusr123", &params);
+            let hash = hash.as_str().parse::<PasswordHash>().unwrap();
+            assert!(hash.verify("nbusr123"));
+            assert!(!hash.verify("nbusr124"));
+            assert!(!hash.needs_rehash(&params));
+        }
+    }
+
+    #[test]
+    fn needs_rehash() {
+        let params = weak_params(Algorithm::Scrypt);
+        let hash = PasswordHash::new("nbusr123", &params);
+        assert!(hash.needs_rehash(&weak_params(Algorithm::Argon2id)));
+        assert!(hash.needs_rehash(&Params { scrypt_log_n: 5, ..params }));
+    }
+
+    // Reference hash computed by Python's hashlib.scrypt
+    #[test]
+    fn scrypt_reference() {
+        let hash = "$scrypt$ln=4,r=8,p=1$c29tZXNhbHQ$7xe5L3Roj67jYaBKf3ePT2Y6rVHHGUWO44Z8iz+O6PQ".parse::<PasswordHash>().unwrap();
+        assert!(hash.verify("password"));
+        assert!(!hash.verify("Password"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!("".parse::<PasswordHash>().is_err());
+        assert!("$md5$foo$bar".parse::<PasswordHash>().is_err());
+        assert!("$scrypt$ln=15,r=8$AAAA$AAAA".parse::<PasswordHash>().is_err());
+        assert!("$argon2id$v=16$m=64,t=1,p=1$AAAA$AAAA".parse::<PasswordHash>().is_err());
+        assert!(!Params { argon2_memory_kib: 0, ..Params::default() }.check().is_ok());
+    }
+}
@@ -1,7 +1,10 @@
 str_char_whitelist_newtype!(UserName, UserNameError, "user name", |c| c != '_' && c != '-' && !('a'..='z').contains(&c));
 
-impl UserName {
-    pub const ADMIN: UserName<&'static str> = UserName("admin");
+str_char_whitelist_newtype!(GroupName, GroupNameError, "group name", |c| c != '_' && c != '-' && !('a'..='z').contains(&c));
+
+impl GroupName {
+    /// Members of this group are administrators
+    pub const ADMINS: GroupName<&'static str> = GroupName("admins");
 }
 
 pub use authenticated::AuthenticatedUser;
@@ -9,13 +12,26 @@ pub use authenticated::AuthenticatedUser;
 mod authenticated {
     pub struct AuthenticatedUser {
         name: super::UserName,
+        session_id: Option<super::SessionId>,
+        groups: Vec<super::GroupName>,
     }
 
     impl AuthenticatedUser {
         /// Must only be called if it was checked that the user is logged in
-        pub fn user_logged_in(name: super::UserName) -> Self {
+        pub fn user_logged_in(name: super::UserName, session_id: super::SessionId, groups: Vec<super::GroupName>) -> Self {
+            AuthenticatedUser {
+                name,
+                session_id: Some(session_id),
+                groups,
+            }
+        }
+
+        /// Must only be called if the API token of the user was checked
+        pub fn token_authenticated(name: super::UserName, groups: Vec<super::GroupName>) -> Self {
             AuthenticatedUser {
                 name,
+                session_id: None,
+                groups,
             }
         }
 
@@ -23,42 +39,457 @@ mod authenticated {
             &self.name
         }
 
+        pub fn user_name(&self) -> &super::UserName {
+            &self.name
+        }
+
+        /// Identifier of the session the user authenticated with, `None` for API tokens
+        pub fn session_id(&self) -> Option<super::SessionId> {
+            self.session_id
+        }
+
+        pub fn groups(&self) -> &[super::GroupName] {
+            &self.groups
+        }
+
+        pub fn is_member(&self, group: &str) -> bool {
+            self.groups.iter().any(|member_of| **member_of == *group)
+        }
+
         pub fn is_admin(&self) -> bool {
-            self.name.as_ref() == crate::user::Name::ADMIN
+            self.is_member(&super::GroupName::ADMINS)
         }
 
-        pub async fn logout<Db: crate::user::Db>(&self, database: &mut Db) -> Result<(), Db::SetCookieError> {
+        /// Revokes only the current session, other devices stay logged in
+        pub async fn logout<Db: crate::user::Db>(&self, database: &mut Db) -> Result<bool, Db::SessionError> {
+            let session_id = match self.session_id {
+                Some(session_id) => session_id,
+                None => return Ok(false),
+            };
             let name = self.name.as_ref().into_owned();
             database
-                .set_cookie(name, None)
+                .revoke_session(name, session_id)
                 .await
         }
+
+        /// Revokes all sessions of the user including the current one
+        pub async fn logout_everywhere<Db: crate::user::Db>(&self, database: &mut Db) -> Result<(), Db::SessionError> {
+            let name = self.name.as_ref().into_owned();
+            database
+                .revoke_all_sessions(name, None)
+                .await
+        }
+    }
+}
+
+// cookie
+token_newtype!(AuthToken, 16, AuthTokenError);
+
+// Key of the hash of authentication tokens, stored outside of the database so that a dump of the
+// database isn't enough to hijack sessions
+token_newtype!(TokenKey, 32, TokenKeyError);
+
+// Only the hash of `AuthToken` is stored in the database
+token_newtype!(AuthTokenHash, 32, AuthTokenHashError);
+
+fn keyed_hash(key: &TokenKey, data: &[u8]) -> [u8; 32] {
+    use hmac::{Mac, NewMac};
+
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_varkey(&key.0).expect("HMAC accepts keys of any length");
+    mac.update(data);
+
+    let mut output = [0; 32];
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    output
+}
+
+impl AuthToken {
+    pub fn hash(&self, key: &TokenKey) -> AuthTokenHash {
+        AuthTokenHash(keyed_hash(key, &self.0))
+    }
+}
+
+// Cookie forwarded by the reverse proxy from requests to other apps, it's derived from the stored
+// hash so it can't be turned back into `AuthToken` and is only accepted by `/auth/verify`
+token_newtype!(ForwardAuthToken, 32, ForwardAuthTokenError);
+
+impl AuthTokenHash {
+    pub fn forward_auth_token(&self, key: &TokenKey) -> ForwardAuthToken {
+        let mut data = b"forward-auth:".to_vec();
+        data.extend_from_slice(&self.0);
+        ForwardAuthToken(keyed_hash(key, &data))
+    }
+}
+
+// Public identifier of a session, unlike `AuthToken` it's not secret
+token_newtype!(SessionId, 16, SessionIdError);
+
+// Secret part of invitation links
+token_newtype!(InviteToken, 16, InviteTokenError);
+
+// Only the hash of `InviteToken` is stored so that a dump of the database can't be used to sign up
+token_newtype!(InviteTokenHash, 32, InviteTokenHashError);
+
+impl InviteToken {
+    pub fn hash(&self, key: &TokenKey) -> InviteTokenHash {
+        InviteTokenHash(keyed_hash(key, &self.0))
+    }
+}
+
+// Public identifier of an invitation used to revoke it
+token_newtype!(InviteId, 16, InviteIdError);
+
+// Authorization code of the OpenID Connect provider, exchanged by the app for tokens
+token_newtype!(OidcCode, 16, OidcCodeError);
+
+// Only the hash of `OidcCode` is stored
+token_newtype!(OidcCodeHash, 32, OidcCodeHashError);
+
+impl OidcCode {
+    pub fn hash(&self, key: &TokenKey) -> OidcCodeHash {
+        OidcCodeHash(keyed_hash(key, &self.0))
+    }
+}
+
+// Access token of the OpenID Connect provider accepted by the userinfo endpoint
+token_newtype!(OidcAccessToken, 16, OidcAccessTokenError);
+
+// Only the hash of `OidcAccessToken` is stored
+token_newtype!(OidcAccessTokenHash, 32, OidcAccessTokenHashError);
+
+impl OidcAccessToken {
+    pub fn hash(&self, key: &TokenKey) -> OidcAccessTokenHash {
+        OidcAccessTokenHash(keyed_hash(key, &self.0))
+    }
+}
+
+// Personal API token sent in the `Authorization: Bearer` header by scripts
+token_newtype!(ApiToken, 16, ApiTokenError);
+
+// Only the hash of `ApiToken` is stored
+token_newtype!(ApiTokenHash, 32, ApiTokenHashError);
+
+impl ApiToken {
+    pub fn hash(&self, key: &TokenKey) -> ApiTokenHash {
+        ApiTokenHash(keyed_hash(key, &self.0))
+    }
+}
+
+// Public identifier of an API token used to revoke it
+token_newtype!(ApiTokenId, 16, ApiTokenIdError);
+
+/// Operation an API token is allowed to do
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ApiScope {
+    /// Listing apps the user can open (`list-apps`)
+    ListApps,
+    /// Opening the given app (`open-app:<name>`)
+    OpenApp(crate::app::Name),
+}
+
+impl core::fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ApiScope::ListApps => write!(f, "list-apps"),
+            ApiScope::OpenApp(app) => write!(f, "open-app:{}", app),
+        }
     }
 }
 
-token_newtype!(Salt, 16, SaltError);
+#[derive(Debug, thiserror::Error)]
+pub enum ApiScopeError {
+    #[error("unknown scope {0}")]
+    Unknown(String),
+    #[error("invalid application name in scope")]
+    InvalidAppName(#[from] crate::app::NameError),
+}
+
+impl core::str::FromStr for ApiScope {
+    type Err = ApiScopeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        const OPEN_APP: &str = "open-app:";
+
+        if value == "list-apps" {
+            Ok(ApiScope::ListApps)
+        } else if value.starts_with(OPEN_APP) {
+            Ok(ApiScope::OpenApp(core::convert::TryFrom::try_from(value[OPEN_APP.len()..].to_owned())?))
+        } else {
+            Err(ApiScopeError::Unknown(value.to_owned()))
+        }
+    }
+}
+
+impl tokio_postgres::types::ToSql for ApiScope {
+    fn to_sql(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        self.to_string().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+        <&str>::accepts(ty)
+    }
+
+    fn to_sql_checked(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        self.to_string().to_sql_checked(ty, out)
+    }
+}
+
+impl<'a> tokio_postgres::types::FromSql<'a> for ApiScope {
+    fn from_sql(ty: &tokio_postgres::types::Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        Ok(<&str>::from_sql(ty, raw)?.parse()?)
+    }
+
+    fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+        <&str>::accepts(ty)
+    }
+}
+
+/// Number of seconds each TOTP code is valid for (RFC 6238 default)
+pub const TOTP_STEP_SECONDS: u64 = 30;
+
+// Shared secret of RFC 6238 TOTP, 160 bits as recommended by RFC 4226
+token_newtype!(TotpSecret, 20, TotpSecretError);
+
+impl TotpSecret {
+    /// Computes the six-digit code for given time step
+    pub fn code(&self, step: u64) -> u32 {
+        use hmac::{Mac, NewMac};
+
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_varkey(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // dynamic truncation as defined in RFC 4226
+        let offset = (hash[19] & 0x0f) as usize;
+        let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+        value % 1_000_000
+    }
 
-impl Salt {
-    pub const EMPTY: Salt = Salt([0; 16]);
+    /// Encodes the secret the way authenticator apps expect it
+    pub fn to_base32(self) -> String {
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &self.0)
+    }
 }
 
-token_newtype!(HardenedPassword, 32, HardenedPasswordError);
+// Recovery codes have 80 bits of entropy so a plain hash is sufficient, unlike passwords
+token_newtype!(RecoveryCodeHash, 32, RecoveryCodeHashError);
+
+impl RecoveryCodeHash {
+    /// Hashes the code ignoring case, spaces and dashes so that users don't have to retype it exactly
+    pub fn hash(code: &str) -> Self {
+        use sha2::Digest;
 
-impl HardenedPassword {
-    pub fn harden(password: &str, salt: &Salt) -> Self {
-        let params = scrypt::ScryptParams::recommended();
-        let mut output = HardenedPassword([0; 32]);
-        scrypt::scrypt(password.as_ref(), &salt.0, &params, &mut output.0)
-            // The only possible error is input error, which is impossible
-            // because we pass in a constant
-            .expect("Failed to run scrypt");
+        let mut hasher = sha2::Sha256::new();
+        for c in code.chars().filter(|c| *c != '-' && !c.is_whitespace()) {
+            let mut buf = [0; 4];
+            hasher.update(c.to_ascii_lowercase().encode_utf8(&mut buf).as_bytes());
+        }
 
+        let mut output = RecoveryCodeHash([0; 32]);
+        output.0.copy_from_slice(&hasher.finalize());
         output
     }
 }
 
-// cookie
-token_newtype!(AuthToken, 16, AuthTokenError);
+// Identifies a login attempt waiting for the second factor
+token_newtype!(LoginChallenge, 16, LoginChallengeError);
+
+// Challenge of LNURL-auth signed by the wallet, it's not secret because it's shown in the QR code
+token_newtype!(LnurlK1, 32, LnurlK1Error);
+
+impl LnurlK1 {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+// Binds the LNURL-auth challenge to the browser that displayed it so that someone else seeing the
+// QR code can't take over the login
+token_newtype!(LnurlSecret, 16, LnurlSecretError);
+
+/// Public key the wallet derived for this server, identifies the user in LNURL-auth
+///
+/// Stored and displayed as 33-byte compressed key.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct LinkingKey(secp256k1::PublicKey);
+
+impl LinkingKey {
+    pub fn as_public_key(&self) -> &secp256k1::PublicKey {
+        &self.0
+    }
+
+    /// Short form displayed to the user, the first bytes of the key after the parity byte
+    pub fn fingerprint(&self) -> String {
+        self.0.serialize()[1..9]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+impl From<secp256k1::PublicKey> for LinkingKey {
+    fn from(value: secp256k1::PublicKey) -> Self {
+        LinkingKey(value)
+    }
+}
+
+impl core::fmt::Display for LinkingKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for b in &self.0.serialize()[..] {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl slog::Value for LinkingKey {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LinkingKeyError {
+    #[error("the linking key is not hex-encoded")]
+    Hex(#[from] hex::FromHexError),
+    #[error("the linking key is not a valid public key")]
+    Invalid(#[from] secp256k1::Error),
+}
+
+impl core::str::FromStr for LinkingKey {
+    type Err = LinkingKeyError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(value)?;
+        Ok(LinkingKey(secp256k1::PublicKey::from_slice(&bytes)?))
+    }
+}
+
+impl tokio_postgres::types::ToSql for LinkingKey {
+    fn to_sql(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        (&self.0.serialize()[..]).to_sql(ty, out)
+    }
+
+    fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+        <&[u8]>::accepts(ty)
+    }
+
+    fn to_sql_checked(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        (&self.0.serialize()[..]).to_sql_checked(ty, out)
+    }
+}
+
+impl<'a> tokio_postgres::types::FromSql<'a> for LinkingKey {
+    fn from_sql(ty: &tokio_postgres::types::Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        Ok(LinkingKey(secp256k1::PublicKey::from_slice(<&'a [u8]>::from_sql(ty, raw)?)?))
+    }
+
+    fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+        <&'a [u8]>::accepts(ty)
+    }
+}
+
+/// Defines a fieldless enum stored in the database as its snake_case name
+macro_rules! str_enum {
+    ($(#[$meta:meta])* $name:ident, $error_name:ident, $what:expr, { $($(#[$variant_meta:meta])* $variant:ident => $string:literal,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)*
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $string,)*
+                }
+            }
+        }
+
+        impl core::fmt::Display for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        #[derive(Debug, thiserror::Error)]
+        #[error("unknown {} {input}", $what)]
+        pub struct $error_name {
+            input: String,
+        }
+
+        impl core::str::FromStr for $name {
+            type Err = $error_name;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                match value {
+                    $($string => Ok($name::$variant),)*
+                    _ => Err($error_name { input: value.to_owned(), }),
+                }
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl tokio_postgres::types::ToSql for $name {
+            fn to_sql(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+                self.as_str().to_sql(ty, out)
+            }
+
+            fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+                <&str>::accepts(ty)
+            }
+
+            fn to_sql_checked(&self, ty: &tokio_postgres::types::Type, out: &mut tokio_postgres::types::private::BytesMut) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + 'static + Sync + Send>> {
+                self.as_str().to_sql_checked(ty, out)
+            }
+        }
+
+        impl<'a> tokio_postgres::types::FromSql<'a> for $name {
+            fn from_sql(ty: &tokio_postgres::types::Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+                Ok(<&str>::from_sql(ty, raw)?.parse()?)
+            }
+
+            fn accepts(ty: &tokio_postgres::types::Type) -> bool {
+                <&str>::accepts(ty)
+            }
+        }
+    };
+}
+
+str_enum!(
+    /// Security-relevant action recorded in the audit log
+    AuditEvent, AuditEventError, "audit event", {
+        Login => "login",
+        Logout => "logout",
+        OpenApp => "open_app",
+    }
+);
+
+str_enum!(
+    /// Result of the action recorded in the audit log
+    AuditOutcome, AuditOutcomeError, "audit outcome", {
+        Success => "success",
+        /// The password was correct, the login continues with the second factor
+        SecondFactorRequired => "second_factor_required",
+        BadCredentials => "bad_credentials",
+        BadSecondFactor => "bad_second_factor",
+        Disabled => "disabled",
+        Throttled => "throttled",
+        /// The access list of the app doesn't allow the user
+        NotAllowed => "not_allowed",
+        NonAdmin => "non_admin",
+        /// The entry point of the app refused the user
+        Rejected => "rejected",
+        /// Internal error, the user may or may not be allowed
+        Failed => "failed",
+    }
+);
 
 #[cfg(test)]
 mod tests {
@@ -74,4 +505,45 @@ mod tests {
     test_str_val_err!(dot_user_name, UserName, "foo.bar");
     test_str_val_err!(at_user_name, UserName, "foo@bar");
     test_str_val_err!(slash_user_name, UserName, "foo/bar");
+
+    use super::GroupName;
+
+    test_str_val_ok!(simple_group_name, GroupName, "family");
+    test_str_val_ok!(dash_group_name, GroupName, "lightning-operators");
+    test_str_val_err!(space_group_name, GroupName, "foo bar");
+    test_str_val_err!(comma_group_name, GroupName, "foo,bar");
+
+    use super::{AuthToken, TokenKey};
+
+    #[test]
+    fn auth_token_hash() {
+        let token = "000102030405060708090a0b0c0d0e0f".parse::<AuthToken>().unwrap();
+        let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f".parse::<TokenKey>().unwrap();
+        assert_eq!(token.hash(&key).to_string(), "d8b99f2709a3ca74172cbe93824c1f29b23a0c1e9c21bd851ff2d2c39dbef14e");
+        assert_ne!(token.hash(&key), token.hash(&TokenKey::random()));
+    }
+
+    use super::{TotpSecret, RecoveryCodeHash};
+
+    // Test vectors from RFC 6238 appendix B, truncated to six digits
+    #[test]
+    fn totp_rfc_vectors() {
+        let secret = "3132333435363738393031323334353637383930".parse::<TotpSecret>().unwrap();
+        assert_eq!(secret.code(59 / 30), 287_082);
+        assert_eq!(secret.code(1_111_111_109 / 30), 81804);
+        assert_eq!(secret.code(1_234_567_890 / 30), 5924);
+        assert_eq!(secret.code(20_000_000_000 / 30), 353_130);
+    }
+
+    #[test]
+    fn totp_base32() {
+        let secret = "3132333435363738393031323334353637383930".parse::<TotpSecret>().unwrap();
+        assert_eq!(secret.to_base32(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn recovery_code_normalization() {
+        assert_eq!(RecoveryCodeHash::hash("abcd-efgh-ijkl-mnop"), RecoveryCodeHash::hash("ABCD EFGH ijkl mnop"));
+        assert_ne!(RecoveryCodeHash::hash("abcd-efgh-ijkl-mnop"), RecoveryCodeHash::hash("abcd-efgh-ijkl-mnoq"));
+    }
 }
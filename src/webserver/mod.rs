@@ -21,11 +21,109 @@ pub enum HttpMethod {
     Other,
 }
 
+/// Name of the cookie holding the CSRF token, it's readable by scripts
+pub const CSRF_COOKIE: &str = "csrf_token";
+/// Name of the form field submitting the CSRF token
+pub const CSRF_FIELD: &str = "csrf_token";
+/// Name of the header submitting the CSRF token, alternative to the form field
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Path of the cookies of the dashboard, they aren't sent to other apps on the same host
+pub fn cookie_path(prefix: &str) -> &str {
+    if prefix.is_empty() {
+        "/"
+    } else {
+        prefix
+    }
+}
+
+// Double-submit token, a foreign page can't read the cookie so it can't submit the same value
+token_newtype!(CsrfToken, 16, CsrfTokenError);
+
+#[derive(Debug, thiserror::Error)]
+pub enum CsrfError {
+    #[error("the request is missing the CSRF cookie")]
+    MissingCookie,
+    #[error("the request is missing the CSRF token")]
+    MissingToken,
+    #[error("invalid CSRF token")]
+    InvalidToken(#[source] CsrfTokenError),
+    #[error("the CSRF token doesn't match the cookie")]
+    Mismatch,
+    #[error("the request came from foreign origin {origin}")]
+    ForeignOrigin { origin: String },
+    #[error("failed to decode form data")]
+    PostForm(#[source] PostFormError),
+}
+
+/// Returns the `host[:port]` part of an URL
+fn url_authority(url: &str) -> Option<&str> {
+    let start = url.find("://")? + 3;
+    let rest = &url[start..];
+    let end = rest.find(|c| c == '/' || c == '?' || c == '#').unwrap_or_else(|| rest.len());
+    Some(&rest[..end])
+}
+
 pub trait Request {
     fn path(&self) -> &str;
     fn method(&self) -> HttpMethod;
     fn post_form_arg(&self, key: &str) -> Result<Option<&str>, PostFormError>;
+    /// Returns the decoded value of the argument in the query string
+    fn query_arg(&self, key: &str) -> Option<&str>;
     fn get_cookie(&self, key: &str) -> Option<&str>;
+    /// Returns the value of the header if it's present and valid UTF-8
+    fn get_header(&self, name: &str) -> Option<&str>;
+
+    /// Returns the CSRF token stored in the cookie if it's valid
+    fn csrf_cookie(&self) -> Option<CsrfToken> {
+        self.get_cookie(CSRF_COOKIE)?.parse().ok()
+    }
+
+    /// Checks that `Origin` (or `Referer` if missing) points to the host the request was sent to.
+    ///
+    /// Requests without either header are accepted and rely on the CSRF token.
+    fn check_origin(&self) -> Result<(), CsrfError> {
+        let source = match self.get_header("origin").or_else(|| self.get_header("referer")) {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        // The reverse proxy may rewrite Host, browsers don't allow pages to set X-Forwarded-Host
+        let host = self
+            .get_header("x-forwarded-host")
+            .and_then(|hosts| hosts.rsplit(',').next())
+            .or_else(|| self.get_header("host"))
+            .map(str::trim);
+
+        match (url_authority(source), host) {
+            (Some(source_host), Some(host)) if source_host.eq_ignore_ascii_case(host) => Ok(()),
+            _ => Err(CsrfError::ForeignOrigin { origin: source.to_owned(), }),
+        }
+    }
+
+    /// Checks the origin and that the token submitted in the header or form matches the cookie
+    fn check_csrf(&self) -> Result<(), CsrfError> {
+        self.check_origin()?;
+
+        let cookie = self
+            .get_cookie(CSRF_COOKIE)
+            .ok_or(CsrfError::MissingCookie)?
+            .parse::<CsrfToken>()
+            .map_err(CsrfError::InvalidToken)?;
+        let submitted = match self.get_header(CSRF_HEADER) {
+            Some(token) => token,
+            None => self
+                .post_form_arg(CSRF_FIELD)
+                .map_err(CsrfError::PostForm)?
+                .ok_or(CsrfError::MissingToken)?,
+        };
+        let submitted = submitted.parse::<CsrfToken>().map_err(CsrfError::InvalidToken)?;
+
+        if submitted == cookie {
+            Ok(())
+        } else {
+            Err(CsrfError::Mismatch)
+        }
+    }
 }
 
 pub trait Connection {
@@ -81,6 +179,118 @@ pub trait ResponseBuilder: Sized {
     fn with_status(status: u16) -> Self;
     fn set_body(&mut self, body: Vec<u8>);
     fn set_content_type(&mut self, content_type: &str);
-    fn set_cookie(&mut self, key: &str, value: &str, expires_after_seconds: Option<u64>);
+    /// Sets a cookie hidden from scripts and sent only to paths below `path`
+    fn set_cookie(&mut self, key: &str, value: &str, path: &str, expires_after_seconds: Option<u64>);
+    /// Sets the CSRF cookie for the whole application, unlike other cookies it's readable by scripts
+    fn set_csrf_cookie(&mut self, token: &CsrfToken, path: &str);
     fn redirect(url: &str, kind: RedirectKind) -> Self;
+    /// Panics if the value is not a valid header value, only use it with checked values
+    fn set_header(&mut self, name: &'static str, value: &str);
+    /// Adds the header even if it's already present
+    ///
+    /// Panics if the name or the value is invalid, only use it with checked values
+    fn append_header(&mut self, name: &str, value: &str);
+}
+
+/// Creates a response with the value serialized as JSON
+pub fn json_response<B: ResponseBuilder, T: serde::Serialize>(value: &T) -> B {
+    let serialized_response = serde_json::to_string(value).expect("Serialization to string should never fail");
+    let mut builder = B::with_status(200);
+    builder.set_content_type("application/json");
+    builder.set_body(serialized_response.into());
+
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{Request, HttpMethod, PostFormError, CsrfError};
+
+    #[derive(Default)]
+    struct TestRequest {
+        headers: HashMap<&'static str, &'static str>,
+        cookies: HashMap<&'static str, &'static str>,
+        form: HashMap<&'static str, &'static str>,
+    }
+
+    impl Request for TestRequest {
+        fn path(&self) -> &str {
+            "/dashboard/logout"
+        }
+
+        fn method(&self) -> HttpMethod {
+            HttpMethod::Post
+        }
+
+        fn post_form_arg(&self, key: &str) -> Result<Option<&str>, PostFormError> {
+            Ok(self.form.get(key).copied())
+        }
+
+        fn query_arg(&self, _key: &str) -> Option<&str> {
+            None
+        }
+
+        fn get_cookie(&self, key: &str) -> Option<&str> {
+            self.cookies.get(key).copied()
+        }
+
+        fn get_header(&self, name: &str) -> Option<&str> {
+            self.headers.get(name).copied()
+        }
+    }
+
+    const TOKEN: &str = "000102030405060708090a0b0c0d0e0f";
+
+    #[test]
+    fn csrf_token() {
+        let mut request = TestRequest::default();
+        match request.check_csrf() {
+            Err(CsrfError::MissingCookie) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        request.cookies.insert("csrf_token", TOKEN);
+        match request.check_csrf() {
+            Err(CsrfError::MissingToken) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        request.form.insert("csrf_token", "0f0e0d0c0b0a09080706050403020100");
+        match request.check_csrf() {
+            Err(CsrfError::Mismatch) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        request.form.insert("csrf_token", TOKEN);
+        request.check_csrf().unwrap();
+
+        request.form.clear();
+        request.headers.insert("x-csrf-token", TOKEN);
+        request.check_csrf().unwrap();
+    }
+
+    #[test]
+    fn origin() {
+        let mut request = TestRequest::default();
+        request.headers.insert("host", "example.com:8080");
+        request.check_origin().unwrap();
+
+        request.headers.insert("referer", "https://example.com:8080/dashboard/login");
+        request.check_origin().unwrap();
+
+        request.headers.insert("origin", "https://evil.example.com");
+        assert!(request.check_origin().is_err());
+
+        request.headers.insert("origin", "null");
+        assert!(request.check_origin().is_err());
+
+        request.headers.insert("origin", "https://EXAMPLE.com:8080");
+        request.check_origin().unwrap();
+
+        request.headers.insert("host", "127.0.0.1:9009");
+        assert!(request.check_origin().is_err());
+        request.headers.insert("x-forwarded-host", "example.com:8080");
+        request.check_origin().unwrap();
+    }
 }
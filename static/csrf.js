@@ -0,0 +1,12 @@
+// Forms have to submit the CSRF token set by the server in a cookie, requests made by scripts send
+// it in the X-CSRF-Token header
+var csrfCookie = document.cookie.match(/(?:^|; )csrf_token=([0-9a-f]+)/);
+var csrfToken = csrfCookie ? csrfCookie[1] : "";
+var postForms = document.querySelectorAll("form[method=post]");
+for (var i = 0; i < postForms.length; i++) {
+    var csrfInput = document.createElement("input");
+    csrfInput.type = "hidden";
+    csrfInput.name = "csrf_token";
+    csrfInput.value = csrfToken;
+    postForms[i].appendChild(csrfInput);
+}
@@ -5,43 +5,97 @@ toggleBtn = function(){
     btn.style.display = "block";
 }
 
+showWarning = function(message) {
+    document.getElementById("fort-warning").innerHTML = message;
+    document.getElementById("fort-warning").parentNode.classList.add("fort-red");
+}
+
+// The wallet signs the challenge on its own, the page polls until it's done and then finishes the
+// login in this browser
+startLnurl = function() {
+    var request = new XMLHttpRequest();
+    request.open("POST", "/dashboard/lnurl-auth/login");
+    request.setRequestHeader("X-CSRF-Token", csrfToken);
+    request.onload = function() {
+        if (request.status != 200) {
+            showWarning("Login with Lightning is not available.");
+            return;
+        }
+        var challenge = JSON.parse(request.responseText);
+        document.getElementsByClassName("fort-loginForm")[0].style.display = "none";
+        document.getElementById("fort-lnurlQr").innerHTML = challenge.qr_svg;
+        document.getElementById("fort-lnurlLink").href = "lightning:" + challenge.lnurl;
+        document.getElementById("fort-lnurl").style.display = "block";
+        pollLnurl(challenge.k1);
+    };
+    request.onerror = function() {
+        showWarning("Something went wrong. Please try again!");
+    };
+    request.send();
+}
+
+pollLnurl = function(k1) {
+    var request = new XMLHttpRequest();
+    request.open("GET", "/dashboard/lnurl-auth/status/" + k1);
+    request.onload = function() {
+        var status = request.status == 200 ? JSON.parse(request.responseText).status : "failed";
+        if (status == "pending") {
+            setTimeout(function() { pollLnurl(k1); }, 2000);
+        } else if (status == "verified") {
+            document.getElementById("fort-lnurlK1").value = k1;
+            document.getElementById("fort-lnurlRememberMe").disabled = !document.getElementById("rememberMe").checked;
+            document.getElementById("fort-lnurlForm").submit();
+        } else if (status == "expired") {
+            document.getElementById("fort-lnurl").style.display = "none";
+            document.getElementsByClassName("fort-loginForm")[0].style.display = "block";
+            showWarning("The code expired. Please try again!");
+        } else {
+            document.getElementById("fort-lnurl").style.display = "none";
+            document.getElementsByClassName("fort-loginForm")[0].style.display = "block";
+            showWarning("This wallet is not linked to any account. Please log in with your password and link it first!");
+        }
+    };
+    request.send();
+}
+
 var urlString = window.location.href; 
 var paramString = urlString.split("#")[1]; 
 var paramsArr = paramString.split("&"); 
 
 for (var i = 0; i < paramsArr.length; i++) { 
     var pair = paramsArr[i].split("=");
-    if ( pair[0] == "uninitialized" && pair[1] == "true" ) {
-        document.getElementById("fort-warning").innerHTML = "Welcome and please set a new password!";
-        document.getElementById("fort-warning").parentNode.classList.add("fort-red");
-        document.getElementById("fort-loginRePass").style.display = "flex";
-        document.getElementById("fort-loginBtnMain").disabled = true;	
-    }
     if ( pair[0] == "failure" && pair[1] == "credentials" ) {
         document.getElementById("fort-warning").innerHTML = "Incorrect password. Please try again!";
         document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
     }
+    if ( pair[0] == "failure" && pair[1] == "disabled" ) {
+        document.getElementById("fort-warning").innerHTML = "This account is disabled. Please contact the administrator!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+    if ( pair[0] == "second_factor" && pair[1] == "true" ) {
+        document.getElementById("fort-warning").innerHTML = "Please enter the code from your authenticator app.";
+        document.getElementsByClassName("fort-loginForm")[0].style.display = "none";
+        document.getElementById("fort-secondFactorForm").style.display = "block";
+    }
+    if ( pair[0] == "failure" && pair[1] == "second_factor" ) {
+        document.getElementById("fort-warning").innerHTML = "Invalid or expired code. Please log in again!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+    if ( pair[0] == "failure" && pair[1] == "throttled" ) {
+        document.getElementById("fort-warning").innerHTML = "Too many failed attempts. Please wait a while and try again!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+    if ( pair[0] == "failure" && pair[1] == "invite" ) {
+        document.getElementById("fort-warning").innerHTML = "This invitation is invalid, expired or was already used. Please ask the administrator for a new one!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+    if ( pair[0] == "failure" && pair[1] == "lnurl" ) {
+        document.getElementById("fort-warning").innerHTML = "Login with Lightning failed or expired. Please try again!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
     if ( pair[0] == "failure" && pair[1] == "input" ) {
         document.getElementById("fort-warning").innerHTML = "Something went wrong. Please try again!";
         document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
     }
 }
 
-checkInput = function(){
-    var firstInput = document.getElementById("inputPassword").value;
-    var secondInput = document.getElementById("inputRePassword").value;
-
-    if (firstInput === secondInput) {
-        // do something here if inputs are same
-        document.getElementById("fort-info").style.display = "none";
-        console.log("Good to go!");
-        document.getElementById("fort-loginBtnMain").disabled = false;
-    } else if (firstInput > secondInput) {
-        document.getElementById("fort-loginBtnMain").disabled = true;
-        document.getElementById("fort-info").style.display = "flex";
-        
-    } else {
-        document.getElementById("fort-loginBtnMain").disabled = true;
-        document.getElementById("fort-info").style.display = "flex";
-    }
-}
\ No newline at end of file
@@ -0,0 +1,32 @@
+var urlString = window.location.href; 
+var paramString = urlString.split("#")[1]; 
+var paramsArr = paramString ? paramString.split("&") : []; 
+
+for (var i = 0; i < paramsArr.length; i++) { 
+    var pair = paramsArr[i].split("=");
+    if ( pair[0] == "failure" && pair[1] == "token" ) {
+        document.getElementById("fort-warning").innerHTML = "Invalid setup token. Please check the log and try again!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+    if ( pair[0] == "failure" && pair[1] == "exists" ) {
+        document.getElementById("fort-warning").innerHTML = "This user already exists. Please choose a different name!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+    if ( pair[0] == "failure" && pair[1] == "input" ) {
+        document.getElementById("fort-warning").innerHTML = "Something went wrong. Please try again!";
+        document.getElementById("fort-warning").parentNode.classList.add("fort-red");	
+    }
+}
+
+checkInput = function(){
+    var firstInput = document.getElementById("inputPassword").value;
+    var secondInput = document.getElementById("inputRePassword").value;
+
+    if (firstInput !== "" && firstInput === secondInput) {
+        document.getElementById("fort-info").style.display = "none";
+        document.getElementById("fort-loginBtnMain").disabled = false;
+    } else {
+        document.getElementById("fort-loginBtnMain").disabled = true;
+        document.getElementById("fort-info").style.display = secondInput === "" ? "none" : "flex";
+    }
+}